@@ -0,0 +1,87 @@
+//! Arbitrary sequences of transactions on a few clients and ids, so that disputes, holds and
+//! reversals keep landing on transactions that exist. Processing may reject any of them, but
+//! must not panic, and after every step the balances must still add up to the stored
+//! transactions, see `payments::invariants`.
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+
+use payments::invariants::verify_funds;
+use payments::transactions::{Amount, Clock, PaymentEngine, Transaction};
+
+#[derive(Debug, Arbitrary)]
+enum Op {
+    Deposit(u8, u8, Cents),
+    Withdrawal(u8, u8, Cents),
+    Hold(u8, u8, Cents),
+    Dispute(u8, u8),
+    Resolve(u8, u8),
+    Chargeback(u8, u8),
+    Reversal(u8, u8),
+    ChargebackReversal(u8, u8),
+    Capture(u8, u8),
+    Release(u8, u8),
+}
+
+/// Amount in hundredths, up to the largest amounts the engine has to add up.
+#[derive(Debug, Arbitrary)]
+struct Cents(i64);
+
+impl Cents {
+    fn amount(&self) -> Amount {
+        let cents = self.0 % 1_000_000_000_000;
+        let sign = if cents < 0 { "-" } else { "" };
+        let cents = cents.unsigned_abs();
+        format!("{}{}.{:02}", sign, cents / 100, cents % 100)
+            .parse()
+            .unwrap()
+    }
+}
+
+fuzz_target!(|ops: Vec<Op>| {
+    let mut engine = PaymentEngine::new();
+    engine.set_clock(Clock::Simulated(0));
+    for (step, op) in ops.iter().enumerate() {
+        if let Some(transaction) = transaction(op) {
+            let _ = engine.process_transaction(transaction);
+        }
+        let discrepancies = verify_funds(
+            engine.accounts_iter(),
+            engine.transactions_iter(),
+            engine.tombstones(),
+            engine.pruned_funds(),
+        );
+        assert!(
+            discrepancies.is_empty(),
+            "after step {} of {:?}: {:?}",
+            step,
+            ops,
+            discrepancies
+        );
+    }
+});
+
+/// The transaction of `op`, with clients below 4 and ids below 16; `None` for amounts the
+/// constructors reject.
+fn transaction(op: &Op) -> Option<Transaction> {
+    let client = |client: &u8| u16::from(*client % 4);
+    let tx = |tx: &u8| u32::from(*tx % 16);
+    match op {
+        Op::Deposit(c, t, cents) => Transaction::new_deposit(client(c), tx(t), cents.amount()).ok(),
+        Op::Withdrawal(c, t, cents) => {
+            Transaction::new_withdrawal(client(c), tx(t), cents.amount()).ok()
+        }
+        Op::Hold(c, t, cents) => Transaction::new_hold(client(c), tx(t), cents.amount()).ok(),
+        Op::Dispute(c, t) => Some(Transaction::new_dispute(client(c), tx(t))),
+        Op::Resolve(c, t) => Some(Transaction::new_resolve(client(c), tx(t))),
+        Op::Chargeback(c, t) => Some(Transaction::new_chargeback(client(c), tx(t))),
+        Op::Reversal(c, t) => Some(Transaction::new_reversal(client(c), tx(t))),
+        Op::ChargebackReversal(c, t) => {
+            Some(Transaction::new_chargeback_reversal(client(c), tx(t)))
+        }
+        Op::Capture(c, t) => Some(Transaction::new_capture(client(c), tx(t))),
+        Op::Release(c, t) => Some(Transaction::new_release(client(c), tx(t))),
+    }
+}
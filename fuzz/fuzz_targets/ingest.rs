@@ -0,0 +1,76 @@
+//! Arbitrary bytes as an input file: CSV under the dialect and precision policy picked by the
+//! first byte, every statement format, and the JSON read back from the dead letter queue and
+//! from snapshots. Nothing read may panic, neither the parsing nor the engine processing what
+//! was parsed.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use payments::dlq::DeadLetter;
+use payments::ingest::{
+    check_records, read_statement, records_from_reader, AccountMap, AmountFormat, CsvDialect,
+    InputFormat, PrecisionMode, PrecisionPolicy,
+};
+use payments::snapshot::EngineState;
+use payments::transactions::{Clock, PaymentEngine};
+
+const STATEMENTS: [InputFormat; 3] = [InputFormat::Iso20022, InputFormat::Ofx, InputFormat::Qif];
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&options, input)) = data.split_first() else {
+        return;
+    };
+    let dialect = dialect(options);
+    let precision = PrecisionPolicy {
+        max_decimal_places: 4,
+        mode: if options & 0x40 == 0 {
+            PrecisionMode::Reject
+        } else {
+            PrecisionMode::Truncate
+        },
+    };
+
+    let mut engine = PaymentEngine::new();
+    engine.set_clock(Clock::Simulated(0));
+    for record in records_from_reader(input, &dialect) {
+        if let Ok(transaction) = record.into_transaction(&precision) {
+            let _ = engine.process_transaction(transaction);
+        }
+    }
+    let _ = check_records(input, &precision, &dialect);
+
+    let accounts: AccountMap = [("ACC-1".to_string(), 1)].into_iter().collect();
+    for format in STATEMENTS {
+        if let Ok(records) = read_statement(input, format, &accounts) {
+            for record in records.into_iter().flatten() {
+                if let Ok(transaction) = record.into_transaction(&precision) {
+                    let _ = engine.process_transaction(transaction);
+                }
+            }
+        }
+    }
+
+    for line in input.split(|byte| *byte == b'\n') {
+        let _ = serde_json::from_slice::<DeadLetter>(line);
+    }
+    if let Ok(state) = serde_json::from_slice::<EngineState>(input) {
+        PaymentEngine::new().restore(state);
+    }
+});
+
+/// The dialect encoded by the bits of `options`.
+fn dialect(options: u8) -> CsvDialect {
+    CsvDialect {
+        delimiter: b",;\t|"[usize::from(options & 0x03)],
+        has_headers: options & 0x04 == 0,
+        quoting: options & 0x08 == 0,
+        amounts: [
+            AmountFormat::Plain,
+            AmountFormat::Point,
+            AmountFormat::Comma,
+            AmountFormat::Auto,
+        ][usize::from((options >> 4) & 0x03)],
+        ..CsvDialect::default()
+    }
+}
@@ -0,0 +1,37 @@
+/* C interface of the payments engine, built with `cargo build --release --features ffi`. */
+
+#ifndef PAYMENTS_H
+#define PAYMENTS_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Status codes of payments_process_csv_line. */
+#define PAYMENTS_OK 0
+#define PAYMENTS_REJECTED 1
+#define PAYMENTS_INVALID 2
+#define PAYMENTS_BAD_ARGUMENT (-1)
+
+typedef struct PaymentEngine PaymentEngine;
+
+/* Engine with the default policies, released with payments_engine_free. */
+PaymentEngine *payments_engine_new(void);
+
+/* Applies one `type,client,tx,amount` line without header, returns a PAYMENTS_* code. */
+int32_t payments_process_csv_line(PaymentEngine *engine, const char *line);
+
+/* JSON array of the accounts, released with payments_string_free; NULL if engine is NULL. */
+char *payments_export_accounts_json(const PaymentEngine *engine);
+
+void payments_string_free(char *string);
+
+void payments_engine_free(PaymentEngine *engine);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,245 @@
+//! Python bindings of the engine, for driving it from pandas pipelines. Build and install them
+//! into the current virtualenv with `maturin develop --release` from this directory, then
+//! `import payments`.
+//!
+//! Records go through the CSV parsing of the command line tool, so every record type and
+//! column it reads is accepted with the same precision rules. Amounts come back as
+//! `decimal.Decimal`, so no precision is lost.
+
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{IntoPyDict, PyDict, PyList, PyTuple};
+
+use payments::ingest::{records_from_reader, CsvDialect, PrecisionPolicy};
+use payments::transactions::{
+    Account, Client, PaymentEngine, TransactionId, TransactionValidationError,
+};
+
+create_exception!(
+    payments,
+    TransactionRejected,
+    PyException,
+    "The engine refused a transaction; the arguments are its error code and the reason."
+);
+
+/// Columns of records given as tuples.
+const COLUMNS: [&str; 4] = ["type", "client", "tx", "amount"];
+
+/// An engine with the default policies, kept across calls.
+#[pyclass(name = "PaymentEngine", module = "payments")]
+#[derive(Default)]
+struct Engine {
+    engine: PaymentEngine,
+}
+
+#[pymethods]
+impl Engine {
+    #[new]
+    fn new() -> Self {
+        Self::default()
+    }
+
+    /// Applies one record. `kind` is a record type of the input such as `deposit` or
+    /// `chargeback`, and `amount` a string, int, float or Decimal, left out by the types
+    /// without one. Raises ValueError for records that aren't valid and TransactionRejected
+    /// for those the engine refuses.
+    #[pyo3(signature = (kind, client, tx, amount=None))]
+    fn process_transaction(
+        &mut self,
+        kind: &str,
+        client: Client,
+        tx: TransactionId,
+        amount: Option<&Bound<'_, PyAny>>,
+    ) -> PyResult<()> {
+        let mut wtr = csv::Writer::from_writer(vec![]);
+        let amount = amount.map(text).transpose()?.unwrap_or_default();
+        write_row(
+            &mut wtr,
+            [kind.to_string(), client.to_string(), tx.to_string(), amount],
+        )?;
+        let line = into_text(wtr)?;
+        let dialect = CsvDialect {
+            has_headers: false,
+            ..CsvDialect::default()
+        };
+        let record = records_from_reader(line.as_bytes(), &dialect)
+            .next()
+            .ok_or_else(|| PyValueError::new_err(format!("invalid record: {}", line.trim())))?;
+        let transaction = record
+            .into_transaction(&PrecisionPolicy::default())
+            .map_err(|err| PyValueError::new_err(err.to_string()))?;
+        self.engine
+            .process_transaction(transaction)
+            .map_err(|err| TransactionRejected::new_err((err.code(), err.to_string())))
+    }
+
+    /// Applies `records` in order: a pandas DataFrame with the columns of the input (`type`,
+    /// `client`, `tx`, `amount` and the optional ones), a list of dicts keyed by those columns
+    /// or a list of `(type, client, tx, amount)` tuples. Returns a dict with the number of
+    /// records `applied`, the number that were `invalid` and the `rejected` ones, each a dict
+    /// with its `client`, `tx`, `code` and `reason`.
+    fn process_batch<'py>(
+        &mut self,
+        py: Python<'py>,
+        records: &Bound<'py, PyAny>,
+    ) -> PyResult<Bound<'py, PyDict>> {
+        let rows = records.len()?;
+        let input = batch_csv(py, records)?;
+        let mut read = 0;
+        let mut applied = 0;
+        let rejected = PyList::empty_bound(py);
+        for record in records_from_reader(input.as_bytes(), &CsvDialect::default()) {
+            read += 1;
+            let result = record
+                .into_transaction(&PrecisionPolicy::default())
+                .and_then(|transaction| self.engine.process_transaction(transaction));
+            match result {
+                Ok(()) => applied += 1,
+                Err(err) => rejected.append(rejection(py, &err)?)?,
+            }
+        }
+        let result = PyDict::new_bound(py);
+        result.set_item("applied", applied)?;
+        result.set_item("invalid", rows.saturating_sub(read))?;
+        result.set_item("rejected", rejected)?;
+        Ok(result)
+    }
+
+    /// The account of `client` as a dict with its `client`, `available`, `held`, `total` and
+    /// `locked` keys, None for clients without one.
+    fn get_account<'py>(
+        &self,
+        py: Python<'py>,
+        client: Client,
+    ) -> PyResult<Option<Bound<'py, PyDict>>> {
+        self.engine
+            .get_account(client)
+            .map(|account| account_dict(py, account))
+            .transpose()
+    }
+
+    /// Every account as a dict like `get_account`, sorted by client; `pandas.DataFrame` makes
+    /// them a report.
+    fn accounts<'py>(&self, py: Python<'py>) -> PyResult<Vec<Bound<'py, PyDict>>> {
+        self.engine
+            .accounts_iter()
+            .map(|account| account_dict(py, account))
+            .collect()
+    }
+}
+
+/// The rows of a batch as a CSV document with a header row.
+fn batch_csv(py: Python<'_>, records: &Bound<'_, PyAny>) -> PyResult<String> {
+    // DataFrames write themselves in the input format
+    if records.hasattr("to_csv")? {
+        let options = [("index", false)].into_py_dict_bound(py);
+        return records.call_method("to_csv", (), Some(&options))?.extract();
+    }
+    let rows: Vec<Bound<'_, PyAny>> = records.iter()?.collect::<PyResult<_>>()?;
+    let mut wtr = csv::Writer::from_writer(vec![]);
+    if rows.iter().all(|row| row.is_instance_of::<PyDict>()) {
+        // every column any of the dicts has, in the order they first appear
+        let mut columns: Vec<String> = vec![];
+        for row in &rows {
+            for key in row.downcast::<PyDict>()?.keys() {
+                let key: String = key.extract()?;
+                if !columns.contains(&key) {
+                    columns.push(key);
+                }
+            }
+        }
+        write_row(&mut wtr, columns.iter().cloned())?;
+        for row in &rows {
+            let row = row.downcast::<PyDict>()?;
+            let mut values = vec![];
+            for column in &columns {
+                values.push(match row.get_item(column)? {
+                    Some(value) => text(&value)?,
+                    None => String::new(),
+                });
+            }
+            write_row(&mut wtr, values)?;
+        }
+    } else {
+        write_row(&mut wtr, COLUMNS.map(String::from))?;
+        for row in &rows {
+            let row = row
+                .downcast::<PyTuple>()
+                .map_err(|_| PyValueError::new_err("records must all be dicts or all be tuples"))?;
+            if !(3..=COLUMNS.len()).contains(&row.len()) {
+                return Err(PyValueError::new_err(
+                    "tuples must be (type, client, tx) or (type, client, tx, amount)",
+                ));
+            }
+            let mut values: Vec<String> = row
+                .iter()
+                .map(|value| text(&value))
+                .collect::<PyResult<_>>()?;
+            values.resize(COLUMNS.len(), String::new());
+            write_row(&mut wtr, values)?;
+        }
+    }
+    into_text(wtr)
+}
+
+/// A cell of the CSV input: empty for None, what `str` gives otherwise.
+fn text(value: &Bound<'_, PyAny>) -> PyResult<String> {
+    if value.is_none() {
+        return Ok(String::new());
+    }
+    value.str()?.extract()
+}
+
+fn write_row<I>(wtr: &mut csv::Writer<Vec<u8>>, values: I) -> PyResult<()>
+where
+    I: IntoIterator<Item = String>,
+{
+    wtr.write_record(values)
+        .map_err(|err| PyValueError::new_err(err.to_string()))
+}
+
+fn into_text(wtr: csv::Writer<Vec<u8>>) -> PyResult<String> {
+    let bytes = wtr
+        .into_inner()
+        .map_err(|err| PyValueError::new_err(err.to_string()))?;
+    String::from_utf8(bytes).map_err(|err| PyValueError::new_err(err.to_string()))
+}
+
+fn account_dict<'py>(py: Python<'py>, account: &Account) -> PyResult<Bound<'py, PyDict>> {
+    let decimal = py.import_bound("decimal")?.getattr("Decimal")?;
+    let dict = PyDict::new_bound(py);
+    dict.set_item("client", account.client())?;
+    for (key, amount) in [
+        ("available", account.available()),
+        ("held", account.held()),
+        ("total", account.total()),
+    ] {
+        dict.set_item(key, decimal.call1((amount.to_string(),))?)?;
+    }
+    dict.set_item("locked", account.frozen())?;
+    Ok(dict)
+}
+
+fn rejection<'py>(
+    py: Python<'py>,
+    err: &TransactionValidationError,
+) -> PyResult<Bound<'py, PyDict>> {
+    let dict = PyDict::new_bound(py);
+    dict.set_item("client", err.client())?;
+    dict.set_item("tx", err.tx())?;
+    dict.set_item("code", err.code())?;
+    dict.set_item("reason", err.to_string())?;
+    Ok(dict)
+}
+
+#[pymodule]
+#[pyo3(name = "payments")]
+fn payments_module(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<Engine>()?;
+    module.add(
+        "TransactionRejected",
+        module.py().get_type_bound::<TransactionRejected>(),
+    )?;
+    Ok(())
+}
@@ -0,0 +1,71 @@
+"""Run with `maturin develop` and `pytest` from the python directory."""
+
+from decimal import Decimal
+
+import pytest
+
+import payments
+
+
+def test_transactions_are_applied_one_by_one():
+    engine = payments.PaymentEngine()
+    engine.process_transaction("deposit", 1, 1, "10.5")
+    engine.process_transaction("withdrawal", 1, 2, Decimal("0.5"))
+    engine.process_transaction("dispute", 1, 1)
+
+    assert engine.get_account(1) == {
+        "client": 1,
+        "available": Decimal("-0.5"),
+        "held": Decimal("10.5"),
+        "total": Decimal("10"),
+        "locked": False,
+    }
+    assert engine.get_account(2) is None
+
+    with pytest.raises(payments.TransactionRejected) as rejected:
+        engine.process_transaction("withdrawal", 1, 3, 100)
+    assert rejected.value.args[0] == "insufficient_funds"
+    with pytest.raises(ValueError):
+        engine.process_transaction("deposit", 1, 4, "abc")
+
+
+def test_batches_come_from_lists_of_tuples_or_dicts():
+    engine = payments.PaymentEngine()
+    result = engine.process_batch(
+        [("deposit", 1, 1, 2.5), ("deposit", 2, 2, "1"), ("withdrawal", 2, 3, 5)]
+    )
+    assert result["applied"] == 2
+    assert result["invalid"] == 0
+    assert [rejection["tx"] for rejection in result["rejected"]] == [3]
+    assert result["rejected"][0]["code"] == "insufficient_funds"
+
+    result = engine.process_batch(
+        [
+            {"type": "deposit", "client": 3, "tx": 4, "amount": "1", "memo": "INV-1"},
+            {"type": "dispute", "client": 3, "tx": 4},
+            {"type": "deposit", "client": 3, "tx": 5, "amount": "not a number"},
+        ]
+    )
+    assert (result["applied"], result["invalid"]) == (2, 1)
+    assert [account["client"] for account in engine.accounts()] == [1, 2, 3]
+    assert engine.get_account(3)["held"] == Decimal("1")
+
+
+def test_batches_come_from_dataframes():
+    pandas = pytest.importorskip("pandas")
+    frame = pandas.DataFrame(
+        {
+            "type": ["deposit", "deposit", "chargeback"],
+            "client": [1, 1, 1],
+            "tx": [1, 2, 1],
+            "amount": ["3", "4", None],
+        }
+    )
+    engine = payments.PaymentEngine()
+    result = engine.process_batch(frame)
+    assert result["applied"] == 2
+    assert result["rejected"][0]["tx"] == 1
+
+    report = pandas.DataFrame(engine.accounts())
+    assert list(report.columns) == ["client", "available", "held", "total", "locked"]
+    assert report.loc[0, "total"] == Decimal("7")
@@ -0,0 +1,192 @@
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::thread::{self, JoinHandle};
+
+use crate::diagnostics::ErrorEvent;
+use crate::dlq::{self, report_dead_letter};
+use crate::ledger::JournalEntry;
+use crate::metrics::EngineMetrics;
+use crate::shared::TransactionIds;
+use crate::snapshot::PeriodStatement;
+use crate::transactions::{Account, PaymentEngine, Transaction, TransactionId, FIRST_GENERATED_ID};
+
+/// Number of transactions buffered per worker before `route` blocks.
+const MAILBOX_SIZE: usize = 1024;
+
+/// Processes transactions on worker threads, each owning the accounts of the clients routed
+/// to it. Transactions of one client always reach the same worker, so they are applied in the
+/// order they were routed, while different clients are processed in parallel without sharing
+/// any account state.
+///
+/// Every worker has its own engine, so stateful risk scorers and observers only see the
+/// transactions of their worker's clients. Transaction ids are claimed in routing order and
+/// stay claimed even when the worker later rejects the transaction.
+pub struct ActorRouter {
+    mailboxes: Vec<SyncSender<Transaction>>,
+    workers: Vec<JoinHandle<PaymentEngine>>,
+    ids: TransactionIds,
+}
+
+impl ActorRouter {
+    pub fn new(worker_count: usize) -> Self {
+        Self::with_engines(worker_count, PaymentEngine::new)
+    }
+
+    /// Gives every worker an engine built by `make_engine`.
+    pub fn with_engines<F>(worker_count: usize, make_engine: F) -> Self
+    where
+        F: Fn() -> PaymentEngine,
+    {
+        let mut mailboxes = vec![];
+        let mut workers = vec![];
+        let worker_count = worker_count.max(1);
+        for worker in 0..worker_count {
+            let (sender, receiver) = sync_channel::<Transaction>(MAILBOX_SIZE);
+            let mut engine = make_engine();
+            engine.set_generated_ids(
+                FIRST_GENERATED_ID + worker as TransactionId,
+                worker_count as TransactionId,
+            );
+            workers.push(thread::spawn(move || {
+                for transaction in receiver {
+                    let memo = transaction.memo().map(str::to_string);
+                    let retry = dlq::enabled().then(|| transaction.clone());
+                    if let Err(err) = engine.process_transaction(transaction) {
+                        report_dead_letter(
+                            ErrorEvent::rejected(&err).with_memo(memo.as_deref()),
+                            retry,
+                            None,
+                        );
+                    }
+                }
+                engine
+            }));
+            mailboxes.push(sender);
+        }
+        Self {
+            mailboxes,
+            workers,
+            ids: TransactionIds::default(),
+        }
+    }
+
+    /// Hands the transaction to the worker owning its client, blocking while that
+    /// worker's mailbox is full. Clock moves and period ends go to every worker.
+    pub fn route(&self, transaction: Transaction) {
+        if let Transaction::AdvanceTime { .. } | Transaction::ClosePeriod { .. } = transaction {
+            for mailbox in &self.mailboxes {
+                if mailbox.send(transaction.clone()).is_err() {
+                    log::error!("worker stopped, {} dropped", transaction.kind().name());
+                }
+            }
+            return;
+        }
+        if let Err(err) = self.ids.claim(&transaction) {
+            let event = ErrorEvent::rejected(&err).with_memo(transaction.memo());
+            report_dead_letter(event, Some(transaction), None);
+            return;
+        }
+        let mailbox = &self.mailboxes[transaction.client() as usize % self.mailboxes.len()];
+        // a worker only stops once its mailbox is dropped, unless it panicked
+        if mailbox.send(transaction).is_err() {
+            log::error!("worker stopped, transaction dropped");
+        }
+    }
+
+    /// Waits for every routed transaction to be processed and returns the workers' engines.
+    pub fn finish(self) -> Vec<PaymentEngine> {
+        drop(self.mailboxes);
+        self.workers
+            .into_iter()
+            .filter_map(|worker| worker.join().ok())
+            .collect()
+    }
+}
+
+/// Accounts of all engines, sorted by client.
+pub fn merged_accounts(engines: &[PaymentEngine]) -> Vec<&Account> {
+    let mut accounts: Vec<&Account> = engines
+        .iter()
+        .flat_map(|engine| engine.iter_accounts())
+        .collect();
+    accounts.sort_by_key(|account| account.client());
+    accounts
+}
+
+pub fn merged_transactions(engines: &[PaymentEngine]) -> Vec<&Transaction> {
+    let mut transactions: Vec<&Transaction> = engines
+        .iter()
+        .flat_map(|engine| engine.transactions_iter())
+        .collect();
+    transactions.sort_by_key(|transaction| transaction.tx());
+    transactions
+}
+
+/// Journal entries of every engine, each engine's in posting order.
+pub fn merged_journal(engines: &[PaymentEngine]) -> Vec<&JournalEntry> {
+    engines
+        .iter()
+        .flat_map(|engine| engine.ledger().entries())
+        .collect()
+}
+
+/// Statements of every engine, by period and client.
+pub fn merged_statements(engines: &[PaymentEngine]) -> Vec<&PeriodStatement> {
+    let mut statements: Vec<&PeriodStatement> = engines
+        .iter()
+        .flat_map(|engine| engine.statements())
+        .collect();
+    statements.sort_by_key(|statement| (statement.period, statement.client));
+    statements
+}
+
+pub fn merged_metrics(engines: &[PaymentEngine]) -> EngineMetrics {
+    let mut metrics = EngineMetrics::default();
+    for engine in engines {
+        metrics.merge(&engine.metrics());
+    }
+    metrics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::amount;
+    use crate::transactions::TransactionKind;
+
+    #[test]
+    fn transactions_of_a_client_are_applied_in_order() {
+        let router = ActorRouter::new(3);
+        for client in 1..=6u16 {
+            let base = client as u32 * 1000;
+            router.route(Transaction::new_deposit(client, base, amount!(10.0)).unwrap());
+            router.route(Transaction::new_withdrawal(client, base + 1, amount!(4.0)).unwrap());
+            router.route(Transaction::new_dispute(client, base));
+            router.route(Transaction::new_resolve(client, base));
+        }
+        let engines = router.finish();
+        assert_eq!(engines.len(), 3);
+
+        let accounts = merged_accounts(&engines);
+        let clients: Vec<_> = accounts.iter().map(|account| account.client()).collect();
+        assert_eq!(clients, vec![1, 2, 3, 4, 5, 6]);
+        for account in accounts {
+            assert_eq!(account.available(), amount!(6.0));
+            assert_eq!(account.held(), amount!(0.0));
+        }
+        assert_eq!(
+            merged_metrics(&engines).applied(TransactionKind::Resolve),
+            6
+        );
+    }
+
+    #[test]
+    fn transaction_ids_are_unique_across_workers() {
+        let router = ActorRouter::new(2);
+        router.route(Transaction::new_deposit(1, 1, amount!(10.0)).unwrap());
+        router.route(Transaction::new_deposit(2, 1, amount!(10.0)).unwrap());
+        let engines = router.finish();
+        let accounts = merged_accounts(&engines);
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].client(), 1);
+    }
+}
@@ -0,0 +1,337 @@
+//! Monetary amounts.
+//!
+//! Amounts are `rust_decimal::Decimal`s by default. Building with the `fixed-amount` feature
+//! stores them as `i64` counts of 1/10000 units instead, which is considerably faster but limits
+//! amounts to 4 decimal places and about ±922 trillion.
+
+pub use rust_decimal::RoundingStrategy;
+
+#[cfg(not(feature = "fixed-amount"))]
+pub type Amount = rust_decimal::Decimal;
+
+#[cfg(feature = "fixed-amount")]
+pub use fixed::{Amount, ParseAmountError};
+
+/// The amount as a `Decimal`, whichever backend is selected.
+#[cfg(not(feature = "fixed-amount"))]
+pub fn to_decimal(amount: Amount) -> rust_decimal::Decimal {
+    amount
+}
+
+#[cfg(feature = "fixed-amount")]
+pub fn to_decimal(amount: Amount) -> rust_decimal::Decimal {
+    rust_decimal::Decimal::new(amount.units(), 4)
+}
+
+/// `#[serde(with = "crate::amount::text")]` stores amounts as exact decimal strings, for
+/// formats where the default representation could lose precision.
+pub mod text {
+    use super::Amount;
+    use serde::de::{self, Deserialize, Deserializer};
+    use serde::ser::Serializer;
+
+    pub fn serialize<S: Serializer>(amount: &Amount, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(amount)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Amount, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// Builds an `Amount` from a literal in tests, whichever backend is selected.
+#[cfg(all(test, not(feature = "fixed-amount")))]
+macro_rules! amount {
+    ($($value:tt)*) => {
+        rust_decimal_macros::dec!($($value)*)
+    };
+}
+
+#[cfg(all(test, feature = "fixed-amount"))]
+macro_rules! amount {
+    ($($value:tt)*) => {
+        stringify!($($value)*)
+            .replace(' ', "")
+            .parse::<$crate::amount::Amount>()
+            .unwrap()
+    };
+}
+
+#[cfg(test)]
+pub(crate) use amount;
+
+#[cfg(feature = "fixed-amount")]
+mod fixed {
+    use rust_decimal::prelude::ToPrimitive;
+    use rust_decimal::{Decimal, RoundingStrategy};
+    use serde::de::{self, Deserialize, Deserializer, Visitor};
+    use serde::ser::{Serialize, Serializer};
+    use std::fmt;
+    use std::ops::{Add, Neg, Sub};
+    use std::str::FromStr;
+    use thiserror::Error;
+
+    const DECIMAL_PLACES: u32 = 4;
+    const UNITS: i64 = 10_000;
+
+    #[derive(Debug, Error, PartialEq, Eq)]
+    pub enum ParseAmountError {
+        #[error("invalid amount")]
+        Invalid,
+        #[error("amount has more than {} decimal places", DECIMAL_PLACES)]
+        TooPrecise,
+        #[error("amount out of range")]
+        OutOfRange,
+    }
+
+    /// Amount stored as a whole number of 1/10000 units. Mirrors the part of `Decimal`'s API
+    /// the crate relies on.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    pub struct Amount(i64);
+
+    impl Amount {
+        pub const ZERO: Amount = Amount(0);
+        // symmetric bounds, so negating an amount never overflows
+        pub const MIN: Amount = Amount(-i64::MAX);
+        pub const MAX: Amount = Amount(i64::MAX);
+
+        pub fn from_units(units: i64) -> Self {
+            Amount(units.max(-i64::MAX))
+        }
+
+        /// The amount in 1/10000 units.
+        pub fn units(self) -> i64 {
+            self.0
+        }
+
+        pub fn checked_add(self, other: Amount) -> Option<Amount> {
+            self.0
+                .checked_add(other.0)
+                .filter(|units| *units != i64::MIN)
+                .map(Amount)
+        }
+
+        pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+            self.0
+                .checked_sub(other.0)
+                .filter(|units| *units != i64::MIN)
+                .map(Amount)
+        }
+
+        pub fn abs(self) -> Amount {
+            Amount(self.0.abs())
+        }
+
+        pub fn is_sign_negative(self) -> bool {
+            self.0 < 0
+        }
+
+        /// Amounts have no trailing zeros to strip; kept for parity with `Decimal`.
+        pub fn normalize(self) -> Amount {
+            self
+        }
+
+        /// Number of decimal places needed to write the amount without trailing zeros.
+        pub fn scale(self) -> u32 {
+            let mut units = self.0;
+            let mut scale = DECIMAL_PLACES;
+            while scale > 0 && units % 10 == 0 {
+                units /= 10;
+                scale -= 1;
+            }
+            scale
+        }
+
+        pub fn round_dp(self, dp: u32) -> Amount {
+            self.round_dp_with_strategy(dp, RoundingStrategy::MidpointNearestEven)
+        }
+
+        /// Rounds through `Decimal`; this only happens at the input and output edges.
+        pub fn round_dp_with_strategy(self, dp: u32, strategy: RoundingStrategy) -> Amount {
+            if dp >= DECIMAL_PLACES {
+                return self;
+            }
+            let rounded = Decimal::new(self.0, DECIMAL_PLACES).round_dp_with_strategy(dp, strategy);
+            (rounded * Decimal::new(UNITS, 0))
+                .to_i64()
+                .map(Amount::from_units)
+                .unwrap_or(if self.is_sign_negative() {
+                    Amount::MIN
+                } else {
+                    Amount::MAX
+                })
+        }
+    }
+
+    impl Add for Amount {
+        type Output = Amount;
+
+        fn add(self, other: Amount) -> Amount {
+            self.checked_add(other).expect("Addition overflowed")
+        }
+    }
+
+    impl Sub for Amount {
+        type Output = Amount;
+
+        fn sub(self, other: Amount) -> Amount {
+            self.checked_sub(other).expect("Subtraction overflowed")
+        }
+    }
+
+    impl Neg for Amount {
+        type Output = Amount;
+
+        fn neg(self) -> Amount {
+            Amount(-self.0)
+        }
+    }
+
+    impl FromStr for Amount {
+        type Err = ParseAmountError;
+
+        /// Parses plain decimal notation exactly; values that don't fit are rejected rather
+        /// than rounded.
+        fn from_str(value: &str) -> Result<Self, Self::Err> {
+            let value = value.trim();
+            let (negative, digits) = match value.strip_prefix('-') {
+                Some(digits) => (true, digits),
+                None => (false, value.strip_prefix('+').unwrap_or(value)),
+            };
+            let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+            if (whole.is_empty() && fraction.is_empty())
+                || !whole
+                    .bytes()
+                    .chain(fraction.bytes())
+                    .all(|b| b.is_ascii_digit())
+            {
+                return Err(ParseAmountError::Invalid);
+            }
+            let fraction = fraction.trim_end_matches('0');
+            if fraction.len() > DECIMAL_PLACES as usize {
+                return Err(ParseAmountError::TooPrecise);
+            }
+
+            let whole: i64 = match whole.trim_start_matches('0') {
+                "" => 0,
+                whole => whole.parse().map_err(|_| ParseAmountError::OutOfRange)?,
+            };
+            let fraction: i64 = format!("{:0<4}", fraction).parse().unwrap_or(0);
+            let units = whole
+                .checked_mul(UNITS)
+                .and_then(|units| units.checked_add(fraction))
+                .ok_or(ParseAmountError::OutOfRange)?;
+            Ok(Amount(if negative { -units } else { units }))
+        }
+    }
+
+    impl fmt::Display for Amount {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let sign = if self.is_sign_negative() { "-" } else { "" };
+            let units = self.0.unsigned_abs();
+            let whole = units / UNITS as u64;
+            let fraction = units % UNITS as u64;
+            if fraction == 0 {
+                return write!(f, "{}{}.0", sign, whole);
+            }
+            let fraction = format!("{:04}", fraction);
+            write!(f, "{}{}.{}", sign, whole, fraction.trim_end_matches('0'))
+        }
+    }
+
+    impl Serialize for Amount {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            serializer.collect_str(self)
+        }
+    }
+
+    struct AmountVisitor;
+
+    impl<'de> Visitor<'de> for AmountVisitor {
+        type Value = Amount;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            write!(
+                formatter,
+                "an amount with at most {} decimal places",
+                DECIMAL_PLACES
+            )
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<Amount, E> {
+            value.parse().map_err(E::custom)
+        }
+
+        fn visit_f64<E: de::Error>(self, value: f64) -> Result<Amount, E> {
+            // the shortest representation round-trips, so 0.1 stays 0.1
+            self.visit_str(&value.to_string())
+        }
+
+        fn visit_i64<E: de::Error>(self, value: i64) -> Result<Amount, E> {
+            value
+                .checked_mul(UNITS)
+                .map(Amount::from_units)
+                .ok_or_else(|| E::custom(ParseAmountError::OutOfRange))
+        }
+
+        fn visit_u64<E: de::Error>(self, value: u64) -> Result<Amount, E> {
+            i64::try_from(value)
+                .map_err(|_| E::custom(ParseAmountError::OutOfRange))
+                .and_then(|value| self.visit_i64(value))
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Amount {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            deserializer.deserialize_str(AmountVisitor)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn amounts_are_parsed_and_formatted_exactly() {
+            for (input, output) in [
+                ("1", "1.0"),
+                ("1.5000", "1.5"),
+                ("-0.0001", "-0.0001"),
+                ("+.25", "0.25"),
+                ("922337203685477.5807", "922337203685477.5807"),
+            ] {
+                assert_eq!(input.parse::<Amount>().unwrap().to_string(), output);
+            }
+            assert_eq!(
+                "1.00001".parse::<Amount>(),
+                Err(ParseAmountError::TooPrecise)
+            );
+            assert_eq!("1e3".parse::<Amount>(), Err(ParseAmountError::Invalid));
+            assert_eq!(
+                "922337203685477.5808".parse::<Amount>(),
+                Err(ParseAmountError::OutOfRange)
+            );
+        }
+
+        #[test]
+        fn rounding_follows_the_strategy() {
+            let amount: Amount = "2.345".parse().unwrap();
+            assert_eq!(amount.round_dp(2).to_string(), "2.34");
+            assert_eq!(
+                amount
+                    .round_dp_with_strategy(2, RoundingStrategy::MidpointAwayFromZero)
+                    .to_string(),
+                "2.35"
+            );
+            assert_eq!(amount.scale(), 3);
+        }
+    }
+}
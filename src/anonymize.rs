@@ -0,0 +1,88 @@
+//! Pseudonyms replacing client ids in the outputs of a run, see `--anonymize`.
+//!
+//! A pseudonym is the HMAC-SHA256 of the client id keyed with a secret, so the same client gets
+//! the same pseudonym in every output and every run sharing the secret, and can't be recovered
+//! without it.
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::transactions::Client;
+
+/// Hex digits kept from the HMAC.
+const PSEUDONYM_LEN: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Anonymizer {
+    /// SHA-256 of the secret, which keeps the type `Copy` whatever the secret's length.
+    key: [u8; 32],
+}
+
+impl std::fmt::Debug for Anonymizer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Anonymizer").finish_non_exhaustive()
+    }
+}
+
+impl Anonymizer {
+    pub fn new(secret: &[u8]) -> Self {
+        Self {
+            key: Sha256::digest(secret).into(),
+        }
+    }
+
+    pub fn pseudonym(&self, client: Client) -> String {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(&client.to_be_bytes());
+        let mut pseudonym = String::with_capacity(PSEUDONYM_LEN);
+        for byte in &mac.finalize().into_bytes()[..PSEUDONYM_LEN / 2] {
+            pseudonym.push_str(&format!("{:02x}", byte));
+        }
+        pseudonym
+    }
+
+    /// `text` with the mentions of `client N`, as in rejection reasons, replaced by the
+    /// pseudonym of `client`.
+    pub fn scrub(&self, client: Client, text: &str) -> String {
+        let mention = format!("client {}", client);
+        let mut scrubbed = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(start) = rest.find(&mention) {
+            let end = start + mention.len();
+            scrubbed.push_str(&rest[..start]);
+            if rest[end..].starts_with(|c: char| c.is_ascii_digit()) {
+                scrubbed.push_str(&mention);
+            } else {
+                scrubbed.push_str("client ");
+                scrubbed.push_str(&self.pseudonym(client));
+            }
+            rest = &rest[end..];
+        }
+        scrubbed.push_str(rest);
+        scrubbed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pseudonyms_depend_on_the_secret() {
+        let anonymizer = Anonymizer::new(b"secret");
+        let pseudonym = anonymizer.pseudonym(1);
+        assert_eq!(pseudonym.len(), PSEUDONYM_LEN);
+        assert_eq!(pseudonym, Anonymizer::new(b"secret").pseudonym(1));
+        assert_ne!(pseudonym, anonymizer.pseudonym(2));
+        assert_ne!(pseudonym, Anonymizer::new(b"other").pseudonym(1));
+
+        assert_eq!(
+            anonymizer.scrub(1, "account of client 1 is frozen, client 12 is not"),
+            format!(
+                "account of client {} is frozen, client 12 is not",
+                pseudonym
+            )
+        );
+    }
+}
@@ -0,0 +1,92 @@
+//! Bloom filter of the transaction ids an engine stores, see
+//! `PaymentEngine::set_bloom_filter`.
+//!
+//! Most records of a deposit-heavy input carry a new id, and the filter answers "never seen"
+//! for them without probing the transaction store. Only the ids it may have seen are looked up.
+//! Ids are never removed from the filter. Ids dropped by `prune` or reverted by `rollback` stay
+//! "maybe seen", which only costs the lookup the filter would have saved.
+
+use crate::transactions::TransactionId;
+
+/// Rate of "maybe seen" answers for ids never inserted, as long as at most the expected number
+/// of ids were inserted.
+const FALSE_POSITIVE_RATE: f64 = 0.01;
+
+#[derive(Debug, Clone)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    /// Filter sized for `expected` ids. More ids can be inserted, at the price of more false
+    /// positives.
+    pub fn new(expected: usize) -> Self {
+        let expected = expected.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-expected * FALSE_POSITIVE_RATE.ln() / (ln2 * ln2)).ceil() as usize;
+        let words = bits.div_ceil(64).max(1);
+        let hashes = ((words * 64) as f64 / expected * ln2)
+            .round()
+            .clamp(1.0, 16.0) as u32;
+        Self {
+            bits: vec![0; words],
+            hashes,
+        }
+    }
+
+    pub fn insert(&mut self, tx: TransactionId) {
+        for bit in self.positions(tx) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    /// `false` when `tx` was never inserted, `true` when it may have been.
+    pub fn may_contain(&self, tx: TransactionId) -> bool {
+        self.positions(tx)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    pub fn clear(&mut self) {
+        self.bits.fill(0);
+    }
+
+    /// Bits set for `tx`, by double hashing.
+    fn positions(&self, tx: TransactionId) -> impl Iterator<Item = usize> {
+        let len = (self.bits.len() * 64) as u64;
+        let first = mix(u64::from(tx));
+        let step = mix(first) | 1;
+        (0..u64::from(self.hashes))
+            .map(move |i| (first.wrapping_add(i.wrapping_mul(step)) % len) as usize)
+    }
+}
+
+/// splitmix64 finalizer, spreading consecutive ids over the whole filter.
+fn mix(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inserted_ids_are_always_found() {
+        let mut filter = BloomFilter::new(10_000);
+        for tx in (0..20_000).step_by(2) {
+            filter.insert(tx);
+        }
+        assert!((0..20_000).step_by(2).all(|tx| filter.may_contain(tx)));
+        let false_positives = (1..20_000)
+            .step_by(2)
+            .filter(|tx| filter.may_contain(*tx))
+            .count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+
+        filter.clear();
+        assert!(!filter.may_contain(0));
+    }
+}
@@ -0,0 +1,234 @@
+//! Write-through copy of the account balances in Redis, for fast reads by other services and as
+//! a cache shared by the replicas of `serve`, see `--redis`.
+//!
+//! Every account is a hash at `<prefix>:<client>` with the `available`, `held`, `total` and
+//! `locked` fields of the account report. Commands are sent in the Redis protocol over a plain
+//! TCP connection, opened on the first update.
+
+use rustc_hash::FxHashMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use crate::diagnostics::{report, ErrorEvent};
+use crate::observer::EngineObserver;
+use crate::transactions::{Account, Client, TransactionId, TransactionKind};
+
+/// Longest wait for Redis to accept a connection, a command or to answer it.
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Time between two attempts to reach Redis after a failure.
+const RETRY: Duration = Duration::from_secs(1);
+
+/// Observer writing the account of every applied transaction to Redis. Updates that can't be
+/// written are kept, newest state per client, and written once Redis can be reached again.
+pub struct RedisCache {
+    address: String,
+    prefix: String,
+    connection: Option<BufReader<TcpStream>>,
+    /// Accounts not written yet.
+    pending: FxHashMap<Client, Account>,
+    /// Set after a failure, until which Redis isn't tried again.
+    retry_at: Option<Instant>,
+}
+
+impl RedisCache {
+    /// Cache on the Redis server at `address` (`host:port`), with keys starting with `prefix`.
+    pub fn new(address: &str, prefix: &str) -> Self {
+        Self {
+            address: address.to_string(),
+            prefix: prefix.to_string(),
+            connection: None,
+            pending: FxHashMap::default(),
+            retry_at: None,
+        }
+    }
+
+    /// Checks that the Redis server at `address` answers, over a connection of its own.
+    pub fn ping(address: &str) -> io::Result<()> {
+        RedisCache::new(address, "").command(&["PING"])
+    }
+
+    /// Number of accounts waiting for Redis to be reachable again.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn store(&mut self, account: &Account) {
+        self.pending.insert(account.client(), *account);
+        if self
+            .retry_at
+            .is_some_and(|retry_at| Instant::now() < retry_at)
+        {
+            return;
+        }
+        let mut clients: Vec<Client> = self.pending.keys().copied().collect();
+        clients.sort_unstable();
+        for client in clients {
+            let account = self.pending[&client];
+            if let Err(err) = self.write(&account) {
+                if self.retry_at.is_none() {
+                    report(
+                        ErrorEvent::new("cache_failed", "unable to update redis", err)
+                            .with_client(client),
+                    );
+                }
+                self.connection = None;
+                self.retry_at = Some(Instant::now() + RETRY);
+                return;
+            }
+            self.pending.remove(&client);
+        }
+        if self.retry_at.take().is_some() {
+            log::info!("redis at {} is reachable again", self.address);
+        }
+    }
+
+    fn write(&mut self, account: &Account) -> io::Result<()> {
+        let key = format!("{}:{}", self.prefix, account.client());
+        let available = account.available().to_string();
+        let held = account.held().to_string();
+        let total = account.total().to_string();
+        let locked = account.frozen().to_string();
+        self.command(&[
+            "HSET",
+            &key,
+            "available",
+            &available,
+            "held",
+            &held,
+            "total",
+            &total,
+            "locked",
+            &locked,
+        ])
+    }
+
+    /// Sends a command and waits for its one-line reply.
+    fn command(&mut self, args: &[&str]) -> io::Result<()> {
+        let connection = match &mut self.connection {
+            Some(connection) => connection,
+            None => {
+                let address = self.address.to_socket_addrs()?.next().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, "no address to connect to")
+                })?;
+                let stream = TcpStream::connect_timeout(&address, TIMEOUT)?;
+                stream.set_read_timeout(Some(TIMEOUT))?;
+                stream.set_write_timeout(Some(TIMEOUT))?;
+                self.connection.insert(BufReader::new(stream))
+            }
+        };
+        let mut request = format!("*{}\r\n", args.len());
+        for arg in args {
+            request.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        connection.get_mut().write_all(request.as_bytes())?;
+
+        let mut reply = String::new();
+        if connection.read_line(&mut reply)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        match reply.strip_prefix('-') {
+            Some(error) => Err(io::Error::other(error.trim_end().to_string())),
+            None => Ok(()),
+        }
+    }
+}
+
+impl EngineObserver for RedisCache {
+    fn on_applied(&mut self, _kind: TransactionKind, _tx: TransactionId, account: &Account) {
+        self.store(account);
+    }
+
+    fn on_account_unfrozen(&mut self, account: &Account) {
+        self.store(account);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::amount;
+    use crate::transactions::{PaymentEngine, Transaction};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    /// Answers every command with `reply` and sends its arguments to the returned channel.
+    fn fake_redis(reply: &'static str) -> (String, mpsc::Receiver<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap_or(0) > 0 {
+                let count: usize = line.trim_end()[1..].parse().unwrap();
+                let mut args = vec![];
+                for _ in 0..count * 2 {
+                    line.clear();
+                    reader.read_line(&mut line).unwrap();
+                    args.push(line.trim_end().to_string());
+                }
+                // every other line is the length of the following argument
+                let args = args.into_iter().skip(1).step_by(2).collect();
+                writer.write_all(reply.as_bytes()).unwrap();
+                if sender.send(args).is_err() {
+                    return;
+                }
+                line.clear();
+            }
+        });
+        (address, receiver)
+    }
+
+    #[test]
+    fn applied_transactions_are_written_through() {
+        let (address, commands) = fake_redis(":4\r\n");
+        let mut engine = PaymentEngine::new();
+        engine.subscribe(Box::new(RedisCache::new(&address, "payments:account")));
+        engine
+            .process_transaction(Transaction::new_deposit(7, 1, amount!(3.0)).unwrap())
+            .unwrap();
+        assert!(engine
+            .process_transaction(Transaction::new_withdrawal(7, 2, amount!(5.0)).unwrap())
+            .is_err());
+        engine
+            .process_transaction(Transaction::new_dispute(7, 1))
+            .unwrap();
+
+        let first = commands.recv().unwrap();
+        assert_eq!(first[..2], ["HSET", "payments:account:7"]);
+        let second = commands.recv().unwrap();
+        assert_eq!(second[2], "available");
+        assert_eq!(second[3].parse::<f64>().unwrap(), 0.0);
+        assert_eq!(second[5].parse::<f64>().unwrap(), 3.0);
+        assert_eq!(second[8..], ["locked", "false"]);
+        assert!(commands.try_recv().is_err());
+    }
+
+    #[test]
+    fn failed_updates_are_kept_until_redis_is_back() {
+        let (address, _commands) = fake_redis("-ERR read only replica\r\n");
+        let mut cache = RedisCache::new(&address, "accounts");
+        let mut engine = PaymentEngine::new();
+        engine
+            .process_transaction(Transaction::new_deposit(1, 1, amount!(1.0)).unwrap())
+            .unwrap();
+        engine
+            .process_transaction(Transaction::new_deposit(2, 2, amount!(1.0)).unwrap())
+            .unwrap();
+        cache.store(engine.get_account(1).unwrap());
+        assert_eq!(cache.pending(), 1);
+        // not tried again before the retry delay
+        cache.store(engine.get_account(2).unwrap());
+        assert_eq!(cache.pending(), 2);
+        assert!(cache.connection.is_none());
+
+        let (address, commands) = fake_redis("+PONG\r\n");
+        RedisCache::ping(&address).unwrap();
+        assert_eq!(commands.recv().unwrap(), ["PING"]);
+    }
+}
@@ -0,0 +1,238 @@
+//! Snapshots of long-running commands, taken while records keep flowing, see
+//! `--snapshot-interval`.
+//!
+//! The engine only pauses while its state is copied; a thread of its own writes the copy. The
+//! newest snapshot is at the snapshot path, where `--resume` reads it, and `--keep-snapshots`
+//! older ones are kept at `<path>.1`, `<path>.2` and so on, `<path>.1` being the newest of them.
+
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::diagnostics::{report, ErrorEvent};
+use crate::snapshot::{InputOffset, Snapshot};
+use crate::transactions::PaymentEngine;
+
+/// Writes a snapshot at a path, e.g. `snapshot::save_snapshot`.
+pub type SaveSnapshot = Box<dyn FnMut(&Path, &Snapshot) -> anyhow::Result<()> + Send>;
+
+/// Outcome of the snapshots written so far.
+#[derive(Debug, Clone, Default)]
+pub struct CheckpointStatus {
+    /// When the last snapshot was written.
+    pub last: Option<Instant>,
+    /// Why the last snapshot couldn't be written, until one is.
+    pub error: Option<String>,
+    /// Whether a snapshot is being written.
+    writing: bool,
+}
+
+/// Takes snapshots every `every` applied records or every `interval`, whichever comes first,
+/// as long as records were applied since the last one.
+pub struct Checkpointer {
+    every: Option<u64>,
+    interval: Option<Duration>,
+    replica: Option<String>,
+    /// Records applied since the last snapshot.
+    since: u64,
+    taken_at: Instant,
+    jobs: Option<SyncSender<Snapshot>>,
+    writer: Option<JoinHandle<()>>,
+    status: Arc<Mutex<CheckpointStatus>>,
+}
+
+impl Checkpointer {
+    /// Starts the thread writing the snapshots at `path` with `save`, keeping `keep` of them.
+    pub fn start(
+        path: PathBuf,
+        keep: usize,
+        replica: Option<String>,
+        mut save: SaveSnapshot,
+    ) -> Self {
+        let (jobs, snapshots) = mpsc::sync_channel::<Snapshot>(1);
+        let status = Arc::new(Mutex::new(CheckpointStatus::default()));
+        let written = Arc::clone(&status);
+        let writer = thread::spawn(move || {
+            for snapshot in snapshots {
+                let result = write(&path, keep.max(1), &snapshot, &mut save);
+                let mut status = written.lock().unwrap();
+                status.writing = false;
+                match result {
+                    Ok(()) => {
+                        status.last = Some(Instant::now());
+                        status.error = None;
+                    }
+                    Err(err) => {
+                        report(ErrorEvent::new(
+                            "snapshot_failed",
+                            "unable to save snapshot",
+                            &err,
+                        ));
+                        status.error = Some(err.to_string());
+                    }
+                }
+            }
+        });
+        Self {
+            every: None,
+            interval: None,
+            replica,
+            since: 0,
+            taken_at: Instant::now(),
+            jobs: Some(jobs),
+            writer: Some(writer),
+            status,
+        }
+    }
+
+    pub fn every(mut self, records: u64) -> Self {
+        self.every = Some(records.max(1));
+        self
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = Some(interval);
+        self
+    }
+
+    /// Counts `applied` records towards the next snapshot.
+    pub fn record(&mut self, applied: u64) {
+        self.since += applied;
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.since > 0
+            && (self.every.is_some_and(|every| self.since >= every)
+                || self
+                    .interval
+                    .is_some_and(|interval| self.taken_at.elapsed() >= interval))
+    }
+
+    /// Copies the state of `engine` for the writer, unless it is still writing the previous
+    /// copy; the snapshot is then taken on a later call.
+    pub fn checkpoint(&mut self, engine: &PaymentEngine, offset: InputOffset) {
+        {
+            let mut status = self.status.lock().unwrap();
+            if status.writing {
+                return;
+            }
+            status.writing = true;
+        }
+        let snapshot = self.snapshot(engine, offset);
+        if let Some(jobs) = &self.jobs {
+            jobs.send(snapshot)
+                .expect("the writer runs until the checkpointer is finished");
+        }
+        self.since = 0;
+        self.taken_at = Instant::now();
+    }
+
+    pub fn status(&self) -> CheckpointStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Takes a last snapshot if records were applied since the previous one and waits until
+    /// every snapshot is written.
+    pub fn finish(mut self, engine: &PaymentEngine, offset: InputOffset) -> CheckpointStatus {
+        if self.since > 0 {
+            let snapshot = self.snapshot(engine, offset);
+            self.status.lock().unwrap().writing = true;
+            if let Some(jobs) = &self.jobs {
+                jobs.send(snapshot)
+                    .expect("the writer runs until the checkpointer is finished");
+            }
+        }
+        self.jobs = None;
+        if let Some(writer) = self.writer.take() {
+            writer.join().expect("the snapshot writer doesn't panic");
+        }
+        self.status()
+    }
+
+    fn snapshot(&self, engine: &PaymentEngine, offset: InputOffset) -> Snapshot {
+        Snapshot {
+            replica: self.replica.clone(),
+            offset,
+            engine: engine.state(),
+        }
+    }
+}
+
+/// `path` followed by `suffix`, e.g. `state.json.1`.
+fn with_suffix(path: &Path, suffix: impl std::fmt::Display) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(format!(".{}", suffix));
+    PathBuf::from(name)
+}
+
+/// Writes `snapshot` next to `path`, then shifts the snapshots kept by one and moves it into
+/// place, so that a failed write leaves the kept snapshots alone.
+fn write(
+    path: &Path,
+    keep: usize,
+    snapshot: &Snapshot,
+    save: &mut SaveSnapshot,
+) -> anyhow::Result<()> {
+    let next = with_suffix(path, "next");
+    save(&next, snapshot)?;
+    if keep > 1 && path.exists() {
+        for older in (1..keep - 1).rev() {
+            let kept = with_suffix(path, older);
+            if kept.exists() {
+                fs::rename(&kept, with_suffix(path, older + 1))?;
+            }
+        }
+        fs::rename(path, with_suffix(path, 1))?;
+    }
+    fs::rename(&next, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::amount;
+    use crate::snapshot::{load_snapshot, save_snapshot};
+    use crate::transactions::Transaction;
+
+    #[test]
+    fn the_newest_snapshots_are_kept() {
+        let dir = std::env::temp_dir().join(format!("payments-checkpoint-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state.json");
+        let mut checkpointer = Checkpointer::start(
+            path.clone(),
+            2,
+            None,
+            Box::new(|path, snapshot| save_snapshot(path, snapshot)),
+        )
+        .every(2);
+        let mut engine = PaymentEngine::new();
+        for tx in 1..=5 {
+            engine
+                .process_transaction(Transaction::new_deposit(1, tx, amount!(1.0)).unwrap())
+                .unwrap();
+            checkpointer.record(1);
+            if checkpointer.is_due() {
+                checkpointer.checkpoint(&engine, InputOffset::default());
+                // waits for the writer, so that no snapshot is skipped
+                while checkpointer.status().writing {
+                    thread::sleep(Duration::from_millis(1));
+                }
+            }
+        }
+        let status = checkpointer.finish(&engine, InputOffset::default());
+        assert!(status.last.is_some() && status.error.is_none());
+
+        let transactions = |path: &Path| load_snapshot(path).unwrap().engine.transactions.len();
+        assert_eq!(transactions(&path), 5);
+        assert_eq!(transactions(&with_suffix(&path, 1)), 4);
+        assert!(!with_suffix(&path, 2).exists());
+        assert!(!with_suffix(&path, "next").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,165 @@
+//! Attributes of clients known before any of their transactions, read from a client file and
+//! consulted by the validation `Rules`.
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::ingest::read_records;
+use crate::transactions::{Amount, Client};
+
+/// Where a client stands with identity verification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KycStatus {
+    #[default]
+    Unknown,
+    Pending,
+    Verified,
+    Rejected,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientProfile {
+    /// Account tier, see `Rules::tiers`.
+    pub tier: Option<String>,
+    pub kyc: KycStatus,
+    /// Currency the client's account is kept in, e.g. `EUR`.
+    pub currency: Option<String>,
+    /// Transactions of blocked clients are rejected like those of the blocklist.
+    pub blocked: bool,
+    /// Id of the client in the system it comes from.
+    pub external_id: Option<String>,
+    /// Funds the client must keep available, over the reserve of its tier, see
+    /// `Rules::reserve`.
+    pub reserve: Option<Amount>,
+}
+
+/// A row of a client file; every column but `client` may be left empty or left out.
+#[derive(Debug, Deserialize)]
+struct ClientRecord {
+    client: Client,
+    #[serde(default)]
+    tier: Option<String>,
+    #[serde(default)]
+    kyc: Option<KycStatus>,
+    #[serde(default)]
+    currency: Option<String>,
+    #[serde(default)]
+    blocked: Option<bool>,
+    #[serde(default)]
+    external_id: Option<String>,
+    #[serde(default)]
+    reserve: Option<Amount>,
+}
+
+impl From<ClientRecord> for ClientProfile {
+    fn from(record: ClientRecord) -> Self {
+        Self {
+            tier: record.tier,
+            kyc: record.kyc.unwrap_or_default(),
+            currency: record.currency,
+            blocked: record.blocked.unwrap_or_default(),
+            external_id: record.external_id,
+            reserve: record.reserve,
+        }
+    }
+}
+
+/// Profiles by client; clients without one get `ClientProfile::default()`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientProfiles(HashMap<Client, ClientProfile>);
+
+impl ClientProfiles {
+    /// Reads `client,tier,kyc,currency,blocked,external_id,reserve` rows with a header row. A
+    /// client listed twice keeps its last row.
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let records: Vec<ClientRecord> = read_records(path)?;
+        Ok(records
+            .into_iter()
+            .map(|record| (record.client, record.into()))
+            .collect())
+    }
+
+    pub fn get(&self, client: Client) -> Option<&ClientProfile> {
+        self.0.get(&client)
+    }
+
+    /// Profile of `client`, created with the defaults when missing.
+    pub fn entry(&mut self, client: Client) -> &mut ClientProfile {
+        self.0.entry(client).or_default()
+    }
+
+    /// Takes over the profiles of `other`, replacing those of the same clients.
+    pub fn extend(&mut self, other: ClientProfiles) {
+        self.0.extend(other.0);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Client, &ClientProfile)> {
+        self.0.iter().map(|(client, profile)| (*client, profile))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ExternalIdRecord {
+    client: Client,
+    external_id: String,
+}
+
+/// `client,external_id` rows with a header row, mapping clients to the ids downstream systems
+/// know them by.
+pub fn load_external_ids<P: AsRef<Path>>(path: P) -> anyhow::Result<HashMap<Client, String>> {
+    let records: Vec<ExternalIdRecord> = read_records(path)?;
+    Ok(records
+        .into_iter()
+        .map(|record| (record.client, record.external_id))
+        .collect())
+}
+
+impl FromIterator<(Client, ClientProfile)> for ClientProfiles {
+    fn from_iter<I: IntoIterator<Item = (Client, ClientProfile)>>(iter: I) -> Self {
+        ClientProfiles(iter.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn profiles_are_read_with_defaults_for_empty_columns() {
+        let path =
+            std::env::temp_dir().join(format!("payments-clients-{}.csv", std::process::id()));
+        fs::write(
+            &path,
+            "client,tier,kyc,blocked,external_id\n\
+             1,basic,verified,,c-1\n\
+             2,,pending,true,\n",
+        )
+        .unwrap();
+        let profiles = ClientProfiles::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            profiles.get(1),
+            Some(&ClientProfile {
+                tier: Some("basic".to_string()),
+                kyc: KycStatus::Verified,
+                currency: None,
+                blocked: false,
+                external_id: Some("c-1".to_string()),
+                reserve: None,
+            })
+        );
+        let second = profiles.get(2).unwrap();
+        assert_eq!(second.tier, None);
+        assert_eq!(second.kyc, KycStatus::Pending);
+        assert!(second.blocked);
+        assert!(profiles.get(3).is_none());
+    }
+}
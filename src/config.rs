@@ -0,0 +1,966 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::anonymize::Anonymizer;
+use crate::clients::{load_external_ids, ClientProfiles};
+use crate::diagnostics::ErrorFormat;
+#[cfg(feature = "native")]
+use crate::encryption::EncryptionKey;
+use crate::export::{
+    ExportOptions, OutputFormat, Rounding, StatementDate, StatementOptions, MAX_PRECISION,
+};
+use crate::ingest::{
+    AccountMap, AmountFormat, CsvDialect, InputFormat, PrecisionMode, PrecisionPolicy, SignatureKey,
+};
+use crate::publish::AccountPublisher;
+use crate::risk::{RiskRules, RulesRiskScorer};
+use crate::rules::{load_blocklist, load_client_tiers, load_rules, Rules};
+#[cfg(feature = "native")]
+use crate::server::{Authenticator, ServerLimits};
+use crate::transactions::{
+    ChargebackAction, Clock, DisputePolicy, PaymentEngine, PeriodLength, Retention, StorageMode,
+    TransactionId,
+};
+use crate::velocity::VelocityRules;
+#[cfg(feature = "native")]
+use crate::webhook::{Endpoint, Retry, WebhookNotifier};
+
+/// Prefix of environment variables overriding configuration keys.
+pub const ENV_PREFIX: &str = "PAYMENTS_";
+
+/// Settings of a processing run. Every section and key is optional.
+///
+/// ```toml
+/// [input]
+/// max_decimal_places = 2
+/// precision_mode = "truncate"
+/// format = "ofx"
+/// accounts = "accounts.csv"
+/// delimiter = ";"
+/// headers = false
+/// amount_format = "comma"
+/// columns = ["client", "tx", "type", "amount"]
+/// skip_duplicates = true
+/// signature_key = "shared secret"
+/// manifest = "transactions.csv.manifest"
+///
+/// [input.rename]
+/// txn_type = "type"
+/// customer_id = "client"
+///
+/// [output]
+/// precision = 2
+/// rounding = "half-up"
+/// format = "parquet"
+/// transactions = "transactions.parquet"
+/// statements = "statements"
+/// statement_date = "2024-01-31"
+/// journal = "journal.csv"
+/// currency = "EUR"
+/// anonymize = true
+/// anonymize_secret = "change me"
+/// publish = "updates.fifo"
+/// publish_interval_ms = 500
+/// tenant_reports = "tenants"
+/// shards = 8
+/// shard_dir = "report"
+/// suspicious_activity = "sar.csv"
+/// recovery_report = "negative-accounts.csv"
+/// id_map = "external-ids.csv"
+/// dead_letters = "dead-letters.jsonl"
+///
+/// [disputes]
+/// chargeback_threshold = 1
+/// chargeback_action = "flag"
+///
+/// [risk]
+/// large_amount = 10000.0
+///
+/// [velocity]
+/// small_deposit = 10.0
+/// max_disputes = 3
+///
+/// [limits]
+/// rules = "rules.toml"
+/// blocklist = "blocked-clients.txt"
+/// client_tiers = "client-tiers.csv"
+/// clients = "clients.csv"
+/// deposit_hold_secs = 172800
+/// recovery = true
+///
+/// [storage]
+/// mode = "compact"
+/// workers = 4
+/// prune_older_than = 1000000
+/// prune_resolved = true
+/// snapshot_interval_secs = 60
+/// keep_snapshots = 5
+/// dedupe_index = "seen.idx"
+/// bloom_filter = 10000000
+///
+/// [encryption]
+/// key_file = "payments.key"
+/// snapshots = true
+/// reports = true
+///
+/// [server]
+/// tokens = "tokens.csv"
+/// jwt_secret = "change me"
+/// rate_limit = 100.0
+/// client_rate_limit = 10.0
+/// queue = 256
+/// max_body = 1048576
+/// webhooks = ["http://hooks.internal/payments"]
+/// webhook_dead_letter = "webhooks.jsonl"
+///
+/// [logging]
+/// level = "info"
+/// errors_format = "json"
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub input: InputConfig,
+    pub output: OutputConfig,
+    pub disputes: DisputePolicy,
+    pub risk: RiskRules,
+    pub velocity: VelocityRules,
+    pub limits: LimitsConfig,
+    pub storage: StorageConfig,
+    pub encryption: EncryptionConfig,
+    pub server: ServerConfig,
+    pub logging: LoggingConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InputConfig {
+    pub format: InputFormat,
+    /// `account,client` file mapping the account ids of statement formats to clients.
+    pub accounts: Option<PathBuf>,
+    pub max_decimal_places: u32,
+    pub precision_mode: PrecisionMode,
+    /// Parse the input on a separate thread.
+    pub pipeline: bool,
+    /// Memory-map the input.
+    pub fast_io: bool,
+    /// Field separator of CSV inputs, an ASCII character.
+    pub delimiter: char,
+    /// Whether CSV inputs start with a header row.
+    pub headers: bool,
+    /// Column order of CSV inputs without a header row.
+    pub columns: Vec<String>,
+    /// Quote character of CSV inputs, an ASCII character.
+    pub quote: char,
+    /// Whether quotes are recognized at all in CSV inputs.
+    pub quoting: bool,
+    /// CSV column names mapped to the names the engine expects, e.g. `customer_id = "client"`.
+    pub rename: HashMap<String, String>,
+    /// How amounts of CSV inputs are written, e.g. `1.234,56` with `comma`.
+    pub amount_format: AmountFormat,
+    /// Number of input records to apply, the rest is ignored so that balances show the state
+    /// right after that record.
+    pub as_of: Option<u64>,
+    /// Stops applying input records once the simulated clock passes this time, in seconds since
+    /// the Unix epoch: an `advance_time` record beyond it only moves the clock up to it, and
+    /// the records after it are ignored. Needs `start_time`.
+    pub as_of_time: Option<u64>,
+    /// Silently drops deposits, withdrawals and holds repeating a record already seen, see
+    /// `DedupeIndex`.
+    pub skip_duplicates: bool,
+    /// `tx,amount` rows applied after the input, setting the amount of a deposit or withdrawal
+    /// or voiding it.
+    pub corrections: Option<PathBuf>,
+    /// Starts the engine on a simulated clock at this time, in seconds since the Unix epoch,
+    /// moved by `advance_time` records only. Scheduled records follow the system clock otherwise.
+    pub start_time: Option<u64>,
+    /// `client,tx,amount,interval,count,start` rows of recurrences, expanded into scheduled
+    /// deposits and withdrawals before the input is read.
+    pub recurring: Option<PathBuf>,
+    /// Closes a period whenever the engine clock moves into the next day, week or month, see
+    /// `PaymentEngine::set_period_length`; `close_period` records close one either way.
+    pub period: Option<PeriodLength>,
+    /// Secret shared with the partners signing CSV rows, see `SignatureKey`; rows without a
+    /// valid `signature` column are then rejected. Best set through
+    /// `PAYMENTS_INPUT_SIGNATURE_KEY`.
+    pub signature_key: Option<String>,
+    /// Manifest of the input, checked before and after it is processed, see `Manifest`.
+    pub manifest: Option<PathBuf>,
+}
+
+impl Default for InputConfig {
+    fn default() -> Self {
+        let dialect = CsvDialect::default();
+        Self {
+            format: InputFormat::default(),
+            accounts: None,
+            max_decimal_places: PrecisionPolicy::default().max_decimal_places,
+            precision_mode: PrecisionMode::default(),
+            pipeline: false,
+            fast_io: false,
+            delimiter: dialect.delimiter as char,
+            headers: dialect.has_headers,
+            columns: dialect.columns,
+            quote: dialect.quote as char,
+            quoting: dialect.quoting,
+            rename: dialect.renames,
+            amount_format: dialect.amounts,
+            as_of: None,
+            as_of_time: None,
+            skip_duplicates: false,
+            corrections: None,
+            start_time: None,
+            recurring: None,
+            period: None,
+            signature_key: None,
+            manifest: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputConfig {
+    /// Number of decimal places in the account report, at most `MAX_PRECISION`.
+    pub precision: u32,
+    pub rounding: Rounding,
+    /// Adds per-account dispute and chargeback counters to the report.
+    pub extended: bool,
+    /// Adds the funds in the escrows of each account to the report.
+    pub show_escrow: bool,
+    pub format: OutputFormat,
+    /// File receiving the stored transactions once processing is done.
+    pub transactions: Option<PathBuf>,
+    /// Directory receiving one MT940 statement per client once processing is done.
+    pub statements: Option<PathBuf>,
+    /// Date of the statements and journal lines, today when not set.
+    pub statement_date: Option<StatementDate>,
+    /// CSV file receiving the general-ledger lines of every journal entry.
+    pub journal: Option<PathBuf>,
+    /// CSV file receiving the statement of every account at the end of each closed period.
+    pub period_statements: Option<PathBuf>,
+    pub currency: String,
+    /// Replaces client ids by keyed pseudonyms in the reports and error events.
+    pub anonymize: bool,
+    /// Key of the pseudonyms, best set through `PAYMENTS_OUTPUT_ANONYMIZE_SECRET`.
+    pub anonymize_secret: Option<String>,
+    /// File or named pipe receiving an update message per applied transaction, see `publish`.
+    pub publish: Option<PathBuf>,
+    /// Milliseconds between two flushes of the published updates, flushed after every one when
+    /// not set.
+    pub publish_interval_ms: Option<u64>,
+    /// Directory receiving one account report per tenant, records then need a `tenant` column.
+    pub tenant_reports: Option<PathBuf>,
+    /// Number of files the account report is split into, written to `shard_dir` with an index
+    /// instead of to stdout, see `export::write_shards`.
+    pub shards: Option<usize>,
+    pub shard_dir: Option<PathBuf>,
+    /// `client,external_id` file; the outputs keyed by client then list the external id of
+    /// each client next to its id, as do they with the external ids of `[limits] clients`.
+    pub id_map: Option<PathBuf>,
+    /// CSV file receiving the patterns of the `velocity` rules detected during the run.
+    pub suspicious_activity: Option<PathBuf>,
+    /// File receiving the report of the accounts left with negative available funds, in the
+    /// format of the account report.
+    pub recovery_report: Option<PathBuf>,
+    /// JSON Lines file the records that couldn't be processed are appended to, see `dlq`.
+    pub dead_letters: Option<PathBuf>,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        let options = ExportOptions::default();
+        Self {
+            precision: options.precision,
+            rounding: options.rounding,
+            extended: options.extended,
+            show_escrow: options.show_escrow,
+            format: options.format,
+            transactions: None,
+            statements: None,
+            statement_date: None,
+            journal: None,
+            period_statements: None,
+            currency: "EUR".to_string(),
+            anonymize: false,
+            anonymize_secret: None,
+            publish: None,
+            publish_interval_ms: None,
+            tenant_reports: None,
+            shards: None,
+            shard_dir: None,
+            id_map: None,
+            suspicious_activity: None,
+            recovery_report: None,
+            dead_letters: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// TOML file with validation `Rules`.
+    pub rules: Option<PathBuf>,
+    /// File of blocked client ids, one per line, see `load_blocklist`.
+    pub blocklist: Option<PathBuf>,
+    /// `client,tier` file assigning clients to the tiers of the rules, see `load_client_tiers`.
+    pub client_tiers: Option<PathBuf>,
+    /// CSV file of client attributes loaded before any transaction, see `ClientProfiles::load`.
+    pub clients: Option<PathBuf>,
+    /// Apply manual adjustments, see `PaymentEngine::set_allow_adjustments`.
+    pub allow_adjustments: bool,
+    /// Seconds of the engine clock deposits stay held before they become available, see
+    /// `PaymentEngine::set_deposit_hold`.
+    pub deposit_hold_secs: Option<u64>,
+    /// Deposits offset negative available balances first, see `PaymentEngine::set_recovery`.
+    pub recovery: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub mode: StorageMode,
+    /// Number of engines processing clients in parallel.
+    pub workers: usize,
+    /// File periodically receiving the engine state.
+    pub snapshot: Option<PathBuf>,
+    /// Number of processed records between two snapshots.
+    pub snapshot_every: u64,
+    /// Seconds between two snapshots of `serve` and `watch`, taken in the background.
+    pub snapshot_interval_secs: Option<u64>,
+    /// Snapshots `serve` and `watch` keep, the newest at `snapshot` and the older ones at
+    /// `<snapshot>.1`, `<snapshot>.2` and so on.
+    pub keep_snapshots: usize,
+    /// `prune` drops transactions more than this many ids below the newest one.
+    pub prune_older_than: Option<TransactionId>,
+    /// `prune` drops reversed, resolved, charged back and released transactions.
+    pub prune_resolved: bool,
+    /// File keeping the records seen by `skip_duplicates` across runs.
+    pub dedupe_index: Option<PathBuf>,
+    /// Expected number of transactions of a bloom filter checked before the stored
+    /// transactions, see `PaymentEngine::set_bloom_filter`.
+    pub bloom_filter: Option<usize>,
+    /// Id of this engine among replicas processing the same clients, recorded in snapshots for
+    /// `payments merge --replicated`.
+    pub replica: Option<String>,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            mode: StorageMode::default(),
+            workers: 1,
+            snapshot: None,
+            snapshot_every: 100_000,
+            snapshot_interval_secs: None,
+            keep_snapshots: 1,
+            prune_older_than: None,
+            prune_resolved: false,
+            dedupe_index: None,
+            bloom_filter: None,
+            replica: None,
+        }
+    }
+}
+
+/// AES-256-GCM encryption of what the run writes, see `encryption`. Encrypted snapshots are
+/// decrypted with the key whatever `snapshots` says.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EncryptionConfig {
+    /// Secret the key is derived from, best set through `PAYMENTS_ENCRYPTION_KEY`.
+    pub key: Option<String>,
+    /// File holding the secret instead of `key`, trailing whitespace excluded.
+    pub key_file: Option<PathBuf>,
+    /// Encrypts the snapshots written.
+    pub snapshots: bool,
+    /// Encrypts the account report.
+    pub reports: bool,
+}
+
+/// Bearer tokens `payments serve` requires, see `server::Authenticator`, its limits, see
+/// `server::ServerLimits`, and the webhooks it notifies, see `WebhookNotifier`. Without tokens,
+/// the server answers every request.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// CSV file of static tokens, with `token` and `role` columns, the role being `read`,
+    /// `submit` or `admin`.
+    pub tokens: Option<PathBuf>,
+    /// Secret of the HS256 JWTs accepted, best set through `PAYMENTS_SERVER_JWT_SECRET`.
+    pub jwt_secret: Option<String>,
+    /// Submissions per second over all callers.
+    pub rate_limit: Option<f64>,
+    /// Submissions per second of a single caller.
+    pub client_rate_limit: Option<f64>,
+    /// Requests received but not served yet.
+    pub queue: usize,
+    /// Bytes a request body may have at most.
+    pub max_body: usize,
+    /// `http://` URLs notified of every chargeback and frozen account.
+    pub webhooks: Vec<String>,
+    /// Attempts at a notification after the first one a webhook didn't accept.
+    pub webhook_retries: u32,
+    /// Wait before the first retry of a notification, doubled for each following one.
+    pub webhook_backoff_ms: u64,
+    /// Notifications waiting for delivery; further ones go to the dead-letter file.
+    pub webhook_queue: usize,
+    /// File the notifications that weren't delivered are appended to, as JSON lines.
+    pub webhook_dead_letter: Option<PathBuf>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            tokens: None,
+            jwt_secret: None,
+            rate_limit: None,
+            client_rate_limit: None,
+            queue: 1024,
+            max_body: 16 * 1024 * 1024,
+            webhooks: vec![],
+            webhook_retries: 5,
+            webhook_backoff_ms: 500,
+            webhook_queue: 1024,
+            webhook_dead_letter: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// `env_logger` filter used when `RUST_LOG` is not set, e.g. `info` or `payments=debug`.
+    pub level: Option<String>,
+    /// How skipped records and other problems are reported.
+    pub errors_format: ErrorFormat,
+}
+
+impl Config {
+    /// Reads the TOML file at `path`, if any, then applies overrides from the environment.
+    /// `PAYMENTS_<SECTION>_<KEY>` sets `key` in `[section]`, e.g. `PAYMENTS_OUTPUT_PRECISION=2`.
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Config> {
+        let content = match path {
+            Some(path) => fs::read_to_string(path)?,
+            None => String::new(),
+        };
+        let mut value: toml::Value = toml::from_str(&content)?;
+        apply_overrides(&mut value, std::env::vars())?;
+        let config: Config = value.try_into()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.output.precision > MAX_PRECISION {
+            anyhow::bail!("output precision must be at most {}", MAX_PRECISION);
+        }
+        if self.output.format == OutputFormat::Parquet && !cfg!(feature = "parquet") {
+            anyhow::bail!("built without parquet support, enable the `parquet` feature");
+        }
+        if self.output.format == OutputFormat::Xlsx && !cfg!(feature = "xlsx") {
+            anyhow::bail!("built without xlsx support, enable the `xlsx` feature");
+        }
+        if self.output.format == OutputFormat::Xlsx && self.output.transactions.is_some() {
+            anyhow::bail!("the transaction history can't be written as xlsx");
+        }
+        for (name, value) in [
+            ("delimiter", self.input.delimiter),
+            ("quote", self.input.quote),
+        ] {
+            if !value.is_ascii() {
+                anyhow::bail!("CSV {} must be an ASCII character, not {}", name, value);
+            }
+        }
+        for column in ["type", "client", "tx"] {
+            if !self.input.headers && !self.input.columns.iter().any(|name| name == column) {
+                anyhow::bail!(
+                    "columns must include {} when inputs have no headers",
+                    column
+                );
+            }
+        }
+        if self.input.manifest.is_some() && self.input.format != InputFormat::Csv {
+            anyhow::bail!("manifests count CSV rows, they need csv inputs");
+        }
+        if self.encryption.reports && self.output.tenant_reports.is_some() {
+            anyhow::bail!("per-tenant reports can't be encrypted");
+        }
+        if let Some(shards) = self.output.shards {
+            if shards == 0 {
+                anyhow::bail!("shards must split the report into at least one file");
+            }
+            if self.output.shard_dir.is_none() {
+                anyhow::bail!("shards need a shard_dir to write the shards to");
+            }
+            if self.encryption.reports || self.output.anonymize {
+                anyhow::bail!(
+                    "sharded reports can't be encrypted, and their index would reveal anonymized \
+                     client ids"
+                );
+            }
+            if self.output.format == OutputFormat::Xlsx || self.output.tenant_reports.is_some() {
+                anyhow::bail!("sharded reports are csv or parquet reports of a single engine");
+            }
+        }
+        if self.encryption.key.is_some() && self.encryption.key_file.is_some() {
+            anyhow::bail!("set either an encryption key or a key file, not both");
+        }
+        if (self.encryption.snapshots || self.encryption.reports)
+            && self.encryption.key.is_none()
+            && self.encryption.key_file.is_none()
+        {
+            anyhow::bail!(
+                "encryption needs a key, set key_file in [encryption] or {}ENCRYPTION_KEY",
+                ENV_PREFIX
+            );
+        }
+        if (self.encryption.key.is_some() || self.encryption.key_file.is_some())
+            && !cfg!(feature = "native")
+        {
+            anyhow::bail!("built without encryption support, enable the `native` feature");
+        }
+        if self
+            .server
+            .jwt_secret
+            .as_ref()
+            .is_some_and(String::is_empty)
+        {
+            anyhow::bail!("jwt_secret must not be empty");
+        }
+        for (name, rate) in [
+            ("rate_limit", self.server.rate_limit),
+            ("client_rate_limit", self.server.client_rate_limit),
+        ] {
+            if rate.is_some_and(|rate| !(rate.is_finite() && rate > 0.0)) {
+                anyhow::bail!("{} must be a positive number of requests per second", name);
+            }
+        }
+        if self.storage.snapshot_interval_secs == Some(0) {
+            anyhow::bail!("snapshot_interval_secs must be at least one second");
+        }
+        if self.storage.keep_snapshots == 0 {
+            anyhow::bail!("keep_snapshots must keep at least the newest snapshot");
+        }
+        if self.limits.deposit_hold_secs == Some(0) {
+            anyhow::bail!("deposit_hold_secs must hold deposits for at least one second");
+        }
+        if self.input.as_of_time.is_some() && self.input.start_time.is_none() {
+            anyhow::bail!("as_of_time follows the simulated clock, set a start_time");
+        }
+        if self.storage.bloom_filter == Some(0) {
+            anyhow::bail!("bloom_filter must expect at least one transaction");
+        }
+        if self.server.queue == 0 {
+            anyhow::bail!("the server queue must hold at least one request");
+        }
+        if self.server.max_body == 0 {
+            anyhow::bail!("max_body must allow request bodies of at least one byte");
+        }
+        if let Some(key) = &self.input.signature_key {
+            if key.is_empty() {
+                anyhow::bail!("signature_key must not be empty");
+            }
+            if self.input.format != InputFormat::Csv {
+                anyhow::bail!("only CSV rows are signed, signature_key needs csv inputs");
+            }
+        }
+        if self.output.statements.is_some() && self.storage.mode == StorageMode::Compact {
+            anyhow::bail!(
+                "statements need every withdrawal, they can't be written in compact mode"
+            );
+        }
+        if self.output.journal.is_some() && self.storage.mode == StorageMode::Compact {
+            anyhow::bail!("journal lines are not kept in compact mode");
+        }
+        if self.storage.dedupe_index.is_some() && !self.input.skip_duplicates {
+            anyhow::bail!("a dedupe index is only kept with skip_duplicates");
+        }
+        if self.storage.replica.is_some() && self.storage.snapshot.is_none() {
+            anyhow::bail!("a replica id is only recorded in snapshots, set a snapshot file");
+        }
+        if self.input.corrections.is_some()
+            && (self.storage.snapshot.is_some() || self.output.tenant_reports.is_some())
+        {
+            anyhow::bail!(
+                "corrections are applied after the whole input, they can't be combined with \
+                 snapshots or per-tenant reports"
+            );
+        }
+        if self.input.recurring.is_some()
+            && (self.storage.snapshot.is_some() || self.output.tenant_reports.is_some())
+        {
+            anyhow::bail!(
+                "recurrences are expanded before the whole input, they can't be combined with \
+                 snapshots or per-tenant reports"
+            );
+        }
+        if self.output.tenant_reports.is_some() {
+            if self.output.format == OutputFormat::Xlsx {
+                anyhow::bail!("per-tenant reports are written as csv or parquet");
+            }
+            if self.output.transactions.is_some()
+                || self.output.statements.is_some()
+                || self.output.journal.is_some()
+                || self.output.publish.is_some()
+                || self.output.suspicious_activity.is_some()
+                || self.output.recovery_report.is_some()
+                || self.output.dead_letters.is_some()
+            {
+                anyhow::bail!(
+                    "per-tenant runs only write account reports, not transactions, statements, \
+                     journals, published updates, suspicious activity, recovery reports or dead \
+                     letters"
+                );
+            }
+            if self.storage.workers > 1
+                || self.storage.snapshot.is_some()
+                || self.input.skip_duplicates
+            {
+                anyhow::bail!(
+                    "per-tenant runs can't be combined with workers, snapshots or skip_duplicates"
+                );
+            }
+        }
+        if self.output.anonymize {
+            if self
+                .output
+                .anonymize_secret
+                .as_deref()
+                .unwrap_or("")
+                .is_empty()
+            {
+                anyhow::bail!(
+                    "anonymizing needs a secret, set anonymize_secret in [output] or \
+                     {}OUTPUT_ANONYMIZE_SECRET",
+                    ENV_PREFIX
+                );
+            }
+            if self.output.format == OutputFormat::Parquet {
+                anyhow::bail!("parquet reports can't be anonymized, their client ids are numbers");
+            }
+            if self.output.statements.is_some() {
+                anyhow::bail!("statements can't be anonymized, they are named after the clients");
+            }
+            if self.output.id_map.is_some() {
+                anyhow::bail!("external ids would reveal the anonymized clients");
+            }
+            if self.output.publish.is_some() {
+                anyhow::bail!("published updates can't be anonymized, they are keyed by client");
+            }
+        }
+        if self.server.webhook_dead_letter.is_some() && self.server.webhooks.is_empty() {
+            anyhow::bail!(
+                "webhook_dead_letter only collects notifications of webhooks, set webhooks"
+            );
+        }
+        if self.server.webhook_queue == 0 {
+            anyhow::bail!("the webhook queue must hold at least one notification");
+        }
+        Ok(())
+    }
+
+    pub fn precision_policy(&self) -> PrecisionPolicy {
+        PrecisionPolicy {
+            max_decimal_places: self.input.max_decimal_places,
+            mode: self.input.precision_mode,
+        }
+    }
+
+    pub fn csv_dialect(&self) -> CsvDialect {
+        CsvDialect {
+            delimiter: self.input.delimiter as u8,
+            has_headers: self.input.headers,
+            columns: self.input.columns.clone(),
+            quote: self.input.quote as u8,
+            quoting: self.input.quoting,
+            renames: self.input.rename.clone(),
+            amounts: self.input.amount_format,
+            signature_key: self
+                .input
+                .signature_key
+                .as_deref()
+                .map(|key| SignatureKey::new(key.as_bytes())),
+        }
+    }
+
+    pub fn export_options(&self) -> ExportOptions {
+        let flags_risk = !self.risk.veto
+            && (self.risk.large_amount.is_some() || self.risk.max_withdrawals.is_some());
+        ExportOptions {
+            precision: self.output.precision,
+            rounding: self.output.rounding,
+            show_flags: self.disputes.chargeback_action == ChargebackAction::Flag || flags_risk,
+            extended: self.output.extended,
+            show_escrow: self.output.show_escrow,
+            format: self.output.format,
+            anonymizer: self.anonymizer(),
+            tiers: HashMap::new(),
+            external_ids: HashMap::new(),
+            reserves: HashMap::new(),
+            default_reserve: None,
+        }
+    }
+
+    /// Pseudonymizes client ids when `anonymize` is set.
+    pub fn anonymizer(&self) -> Option<Anonymizer> {
+        match (&self.output.anonymize_secret, self.output.anonymize) {
+            (Some(secret), true) => Some(Anonymizer::new(secret.as_bytes())),
+            _ => None,
+        }
+    }
+
+    /// Key of `[encryption]`, when one is configured.
+    #[cfg(feature = "native")]
+    pub fn encryption_key(&self) -> anyhow::Result<Option<EncryptionKey>> {
+        let secret = match (&self.encryption.key, &self.encryption.key_file) {
+            (Some(key), _) => key.clone(),
+            (None, Some(path)) => fs::read_to_string(path)
+                .map_err(|err| anyhow::anyhow!("unable to read {}: {}", path.display(), err))?
+                .trim_end()
+                .to_string(),
+            (None, None) => return Ok(None),
+        };
+        if secret.is_empty() {
+            anyhow::bail!("the encryption key must not be empty");
+        }
+        Ok(Some(EncryptionKey::new(secret.as_bytes())))
+    }
+
+    /// Tokens of `[server]`, which accepts every request when none are configured.
+    #[cfg(feature = "native")]
+    pub fn authenticator(&self) -> anyhow::Result<Authenticator> {
+        let mut auth = Authenticator::new();
+        if let Some(path) = &self.server.tokens {
+            auth = auth.with_tokens_file(path)?;
+        }
+        if let Some(secret) = &self.server.jwt_secret {
+            auth = auth.with_jwt_secret(secret.as_bytes());
+        }
+        Ok(auth)
+    }
+
+    #[cfg(feature = "native")]
+    pub fn server_limits(&self) -> ServerLimits {
+        ServerLimits {
+            rate: self.server.rate_limit,
+            client_rate: self.server.client_rate_limit,
+            queue: self.server.queue,
+            max_body: self.server.max_body,
+        }
+    }
+
+    /// Publisher of the account updates, when a `publish` file is configured.
+    pub fn publisher(&self) -> anyhow::Result<Option<AccountPublisher>> {
+        let path = match &self.output.publish {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let file = fs::File::create(path)
+            .map_err(|err| anyhow::anyhow!("unable to open {}: {}", path.display(), err))?;
+        Ok(Some(AccountPublisher::new(
+            Box::new(io::BufWriter::new(file)),
+            self.output.publish_interval_ms.map(Duration::from_millis),
+        )))
+    }
+
+    pub fn retention(&self) -> Retention {
+        Retention {
+            older_than: self.storage.prune_older_than,
+            resolved: self.storage.prune_resolved,
+        }
+    }
+
+    pub fn statement_options(&self) -> StatementOptions {
+        StatementOptions {
+            date: self
+                .output
+                .statement_date
+                .unwrap_or_else(StatementDate::today),
+            currency: self.output.currency.clone(),
+        }
+    }
+
+    pub fn account_map(&self) -> anyhow::Result<AccountMap> {
+        match &self.input.accounts {
+            Some(path) => AccountMap::load(path),
+            None => Ok(AccountMap::default()),
+        }
+    }
+
+    /// The `rules` file with the clients of the `blocklist` file added to them.
+    pub fn load_rules(&self) -> anyhow::Result<Option<Rules>> {
+        let mut rules = self.limits.rules.as_ref().map(load_rules).transpose()?;
+        if let Some(path) = &self.limits.blocklist {
+            rules
+                .get_or_insert_with(Rules::default)
+                .blocked_clients
+                .extend(load_blocklist(path)?);
+        }
+        if let Some(path) = &self.limits.clients {
+            rules
+                .get_or_insert_with(Rules::default)
+                .set_profiles(ClientProfiles::load(path)?)?;
+        }
+        if let Some(path) = &self.output.id_map {
+            rules
+                .get_or_insert_with(Rules::default)
+                .assign_external_ids(load_external_ids(path)?);
+        }
+        if let Some(path) = &self.limits.client_tiers {
+            rules
+                .get_or_insert_with(Rules::default)
+                .assign_tiers(load_client_tiers(path)?)?;
+        }
+        Ok(rules)
+    }
+
+    /// Engine set up with this configuration; `rules` usually comes from `load_rules`.
+    pub fn engine(&self, rules: Option<&Rules>) -> PaymentEngine {
+        let mut engine = PaymentEngine::with_dispute_policy(self.disputes);
+        engine.set_risk_scorer(Box::new(RulesRiskScorer::new(self.risk.clone())));
+        engine.set_velocity_rules(self.velocity.clone());
+        engine.set_storage_mode(self.storage.mode);
+        engine.keep_journal(self.output.journal.is_some());
+        engine.set_bloom_filter(self.storage.bloom_filter);
+        engine.set_allow_adjustments(self.limits.allow_adjustments);
+        engine.set_deposit_hold(self.limits.deposit_hold_secs);
+        engine.set_recovery(self.limits.recovery);
+        if let Some(start) = self.input.start_time {
+            engine.set_clock(Clock::Simulated(start));
+        }
+        engine.set_period_length(self.input.period);
+        if let Some(rules) = rules {
+            engine.set_rules(rules.clone());
+        }
+        engine
+    }
+
+    /// Notifier of the configured webhooks, if there are any.
+    #[cfg(feature = "native")]
+    pub fn webhooks(&self) -> anyhow::Result<Option<WebhookNotifier>> {
+        if self.server.webhooks.is_empty() {
+            return Ok(None);
+        }
+        let endpoints = self
+            .server
+            .webhooks
+            .iter()
+            .map(|url| url.parse().map_err(anyhow::Error::msg))
+            .collect::<anyhow::Result<Vec<Endpoint>>>()?;
+        let retry = Retry {
+            retries: self.server.webhook_retries,
+            backoff: Duration::from_millis(self.server.webhook_backoff_ms),
+        };
+        let dead_letter = self.server.webhook_dead_letter.as_deref();
+        WebhookNotifier::new(endpoints, retry, self.server.webhook_queue, dead_letter)
+            .map(Some)
+            .map_err(|err| anyhow::anyhow!("unable to open the webhook dead-letter file: {}", err))
+    }
+}
+
+fn apply_overrides<I>(value: &mut toml::Value, vars: I) -> anyhow::Result<()>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    let root = value
+        .as_table_mut()
+        .ok_or_else(|| anyhow::anyhow!("configuration must be a table"))?;
+    for (name, raw) in vars {
+        let path = match name.strip_prefix(ENV_PREFIX) {
+            Some(path) => path.to_lowercase(),
+            None => continue,
+        };
+        let (section, key) = path
+            .split_once('_')
+            .ok_or_else(|| anyhow::anyhow!("{} does not name a section and key", name))?;
+        // values are read as TOML, so `2` is a number and `true` a boolean; anything else
+        // is taken as a plain string
+        let parsed = toml::from_str::<toml::Value>(&format!("value = {}", raw))
+            .ok()
+            .and_then(|parsed| parsed.get("value").cloned())
+            .unwrap_or(toml::Value::String(raw));
+        root.entry(section.to_string())
+            .or_insert_with(|| toml::Value::Table(toml::value::Table::new()))
+            .as_table_mut()
+            .ok_or_else(|| anyhow::anyhow!("{} is not a section", section))?
+            .insert(key.to_string(), parsed);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(content: &str, vars: &[(&str, &str)]) -> anyhow::Result<Config> {
+        let mut value: toml::Value = toml::from_str(content)?;
+        apply_overrides(
+            &mut value,
+            vars.iter()
+                .map(|(name, value)| (name.to_string(), value.to_string())),
+        )?;
+        let config: Config = value.try_into()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn missing_keys_keep_their_defaults() {
+        let config = config(
+            r#"
+            [output]
+            rounding = "half-up"
+
+            [disputes]
+            chargeback_action = "flag"
+            "#,
+            &[],
+        )
+        .unwrap();
+        assert_eq!(config.output.precision, 4);
+        assert_eq!(config.output.rounding, Rounding::HalfUp);
+        assert_eq!(config.risk.withdrawal_window, 100);
+        assert_eq!(config.storage.workers, 1);
+        assert!(config.export_options().show_flags);
+    }
+
+    #[test]
+    fn environment_overrides_the_file() {
+        let config = config(
+            "[output]\nprecision = 2\n",
+            &[
+                ("PAYMENTS_OUTPUT_PRECISION", "3"),
+                ("PAYMENTS_STORAGE_MODE", "compact"),
+                ("PAYMENTS_LOGGING_LEVEL", "debug"),
+                ("PAYMENTS_LOGGING_ERRORS_FORMAT", "json"),
+                ("HOME", "/root"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.output.precision, 3);
+        assert_eq!(config.storage.mode, StorageMode::Compact);
+        assert_eq!(config.logging.level.as_deref(), Some("debug"));
+        assert_eq!(config.logging.errors_format, ErrorFormat::Json);
+    }
+
+    #[test]
+    fn unknown_or_invalid_settings_are_rejected() {
+        assert!(config("[output]\nprecison = 2\n", &[]).is_err());
+        assert!(config("", &[("PAYMENTS_OUTPUT_PRECISION", "11")]).is_err());
+        assert!(config("", &[("PAYMENTS_VERBOSE", "1")]).is_err());
+        assert!(config("[output]\nshards = 4\n", &[]).is_err());
+        assert!(config("[output]\nshards = 4\nshard_dir = \"report\"\n", &[]).is_ok());
+        assert!(config("", &[("PAYMENTS_LIMITS_DEPOSIT_HOLD_SECS", "0")]).is_err());
+        assert!(config("[input]\nas_of_time = 60\n", &[]).is_err());
+        assert!(config("[input]\nas_of_time = 60\nstart_time = 0\n", &[]).is_ok());
+        assert!(config("[server]\nwebhook_dead_letter = \"hooks.jsonl\"\n", &[]).is_err());
+        assert!(config("", &[("PAYMENTS_SERVER_WEBHOOK_QUEUE", "0")]).is_err());
+        assert!(config("", &[("PAYMENTS_SERVER_MAX_BODY", "0")]).is_err());
+    }
+}
@@ -0,0 +1,357 @@
+//! Account state that engines processing the same clients side by side can merge, for
+//! active-active deployments, see `payments merge --replicated`.
+//!
+//! Every engine is a replica with an id of its own and only ever writes its own share of an
+//! account: monotonic counters of the credits and debits of the available and held sub-ledgers,
+//! and a revision counting the transactions it applied to the account. Merging keeps the larger
+//! of every counter per replica, so merges are commutative, associative and idempotent: merging
+//! the state of a replica again, or an older copy of it, changes nothing, and replicas converge
+//! whatever order their states arrive in. Replicas must process disjoint sets of transactions;
+//! a transaction applied by two replicas counts twice.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::snapshot::AccountState;
+use crate::transactions::{saturating_add, Amount, Client};
+
+/// Monotonic counters one replica keeps for an account. Balances are credits minus debits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccountCounters {
+    /// Transactions applied to the account.
+    pub revision: u64,
+    #[serde(with = "crate::amount::text")]
+    pub available_credits: Amount,
+    #[serde(with = "crate::amount::text")]
+    pub available_debits: Amount,
+    #[serde(with = "crate::amount::text")]
+    pub held_credits: Amount,
+    #[serde(with = "crate::amount::text")]
+    pub held_debits: Amount,
+    /// Missing from snapshots taken before escrows existed.
+    #[serde(default, with = "crate::amount::text")]
+    pub escrow_credits: Amount,
+    #[serde(default, with = "crate::amount::text")]
+    pub escrow_debits: Amount,
+}
+
+impl AccountCounters {
+    /// Counters of an account only known by its balances, such as one restored from a snapshot
+    /// taken before the counters existed: a single credit or debit brought each sub-ledger there.
+    pub fn from_balances(available: Amount, held: Amount, escrowed: Amount) -> Self {
+        let (available_credits, available_debits) = split(available);
+        let (held_credits, held_debits) = split(held);
+        let (escrow_credits, escrow_debits) = split(escrowed);
+        Self {
+            revision: 0,
+            available_credits,
+            available_debits,
+            held_credits,
+            held_debits,
+            escrow_credits,
+            escrow_debits,
+        }
+    }
+
+    /// Adds the changes of a journal entry to the available, held and escrow sub-ledgers.
+    pub(crate) fn record(&mut self, available: Amount, held: Amount, escrowed: Amount) {
+        let (credit, debit) = split(available);
+        self.available_credits = saturating_add(self.available_credits, credit);
+        self.available_debits = saturating_add(self.available_debits, debit);
+        let (credit, debit) = split(held);
+        self.held_credits = saturating_add(self.held_credits, credit);
+        self.held_debits = saturating_add(self.held_debits, debit);
+        let (credit, debit) = split(escrowed);
+        self.escrow_credits = saturating_add(self.escrow_credits, credit);
+        self.escrow_debits = saturating_add(self.escrow_debits, debit);
+    }
+
+    pub fn available(&self) -> Amount {
+        difference(self.available_credits, self.available_debits)
+    }
+
+    pub fn held(&self) -> Amount {
+        difference(self.held_credits, self.held_debits)
+    }
+
+    pub fn escrowed(&self) -> Amount {
+        difference(self.escrow_credits, self.escrow_debits)
+    }
+
+    /// Larger of every counter.
+    fn join(&mut self, other: &Self) {
+        self.revision = self.revision.max(other.revision);
+        self.available_credits = self.available_credits.max(other.available_credits);
+        self.available_debits = self.available_debits.max(other.available_debits);
+        self.held_credits = self.held_credits.max(other.held_credits);
+        self.held_debits = self.held_debits.max(other.held_debits);
+        self.escrow_credits = self.escrow_credits.max(other.escrow_credits);
+        self.escrow_debits = self.escrow_debits.max(other.escrow_debits);
+    }
+
+    /// Sum of every counter, for the account as a whole.
+    fn add(&mut self, other: &Self) {
+        self.revision = self.revision.saturating_add(other.revision);
+        self.available_credits = saturating_add(self.available_credits, other.available_credits);
+        self.available_debits = saturating_add(self.available_debits, other.available_debits);
+        self.held_credits = saturating_add(self.held_credits, other.held_credits);
+        self.held_debits = saturating_add(self.held_debits, other.held_debits);
+        self.escrow_credits = saturating_add(self.escrow_credits, other.escrow_credits);
+        self.escrow_debits = saturating_add(self.escrow_debits, other.escrow_debits);
+    }
+}
+
+/// `credits - debits`, at least `Amount::MIN`. Debits are never negative.
+fn difference(credits: Amount, debits: Amount) -> Amount {
+    credits.checked_sub(debits).unwrap_or(Amount::MIN)
+}
+
+/// Positive part and negated negative part of `amount`.
+fn split(amount: Amount) -> (Amount, Amount) {
+    if amount.is_sign_negative() {
+        (Amount::ZERO, -amount)
+    } else {
+        (amount, Amount::ZERO)
+    }
+}
+
+/// Share of one replica in an account.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplicaAccount {
+    pub counters: AccountCounters,
+    pub chargebacks: u32,
+    pub disputes_total: u32,
+    /// As of `counters.revision`, as are `frozen` and `flagged`: they can go back, so the
+    /// newest revision wins.
+    pub disputes_open: u32,
+    pub frozen: bool,
+    pub flagged: bool,
+}
+
+impl ReplicaAccount {
+    fn join(&mut self, other: &Self) {
+        // ties between different states can't happen while replica ids are unique, they are
+        // still settled the same way on every replica
+        let newer = (
+            other.counters.revision,
+            other.frozen,
+            other.flagged,
+            other.disputes_open,
+        ) > (
+            self.counters.revision,
+            self.frozen,
+            self.flagged,
+            self.disputes_open,
+        );
+        if newer {
+            self.disputes_open = other.disputes_open;
+            self.frozen = other.frozen;
+            self.flagged = other.flagged;
+        }
+        self.counters.join(&other.counters);
+        self.chargebacks = self.chargebacks.max(other.chargebacks);
+        self.disputes_total = self.disputes_total.max(other.disputes_total);
+    }
+}
+
+/// An account as the union of the shares of every replica that applied transactions to it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MergeableAccount {
+    pub client: Client,
+    pub replicas: BTreeMap<String, ReplicaAccount>,
+}
+
+impl MergeableAccount {
+    pub fn merge(&mut self, other: &MergeableAccount) {
+        for (replica, share) in &other.replicas {
+            match self.replicas.get_mut(replica) {
+                Some(mine) => mine.join(share),
+                None => {
+                    self.replicas.insert(replica.clone(), share.clone());
+                }
+            }
+        }
+    }
+
+    /// Counters of all replicas together.
+    pub fn counters(&self) -> AccountCounters {
+        let mut counters = AccountCounters::default();
+        for share in self.replicas.values() {
+            counters.add(&share.counters);
+        }
+        counters
+    }
+
+    pub fn available(&self) -> Amount {
+        self.counters().available()
+    }
+
+    pub fn held(&self) -> Amount {
+        self.counters().held()
+    }
+
+    /// Locked as soon as one replica locked it.
+    pub fn frozen(&self) -> bool {
+        self.replicas.values().any(|share| share.frozen)
+    }
+
+    /// The merged account, in the form snapshots keep accounts in.
+    pub fn state(&self) -> AccountState {
+        let counters = self.counters();
+        let shares = self.replicas.values();
+        AccountState {
+            client: self.client,
+            available: counters.available(),
+            held: counters.held(),
+            escrowed: counters.escrowed(),
+            frozen: self.frozen(),
+            chargebacks: shares.clone().map(|share| share.chargebacks).sum(),
+            flagged: shares.clone().any(|share| share.flagged),
+            disputes_open: shares.clone().map(|share| share.disputes_open).sum(),
+            disputes_total: shares.map(|share| share.disputes_total).sum(),
+            counters: Some(counters),
+        }
+    }
+}
+
+/// Mergeable accounts of every client, ordered by client.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplicatedState {
+    pub accounts: BTreeMap<Client, MergeableAccount>,
+}
+
+impl ReplicatedState {
+    /// State with the `accounts` of one replica, e.g. from `PaymentEngine::state`.
+    pub fn of<'a, I>(replica: &str, accounts: I) -> Self
+    where
+        I: IntoIterator<Item = &'a AccountState>,
+    {
+        let accounts = accounts
+            .into_iter()
+            .map(|account| {
+                let share = ReplicaAccount {
+                    counters: account.counters.unwrap_or_else(|| {
+                        AccountCounters::from_balances(
+                            account.available,
+                            account.held,
+                            account.escrowed,
+                        )
+                    }),
+                    chargebacks: account.chargebacks,
+                    disputes_total: account.disputes_total,
+                    disputes_open: account.disputes_open,
+                    frozen: account.frozen,
+                    flagged: account.flagged,
+                };
+                let merged = MergeableAccount {
+                    client: account.client,
+                    replicas: BTreeMap::from([(replica.to_string(), share)]),
+                };
+                (account.client, merged)
+            })
+            .collect();
+        Self { accounts }
+    }
+
+    pub fn merge(&mut self, other: &ReplicatedState) {
+        for (client, account) in &other.accounts {
+            match self.accounts.get_mut(client) {
+                Some(mine) => mine.merge(account),
+                None => {
+                    self.accounts.insert(*client, account.clone());
+                }
+            }
+        }
+    }
+
+    pub fn get(&self, client: Client) -> Option<&MergeableAccount> {
+        self.accounts.get(&client)
+    }
+
+    /// Merged accounts, ordered by client.
+    pub fn account_states(&self) -> Vec<AccountState> {
+        self.accounts
+            .values()
+            .map(MergeableAccount::state)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::amount;
+    use crate::transactions::{PaymentEngine, Transaction};
+
+    fn replica(id: &str, transactions: Vec<Transaction>) -> ReplicatedState {
+        let mut engine = PaymentEngine::new();
+        for transaction in transactions {
+            let _ = engine.process_transaction(transaction);
+        }
+        ReplicatedState::of(id, &engine.state().accounts)
+    }
+
+    #[test]
+    fn replicas_converge_whatever_the_merge_order() {
+        let east = replica(
+            "east",
+            vec![
+                Transaction::new_deposit(1, 1, amount!(10.0)).unwrap(),
+                Transaction::new_withdrawal(1, 2, amount!(4.0)).unwrap(),
+            ],
+        );
+        let east_before = replica(
+            "east",
+            vec![Transaction::new_deposit(1, 1, amount!(10.0)).unwrap()],
+        );
+        let west = replica(
+            "west",
+            vec![
+                Transaction::new_deposit(1, 3, amount!(5.0)).unwrap(),
+                Transaction::new_dispute(1, 3),
+                Transaction::new_deposit(2, 4, amount!(1.0)).unwrap(),
+            ],
+        );
+
+        let mut forward = east.clone();
+        forward.merge(&west);
+        // a retried or late delivery changes nothing
+        forward.merge(&east_before);
+        forward.merge(&west);
+        let mut backward = west.clone();
+        backward.merge(&east_before);
+        backward.merge(&east);
+        assert_eq!(forward, backward);
+
+        let account = forward.get(1).unwrap();
+        assert_eq!(account.available(), amount!(6.0));
+        assert_eq!(account.held(), amount!(5.0));
+        let counters = account.counters();
+        assert_eq!(counters.revision, 4);
+        assert_eq!(counters.available_credits, amount!(15.0));
+        assert_eq!(counters.available_debits, amount!(9.0));
+        let states = forward.account_states();
+        assert_eq!(states.len(), 2);
+        assert_eq!(states[0].disputes_open, 1);
+        assert_eq!(states[1].available, amount!(1.0));
+    }
+
+    #[test]
+    fn newest_revision_decides_the_lock() {
+        let mut engine = PaymentEngine::new();
+        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, amount!(3.0)).unwrap());
+        let _ = engine.process_transaction(Transaction::new_dispute(1, 1));
+        let disputed = ReplicatedState::of("a", &engine.state().accounts);
+        let _ = engine.process_transaction(Transaction::new_chargeback(1, 1));
+        let charged_back = ReplicatedState::of("a", &engine.state().accounts);
+
+        let mut merged = charged_back.clone();
+        merged.merge(&disputed);
+        assert_eq!(merged, charged_back);
+        let account = merged.get(1).unwrap().state();
+        assert!(account.frozen);
+        assert_eq!(account.disputes_open, 0);
+        assert_eq!(account.chargebacks, 1);
+        assert_eq!(account.available, amount!(0.0));
+    }
+}
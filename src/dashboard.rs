@@ -0,0 +1,206 @@
+//! Live terminal dashboard shown on stderr while a file is processed, see `--tui`.
+//!
+//! Frames are plain text redrawn in place with ANSI escape sequences: throughput, the engine
+//! counters, the accounts with the largest totals and the most recent rejections.
+
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::observer::{EngineObserver, Rejection};
+use crate::transactions::{
+    Account, Client, PaymentEngine, TransactionId, TransactionValidationError,
+};
+
+/// Time between two frames.
+const REFRESH: Duration = Duration::from_millis(500);
+
+/// Number of accounts and rejections listed.
+const ROWS: usize = 10;
+
+/// Clears the terminal and moves the cursor to its top left corner.
+const CLEAR: &str = "\x1b[2J\x1b[H";
+
+/// Most recent rejections, shared between the dashboard and the observer it subscribes.
+type RecentRejections = Arc<Mutex<VecDeque<Rejection>>>;
+
+pub struct Dashboard {
+    started: Instant,
+    drawn: Option<Instant>,
+    records: u64,
+    rejections: RecentRejections,
+}
+
+impl Default for Dashboard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Dashboard {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            drawn: None,
+            records: 0,
+            rejections: RecentRejections::default(),
+        }
+    }
+
+    /// Observer to subscribe to the engine so that rejections show up on the dashboard.
+    pub fn observer(&self) -> Box<dyn EngineObserver> {
+        Box::new(RejectionFeed(Arc::clone(&self.rejections)))
+    }
+
+    /// Counts a processed record and redraws when the last frame is old enough.
+    pub fn record(&mut self, engine: &PaymentEngine) {
+        self.records += 1;
+        if self.drawn.is_none_or(|drawn| drawn.elapsed() >= REFRESH) {
+            self.draw(engine);
+        }
+    }
+
+    /// Draws the final frame, which stays on screen.
+    pub fn finish(&mut self, engine: &PaymentEngine) {
+        self.draw(engine);
+    }
+
+    fn draw(&mut self, engine: &PaymentEngine) {
+        let frame = self.render(engine, self.started.elapsed());
+        // a broken terminal must not stop processing
+        let _ = write!(io::stderr().lock(), "{}{}", CLEAR, frame);
+        self.drawn = Some(Instant::now());
+    }
+
+    /// Text of one frame after `elapsed` time.
+    pub fn render(&self, engine: &PaymentEngine, elapsed: Duration) -> String {
+        let metrics = engine.metrics();
+        let applied: u64 = metrics.applied.values().sum();
+        let seconds = elapsed.as_secs_f64();
+        let throughput = if seconds > 0.0 {
+            self.records as f64 / seconds
+        } else {
+            0.0
+        };
+
+        let mut frame = String::new();
+        let _ = writeln!(
+            frame,
+            "{} records in {:.1}s, {:.0} records/s",
+            self.records, seconds, throughput
+        );
+        let _ = writeln!(
+            frame,
+            "applied {}  rejected {}  open disputes {}  frozen accounts {}",
+            applied, metrics.rejected, metrics.open_disputes, metrics.frozen_accounts
+        );
+
+        let _ = writeln!(frame, "\ntop accounts by total");
+        let _ = writeln!(
+            frame,
+            "{:>8} {:>16} {:>16} {:>16}",
+            "client", "available", "held", "total"
+        );
+        for account in top_accounts(engine) {
+            let _ = writeln!(
+                frame,
+                "{:>8} {:>16} {:>16} {:>16}{}",
+                account.client(),
+                account.available(),
+                account.held(),
+                account.total(),
+                if account.frozen() { "  frozen" } else { "" }
+            );
+        }
+
+        let _ = writeln!(frame, "\nrecent rejections");
+        for rejection in self.rejections.lock().unwrap().iter().rev() {
+            let _ = writeln!(
+                frame,
+                "tx {} client {}: {}",
+                rejection.tx, rejection.client, rejection.reason
+            );
+        }
+        frame
+    }
+}
+
+/// The `ROWS` accounts with the largest totals, largest first.
+fn top_accounts(engine: &PaymentEngine) -> Vec<&Account> {
+    let mut accounts: Vec<&Account> = engine.accounts_iter().collect();
+    let by_total = |left: &&Account, right: &&Account| {
+        right
+            .total()
+            .cmp(&left.total())
+            .then(left.client().cmp(&right.client()))
+    };
+    if accounts.len() > ROWS {
+        accounts.select_nth_unstable_by(ROWS, by_total);
+        accounts.truncate(ROWS);
+    }
+    accounts.sort_by(by_total);
+    accounts
+}
+
+struct RejectionFeed(RecentRejections);
+
+impl EngineObserver for RejectionFeed {
+    fn on_rejected(
+        &mut self,
+        client: Client,
+        tx: TransactionId,
+        error: &TransactionValidationError,
+    ) {
+        let mut rejections = self.0.lock().unwrap();
+        if rejections.len() == ROWS {
+            rejections.pop_front();
+        }
+        rejections.push_back(Rejection {
+            client,
+            tx,
+            code: error.code(),
+            reason: error.to_string(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::amount;
+    use crate::transactions::{Amount, Transaction};
+
+    #[test]
+    fn frames_list_top_accounts_and_recent_rejections() {
+        let mut engine = PaymentEngine::new();
+        let mut dashboard = Dashboard::new();
+        engine.subscribe(dashboard.observer());
+        for client in 1..=12 {
+            let deposit = Transaction::new_deposit(client, client as u32, amount!(1)).unwrap();
+            let _ = engine.process_transaction(deposit);
+            dashboard.records += 1;
+        }
+        let _ = engine.process_transaction(Transaction::new_deposit(12, 13, amount!(5)).unwrap());
+        let _ = engine.process_transaction(Transaction::new_dispute(3, 99));
+        dashboard.records += 2;
+
+        let frame = dashboard.render(&engine, Duration::from_secs(2));
+        let lines: Vec<&str> = frame.lines().collect();
+        assert_eq!(lines[0], "14 records in 2.0s, 7 records/s");
+        assert_eq!(
+            lines[1],
+            "applied 13  rejected 1  open disputes 0  frozen accounts 0"
+        );
+        let top = [amount!(6), Amount::ZERO, amount!(6)].map(|amount| amount.to_string());
+        assert_eq!(
+            lines[5].split_whitespace().collect::<Vec<_>>(),
+            ["12", &top[0], &top[1], &top[2]]
+        );
+        assert_eq!(lines[6].split_whitespace().next(), Some("1"));
+        assert_eq!(lines[14].split_whitespace().next(), Some("9"));
+        assert_eq!(lines[16], "recent rejections");
+        assert!(lines[17].starts_with("tx 99 client 3: "));
+    }
+}
@@ -0,0 +1,147 @@
+//! Ids of the deposits, withdrawals and holds seen by this and earlier runs, so that inputs
+//! overlapping an already processed one can be re-run, see `--skip-duplicates`.
+//!
+//! Each id is kept with a fingerprint of its record (type, client and amount), which tells a
+//! replay of the same record from a different record reusing the id. The index file holds
+//! 12 bytes per id, the id and the fingerprint in little-endian order.
+
+use rustc_hash::FxHashMap;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::transactions::{Transaction, TransactionId};
+
+const ENTRY_LEN: usize = 12;
+
+/// What the index knows of a record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Seen {
+    /// First record with its id, or a record that doesn't create a transaction.
+    New,
+    /// Same id, type, client and amount as a record seen before.
+    Replay,
+    /// Same id as a record seen before, but not the same record.
+    Conflict,
+}
+
+#[derive(Debug, Default)]
+pub struct DedupeIndex {
+    seen: FxHashMap<TransactionId, u64>,
+}
+
+impl DedupeIndex {
+    /// Index saved by `save`, empty when `path` doesn't exist yet.
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let bytes = fs::read(path)?;
+        if bytes.len() % ENTRY_LEN != 0 {
+            anyhow::bail!("{} is not a dedupe index", path.display());
+        }
+        let seen = bytes
+            .chunks_exact(ENTRY_LEN)
+            .map(|entry| {
+                let (tx, fingerprint) = entry.split_at(4);
+                (
+                    TransactionId::from_le_bytes(tx.try_into().unwrap()),
+                    u64::from_le_bytes(fingerprint.try_into().unwrap()),
+                )
+            })
+            .collect();
+        Ok(Self { seen })
+    }
+
+    /// Writes the index next to `path` first and then moves it into place, like snapshots.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+
+        let mut entries: Vec<(&TransactionId, &u64)> = self.seen.iter().collect();
+        entries.sort_unstable();
+        let mut writer = BufWriter::new(File::create(&partial)?);
+        for (tx, fingerprint) in entries {
+            writer.write_all(&tx.to_le_bytes())?;
+            writer.write_all(&fingerprint.to_le_bytes())?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(&partial, path)?;
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+
+    /// Looks `transaction` up and remembers it when its id is new.
+    pub fn check(&mut self, transaction: &Transaction) -> Seen {
+        let fingerprint = match fingerprint(transaction) {
+            Some(fingerprint) => fingerprint,
+            None => return Seen::New,
+        };
+        match self.seen.get(&transaction.tx()) {
+            None => {
+                self.seen.insert(transaction.tx(), fingerprint);
+                Seen::New
+            }
+            Some(seen) if *seen == fingerprint => Seen::Replay,
+            Some(_) => Seen::Conflict,
+        }
+    }
+}
+
+/// Stable across runs and builds, unlike `std::hash`. `None` for records that refer to another
+/// transaction instead of creating one.
+fn fingerprint(transaction: &Transaction) -> Option<u64> {
+    let amount = transaction.amount()?;
+    let record = format!(
+        "{}:{}:{}",
+        transaction.kind().name(),
+        transaction.client(),
+        amount.normalize()
+    );
+    let digest = Sha256::digest(record.as_bytes());
+    Some(u64::from_le_bytes(digest[..8].try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::amount;
+
+    #[test]
+    fn replays_and_conflicts_survive_a_reload() {
+        let mut index = DedupeIndex::default();
+        let deposit = Transaction::new_deposit(1, 1, amount!(2.5)).unwrap();
+        assert_eq!(index.check(&deposit), Seen::New);
+        assert_eq!(index.check(&Transaction::new_dispute(1, 1)), Seen::New);
+        assert_eq!(index.len(), 1);
+
+        let path = std::env::temp_dir().join(format!("payments-dedupe-{}.idx", std::process::id()));
+        index.save(&path).unwrap();
+        let mut index = DedupeIndex::load(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        let replay = Transaction::new_deposit(1, 1, amount!(2.50)).unwrap();
+        assert_eq!(index.check(&replay), Seen::Replay);
+        let conflict = Transaction::new_withdrawal(1, 1, amount!(2.5)).unwrap();
+        assert_eq!(index.check(&conflict), Seen::Conflict);
+        assert_eq!(
+            index.check(&Transaction::new_deposit(2, 1, amount!(2.5)).unwrap()),
+            Seen::Conflict
+        );
+        assert_eq!(
+            index.check(&Transaction::new_deposit(1, 2, amount!(2.5)).unwrap()),
+            Seen::New
+        );
+    }
+}
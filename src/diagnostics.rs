@@ -0,0 +1,191 @@
+//! Problems met while processing that don't stop the run, such as skipped records.
+//!
+//! They are logged as warnings by default. With `ErrorFormat::Json` each one is written to stderr
+//! as a single-line JSON object instead, whatever the log level, so pipelines can route them.
+
+use serde::{Deserialize, Serialize};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::RwLock;
+
+use crate::anonymize::Anonymizer;
+use crate::transactions::{Client, TransactionId, TransactionValidationError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorFormat {
+    /// `log::warn!` messages.
+    #[default]
+    Text,
+    /// One JSON object per line on stderr.
+    Json,
+}
+
+impl std::str::FromStr for ErrorFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(ErrorFormat::Text),
+            "json" => Ok(ErrorFormat::Json),
+            _ => Err(format!("unknown error format: {}", s)),
+        }
+    }
+}
+
+static FORMAT: AtomicU8 = AtomicU8::new(0);
+
+/// Selects how `report` emits problems, for the whole process.
+pub fn set_error_format(format: ErrorFormat) {
+    FORMAT.store(format as u8, Ordering::Relaxed);
+}
+
+static ANONYMIZER: RwLock<Option<Anonymizer>> = RwLock::new(None);
+
+/// Makes `report` replace client ids by their pseudonyms, for the whole process.
+pub fn set_anonymizer(anonymizer: Option<Anonymizer>) {
+    *ANONYMIZER.write().unwrap() = anonymizer;
+}
+
+pub fn error_format() -> ErrorFormat {
+    match FORMAT.load(Ordering::Relaxed) {
+        0 => ErrorFormat::Text,
+        _ => ErrorFormat::Json,
+    }
+}
+
+/// A single problem. `context` only prefixes the text message, it isn't part of the JSON object.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ErrorEvent {
+    pub code: &'static str,
+    pub tx: Option<TransactionId>,
+    pub client: Option<Client>,
+    pub reason: String,
+    /// Input line the record starts on, when known.
+    pub line: Option<u64>,
+    /// Memo of the record, only present when it has one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memo: Option<String>,
+    #[serde(skip)]
+    pub context: &'static str,
+}
+
+impl ErrorEvent {
+    pub fn new(code: &'static str, context: &'static str, reason: impl ToString) -> Self {
+        Self {
+            code,
+            tx: None,
+            client: None,
+            reason: reason.to_string(),
+            line: None,
+            memo: None,
+            context,
+        }
+    }
+
+    /// A record that couldn't be turned into a transaction.
+    pub fn unparsable(err: &TransactionValidationError) -> Self {
+        Self::new(err.code(), "unable to parse transaction", err)
+            .with_transaction(err.client(), err.tx())
+    }
+
+    /// A transaction the engine refused.
+    pub fn rejected(err: &TransactionValidationError) -> Self {
+        Self::new(err.code(), "unable to process transaction", err)
+            .with_transaction(err.client(), err.tx())
+    }
+
+    pub fn with_transaction(mut self, client: Client, tx: TransactionId) -> Self {
+        self.client = Some(client);
+        self.tx = Some(tx);
+        self
+    }
+
+    pub fn with_client(mut self, client: Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    pub fn with_line(mut self, line: Option<u64>) -> Self {
+        self.line = line;
+        self
+    }
+
+    pub fn with_memo(mut self, memo: Option<&str>) -> Self {
+        self.memo = memo.map(str::to_string);
+        self
+    }
+}
+
+impl std::fmt::Display for ErrorEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if !self.context.is_empty() {
+            write!(f, "{}: ", self.context)?;
+        }
+        write!(f, "{}", self.reason)?;
+        if let Some(memo) = &self.memo {
+            write!(f, " (memo: {})", memo)?;
+        }
+        Ok(())
+    }
+}
+
+/// Emits `event` in the selected `ErrorFormat`.
+pub fn report(mut event: ErrorEvent) {
+    let anonymizer = *ANONYMIZER.read().unwrap();
+    let pseudonym = match (anonymizer, event.client) {
+        (Some(anonymizer), Some(client)) => {
+            event.reason = anonymizer.scrub(client, &event.reason);
+            Some(anonymizer.pseudonym(client))
+        }
+        _ => None,
+    };
+    match error_format() {
+        ErrorFormat::Text => log::warn!("{}", event),
+        ErrorFormat::Json => {
+            let mut value = serde_json::to_value(&event).expect("error events serialize to JSON");
+            if let Some(pseudonym) = pseudonym {
+                value["client"] = pseudonym.into();
+            }
+            let mut line = serde_json::to_vec(&value).expect("error events serialize to JSON");
+            line.push(b'\n');
+            // a failing stderr leaves nowhere else to report to
+            let _ = io::stderr().lock().write_all(&line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_serialize_with_their_context() {
+        let err = TransactionValidationError::FrozenAccount { client: 3, tx: 7 };
+        let event = ErrorEvent::rejected(&err).with_line(Some(12));
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"code":"frozen_account","tx":7,"client":3,"reason":"account of client 3 is frozen","line":12}"#
+        );
+        assert_eq!(
+            event.to_string(),
+            "unable to process transaction: account of client 3 is frozen"
+        );
+
+        let event = ErrorEvent::new("write_failed", "", "disk full");
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"code":"write_failed","tx":null,"client":null,"reason":"disk full","line":null}"#
+        );
+        assert_eq!(event.to_string(), "disk full");
+        let event = ErrorEvent::rejected(&err).with_memo(Some("INV-42"));
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"code":"frozen_account","tx":7,"client":3,"reason":"account of client 3 is frozen","line":null,"memo":"INV-42"}"#
+        );
+        assert_eq!(
+            event.to_string(),
+            "unable to process transaction: account of client 3 is frozen (memo: INV-42)"
+        );
+    }
+}
@@ -0,0 +1,134 @@
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io;
+
+use crate::ingest::ReportRecord;
+use crate::transactions::Client;
+
+/// A client whose account differs between two reports. `old` or `new` is missing when the
+/// client only appears in one of them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccountDiff {
+    pub client: Client,
+    pub old: Option<ReportRecord>,
+    pub new: Option<ReportRecord>,
+}
+
+impl AccountDiff {
+    pub fn change(&self) -> &'static str {
+        match (&self.old, &self.new) {
+            (None, _) => "added",
+            (_, None) => "removed",
+            _ => "changed",
+        }
+    }
+}
+
+impl Serialize for AccountDiff {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let old = self.old.as_ref();
+        let new = self.new.as_ref();
+        let mut state = serializer.serialize_struct("AccountDiff", 8)?;
+        state.serialize_field("client", &self.client)?;
+        state.serialize_field("change", self.change())?;
+        state.serialize_field("old_available", &old.map(|record| record.available))?;
+        state.serialize_field("new_available", &new.map(|record| record.available))?;
+        state.serialize_field("old_held", &old.map(|record| record.held))?;
+        state.serialize_field("new_held", &new.map(|record| record.held))?;
+        state.serialize_field("old_locked", &old.map(|record| record.locked))?;
+        state.serialize_field("new_locked", &new.map(|record| record.locked))?;
+        state.end()
+    }
+}
+
+/// Clients whose available or held funds or locked state differ between two account reports,
+/// ordered by client. Amounts are compared by value, so `1.5` equals `1.50`.
+pub fn diff_reports(old: &[ReportRecord], new: &[ReportRecord]) -> Vec<AccountDiff> {
+    let old: BTreeMap<Client, &ReportRecord> =
+        old.iter().map(|record| (record.client, record)).collect();
+    let new: BTreeMap<Client, &ReportRecord> =
+        new.iter().map(|record| (record.client, record)).collect();
+
+    let mut diffs = vec![];
+    for (client, old_record) in old.iter() {
+        match new.get(client) {
+            Some(new_record) if new_record == old_record => {}
+            new_record => diffs.push(AccountDiff {
+                client: *client,
+                old: Some(**old_record),
+                new: new_record.map(|record| **record),
+            }),
+        }
+    }
+    for (client, new_record) in new.iter() {
+        if !old.contains_key(client) {
+            diffs.push(AccountDiff {
+                client: *client,
+                old: None,
+                new: Some(**new_record),
+            });
+        }
+    }
+    diffs.sort_by_key(|diff| diff.client);
+    diffs
+}
+
+pub fn diffs_as_csv<W: io::Write>(diffs: &[AccountDiff], output: W) -> Result<(), Box<dyn Error>> {
+    let mut wtr = csv::Writer::from_writer(output);
+    for diff in diffs {
+        wtr.serialize(diff)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::amount;
+    use crate::transactions::Amount;
+
+    fn record(client: Client, available: Amount, held: Amount, locked: bool) -> ReportRecord {
+        ReportRecord {
+            client,
+            available,
+            held,
+            locked,
+        }
+    }
+
+    #[test]
+    fn only_differing_clients_are_reported() {
+        let old = [
+            record(1, amount!(1.5), amount!(0.0), false),
+            record(2, amount!(2.0), amount!(0.0), false),
+            record(3, amount!(3.0), amount!(0.0), false),
+        ];
+        let new = [
+            record(4, amount!(4.0), amount!(0.0), false),
+            record(2, amount!(2.0), amount!(0.0), true),
+            record(1, amount!(1.50), amount!(0.0), false),
+        ];
+        let diffs = diff_reports(&old, &new);
+        assert_eq!(
+            diffs
+                .iter()
+                .map(|diff| (diff.client, diff.change()))
+                .collect::<Vec<_>>(),
+            vec![(2, "changed"), (3, "removed"), (4, "added")]
+        );
+
+        let mut output = vec![];
+        diffs_as_csv(&diffs[..2], &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,change,old_available,new_available,old_held,new_held,old_locked,new_locked\n\
+             2,changed,2.0,2.0,0.0,0.0,false,true\n\
+             3,removed,3.0,,0.0,,false,\n"
+        );
+    }
+}
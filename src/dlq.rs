@@ -0,0 +1,205 @@
+//! Dead-letter queue: the records a run couldn't process, kept with the reason and the
+//! transaction so that `payments retry-dlq` can apply them once the cause is fixed.
+//!
+//! Like `diagnostics`, the queue is process wide. Once `open` selected its file, every `push`
+//! appends a JSON line to it.
+
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, LineWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use crate::diagnostics::{report, ErrorEvent};
+use crate::transactions::{BatchResult, PaymentEngine, Transaction, TransactionValidationError};
+
+/// A record that failed, as the line written to the queue.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub code: String,
+    pub reason: String,
+    /// Input line the record starts on, when known.
+    pub line: Option<u64>,
+    /// Batch of the record; the records of a batch are retried all or nothing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch: Option<String>,
+    /// Not set for records that couldn't be parsed, which have to be fixed in the input and
+    /// are never retried.
+    pub transaction: Option<Transaction>,
+}
+
+impl DeadLetter {
+    pub fn new(event: &ErrorEvent, transaction: Option<Transaction>) -> Self {
+        Self {
+            code: event.code.to_string(),
+            reason: event.reason.clone(),
+            line: event.line,
+            batch: None,
+            transaction,
+        }
+    }
+
+    pub fn with_batch(mut self, batch: Option<&str>) -> Self {
+        self.batch = batch.map(str::to_string);
+        self
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static QUEUE: Mutex<Option<LineWriter<File>>> = Mutex::new(None);
+
+/// Makes `push` append to the file at `path`, for the whole process.
+pub fn open<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    *QUEUE.lock().unwrap() = Some(LineWriter::new(file));
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Whether a queue is open, so that callers only keep a copy of their transactions when it is.
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Appends `letter` to the queue, if one is open.
+pub fn push(letter: DeadLetter) {
+    let mut queue = QUEUE.lock().unwrap();
+    let Some(writer) = queue.as_mut() else {
+        return;
+    };
+    let mut line = serde_json::to_vec(&letter).expect("dead letters serialize to JSON");
+    line.push(b'\n');
+    if let Err(err) = writer.write_all(&line) {
+        log::error!("unable to write dead letter: {}", err);
+    }
+}
+
+/// Reports `event` and pushes the record it is about to the queue, `transaction` being `None`
+/// when the record couldn't be parsed.
+pub fn report_dead_letter(
+    event: ErrorEvent,
+    transaction: Option<Transaction>,
+    batch: Option<&str>,
+) {
+    if enabled() {
+        push(DeadLetter::new(&event, transaction).with_batch(batch));
+    }
+    report(event);
+}
+
+/// The letters of the queue file at `path`, in the order they were pushed.
+pub fn read_dead_letters<P: AsRef<Path>>(path: P) -> anyhow::Result<Vec<DeadLetter>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut letters = vec![];
+    for line in reader.lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            letters.push(serde_json::from_str(&line)?);
+        }
+    }
+    Ok(letters)
+}
+
+/// Replaces the queue file at `path` by `letters`.
+pub fn write_dead_letters<P: AsRef<Path>>(path: P, letters: &[DeadLetter]) -> io::Result<()> {
+    let mut content = vec![];
+    for letter in letters {
+        serde_json::to_writer(&mut content, letter)?;
+        content.push(b'\n');
+    }
+    fs::write(path, content)
+}
+
+/// Applies the transactions of `letters`, those of the letters of a batch all or nothing,
+/// reports those rejected again and returns the letters still failing: the rejected ones, with
+/// their new reason, and the unparsable ones.
+pub fn retry(engine: &mut PaymentEngine, letters: Vec<DeadLetter>) -> Vec<DeadLetter> {
+    let mut failing = vec![];
+    let mut letters: Vec<Option<DeadLetter>> = letters.into_iter().map(Some).collect();
+    for index in 0..letters.len() {
+        let Some(letter) = letters[index].take() else {
+            continue;
+        };
+        let Some(batch) = letter.batch.clone() else {
+            match letter.transaction.clone() {
+                Some(transaction) => {
+                    if let Err(error) = engine.process_transaction(transaction) {
+                        failing.push(rejected_again(letter, &error));
+                    }
+                }
+                None => failing.push(letter),
+            }
+            continue;
+        };
+        // the records of a batch needn't be next to each other, the one that couldn't be parsed
+        // is pushed as soon as it is read
+        let mut group = vec![letter];
+        group.extend(
+            letters[index + 1..]
+                .iter_mut()
+                .filter_map(|later| later.take_if(|later| later.batch.as_ref() == Some(&batch))),
+        );
+        let Some(transactions) = group
+            .iter()
+            .map(|letter| letter.transaction.clone())
+            .collect::<Option<Vec<_>>>()
+        else {
+            failing.extend(group);
+            continue;
+        };
+        if let BatchResult::Rejected { index, error } = engine.process_batch(transactions) {
+            group[index] = rejected_again(group[index].clone(), &error);
+            failing.extend(group);
+        }
+    }
+    failing
+}
+
+fn rejected_again(mut letter: DeadLetter, error: &TransactionValidationError) -> DeadLetter {
+    let event = ErrorEvent::rejected(error).with_line(letter.line);
+    letter.code = event.code.to_string();
+    letter.reason = event.reason.clone();
+    report(event);
+    letter
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::amount;
+
+    fn letter(transaction: Option<Transaction>, batch: Option<&str>) -> DeadLetter {
+        let err = TransactionValidationError::FrozenAccount { client: 1, tx: 0 };
+        DeadLetter::new(&ErrorEvent::rejected(&err), transaction).with_batch(batch)
+    }
+
+    #[test]
+    fn letters_still_failing_are_kept_with_their_new_reason() {
+        let mut engine = PaymentEngine::new();
+        let deposit = |tx, amount| Transaction::new_deposit(1, tx, amount).unwrap();
+        let withdrawal = |tx, amount| Transaction::new_withdrawal(1, tx, amount).unwrap();
+        let letters = vec![
+            letter(Some(deposit(1, amount!(10.0))), None),
+            letter(None, None),
+            // the whole batch fails on its withdrawal, its deposit isn't applied either
+            letter(Some(deposit(2, amount!(5.0))), Some("b1")),
+            letter(Some(withdrawal(3, amount!(20.0))), Some("b1")),
+            letter(Some(withdrawal(4, amount!(4.0))), Some("b2")),
+        ];
+
+        let path = std::env::temp_dir().join(format!("payments-dlq-{}.jsonl", std::process::id()));
+        write_dead_letters(&path, &letters).unwrap();
+        let letters = read_dead_letters(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let failing = retry(&mut engine, letters);
+
+        let account = engine.accounts_iter().next().unwrap();
+        assert_eq!(account.available(), amount!(6.0));
+        assert_eq!(failing.len(), 3);
+        assert!(failing[0].transaction.is_none());
+        assert_eq!(failing[1].code, "frozen_account");
+        assert_eq!(failing[2].code, "insufficient_funds");
+        assert_eq!(failing[2].batch.as_deref(), Some("b1"));
+    }
+}
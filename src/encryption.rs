@@ -0,0 +1,89 @@
+//! AES-256-GCM encryption of snapshots and account reports, see `[encryption]`.
+//!
+//! An encrypted file starts with `MAGIC`, followed by a random 12-byte nonce and the ciphertext
+//! with its tag. The key is the SHA-256 of a secret, so the secret should be long and random
+//! rather than a password.
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Nonce};
+use sha2::{Digest, Sha256};
+
+/// First bytes of every encrypted file; plain snapshots and reports never start with them.
+pub const MAGIC: &[u8] = b"PAYMENTS-AES-256-GCM\n";
+
+const NONCE_LEN: usize = 12;
+
+#[derive(Clone)]
+pub struct EncryptionKey {
+    cipher: Aes256Gcm,
+}
+
+impl std::fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncryptionKey").finish_non_exhaustive()
+    }
+}
+
+impl EncryptionKey {
+    pub fn new(secret: &[u8]) -> Self {
+        let key: [u8; 32] = Sha256::digest(secret).into();
+        Self {
+            cipher: Aes256Gcm::new(&key.into()),
+        }
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| anyhow::anyhow!("unable to encrypt"))?;
+        let mut sealed = Vec::with_capacity(MAGIC.len() + NONCE_LEN + ciphertext.len());
+        sealed.extend_from_slice(MAGIC);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    /// The plaintext of `sealed`, written by `encrypt` with the same secret.
+    pub fn decrypt(&self, sealed: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let body = sealed
+            .strip_prefix(MAGIC)
+            .ok_or_else(|| anyhow::anyhow!("the data is not encrypted"))?;
+        if body.len() < NONCE_LEN {
+            anyhow::bail!("the encrypted data is truncated");
+        }
+        let (nonce, ciphertext) = body.split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow::anyhow!("wrong key, or the encrypted data was altered"))
+    }
+}
+
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_same_secret_decrypts() {
+        let key = EncryptionKey::new(b"secret");
+        let sealed = key.encrypt(b"client,available\n1,10.0\n").unwrap();
+        assert!(is_encrypted(&sealed));
+        assert!(!sealed
+            .windows(b"available".len())
+            .any(|window| window == b"available"));
+        assert_eq!(key.decrypt(&sealed).unwrap(), b"client,available\n1,10.0\n");
+        // every encryption draws a new nonce
+        assert_ne!(key.encrypt(b"client,available\n1,10.0\n").unwrap(), sealed);
+
+        assert!(EncryptionKey::new(b"other").decrypt(&sealed).is_err());
+        let mut altered = sealed.clone();
+        *altered.last_mut().unwrap() ^= 1;
+        assert!(key.decrypt(&altered).is_err());
+        assert!(key.decrypt(b"{}").is_err());
+    }
+}
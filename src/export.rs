@@ -1,15 +1,987 @@
-use crate::transactions::Account;
+use crate::amount::RoundingStrategy;
+use crate::anonymize::Anonymizer;
+use crate::ledger::{JournalEntry, LedgerAccount};
+use crate::metrics::{EngineMetrics, MerchantStats, PeriodActivity};
+use crate::observer::Rejection;
+use crate::rules::Rules;
+use crate::snapshot::PeriodStatement;
+use crate::transactions::{Account, Amount, Client, DisputeState, Transaction, TransactionKind};
+use crate::velocity::SuspiciousActivity;
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+use serde::Deserialize;
+use std::collections::HashMap;
 use std::error::Error;
 use std::io;
 
-pub fn accounts_info_as_csv<W: io::Write>(
-    accounts: Vec<Account>,
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Rounding {
+    HalfUp,
+    #[default]
+    Bankers,
+    Truncate,
+}
+
+impl Rounding {
+    fn strategy(self) -> RoundingStrategy {
+        match self {
+            Rounding::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+            Rounding::Bankers => RoundingStrategy::MidpointNearestEven,
+            Rounding::Truncate => RoundingStrategy::ToZero,
+        }
+    }
+}
+
+impl std::str::FromStr for Rounding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "half-up" => Ok(Rounding::HalfUp),
+            "bankers" => Ok(Rounding::Bankers),
+            "truncate" => Ok(Rounding::Truncate),
+            _ => Err(format!("unknown rounding strategy: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    /// Needs the `parquet` feature.
+    Parquet,
+    /// Needs the `xlsx` feature. Only the account report, with the rejected transactions.
+    Xlsx,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(OutputFormat::Csv),
+            "parquet" => Ok(OutputFormat::Parquet),
+            "xlsx" => Ok(OutputFormat::Xlsx),
+            _ => Err(format!("unknown output format: {}", s)),
+        }
+    }
+}
+
+#[cfg(feature = "arrow")]
+mod arrow;
+#[cfg(feature = "parquet")]
+mod columnar;
+mod mt940;
+mod shard;
+#[cfg(feature = "xlsx")]
+mod xlsx;
+
+pub const MAX_PRECISION: u32 = 10;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportOptions {
+    /// Number of decimal places, at most `MAX_PRECISION`.
+    pub precision: u32,
+    pub rounding: Rounding,
+    /// Adds the `flagged` column set by chargeback and risk rules.
+    pub show_flags: bool,
+    /// Adds the `disputes_open`, `disputes_total` and `chargebacks` columns.
+    pub extended: bool,
+    /// Adds the `escrowed` column, the funds in the escrows of the account.
+    pub show_escrow: bool,
+    /// Format of the account report and transaction history.
+    pub format: OutputFormat,
+    /// Replaces client ids by their pseudonyms, only in CSV and xlsx outputs.
+    pub anonymizer: Option<Anonymizer>,
+    /// Tier of each client, listed in a `tier` column of the extended report when not empty.
+    pub tiers: HashMap<Client, String>,
+    /// Id of each client in downstream systems, listed in an `external_id` column next to the
+    /// client id of every output keyed by client when not empty.
+    pub external_ids: HashMap<Client, String>,
+    /// Reserve of each client with a reserve of its own or of its tier, see `Rules::reserve`.
+    /// The extended report lists the reserves in a `reserve` column when this or
+    /// `default_reserve` is set.
+    pub reserves: HashMap<Client, Amount>,
+    /// Reserve of the other clients.
+    pub default_reserve: Option<Amount>,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self {
+            precision: 4,
+            rounding: Rounding::default(),
+            show_flags: false,
+            extended: false,
+            show_escrow: false,
+            format: OutputFormat::default(),
+            anonymizer: None,
+            tiers: HashMap::new(),
+            external_ids: HashMap::new(),
+            reserves: HashMap::new(),
+            default_reserve: None,
+        }
+    }
+}
+
+impl ExportOptions {
+    /// Lists the tiers, external ids and reserves of the client profiles of `rules`.
+    pub fn with_profiles(mut self, rules: Option<&Rules>) -> Self {
+        if let Some(rules) = rules {
+            let profiles = || rules.clients.iter();
+            self.tiers = profiles()
+                .filter_map(|(client, profile)| Some((client, profile.tier.clone()?)))
+                .collect();
+            self.reserves = profiles()
+                .filter_map(|(client, _)| Some((client, rules.reserve(client)?)))
+                .collect();
+            self.default_reserve = rules.reserve;
+            // external ids would reveal who the pseudonyms stand for
+            if self.anonymizer.is_none() {
+                self.external_ids = profiles()
+                    .filter_map(|(client, profile)| Some((client, profile.external_id.clone()?)))
+                    .collect();
+            }
+        }
+        self
+    }
+
+    /// Whether outputs keyed by client have an `external_id` column.
+    pub(crate) fn show_external_ids(&self) -> bool {
+        !self.external_ids.is_empty()
+    }
+
+    /// External id of `client`, `None` for clients without one.
+    pub(crate) fn external_id(&self, client: Client) -> Option<&str> {
+        self.external_ids.get(&client).map(String::as_str)
+    }
+
+    /// Whether the report has a `tier` column.
+    pub(crate) fn show_tiers(&self) -> bool {
+        self.extended && !self.tiers.is_empty()
+    }
+
+    pub(crate) fn tier(&self, client: Client) -> &str {
+        self.tiers.get(&client).map_or("", String::as_str)
+    }
+
+    /// Whether the report has a `reserve` column.
+    pub(crate) fn show_reserves(&self) -> bool {
+        self.extended && (self.default_reserve.is_some() || !self.reserves.is_empty())
+    }
+
+    /// Reserve of `client`, zero for clients without one.
+    pub(crate) fn reserve(&self, client: Client) -> Amount {
+        self.reserves
+            .get(&client)
+            .copied()
+            .or(self.default_reserve)
+            .unwrap_or(Amount::ZERO)
+    }
+
+    fn round(&self, amount: Amount) -> Amount {
+        amount.round_dp_with_strategy(self.precision.min(MAX_PRECISION), self.rounding.strategy())
+    }
+
+    fn serialize_client<S: SerializeStruct>(
+        &self,
+        state: &mut S,
+        client: Client,
+    ) -> Result<(), S::Error> {
+        match &self.anonymizer {
+            Some(anonymizer) => state.serialize_field("client", &anonymizer.pseudonym(client))?,
+            None => state.serialize_field("client", &client)?,
+        }
+        if self.show_external_ids() {
+            state.serialize_field("external_id", &self.external_id(client))?;
+        }
+        Ok(())
+    }
+
+    fn account_name(&self, account: LedgerAccount) -> String {
+        match (&self.anonymizer, account) {
+            (Some(anonymizer), LedgerAccount::Available(client)) => {
+                format!("client:{}:available", anonymizer.pseudonym(client))
+            }
+            (Some(anonymizer), LedgerAccount::Held(client)) => {
+                format!("client:{}:held", anonymizer.pseudonym(client))
+            }
+            (Some(anonymizer), LedgerAccount::Escrow(client)) => {
+                format!("client:{}:escrow", anonymizer.pseudonym(client))
+            }
+            _ => account.to_string(),
+        }
+    }
+}
+
+struct AccountRow<'a> {
+    account: &'a Account,
+    options: &'a ExportOptions,
+}
+
+impl Serialize for AccountRow<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let account = self.account;
+        let options = self.options;
+        let mut state = serializer.serialize_struct("Account", 10)?;
+        options.serialize_client(&mut state, account.client())?;
+        state.serialize_field("available", &options.round(account.available()))?;
+        state.serialize_field("held", &options.round(account.held()))?;
+        state.serialize_field("total", &options.round(account.total()))?;
+        state.serialize_field("locked", &account.frozen())?;
+        if options.show_flags {
+            state.serialize_field("flagged", &account.flagged())?;
+        }
+        if options.extended {
+            state.serialize_field("disputes_open", &account.disputes_open())?;
+            state.serialize_field("disputes_total", &account.disputes_total())?;
+            state.serialize_field("chargebacks", &account.chargebacks())?;
+        }
+        if options.show_tiers() {
+            state.serialize_field("tier", options.tier(account.client()))?;
+        }
+        if options.show_reserves() {
+            state.serialize_field("reserve", &options.round(options.reserve(account.client())))?;
+        }
+        if options.show_escrow {
+            state.serialize_field("escrowed", &options.round(account.escrowed()))?;
+        }
+        state.end()
+    }
+}
+
+pub fn accounts_info_as_csv<'a, I, W>(
+    accounts: I,
     output: W,
-) -> Result<(), Box<dyn Error>> {
+    options: &ExportOptions,
+) -> Result<(), Box<dyn Error>>
+where
+    I: IntoIterator<Item = &'a Account>,
+    W: io::Write,
+{
     let mut wtr = csv::Writer::from_writer(output);
     for account in accounts {
-        wtr.serialize(account)?;
+        wtr.serialize(AccountRow { account, options })?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Writes the account report in `options.format`.
+pub fn write_accounts<'a, I, W>(
+    accounts: I,
+    output: W,
+    options: &ExportOptions,
+) -> Result<(), Box<dyn Error>>
+where
+    I: IntoIterator<Item = &'a Account>,
+    W: io::Write + Send,
+{
+    write_report(accounts, &[], output, options)
+}
+
+/// Writes the account report in `options.format`; only xlsx workbooks list the `rejections`.
+pub fn write_report<'a, I, W>(
+    accounts: I,
+    rejections: &[Rejection],
+    output: W,
+    options: &ExportOptions,
+) -> Result<(), Box<dyn Error>>
+where
+    I: IntoIterator<Item = &'a Account>,
+    W: io::Write + Send,
+{
+    match options.format {
+        OutputFormat::Csv => accounts_info_as_csv(accounts, output, options),
+        OutputFormat::Parquet => accounts_as_parquet(accounts, output, options),
+        OutputFormat::Xlsx => accounts_as_xlsx(accounts, rejections, output, options),
+    }
+}
+
+/// A stored transaction in the history. Only deposits, withdrawals and holds are stored;
+/// disputes, resolves and the like show up in the state of the transaction they refer to.
+struct TransactionRow<'a> {
+    transaction: &'a Transaction,
+    options: &'a ExportOptions,
+}
+
+/// Dispute state, whether the transaction was reversed and whether a hold was released.
+fn transaction_state(transaction: &Transaction) -> (DisputeState, bool, bool) {
+    match transaction {
+        Transaction::Deposit {
+            dispute, reversed, ..
+        }
+        | Transaction::Withdrawal {
+            dispute, reversed, ..
+        } => (*dispute, *reversed, false),
+        Transaction::Hold { released, .. } | Transaction::EscrowHold { released, .. } => {
+            (DisputeState::None, false, *released)
+        }
+        _ => (DisputeState::None, false, false),
+    }
+}
+
+impl Serialize for TransactionRow<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let transaction = self.transaction;
+        let (dispute, reversed, released) = transaction_state(transaction);
+        let mut state = serializer.serialize_struct("Transaction", 11)?;
+        state.serialize_field("tx", &transaction.tx())?;
+        self.options
+            .serialize_client(&mut state, transaction.client())?;
+        state.serialize_field("type", transaction.kind().name())?;
+        state.serialize_field(
+            "amount",
+            &transaction
+                .amount()
+                .map(|amount| self.options.round(amount)),
+        )?;
+        state.serialize_field("dispute", dispute.name())?;
+        state.serialize_field("reversed", &reversed)?;
+        state.serialize_field("released", &released)?;
+        state.serialize_field("memo", &transaction.memo())?;
+        state.serialize_field("counterparty", &transaction.counterparty())?;
+        state.serialize_field("reason", &transaction.reason())?;
+        state.serialize_field("escrow", &transaction.escrow())?;
+        state.end()
+    }
+}
+
+/// Writes the transaction history as CSV, amounts rounded like the account report.
+pub fn transactions_as_csv<'a, I, W>(
+    transactions: I,
+    output: W,
+    options: &ExportOptions,
+) -> Result<(), Box<dyn Error>>
+where
+    I: IntoIterator<Item = &'a Transaction>,
+    W: io::Write,
+{
+    let mut wtr = csv::Writer::from_writer(output);
+    for transaction in transactions {
+        wtr.serialize(TransactionRow {
+            transaction,
+            options,
+        })?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Writes journal entries as general-ledger lines of `date`, whatever `options.format`, with
+/// amounts rounded like the account report. Entries recovering a negative balance have the
+/// `recovery` type.
+pub fn journal_as_csv<'a, I, W>(
+    entries: I,
+    date: StatementDate,
+    output: W,
+    options: &ExportOptions,
+) -> Result<(), Box<dyn Error>>
+where
+    I: IntoIterator<Item = &'a JournalEntry>,
+    W: io::Write,
+{
+    let mut wtr = csv::Writer::from_writer(output);
+    wtr.write_record([
+        "date",
+        "tx",
+        "debit_account",
+        "credit_account",
+        "amount",
+        "type",
+        "period",
+    ])?;
+    let date = date.to_string();
+    for entry in entries {
+        wtr.write_record([
+            date.clone(),
+            entry.tx.to_string(),
+            options.account_name(entry.debit),
+            options.account_name(entry.credit),
+            options.round(entry.amount).to_string(),
+            if entry.recovery {
+                "recovery".to_string()
+            } else {
+                entry.kind.name().to_string()
+            },
+            entry.period.to_string(),
+        ])?;
     }
     wtr.flush()?;
     Ok(())
 }
+
+/// Writes the transaction history in `options.format`.
+pub fn write_transactions<'a, I, W>(
+    transactions: I,
+    output: W,
+    options: &ExportOptions,
+) -> Result<(), Box<dyn Error>>
+where
+    I: IntoIterator<Item = &'a Transaction>,
+    W: io::Write + Send,
+{
+    match options.format {
+        OutputFormat::Csv => transactions_as_csv(transactions, output, options),
+        OutputFormat::Parquet => transactions_as_parquet(transactions, output, options),
+        OutputFormat::Xlsx => Err("the transaction history can't be written as xlsx".into()),
+    }
+}
+
+#[cfg(feature = "arrow")]
+pub use self::arrow::{accounts_to_arrow, transactions_to_arrow};
+
+pub use mt940::{
+    account_statement, statement_path, write_statements, StatementDate, StatementOptions,
+};
+
+pub use shard::{index_path, shard_name, write_shards, Shard, SHARD_INDEX};
+
+#[cfg(feature = "parquet")]
+pub use columnar::{accounts_as_parquet, transactions_as_parquet};
+
+#[cfg(not(feature = "parquet"))]
+fn accounts_as_parquet<'a, I, W>(
+    _accounts: I,
+    _output: W,
+    _options: &ExportOptions,
+) -> Result<(), Box<dyn Error>>
+where
+    I: IntoIterator<Item = &'a Account>,
+{
+    Err("built without parquet support, enable the `parquet` feature".into())
+}
+
+#[cfg(not(feature = "parquet"))]
+fn transactions_as_parquet<'a, I, W>(
+    _transactions: I,
+    _output: W,
+    _options: &ExportOptions,
+) -> Result<(), Box<dyn Error>>
+where
+    I: IntoIterator<Item = &'a Transaction>,
+{
+    Err("built without parquet support, enable the `parquet` feature".into())
+}
+
+#[cfg(feature = "xlsx")]
+pub use self::xlsx::accounts_as_xlsx;
+
+#[cfg(not(feature = "xlsx"))]
+fn accounts_as_xlsx<'a, I, W>(
+    _accounts: I,
+    _rejections: &[Rejection],
+    _output: W,
+    _options: &ExportOptions,
+) -> Result<(), Box<dyn Error>>
+where
+    I: IntoIterator<Item = &'a Account>,
+{
+    Err("built without xlsx support, enable the `xlsx` feature".into())
+}
+
+/// Writes `metrics` as `metric,value` rows, amounts rounded like the account report.
+pub fn metrics_as_csv<W: io::Write>(
+    metrics: &EngineMetrics,
+    output: W,
+    options: &ExportOptions,
+) -> Result<(), Box<dyn Error>> {
+    let mut wtr = csv::Writer::from_writer(output);
+    wtr.write_record(["metric", "value"])?;
+    for kind in TransactionKind::ALL {
+        wtr.write_record([
+            format!("applied_{}", kind.name()),
+            metrics.applied(kind).to_string(),
+        ])?;
+    }
+    let rows = [
+        ("rejected", metrics.rejected.to_string()),
+        ("blocked", metrics.blocked.to_string()),
+        ("scheduled", metrics.scheduled.to_string()),
+        (
+            "total_available",
+            options.round(metrics.total_available).to_string(),
+        ),
+        ("total_held", options.round(metrics.total_held).to_string()),
+        (
+            "total_escrowed",
+            options.round(metrics.total_escrowed).to_string(),
+        ),
+        ("open_disputes", metrics.open_disputes.to_string()),
+        ("frozen_accounts", metrics.frozen_accounts.to_string()),
+        ("flagged_accounts", metrics.flagged_accounts.to_string()),
+    ];
+    for (metric, value) in rows {
+        wtr.write_record([metric, value.as_str()])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Writes one row per counterparty, amounts rounded like the account report and the
+/// chargeback rate with 4 decimal places.
+pub fn merchant_stats_as_csv<W: io::Write>(
+    stats: &[MerchantStats],
+    output: W,
+    options: &ExportOptions,
+) -> Result<(), Box<dyn Error>> {
+    let mut wtr = csv::Writer::from_writer(output);
+    wtr.write_record([
+        "counterparty",
+        "transactions",
+        "volume",
+        "chargebacks",
+        "chargeback_volume",
+        "chargeback_rate",
+    ])?;
+    for merchant in stats {
+        wtr.write_record([
+            merchant.counterparty.clone(),
+            merchant.transactions.to_string(),
+            options.round(merchant.volume).to_string(),
+            merchant.chargebacks.to_string(),
+            options.round(merchant.chargeback_volume).to_string(),
+            format!("{:.4}", merchant.chargeback_rate()),
+        ])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+struct ActivityRow<'a> {
+    activity: &'a PeriodActivity,
+    options: &'a ExportOptions,
+}
+
+impl Serialize for ActivityRow<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let activity = self.activity;
+        let options = self.options;
+        let mut state = serializer.serialize_struct("PeriodActivity", 8)?;
+        state.serialize_field("period", &activity.period)?;
+        state.serialize_field("transactions", &activity.transactions)?;
+        state.serialize_field("volume", &options.round(activity.volume()))?;
+        state.serialize_field("deposits", &options.round(activity.deposits))?;
+        state.serialize_field("withdrawals", &options.round(activity.withdrawals))?;
+        state.serialize_field("disputes", &activity.disputes)?;
+        state.serialize_field("chargebacks", &activity.chargebacks)?;
+        state.serialize_field(
+            "chargeback_rate",
+            &format!("{:.4}", activity.chargeback_rate()),
+        )?;
+        state.end()
+    }
+}
+
+/// Writes one row per period, amounts rounded like the account report and the chargeback rate
+/// with 4 decimal places.
+pub fn period_activity_as_csv<W: io::Write>(
+    activity: &[PeriodActivity],
+    output: W,
+    options: &ExportOptions,
+) -> Result<(), Box<dyn Error>> {
+    let mut wtr = csv::Writer::from_writer(output);
+    for activity in activity {
+        wtr.serialize(ActivityRow { activity, options })?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+struct StatementRow<'a> {
+    statement: &'a PeriodStatement,
+    options: &'a ExportOptions,
+}
+
+impl Serialize for StatementRow<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let statement = self.statement;
+        let options = self.options;
+        let mut state = serializer.serialize_struct("PeriodStatement", 10)?;
+        state.serialize_field("period", &statement.period)?;
+        options.serialize_client(&mut state, statement.client)?;
+        state.serialize_field("available", &options.round(statement.available))?;
+        state.serialize_field("held", &options.round(statement.held))?;
+        state.serialize_field("escrowed", &options.round(statement.escrowed))?;
+        state.serialize_field("total", &options.round(statement.total()))?;
+        state.serialize_field("locked", &statement.locked)?;
+        state.serialize_field("credits", &options.round(statement.credits))?;
+        state.serialize_field("debits", &options.round(statement.debits))?;
+        state.serialize_field("transactions", &statement.transactions)?;
+        state.end()
+    }
+}
+
+/// Writes the statements of closed periods, one row per period and account, amounts rounded
+/// like the account report.
+pub fn period_statements_as_csv<'a, I, W>(
+    statements: I,
+    output: W,
+    options: &ExportOptions,
+) -> Result<(), Box<dyn Error>>
+where
+    I: IntoIterator<Item = &'a PeriodStatement>,
+    W: io::Write,
+{
+    let mut wtr = csv::Writer::from_writer(output);
+    for statement in statements {
+        wtr.serialize(StatementRow { statement, options })?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+struct SuspiciousActivityRow<'a> {
+    activity: &'a SuspiciousActivity,
+    options: &'a ExportOptions,
+}
+
+impl Serialize for SuspiciousActivityRow<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let activity = self.activity;
+        let mut state = serializer.serialize_struct("SuspiciousActivity", 5)?;
+        state.serialize_field("detected_at", &activity.time)?;
+        self.options.serialize_client(&mut state, activity.client)?;
+        state.serialize_field("tx", &activity.tx)?;
+        state.serialize_field("pattern", activity.pattern.name())?;
+        state.serialize_field("detail", &activity.detail)?;
+        state.end()
+    }
+}
+
+/// Writes a suspicious activity report, one row per detection with the transaction completing
+/// the pattern and the time it was applied.
+pub fn suspicious_activity_as_csv<W: io::Write>(
+    activity: &[SuspiciousActivity],
+    output: W,
+    options: &ExportOptions,
+) -> Result<(), Box<dyn Error>> {
+    let mut wtr = csv::Writer::from_writer(output);
+    for activity in activity {
+        wtr.serialize(SuspiciousActivityRow { activity, options })?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::amount;
+    use crate::transactions::{PaymentEngine, Transaction};
+
+    #[test]
+    fn accounts_are_written_sorted_by_client() {
+        let mut engine = PaymentEngine::new();
+        for client in [3, 1, 2] {
+            let deposit = Transaction::new_deposit(client, client as u32, amount!(1.5)).unwrap();
+            engine.process_transaction(deposit).unwrap();
+        }
+
+        let mut output = vec![];
+        accounts_info_as_csv(
+            engine.accounts_iter(),
+            &mut output,
+            &ExportOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n\
+             1,1.5,0.0,1.5,false\n\
+             2,1.5,0.0,1.5,false\n\
+             3,1.5,0.0,1.5,false\n"
+        );
+    }
+
+    #[test]
+    fn precision_and_rounding_are_configurable() {
+        let mut engine = PaymentEngine::new();
+        let deposit = Transaction::new_deposit(1, 1, amount!(2.125)).unwrap();
+        engine.process_transaction(deposit).unwrap();
+
+        let report = |rounding| {
+            let mut output = vec![];
+            let options = ExportOptions {
+                precision: 2,
+                rounding,
+                show_flags: true,
+                ..ExportOptions::default()
+            };
+            accounts_info_as_csv(engine.accounts_iter(), &mut output, &options).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        assert_eq!(
+            report(Rounding::Bankers),
+            "client,available,held,total,locked,flagged\n1,2.12,0.0,2.12,false,false\n"
+        );
+        assert_eq!(
+            report(Rounding::HalfUp),
+            "client,available,held,total,locked,flagged\n1,2.13,0.0,2.13,false,false\n"
+        );
+        assert_eq!(
+            report(Rounding::Truncate),
+            "client,available,held,total,locked,flagged\n1,2.12,0.0,2.12,false,false\n"
+        );
+    }
+
+    #[test]
+    fn extended_output_adds_dispute_counters() {
+        let mut engine = PaymentEngine::new();
+        for tx in 1..=3 {
+            let deposit = Transaction::new_deposit(1, tx, amount!(1.0)).unwrap();
+            engine.process_transaction(deposit).unwrap();
+            engine
+                .process_transaction(Transaction::new_dispute(1, tx))
+                .unwrap();
+        }
+        engine
+            .process_transaction(Transaction::new_resolve(1, 1))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::new_chargeback(1, 2))
+            .unwrap();
+
+        let mut output = vec![];
+        let options = ExportOptions {
+            extended: true,
+            ..ExportOptions::default()
+        };
+        accounts_info_as_csv(engine.accounts_iter(), &mut output, &options).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked,disputes_open,disputes_total,chargebacks\n\
+             1,1.0,1.0,2.0,true,1,3,1\n"
+        );
+
+        let mut output = vec![];
+        let options = ExportOptions {
+            tiers: [(1, "premium".to_string())].into_iter().collect(),
+            ..options
+        };
+        accounts_info_as_csv(engine.accounts_iter(), &mut output, &options).unwrap();
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .ends_with(",chargebacks,tier\n1,1.0,1.0,2.0,true,1,3,1,premium\n"));
+
+        let mut output = vec![];
+        let options = ExportOptions {
+            default_reserve: Some(amount!(0.5)),
+            ..options
+        };
+        accounts_info_as_csv(engine.accounts_iter(), &mut output, &options).unwrap();
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .ends_with(",tier,reserve\n1,1.0,1.0,2.0,true,1,3,1,premium,0.5\n"));
+    }
+
+    #[test]
+    fn metrics_are_written_one_per_row() {
+        let mut engine = PaymentEngine::new();
+        let deposit = Transaction::new_deposit(1, 1, amount!(1.5)).unwrap();
+        engine.process_transaction(deposit).unwrap();
+        engine
+            .process_transaction(Transaction::new_dispute(1, 1))
+            .unwrap();
+
+        let mut output = vec![];
+        metrics_as_csv(&engine.metrics(), &mut output, &ExportOptions::default()).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("metric,value\napplied_deposit,1\n"));
+        assert!(output.contains("applied_dispute,1\n"));
+        assert!(output.contains("total_held,1.5\n"));
+        assert!(output.contains("open_disputes,1\n"));
+    }
+
+    #[test]
+    fn journal_lines_name_both_accounts() {
+        let mut engine = PaymentEngine::new();
+        engine.keep_journal(true);
+        let _ = engine.process_transaction(Transaction::new_deposit(4, 1, amount!(3.5)).unwrap());
+        let _ = engine.process_transaction(Transaction::new_dispute(4, 1));
+
+        let mut output = vec![];
+        journal_as_csv(
+            engine.ledger().entries(),
+            "2024-03-01".parse().unwrap(),
+            &mut output,
+            &ExportOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "date,tx,debit_account,credit_account,amount,type,period\n\
+             2024-03-01,1,platform:suspense,client:4:available,3.5,deposit,0\n\
+             2024-03-01,1,client:4:available,client:4:held,3.5,dispute,0\n"
+        );
+    }
+
+    #[test]
+    fn anonymized_outputs_hide_client_ids() {
+        let mut engine = PaymentEngine::new();
+        engine.keep_journal(true);
+        let _ = engine.process_transaction(Transaction::new_deposit(4, 1, amount!(3.5)).unwrap());
+        let anonymizer = Anonymizer::new(b"secret");
+        let pseudonym = anonymizer.pseudonym(4);
+        let options = ExportOptions {
+            anonymizer: Some(anonymizer),
+            ..ExportOptions::default()
+        };
+
+        let mut output = vec![];
+        accounts_info_as_csv(engine.accounts_iter(), &mut output, &options).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!(
+                "client,available,held,total,locked\n{},3.5,0.0,3.5,false\n",
+                pseudonym
+            )
+        );
+
+        let mut output = vec![];
+        journal_as_csv(
+            engine.ledger().entries(),
+            "2024-03-01".parse().unwrap(),
+            &mut output,
+            &options,
+        )
+        .unwrap();
+        assert!(String::from_utf8(output).unwrap().ends_with(&format!(
+            "platform:suspense,client:{}:available,3.5,deposit,0\n",
+            pseudonym
+        )));
+    }
+
+    #[test]
+    fn external_ids_follow_the_client_ids() {
+        let mut engine = PaymentEngine::new();
+        for client in [1, 2] {
+            let deposit = Transaction::new_deposit(client, client as u32, amount!(1.0)).unwrap();
+            engine.process_transaction(deposit).unwrap();
+        }
+        let options = ExportOptions {
+            external_ids: [(1, "6f1c2a9e-5d0b-4c51-9a5e-2f3b8d7c1e04".to_string())]
+                .into_iter()
+                .collect(),
+            ..ExportOptions::default()
+        };
+
+        let mut output = vec![];
+        accounts_info_as_csv(engine.accounts_iter(), &mut output, &options).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,external_id,available,held,total,locked\n\
+             1,6f1c2a9e-5d0b-4c51-9a5e-2f3b8d7c1e04,1.0,0.0,1.0,false\n\
+             2,,1.0,0.0,1.0,false\n"
+        );
+
+        let mut output = vec![];
+        transactions_as_csv(engine.transactions_iter(), &mut output, &options).unwrap();
+        let history = String::from_utf8(output).unwrap();
+        assert!(history.starts_with("tx,client,external_id,type,"));
+        assert!(history.contains("\n1,1,6f1c2a9e-5d0b-4c51-9a5e-2f3b8d7c1e04,deposit,"));
+    }
+
+    #[test]
+    fn merchant_stats_are_written_one_per_row() {
+        let stats = [MerchantStats {
+            counterparty: "shop, east".to_string(),
+            transactions: 3,
+            volume: amount!(12.5),
+            chargebacks: 1,
+            chargeback_volume: amount!(2.5),
+        }];
+        let mut output = vec![];
+        merchant_stats_as_csv(&stats, &mut output, &ExportOptions::default()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "counterparty,transactions,volume,chargebacks,chargeback_volume,chargeback_rate\n\
+             \"shop, east\",3,12.5,1,2.5,0.3333\n"
+        );
+    }
+
+    #[test]
+    fn period_activity_is_written_one_per_row() {
+        let activity = [PeriodActivity {
+            period: "2024-03".to_string(),
+            transactions: 4,
+            deposits: amount!(12.5),
+            withdrawals: amount!(2.5),
+            disputes: 2,
+            chargebacks: 1,
+        }];
+        let mut output = vec![];
+        period_activity_as_csv(&activity, &mut output, &ExportOptions::default()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "period,transactions,volume,deposits,withdrawals,disputes,chargebacks,\
+             chargeback_rate\n\
+             2024-03,4,15.0,12.5,2.5,2,1,0.2500\n"
+        );
+    }
+
+    #[test]
+    fn period_statements_are_written_one_per_period_and_account() {
+        let mut engine = PaymentEngine::new();
+        let _ = engine.process_transaction(Transaction::new_deposit(2, 1, amount!(4.25)).unwrap());
+        engine.close_period();
+        let _ = engine.process_transaction(Transaction::new_dispute(2, 1));
+        engine.close_period();
+
+        let mut output = vec![];
+        period_statements_as_csv(engine.statements(), &mut output, &ExportOptions::default())
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "period,client,available,held,escrowed,total,locked,credits,debits,transactions\n\
+             0,2,4.25,0.0,0.0,4.25,false,4.25,0.0,1\n\
+             1,2,0.0,4.25,0.0,4.25,false,0.0,4.25,1\n"
+        );
+    }
+
+    #[test]
+    fn transaction_history_shows_dispute_state() {
+        let mut engine = PaymentEngine::new();
+        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, amount!(5.0)).unwrap());
+        let _ = engine.process_transaction(
+            Transaction::new_hold(1, 2, amount!(1.0))
+                .unwrap()
+                .with_memo(Some("order 7, ref A".to_string()))
+                .with_counterparty(Some("ACME".to_string())),
+        );
+        let _ = engine.process_transaction(Transaction::new_release(1, 2));
+        let _ = engine.process_transaction(Transaction::new_dispute(1, 1));
+        let _ = engine.process_transaction(Transaction::new_chargeback(1, 1));
+
+        let mut output = vec![];
+        transactions_as_csv(
+            engine.transactions_iter(),
+            &mut output,
+            &ExportOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "tx,client,type,amount,dispute,reversed,released,memo,counterparty,reason,escrow\n\
+             1,1,deposit,5.0,charged_back,false,false,,,,\n\
+             2,1,hold,1.0,none,false,true,\"order 7, ref A\",ACME,,\n"
+        );
+    }
+}
@@ -0,0 +1,182 @@
+//! Arrow record batches of accounts and stored transactions, for handing results to
+//! DataFusion, Polars and the like without going through CSV. Amounts are `Decimal128(38, 4)`,
+//! rounded to 4 decimal places like the default account report.
+
+use arrow_array::{
+    ArrayRef, BooleanArray, Decimal128Array, RecordBatch, StringArray, UInt16Array, UInt32Array,
+};
+use arrow_schema::{DataType, Field, Schema};
+use std::sync::Arc;
+
+use super::transaction_state;
+use crate::amount::to_decimal;
+use crate::transactions::{Account, Amount, Transaction};
+
+const SCALE: u32 = 4;
+
+/// The amount rounded to `SCALE` places, as an unscaled integer.
+fn unscaled(amount: Amount) -> i128 {
+    let value = to_decimal(amount.round_dp(SCALE));
+    value.mantissa() * 10i128.pow(SCALE - value.scale())
+}
+
+fn decimals(values: impl Iterator<Item = Option<i128>>) -> ArrayRef {
+    Arc::new(
+        Decimal128Array::from_iter(values)
+            .with_precision_and_scale(38, SCALE as i8)
+            .expect("38 digits fit a Decimal128"),
+    )
+}
+
+fn decimal_field(name: &str, nullable: bool) -> Field {
+    Field::new(name, DataType::Decimal128(38, SCALE as i8), nullable)
+}
+
+/// One row per account, with the columns of the extended account report plus `flagged` and
+/// `escrowed`.
+pub fn accounts_to_arrow(accounts: &[Account]) -> RecordBatch {
+    let schema = Schema::new(vec![
+        Field::new("client", DataType::UInt16, false),
+        decimal_field("available", false),
+        decimal_field("held", false),
+        decimal_field("total", false),
+        Field::new("locked", DataType::Boolean, false),
+        Field::new("flagged", DataType::Boolean, false),
+        Field::new("disputes_open", DataType::UInt32, false),
+        Field::new("disputes_total", DataType::UInt32, false),
+        Field::new("chargebacks", DataType::UInt32, false),
+        decimal_field("escrowed", false),
+    ]);
+    let amounts = |amount: fn(&Account) -> Amount| {
+        decimals(
+            accounts
+                .iter()
+                .map(|account| Some(unscaled(amount(account)))),
+        )
+    };
+    let counts = |count: fn(&Account) -> u32| -> ArrayRef {
+        Arc::new(UInt32Array::from_iter_values(accounts.iter().map(count)))
+    };
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(UInt16Array::from_iter_values(
+            accounts.iter().map(Account::client),
+        )),
+        amounts(Account::available),
+        amounts(Account::held),
+        amounts(Account::total),
+        Arc::new(BooleanArray::from_iter(
+            accounts.iter().map(|account| Some(account.frozen())),
+        )),
+        Arc::new(BooleanArray::from_iter(
+            accounts.iter().map(|account| Some(account.flagged())),
+        )),
+        counts(Account::disputes_open),
+        counts(Account::disputes_total),
+        counts(Account::chargebacks),
+        amounts(Account::escrowed),
+    ];
+    RecordBatch::try_new(Arc::new(schema), columns).expect("columns match the schema")
+}
+
+/// One row per stored transaction, with the columns of `transactions_as_csv`.
+pub fn transactions_to_arrow(transactions: &[Transaction]) -> RecordBatch {
+    let schema = Schema::new(vec![
+        Field::new("tx", DataType::UInt32, false),
+        Field::new("client", DataType::UInt16, false),
+        Field::new("type", DataType::Utf8, false),
+        decimal_field("amount", true),
+        Field::new("dispute", DataType::Utf8, false),
+        Field::new("reversed", DataType::Boolean, false),
+        Field::new("released", DataType::Boolean, false),
+        Field::new("memo", DataType::Utf8, true),
+        Field::new("counterparty", DataType::Utf8, true),
+        Field::new("reason", DataType::Utf8, true),
+        Field::new("escrow", DataType::Utf8, true),
+    ]);
+    let states: Vec<_> = transactions.iter().map(transaction_state).collect();
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(UInt32Array::from_iter_values(
+            transactions.iter().map(Transaction::tx),
+        )),
+        Arc::new(UInt16Array::from_iter_values(
+            transactions.iter().map(Transaction::client),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            transactions
+                .iter()
+                .map(|transaction| transaction.kind().name()),
+        )),
+        decimals(
+            transactions
+                .iter()
+                .map(|transaction| transaction.amount().map(unscaled)),
+        ),
+        Arc::new(StringArray::from_iter_values(
+            states.iter().map(|state| state.0.name()),
+        )),
+        Arc::new(BooleanArray::from_iter(
+            states.iter().map(|state| Some(state.1)),
+        )),
+        Arc::new(BooleanArray::from_iter(
+            states.iter().map(|state| Some(state.2)),
+        )),
+        Arc::new(StringArray::from_iter(
+            transactions.iter().map(Transaction::memo),
+        )),
+        Arc::new(StringArray::from_iter(
+            transactions.iter().map(Transaction::counterparty),
+        )),
+        Arc::new(StringArray::from_iter(
+            transactions.iter().map(Transaction::reason),
+        )),
+        Arc::new(StringArray::from_iter(
+            transactions.iter().map(Transaction::escrow),
+        )),
+    ];
+    RecordBatch::try_new(Arc::new(schema), columns).expect("columns match the schema")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::amount;
+    use crate::transactions::PaymentEngine;
+    use arrow_array::Array;
+
+    #[test]
+    fn batches_hold_every_account_and_transaction() {
+        let mut engine = PaymentEngine::new();
+        let _ = engine.process_transaction(Transaction::new_deposit(2, 1, amount!(2.125)).unwrap());
+        let _ = engine.process_transaction(Transaction::new_deposit(1, 2, amount!(1.5)).unwrap());
+        let _ = engine.process_transaction(Transaction::new_dispute(2, 1));
+
+        let accounts = accounts_to_arrow(&engine.get_accounts());
+        assert_eq!(accounts.num_rows(), 2);
+        assert_eq!(accounts.num_columns(), 10);
+        let held = accounts
+            .column_by_name("held")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<Decimal128Array>()
+            .unwrap();
+        assert_eq!(held.value_as_string(0), "0.0000");
+        assert_eq!(held.value_as_string(1), "2.1250");
+
+        let transactions: Vec<Transaction> = engine.transactions_iter().cloned().collect();
+        let transactions = transactions_to_arrow(&transactions);
+        assert_eq!(transactions.num_rows(), 2);
+        let dispute = transactions
+            .column_by_name("dispute")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        assert_eq!(dispute.value(0), "open");
+        assert_eq!(dispute.value(1), "none");
+        assert_eq!(
+            transactions.column_by_name("amount").unwrap().null_count(),
+            0
+        );
+        assert_eq!(transactions.column_by_name("memo").unwrap().null_count(), 2);
+    }
+}
@@ -0,0 +1,413 @@
+//! Parquet versions of the account report and transaction history. Amounts are stored as
+//! `DECIMAL(38, precision)`, rounded like the CSV report, and ids as unsigned integers.
+
+use parquet::data_type::{
+    BoolType, ByteArray, ByteArrayType, FixedLenByteArray, FixedLenByteArrayType, Int32Type,
+};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+use std::error::Error;
+use std::io;
+use std::sync::Arc;
+
+use super::{transaction_state, ExportOptions, MAX_PRECISION};
+use crate::amount::to_decimal;
+use crate::transactions::{Account, Amount, Client, Transaction};
+
+/// Values of one column, in the order of the schema fields.
+enum Column {
+    Int32(Vec<i32>),
+    Boolean(Vec<bool>),
+    Text(Vec<ByteArray>),
+    Decimal(Vec<FixedLenByteArray>),
+    /// `None` entries are nulls, the field must be `optional`.
+    OptionalText(Vec<Option<ByteArray>>),
+    /// `None` entries are nulls, the field must be `optional`.
+    OptionalDecimal(Vec<Option<FixedLenByteArray>>),
+}
+
+impl ExportOptions {
+    fn scale(&self) -> u32 {
+        self.precision.min(MAX_PRECISION)
+    }
+
+    /// The rounded amount as the 16 byte big-endian unscaled value Parquet expects.
+    fn unscaled(&self, amount: Amount) -> Result<FixedLenByteArray, Box<dyn Error>> {
+        let mut value = to_decimal(self.round(amount));
+        value.rescale(self.scale());
+        // rescale lowers the scale instead of overflowing
+        if value.scale() != self.scale() {
+            return Err(format!(
+                "{} can't be stored with {} decimal places",
+                amount,
+                self.scale()
+            )
+            .into());
+        }
+        Ok(FixedLenByteArray::from(
+            value.mantissa().to_be_bytes().to_vec(),
+        ))
+    }
+
+    fn decimal_field(&self, repetition: &str, name: &str) -> String {
+        format!(
+            "{} fixed_len_byte_array(16) {} (DECIMAL(38,{}))",
+            repetition,
+            name,
+            self.scale()
+        )
+    }
+}
+
+fn write_columns<W: io::Write + Send>(
+    schema: &str,
+    columns: Vec<Column>,
+    output: W,
+) -> Result<(), Box<dyn Error>> {
+    let schema = Arc::new(parse_message_type(schema)?);
+    let properties = Arc::new(WriterProperties::builder().build());
+    let mut writer = SerializedFileWriter::new(output, schema, properties)?;
+    let mut row_group = writer.next_row_group()?;
+    for column in columns {
+        let mut column_writer = row_group
+            .next_column()?
+            .ok_or("more columns than fields in the schema")?;
+        match column {
+            Column::Int32(values) => {
+                column_writer
+                    .typed::<Int32Type>()
+                    .write_batch(&values, None, None)?;
+            }
+            Column::Boolean(values) => {
+                column_writer
+                    .typed::<BoolType>()
+                    .write_batch(&values, None, None)?;
+            }
+            Column::Text(values) => {
+                column_writer
+                    .typed::<ByteArrayType>()
+                    .write_batch(&values, None, None)?;
+            }
+            Column::OptionalText(values) => {
+                let levels: Vec<i16> = values.iter().map(|value| value.is_some() as i16).collect();
+                let values: Vec<ByteArray> = values.into_iter().flatten().collect();
+                column_writer
+                    .typed::<ByteArrayType>()
+                    .write_batch(&values, Some(&levels), None)?;
+            }
+            Column::Decimal(values) => {
+                column_writer
+                    .typed::<FixedLenByteArrayType>()
+                    .write_batch(&values, None, None)?;
+            }
+            Column::OptionalDecimal(values) => {
+                let levels: Vec<i16> = values.iter().map(|value| value.is_some() as i16).collect();
+                let values: Vec<FixedLenByteArray> = values.into_iter().flatten().collect();
+                column_writer.typed::<FixedLenByteArrayType>().write_batch(
+                    &values,
+                    Some(&levels),
+                    None,
+                )?;
+            }
+        }
+        column_writer.close()?;
+    }
+    row_group.close()?;
+    writer.close()?;
+    Ok(())
+}
+
+/// External ids of `clients`, nulls for clients without one.
+fn external_ids(options: &ExportOptions, clients: impl Iterator<Item = Client>) -> Column {
+    Column::OptionalText(
+        clients
+            .map(|client| options.external_id(client).map(ByteArray::from))
+            .collect(),
+    )
+}
+
+/// Same columns as `accounts_info_as_csv`, as a single row group.
+pub fn accounts_as_parquet<'a, I, W>(
+    accounts: I,
+    output: W,
+    options: &ExportOptions,
+) -> Result<(), Box<dyn Error>>
+where
+    I: IntoIterator<Item = &'a Account>,
+    W: io::Write + Send,
+{
+    let accounts: Vec<&Account> = accounts.into_iter().collect();
+    let amounts = |amount: fn(&Account) -> Amount| -> Result<Column, Box<dyn Error>> {
+        let values = accounts
+            .iter()
+            .map(|account| options.unscaled(amount(account)))
+            .collect::<Result<_, _>>()?;
+        Ok(Column::Decimal(values))
+    };
+    let counts = |count: fn(&Account) -> u32| {
+        Column::Int32(
+            accounts
+                .iter()
+                .map(|account| count(account) as i32)
+                .collect(),
+        )
+    };
+
+    let mut fields = vec![
+        "required int32 client (INTEGER(16,false))".to_string(),
+        options.decimal_field("required", "available"),
+        options.decimal_field("required", "held"),
+        options.decimal_field("required", "total"),
+        "required boolean locked".to_string(),
+    ];
+    let mut columns = vec![
+        Column::Int32(
+            accounts
+                .iter()
+                .map(|account| account.client() as i32)
+                .collect(),
+        ),
+        amounts(Account::available)?,
+        amounts(Account::held)?,
+        amounts(Account::total)?,
+        Column::Boolean(accounts.iter().map(|account| account.frozen()).collect()),
+    ];
+    if options.show_external_ids() {
+        fields.insert(1, "optional binary external_id (STRING)".to_string());
+        columns.insert(
+            1,
+            external_ids(options, accounts.iter().map(|account| account.client())),
+        );
+    }
+    if options.show_flags {
+        fields.push("required boolean flagged".to_string());
+        columns.push(Column::Boolean(
+            accounts.iter().map(|account| account.flagged()).collect(),
+        ));
+    }
+    if options.extended {
+        for (name, count) in [
+            (
+                "disputes_open",
+                Account::disputes_open as fn(&Account) -> u32,
+            ),
+            ("disputes_total", Account::disputes_total),
+            ("chargebacks", Account::chargebacks),
+        ] {
+            fields.push(format!("required int32 {} (INTEGER(32,false))", name));
+            columns.push(counts(count));
+        }
+    }
+    if options.show_tiers() {
+        // clients without a tier are nulls rather than empty strings
+        fields.push("optional binary tier (STRING)".to_string());
+        columns.push(Column::OptionalText(
+            accounts
+                .iter()
+                .map(|account| {
+                    options
+                        .tiers
+                        .get(&account.client())
+                        .map(|tier| ByteArray::from(tier.as_str()))
+                })
+                .collect(),
+        ));
+    }
+    if options.show_reserves() {
+        fields.push(options.decimal_field("required", "reserve"));
+        let reserves = accounts
+            .iter()
+            .map(|account| options.unscaled(options.reserve(account.client())))
+            .collect::<Result<_, _>>()?;
+        columns.push(Column::Decimal(reserves));
+    }
+    if options.show_escrow {
+        fields.push(options.decimal_field("required", "escrowed"));
+        columns.push(amounts(Account::escrowed)?);
+    }
+    write_columns(&message("account", &fields), columns, output)
+}
+
+/// Same columns as `transactions_as_csv`, as a single row group.
+pub fn transactions_as_parquet<'a, I, W>(
+    transactions: I,
+    output: W,
+    options: &ExportOptions,
+) -> Result<(), Box<dyn Error>>
+where
+    I: IntoIterator<Item = &'a Transaction>,
+    W: io::Write + Send,
+{
+    let transactions: Vec<&Transaction> = transactions.into_iter().collect();
+    let states: Vec<_> = transactions
+        .iter()
+        .map(|transaction| transaction_state(transaction))
+        .collect();
+    let mut fields = vec![
+        // ids are unsigned, stored with the same bits
+        "required int32 tx (INTEGER(32,false))".to_string(),
+        "required int32 client (INTEGER(16,false))".to_string(),
+        "required binary type (STRING)".to_string(),
+        options.decimal_field("optional", "amount"),
+        "required binary dispute (STRING)".to_string(),
+        "required boolean reversed".to_string(),
+        "required boolean released".to_string(),
+        "optional binary memo (STRING)".to_string(),
+        "optional binary counterparty (STRING)".to_string(),
+        "optional binary reason (STRING)".to_string(),
+        "optional binary escrow (STRING)".to_string(),
+    ];
+    let mut columns = vec![
+        Column::Int32(
+            transactions
+                .iter()
+                .map(|transaction| transaction.tx() as i32)
+                .collect(),
+        ),
+        Column::Int32(
+            transactions
+                .iter()
+                .map(|transaction| transaction.client() as i32)
+                .collect(),
+        ),
+        Column::Text(
+            transactions
+                .iter()
+                .map(|transaction| ByteArray::from(transaction.kind().name()))
+                .collect(),
+        ),
+        Column::OptionalDecimal(
+            transactions
+                .iter()
+                .map(|transaction| {
+                    let amount = transaction.amount();
+                    amount.map(|amount| options.unscaled(amount)).transpose()
+                })
+                .collect::<Result<_, _>>()?,
+        ),
+        Column::Text(
+            states
+                .iter()
+                .map(|state| ByteArray::from(state.0.name()))
+                .collect(),
+        ),
+        Column::Boolean(states.iter().map(|state| state.1).collect()),
+        Column::Boolean(states.iter().map(|state| state.2).collect()),
+        Column::OptionalText(
+            transactions
+                .iter()
+                .map(|transaction| transaction.memo().map(ByteArray::from))
+                .collect(),
+        ),
+        Column::OptionalText(
+            transactions
+                .iter()
+                .map(|transaction| transaction.counterparty().map(ByteArray::from))
+                .collect(),
+        ),
+        Column::OptionalText(
+            transactions
+                .iter()
+                .map(|transaction| transaction.reason().map(ByteArray::from))
+                .collect(),
+        ),
+        Column::OptionalText(
+            transactions
+                .iter()
+                .map(|transaction| transaction.escrow().map(ByteArray::from))
+                .collect(),
+        ),
+    ];
+    if options.show_external_ids() {
+        fields.insert(2, "optional binary external_id (STRING)".to_string());
+        columns.insert(
+            2,
+            external_ids(
+                options,
+                transactions.iter().map(|transaction| transaction.client()),
+            ),
+        );
+    }
+    write_columns(&message("transaction", &fields), columns, output)
+}
+
+fn message(name: &str, fields: &[String]) -> String {
+    let fields: Vec<String> = fields
+        .iter()
+        .map(|field| format!("  {};\n", field))
+        .collect();
+    format!("message {} {{\n{}}}", name, fields.concat())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::amount;
+    use crate::transactions::PaymentEngine;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    /// Rows of a Parquet file written by `write`, in their JSON-like display form.
+    fn rows<F>(name: &str, write: F) -> Vec<String>
+    where
+        F: FnOnce(&mut std::fs::File) -> Result<(), Box<dyn Error>>,
+    {
+        let path =
+            std::env::temp_dir().join(format!("payments-{}-{}.parquet", name, std::process::id()));
+        write(&mut std::fs::File::create(&path).unwrap()).unwrap();
+        let reader = SerializedFileReader::new(std::fs::File::open(&path).unwrap()).unwrap();
+        let rows = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| row.unwrap().to_string())
+            .collect();
+        std::fs::remove_file(path).unwrap();
+        rows
+    }
+
+    #[test]
+    fn accounts_and_transactions_round_trip() {
+        let mut engine = PaymentEngine::new();
+        let _ = engine.process_transaction(Transaction::new_deposit(2, 1, amount!(2.125)).unwrap());
+        let _ = engine.process_transaction(
+            Transaction::new_deposit(1, 2, amount!(1.0))
+                .unwrap()
+                .with_memo(Some("INV-2".to_string())),
+        );
+        let _ = engine.process_transaction(Transaction::new_dispute(2, 1));
+        let options = ExportOptions {
+            precision: 2,
+            extended: true,
+            ..ExportOptions::default()
+        };
+
+        assert_eq!(
+            rows("accounts", |file| accounts_as_parquet(
+                engine.accounts_iter(),
+                file,
+                &options
+            )),
+            vec![
+                "{client: 1, available: 1.00, held: 0.00, total: 1.00, locked: false, \
+                 disputes_open: 0, disputes_total: 0, chargebacks: 0}",
+                "{client: 2, available: 0.00, held: 2.12, total: 2.12, locked: false, \
+                 disputes_open: 1, disputes_total: 1, chargebacks: 0}",
+            ]
+        );
+        assert_eq!(
+            rows("transactions", |file| transactions_as_parquet(
+                engine.transactions_iter(),
+                file,
+                &options
+            )),
+            vec![
+                "{tx: 1, client: 2, type: \"deposit\", amount: 2.12, dispute: \"open\", \
+                 reversed: false, released: false, memo: null, counterparty: null, \
+                 reason: null, escrow: null}",
+                "{tx: 2, client: 1, type: \"deposit\", amount: 1.00, dispute: \"none\", \
+                 reversed: false, released: false, memo: \"INV-2\", \
+                 counterparty: null, reason: null, escrow: null}",
+            ]
+        );
+    }
+}
@@ -0,0 +1,338 @@
+//! SWIFT MT940 customer statements, one per client, for reconciliation systems that only read
+//! MT940. The engine keeps no booking dates, so a run is a single statement period: every
+//! statement line carries the statement date and the opening balance is the closing balance
+//! minus the activity found in the transaction history.
+
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::error::Error;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{ExportOptions, MAX_PRECISION};
+use crate::amount::to_decimal;
+use crate::transactions::{Account, Client, DisputeState, Transaction};
+
+/// Calendar date of a statement, `YYYY-MM-DD` in configuration and on the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct StatementDate {
+    year: u16,
+    month: u8,
+    day: u8,
+}
+
+impl StatementDate {
+    /// The current UTC date.
+    pub fn today() -> Self {
+        let seconds = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        Self::from_days((seconds / 86_400) as i64)
+    }
+
+    /// Date `days` after 1970-01-01, from Howard Hinnant's `civil_from_days`.
+    fn from_days(days: i64) -> Self {
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + (month <= 2) as i64;
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+        }
+    }
+
+    /// `YYMMDD`, as used in MT940 fields.
+    fn swift(self) -> String {
+        format!("{:02}{:02}{:02}", self.year % 100, self.month, self.day)
+    }
+}
+
+impl std::fmt::Display for StatementDate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+impl std::str::FromStr for StatementDate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid statement date {}, expected YYYY-MM-DD", s);
+        let mut parts = s.splitn(3, '-').map(str::parse::<u16>);
+        let (year, month, day) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(Ok(year)), Some(Ok(month)), Some(Ok(day))) => (year, month, day),
+            _ => return Err(invalid()),
+        };
+        if !(1..=12).contains(&month) || !(1..=31).contains(&day) || year > 9999 {
+            return Err(invalid());
+        }
+        Ok(Self {
+            year,
+            month: month as u8,
+            day: day as u8,
+        })
+    }
+}
+
+impl TryFrom<String> for StatementDate {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// Settings shared by the statements of a run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatementOptions {
+    pub date: StatementDate,
+    /// ISO 4217 code of every balance.
+    pub currency: String,
+}
+
+/// A `:61:` statement line with its `:86:` description; negative amounts are debits.
+struct StatementLine {
+    tx: u32,
+    amount: Decimal,
+    /// `RC`/`RD` mark instead of `C`/`D`.
+    reversal: bool,
+    description: &'static str,
+}
+
+/// Balance movements caused by a stored transaction, in the order they happened. Holds don't
+/// change the booked balance until they are captured, and are then stored as withdrawals;
+/// escrow holds only once they are paid out.
+fn statement_lines(transaction: &Transaction) -> Vec<StatementLine> {
+    let (tx, amount, dispute, reversed, description) = match transaction {
+        Transaction::EscrowHold {
+            tx,
+            amount,
+            paid_out: true,
+            ..
+        } => {
+            return vec![StatementLine {
+                tx: *tx,
+                amount: -to_decimal(*amount),
+                reversal: false,
+                description: "escrow payout",
+            }]
+        }
+        Transaction::Deposit {
+            tx,
+            amount,
+            dispute,
+            reversed,
+            ..
+        } => (*tx, to_decimal(*amount), *dispute, *reversed, "deposit"),
+        Transaction::Withdrawal {
+            tx,
+            amount,
+            dispute,
+            reversed,
+            ..
+        } => (*tx, -to_decimal(*amount), *dispute, *reversed, "withdrawal"),
+        _ => return vec![],
+    };
+    let line = |amount, reversal, description| StatementLine {
+        tx,
+        amount,
+        reversal,
+        description,
+    };
+    let mut lines = vec![line(amount, false, description)];
+    if reversed {
+        lines.push(line(-amount, true, "reversal"));
+    }
+    // a chargeback takes the disputed amount out of the account either way
+    let charged = to_decimal(transaction.amount().unwrap_or_default());
+    if matches!(
+        dispute,
+        DisputeState::ChargedBack | DisputeState::ChargebackReversed
+    ) {
+        lines.push(line(-charged, false, "chargeback"));
+    }
+    if dispute == DisputeState::ChargebackReversed {
+        lines.push(line(charged, false, "chargeback reversal"));
+    }
+    lines
+}
+
+impl ExportOptions {
+    /// `C`/`D` mark followed by the rounded absolute amount with a decimal comma and exactly
+    /// `precision` decimal places.
+    fn swift_amount(&self, amount: Decimal, reversal: bool) -> String {
+        let scale = self.precision.min(MAX_PRECISION);
+        let mut rounded = amount.round_dp_with_strategy(scale, self.rounding.strategy());
+        rounded.rescale(scale);
+        let mark = match (reversal, rounded.is_sign_negative() && !rounded.is_zero()) {
+            // a reversed credit is a debit and the other way around
+            (true, true) => "RC",
+            (true, false) => "RD",
+            (false, true) => "D",
+            (false, false) => "C",
+        };
+        let digits = rounded.abs().to_string().replace('.', ",");
+        if digits.contains(',') {
+            format!("{}{}", mark, digits)
+        } else {
+            format!("{}{},", mark, digits)
+        }
+    }
+}
+
+/// Writes the statement of `account` from its stored `transactions`, with CRLF line endings.
+pub fn account_statement<'a, I, W>(
+    account: &Account,
+    transactions: I,
+    output: W,
+    statement: &StatementOptions,
+    options: &ExportOptions,
+) -> io::Result<()>
+where
+    I: IntoIterator<Item = &'a Transaction>,
+    W: Write,
+{
+    let mut output = io::BufWriter::new(output);
+    let date = statement.date.swift();
+    let lines: Vec<StatementLine> = transactions
+        .into_iter()
+        .filter(|transaction| transaction.client() == account.client())
+        .flat_map(statement_lines)
+        .collect();
+    let closing = to_decimal(account.total());
+    let opening = closing - lines.iter().map(|line| line.amount).sum::<Decimal>();
+    let balance = |amount| options.swift_amount(amount, false);
+    // the mark comes first in balances, then the date and currency
+    let balance_field = |amount| {
+        let value = balance(amount);
+        let (mark, digits) = value.split_at(1);
+        format!("{}{}{}{}", mark, date, statement.currency, digits)
+    };
+
+    write!(output, ":20:{}-{}\r\n", date, account.client())?;
+    write!(output, ":25:{}\r\n", account.client())?;
+    write!(output, ":28C:1/1\r\n")?;
+    write!(output, ":60F:{}\r\n", balance_field(opening))?;
+    for line in &lines {
+        let amount = options.swift_amount(line.amount, line.reversal);
+        write!(output, ":61:{}{}NMSC{}\r\n", date, amount, line.tx)?;
+        write!(output, ":86:{} {}\r\n", line.description, line.tx)?;
+    }
+    write!(output, ":62F:{}\r\n", balance_field(closing))?;
+    write!(
+        output,
+        ":64:{}\r\n",
+        balance_field(to_decimal(account.available()))
+    )?;
+    write!(output, "-\r\n")?;
+    output.flush()
+}
+
+/// Path of the statement of `client` in `dir`, e.g. `7-2024-01-31.sta`.
+pub fn statement_path(dir: &Path, client: Client, date: StatementDate) -> PathBuf {
+    dir.join(format!("{}-{}.sta", client, date))
+}
+
+/// Writes one statement per account to `dir`, creating it if needed.
+pub fn write_statements<'a, A, T>(
+    accounts: A,
+    transactions: T,
+    dir: &Path,
+    statement: &StatementOptions,
+    options: &ExportOptions,
+) -> Result<(), Box<dyn Error>>
+where
+    A: IntoIterator<Item = &'a Account>,
+    T: IntoIterator<Item = &'a Transaction>,
+{
+    fs::create_dir_all(dir)?;
+    let transactions: Vec<&Transaction> = transactions.into_iter().collect();
+    for account in accounts {
+        let path = statement_path(dir, account.client(), statement.date);
+        let file = fs::File::create(&path)
+            .map_err(|err| format!("unable to create {}: {}", path.display(), err))?;
+        account_statement(
+            account,
+            transactions.iter().copied(),
+            file,
+            statement,
+            options,
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::amount;
+    use crate::transactions::PaymentEngine;
+
+    #[test]
+    fn dates_are_parsed_and_derived_from_days() {
+        let date: StatementDate = "2024-02-29".parse().unwrap();
+        assert_eq!(StatementDate::from_days(19_782), date);
+        assert_eq!(date.swift(), "240229");
+        assert_eq!(date.to_string(), "2024-02-29");
+        assert!("2024-13-01".parse::<StatementDate>().is_err());
+        assert!("20240229".parse::<StatementDate>().is_err());
+    }
+
+    #[test]
+    fn statement_balances_match_the_activity() {
+        let mut engine = PaymentEngine::new();
+        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, amount!(100.5)).unwrap());
+        let _ = engine.process_transaction(Transaction::new_withdrawal(1, 2, amount!(20)).unwrap());
+        let _ = engine.process_transaction(Transaction::new_deposit(1, 3, amount!(7.25)).unwrap());
+        let _ = engine.process_transaction(Transaction::new_dispute(1, 3));
+        let _ = engine.process_transaction(Transaction::new_chargeback(1, 3));
+        let _ = engine.process_transaction(Transaction::new_deposit(2, 4, amount!(1)).unwrap());
+
+        let mut output = vec![];
+        let statement = StatementOptions {
+            date: "2024-01-31".parse().unwrap(),
+            currency: "EUR".to_string(),
+        };
+        let options = ExportOptions {
+            precision: 2,
+            ..ExportOptions::default()
+        };
+        let account = engine.get_account(1).unwrap();
+        account_statement(
+            account,
+            engine.transactions_iter(),
+            &mut output,
+            &statement,
+            &options,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            ":20:240131-1\r\n\
+             :25:1\r\n\
+             :28C:1/1\r\n\
+             :60F:C240131EUR0,00\r\n\
+             :61:240131C100,50NMSC1\r\n\
+             :86:deposit 1\r\n\
+             :61:240131D20,00NMSC2\r\n\
+             :86:withdrawal 2\r\n\
+             :61:240131C7,25NMSC3\r\n\
+             :86:deposit 3\r\n\
+             :61:240131D7,25NMSC3\r\n\
+             :86:chargeback 3\r\n\
+             :62F:C240131EUR80,50\r\n\
+             :64:C240131EUR80,50\r\n\
+             -\r\n"
+        );
+    }
+}
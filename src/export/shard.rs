@@ -0,0 +1,134 @@
+//! Account reports split over several files, for engines with too many accounts for a single
+//! report to be convenient. Each shard holds a contiguous run of the accounts sorted by client,
+//! about as many in each, and `index.csv` lists the files with the clients they cover, so that
+//! the shard of a client is found without opening the others.
+
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::{write_accounts, ExportOptions, OutputFormat};
+use crate::transactions::{Account, Client};
+
+/// Name of the index written next to the shards.
+pub const SHARD_INDEX: &str = "index.csv";
+
+/// A file of a sharded report, as listed in the index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Shard {
+    /// Name of the file, relative to the index.
+    pub file: String,
+    pub first_client: Client,
+    pub last_client: Client,
+    pub accounts: usize,
+}
+
+/// Name of the `number`th shard, counting from 1, e.g. `accounts-0003.csv`.
+pub fn shard_name(number: usize, format: OutputFormat) -> String {
+    let extension = match format {
+        OutputFormat::Parquet => "parquet",
+        _ => "csv",
+    };
+    format!("accounts-{:04}.{}", number, extension)
+}
+
+/// Writes `accounts`, sorted by client, as at most `shards` files in `options.format` to `dir`,
+/// creating it if needed, followed by the index. Returns the shards written, none when there
+/// are no accounts.
+pub fn write_shards(
+    accounts: &[&Account],
+    shards: usize,
+    dir: &Path,
+    options: &ExportOptions,
+) -> Result<Vec<Shard>, Box<dyn Error>> {
+    fs::create_dir_all(dir)?;
+    let size = accounts.len().div_ceil(shards.max(1)).max(1);
+    let mut written = vec![];
+    for (index, chunk) in accounts.chunks(size).enumerate() {
+        let file = shard_name(index + 1, options.format);
+        let path = dir.join(&file);
+        let output = fs::File::create(&path)
+            .map_err(|err| format!("unable to create {}: {}", path.display(), err))?;
+        write_accounts(chunk.iter().copied(), io::BufWriter::new(output), options)?;
+        written.push(Shard {
+            file,
+            first_client: chunk[0].client(),
+            last_client: chunk[chunk.len() - 1].client(),
+            accounts: chunk.len(),
+        });
+    }
+    write_index(&written, &index_path(dir))?;
+    Ok(written)
+}
+
+pub fn index_path(dir: &Path) -> PathBuf {
+    dir.join(SHARD_INDEX)
+}
+
+fn write_index(shards: &[Shard], path: &Path) -> Result<(), Box<dyn Error>> {
+    let mut wtr = csv::Writer::from_path(path)?;
+    // the header is written even without shards
+    wtr.write_record(["file", "first_client", "last_client", "accounts"])?;
+    for shard in shards {
+        wtr.write_record([
+            shard.file.clone(),
+            shard.first_client.to_string(),
+            shard.last_client.to_string(),
+            shard.accounts.to_string(),
+        ])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::amount;
+    use crate::transactions::{PaymentEngine, Transaction};
+
+    #[test]
+    fn shards_split_the_sorted_accounts() {
+        let mut engine = PaymentEngine::new();
+        for client in 1..=5 {
+            engine
+                .process_transaction(
+                    Transaction::new_deposit(client * 10, client.into(), amount!(1.0)).unwrap(),
+                )
+                .unwrap();
+        }
+        let dir = std::env::temp_dir().join(format!("payments-shards-{}", std::process::id()));
+        let accounts: Vec<&Account> = engine.accounts_iter().collect();
+        let shards = write_shards(&accounts, 2, &dir, &ExportOptions::default()).unwrap();
+
+        assert_eq!(
+            shards
+                .iter()
+                .map(|shard| (shard.first_client, shard.last_client, shard.accounts))
+                .collect::<Vec<_>>(),
+            vec![(10, 30, 3), (40, 50, 2)]
+        );
+        let second = fs::read_to_string(dir.join("accounts-0002.csv")).unwrap();
+        let clients: Vec<&str> = second
+            .lines()
+            .map(|line| line.split(',').next().unwrap())
+            .collect();
+        assert_eq!(clients, vec!["client", "40", "50"]);
+        assert_eq!(
+            fs::read_to_string(index_path(&dir)).unwrap(),
+            "file,first_client,last_client,accounts\naccounts-0001.csv,10,30,3\naccounts-0002.csv,40,50,2\n"
+        );
+
+        // no accounts, no shards, but an index all the same
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(write_shards(&[], 2, &dir, &ExportOptions::default())
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            fs::read_to_string(index_path(&dir)).unwrap(),
+            "file,first_client,last_client,accounts\n"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,182 @@
+//! Excel workbook with a `Balances` sheet holding the account report and a `Rejected` sheet
+//! listing the transactions the engine refused. Amounts are numbers rounded like the CSV
+//! report and displayed with `precision` decimal places.
+
+use rust_decimal::prelude::ToPrimitive;
+use rust_xlsxwriter::{Format, Workbook, Worksheet, XlsxError};
+use std::error::Error;
+use std::io;
+
+use super::{ExportOptions, MAX_PRECISION};
+use crate::amount::to_decimal;
+use crate::observer::Rejection;
+use crate::transactions::{Account, Amount};
+
+fn header(sheet: &mut Worksheet, columns: &[&str]) -> Result<(), XlsxError> {
+    let bold = Format::new().set_bold();
+    for (col, name) in columns.iter().enumerate() {
+        sheet.write_string_with_format(0, col as u16, *name, &bold)?;
+    }
+    sheet.set_freeze_panes(1, 0)?;
+    Ok(())
+}
+
+/// Account report followed by the rejected transactions, as a single workbook.
+pub fn accounts_as_xlsx<'a, I, W>(
+    accounts: I,
+    rejections: &[Rejection],
+    mut output: W,
+    options: &ExportOptions,
+) -> Result<(), Box<dyn Error>>
+where
+    I: IntoIterator<Item = &'a Account>,
+    W: io::Write,
+{
+    let places = options.precision.min(MAX_PRECISION) as usize;
+    let amount_format = match places {
+        0 => Format::new().set_num_format("0"),
+        places => Format::new().set_num_format(format!("0.{}", "0".repeat(places))),
+    };
+    let number = |amount: Amount| {
+        to_decimal(options.round(amount))
+            .to_f64()
+            .unwrap_or_default()
+    };
+
+    let mut workbook = Workbook::new();
+    let balances = workbook.add_worksheet();
+    balances.set_name("Balances")?;
+    let mut columns = vec!["client"];
+    if options.show_external_ids() {
+        columns.push("external_id");
+    }
+    columns.extend(["available", "held", "total", "locked"]);
+    if options.show_flags {
+        columns.push("flagged");
+    }
+    if options.extended {
+        columns.extend(["disputes_open", "disputes_total", "chargebacks"]);
+    }
+    if options.show_tiers() {
+        columns.push("tier");
+    }
+    if options.show_reserves() {
+        columns.push("reserve");
+    }
+    if options.show_escrow {
+        columns.push("escrowed");
+    }
+    header(balances, &columns)?;
+    for (index, account) in accounts.into_iter().enumerate() {
+        let row = index as u32 + 1;
+        match &options.anonymizer {
+            Some(anonymizer) => {
+                balances.write_string(row, 0, anonymizer.pseudonym(account.client()))?
+            }
+            None => balances.write_number(row, 0, account.client())?,
+        };
+        let mut col = 1;
+        if options.show_external_ids() {
+            if let Some(id) = options.external_id(account.client()) {
+                balances.write_string(row, col, id)?;
+            }
+            col += 1;
+        }
+        for amount in [account.available(), account.held(), account.total()] {
+            balances.write_number_with_format(row, col, number(amount), &amount_format)?;
+            col += 1;
+        }
+        balances.write_boolean(row, col, account.frozen())?;
+        col += 1;
+        if options.show_flags {
+            balances.write_boolean(row, col, account.flagged())?;
+            col += 1;
+        }
+        if options.extended {
+            for count in [
+                account.disputes_open(),
+                account.disputes_total(),
+                account.chargebacks(),
+            ] {
+                balances.write_number(row, col, count)?;
+                col += 1;
+            }
+        }
+        if options.show_tiers() {
+            balances.write_string(row, col, options.tier(account.client()))?;
+            col += 1;
+        }
+        if options.show_reserves() {
+            let reserve = number(options.reserve(account.client()));
+            balances.write_number_with_format(row, col, reserve, &amount_format)?;
+            col += 1;
+        }
+        if options.show_escrow {
+            balances.write_number_with_format(
+                row,
+                col,
+                number(account.escrowed()),
+                &amount_format,
+            )?;
+        }
+    }
+
+    let rejected = workbook.add_worksheet();
+    rejected.set_name("Rejected")?;
+    header(rejected, &["tx", "client", "code", "reason"])?;
+    for (index, rejection) in rejections.iter().enumerate() {
+        let row = index as u32 + 1;
+        rejected.write_number(row, 0, rejection.tx)?;
+        match &options.anonymizer {
+            Some(anonymizer) => {
+                rejected.write_string(row, 1, anonymizer.pseudonym(rejection.client))?;
+                rejected.write_string(
+                    row,
+                    3,
+                    anonymizer.scrub(rejection.client, &rejection.reason),
+                )?;
+            }
+            None => {
+                rejected.write_number(row, 1, rejection.client)?;
+                rejected.write_string(row, 3, rejection.reason.as_str())?;
+            }
+        };
+        rejected.write_string(row, 2, rejection.code)?;
+    }
+
+    output.write_all(&workbook.save_to_buffer()?)?;
+    output.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::amount;
+    use crate::transactions::{PaymentEngine, Transaction};
+
+    #[test]
+    fn workbook_is_a_zip_archive_with_both_sheets() {
+        let mut engine = PaymentEngine::new();
+        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, amount!(1.5)).unwrap());
+        let rejections = [Rejection {
+            client: 1,
+            tx: 2,
+            code: "insufficient_funds",
+            reason: "insufficient funds".to_string(),
+        }];
+
+        let mut output = vec![];
+        accounts_as_xlsx(
+            engine.accounts_iter(),
+            &rejections,
+            &mut output,
+            &ExportOptions::default(),
+        )
+        .unwrap();
+        assert!(output.starts_with(b"PK\x03\x04"));
+        let names = |needle: &[u8]| output.windows(needle.len()).any(|bytes| bytes == needle);
+        assert!(names(b"xl/worksheets/sheet1.xml"));
+        assert!(names(b"xl/worksheets/sheet2.xml"));
+    }
+}
@@ -0,0 +1,160 @@
+//! C ABI for embedding the engine in non-Rust services, built with the `ffi` feature. The
+//! declarations are in `include/payments.h`.
+//!
+//! Engines are opaque pointers owned by the caller and released with `payments_engine_free`.
+//! Strings returned by the library are released with `payments_string_free`.
+
+use std::ffi::{c_char, CStr, CString};
+use std::ptr;
+
+use crate::ingest::{records_from_reader, CsvDialect, PrecisionPolicy};
+use crate::transactions::PaymentEngine;
+
+/// The record was applied.
+pub const PAYMENTS_OK: i32 = 0;
+/// The record was read but the engine refused it, e.g. for insufficient funds.
+pub const PAYMENTS_REJECTED: i32 = 1;
+/// The line is not a `type,client,tx,amount` record or its transaction is invalid.
+pub const PAYMENTS_INVALID: i32 = 2;
+/// A null pointer or text that isn't UTF-8 was passed.
+pub const PAYMENTS_BAD_ARGUMENT: i32 = -1;
+
+/// Engine with the default policies, to be released with `payments_engine_free`.
+#[no_mangle]
+pub extern "C" fn payments_engine_new() -> *mut PaymentEngine {
+    Box::into_raw(Box::new(PaymentEngine::new()))
+}
+
+/// Applies one `type,client,tx,amount` CSV line, without header, and returns one of the
+/// `PAYMENTS_*` status codes.
+///
+/// # Safety
+///
+/// `engine` must come from `payments_engine_new` and not be used by another thread meanwhile,
+/// `line` must be a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn payments_process_csv_line(
+    engine: *mut PaymentEngine,
+    line: *const c_char,
+) -> i32 {
+    if engine.is_null() || line.is_null() {
+        return PAYMENTS_BAD_ARGUMENT;
+    }
+    // SAFETY: both pointers are non-null and valid per the contract above
+    let (engine, line) = unsafe { (&mut *engine, CStr::from_ptr(line)) };
+    let line = match line.to_str() {
+        Ok(line) => line,
+        Err(_) => return PAYMENTS_BAD_ARGUMENT,
+    };
+    let dialect = CsvDialect {
+        has_headers: false,
+        ..CsvDialect::default()
+    };
+    let record = match records_from_reader(line.as_bytes(), &dialect).next() {
+        Some(record) => record,
+        None => return PAYMENTS_INVALID,
+    };
+    let transaction = match record.into_transaction(&PrecisionPolicy::default()) {
+        Ok(transaction) => transaction,
+        Err(_) => return PAYMENTS_INVALID,
+    };
+    match engine.process_transaction(transaction) {
+        Ok(()) => PAYMENTS_OK,
+        Err(_) => PAYMENTS_REJECTED,
+    }
+}
+
+/// Every account as a JSON array of `{"client", "available", "held", "total", "locked"}`
+/// objects sorted by client, amounts being strings so no precision is lost. Returns null when
+/// `engine` is null; the string is released with `payments_string_free`.
+///
+/// # Safety
+///
+/// `engine` must come from `payments_engine_new` and not be changed by another thread
+/// meanwhile.
+#[no_mangle]
+pub unsafe extern "C" fn payments_export_accounts_json(
+    engine: *const PaymentEngine,
+) -> *mut c_char {
+    if engine.is_null() {
+        return ptr::null_mut();
+    }
+    // SAFETY: non-null and valid per the contract above
+    let engine = unsafe { &*engine };
+    let accounts: Vec<serde_json::Value> = engine
+        .iter_accounts()
+        .map(|account| {
+            serde_json::json!({
+                "client": account.client(),
+                "available": account.available().to_string(),
+                "held": account.held().to_string(),
+                "total": account.total().to_string(),
+                "locked": account.frozen(),
+            })
+        })
+        .collect();
+    // JSON text never contains NUL bytes
+    CString::new(serde_json::Value::from(accounts).to_string())
+        .map_or(ptr::null_mut(), CString::into_raw)
+}
+
+/// Releases a string returned by this library; null is ignored.
+///
+/// # Safety
+///
+/// `string` must come from this library and not be released twice.
+#[no_mangle]
+pub unsafe extern "C" fn payments_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        // SAFETY: allocated by `CString::into_raw` per the contract above
+        drop(unsafe { CString::from_raw(string) });
+    }
+}
+
+/// Releases an engine; null is ignored.
+///
+/// # Safety
+///
+/// `engine` must come from `payments_engine_new` and not be released twice.
+#[no_mangle]
+pub unsafe extern "C" fn payments_engine_free(engine: *mut PaymentEngine) {
+    if !engine.is_null() {
+        // SAFETY: allocated by `Box::into_raw` per the contract above
+        drop(unsafe { Box::from_raw(engine) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn engines_are_driven_through_the_c_abi() {
+        let engine = payments_engine_new();
+        let process = |line: &str| {
+            let line = CString::new(line).unwrap();
+            unsafe { payments_process_csv_line(engine, line.as_ptr()) }
+        };
+        assert_eq!(process("deposit,2,1,2.5"), PAYMENTS_OK);
+        assert_eq!(process("deposit, 1, 2, 1.0"), PAYMENTS_OK);
+        assert_eq!(process("withdrawal,1,3,4.0"), PAYMENTS_REJECTED);
+        assert_eq!(process("deposit,1,4,abc"), PAYMENTS_INVALID);
+        assert_eq!(process(""), PAYMENTS_INVALID);
+        assert_eq!(
+            unsafe { payments_process_csv_line(ptr::null_mut(), c"x".as_ptr()) },
+            PAYMENTS_BAD_ARGUMENT
+        );
+
+        let json = unsafe { payments_export_accounts_json(engine) };
+        let accounts: serde_json::Value =
+            serde_json::from_str(unsafe { CStr::from_ptr(json) }.to_str().unwrap()).unwrap();
+        unsafe {
+            payments_string_free(json);
+            payments_engine_free(engine);
+        }
+        assert_eq!(accounts[0]["client"], 1);
+        assert_eq!(accounts[1]["client"], 2);
+        assert_eq!(accounts[1]["locked"], false);
+        assert_eq!(accounts.as_array().unwrap().len(), 2);
+    }
+}
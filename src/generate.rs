@@ -0,0 +1,123 @@
+use std::error::Error;
+use std::io;
+
+use crate::transactions::{Client, TransactionId};
+
+/// Shape of the input written by `generate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GeneratorOptions {
+    pub clients: Client,
+    pub transactions: u64,
+    /// The same seed always produces the same file.
+    pub seed: u64,
+}
+
+impl Default for GeneratorOptions {
+    fn default() -> Self {
+        Self {
+            clients: 100,
+            transactions: 1000,
+            seed: 0,
+        }
+    }
+}
+
+/// splitmix64; good enough for test data and keeps the output stable across releases.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound.max(1)
+    }
+}
+
+fn amount(rng: &mut Rng) -> String {
+    // up to 1000 with 4 decimal places
+    let units = rng.below(10_000_000) + 1;
+    format!("{}.{:04}", units / 10_000, units % 10_000)
+}
+
+/// Writes a synthetic transaction file: mostly deposits and withdrawals, with disputes of earlier
+/// transactions that are later resolved or charged back. Withdrawals are not checked against
+/// balances, so some of them are expected to be rejected.
+pub fn generate<W: io::Write>(options: &GeneratorOptions, output: W) -> Result<(), Box<dyn Error>> {
+    let mut rng = Rng(options.seed);
+    let mut wtr = csv::Writer::from_writer(output);
+    wtr.write_record(["type", "client", "tx", "amount"])?;
+
+    let mut settled: Vec<(Client, TransactionId)> = vec![];
+    let mut disputed: Vec<(Client, TransactionId)> = vec![];
+    let mut next_tx: TransactionId = 1;
+    for _ in 0..options.transactions {
+        let roll = rng.below(100);
+        let (kind, client, tx, amount) = if roll < 10 && !settled.is_empty() {
+            let index = rng.below(settled.len() as u64) as usize;
+            let (client, tx) = settled.swap_remove(index);
+            disputed.push((client, tx));
+            ("dispute", client, tx, String::new())
+        } else if roll < 15 && !disputed.is_empty() {
+            let index = rng.below(disputed.len() as u64) as usize;
+            let (client, tx) = disputed.swap_remove(index);
+            let kind = if rng.below(3) == 0 {
+                "chargeback"
+            } else {
+                "resolve"
+            };
+            (kind, client, tx, String::new())
+        } else {
+            let client = rng.below(options.clients as u64) as Client + 1;
+            let tx = next_tx;
+            next_tx = next_tx.wrapping_add(1);
+            settled.push((client, tx));
+            let kind = if roll < 65 { "deposit" } else { "withdrawal" };
+            (kind, client, tx, amount(&mut rng))
+        };
+        wtr.write_record([kind, &client.to_string(), &tx.to_string(), &amount])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ingest::{records_from_reader, CsvDialect};
+    use crate::transactions::{PaymentEngine, Transaction, TransactionKind};
+
+    fn generated(options: &GeneratorOptions) -> Vec<u8> {
+        let mut output = vec![];
+        generate(options, &mut output).unwrap();
+        output
+    }
+
+    #[test]
+    fn output_is_deterministic_and_processable() {
+        let options = GeneratorOptions {
+            clients: 10,
+            transactions: 500,
+            seed: 7,
+        };
+        let output = generated(&options);
+        assert_eq!(output, generated(&options));
+        assert_ne!(output, generated(&GeneratorOptions { seed: 8, ..options }));
+
+        let mut engine = PaymentEngine::new();
+        let mut records = 0;
+        for record in records_from_reader(output.as_slice(), &CsvDialect::default()) {
+            let transaction = Transaction::try_from(record).unwrap();
+            assert!((1..=10).contains(&transaction.client()));
+            let _ = engine.process_transaction(transaction);
+            records += 1;
+        }
+        assert_eq!(records, 500);
+        assert!(engine.metrics().applied(TransactionKind::Dispute) > 0);
+    }
+}
@@ -1,17 +1,44 @@
-use serde::Deserialize;
+#[cfg(feature = "native")]
+use memmap2::Mmap;
+use serde::de::{self, DeserializeOwned};
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
 use std::fs::File;
-use std::path::PathBuf;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
 
+use crate::amount::RoundingStrategy;
+use crate::snapshot::InputOffset;
 use crate::transactions::{Amount, Client, Transaction, TransactionId, TransactionValidationError};
 
+pub mod iso20022;
+mod locale;
+pub mod ofx;
+pub mod qif;
+mod signature;
+
+pub use locale::AmountFormat;
+pub use signature::{SignatureError, SignatureKey, SIGNATURE_COLUMN};
+
 #[derive(Debug, Deserialize)]
-#[serde(rename_all = "lowercase")]
+#[serde(rename_all = "snake_case")]
 enum TransactionRecordKind {
     Deposit,
     Withdrawal,
     Dispute,
     Resolve,
     Chargeback,
+    ChargebackReversal,
+    Reversal,
+    Hold,
+    Capture,
+    Release,
+    Adjustment,
+    EscrowHold,
+    EscrowRelease,
+    AdvanceTime,
+    Recurring,
+    ClosePeriod,
 }
 
 #[derive(Debug, Deserialize)]
@@ -21,25 +48,178 @@ pub struct TransactionRecord {
     client: Client,
     tx: TransactionId,
     amount: Option<Amount>,
+    /// Free-text `memo` (or `reference`) column tracing the record to upstream systems.
+    #[serde(default, alias = "reference")]
+    memo: Option<String>,
+    /// Merchant or other party on the other side, `counterparty` or `merchant` column.
+    #[serde(default, alias = "merchant")]
+    counterparty: Option<String>,
+    /// Records sharing a `batch_id` (or `batch`) on consecutive lines are applied all or
+    /// nothing.
+    #[serde(default, alias = "batch")]
+    batch_id: Option<String>,
+    /// Partner program the record belongs to, see `tenant::TenantManager`.
+    #[serde(default)]
+    tenant: Option<String>,
+    /// Reason code of an adjustment, ignored by the other types.
+    #[serde(default)]
+    reason: Option<String>,
+    /// Name of the escrow of an escrow hold, ignored by the other types.
+    #[serde(default)]
+    escrow: Option<String>,
+    /// Seconds since the Unix epoch the record is applied at, see `Transaction::Scheduled`;
+    /// the time the clock moves to for `advance_time`.
+    #[serde(default)]
+    execute_at: Option<u64>,
+    /// Seconds between the occurrences of a recurrence, ignored by the other types.
+    #[serde(default)]
+    interval: Option<u64>,
+    /// Occurrences of a recurrence, ignored by the other types.
+    #[serde(default)]
+    count: Option<u32>,
+    /// Set by the readers of this module.
+    #[serde(skip)]
+    line: Option<u64>,
+    /// Set by the readers of this module when the dialect has a `signature_key` the row doesn't
+    /// verify with.
+    #[serde(skip)]
+    signature_error: Option<SignatureError>,
 }
 
-impl std::convert::TryFrom<TransactionRecord> for Transaction {
-    type Error = TransactionValidationError;
+/// Layout of transaction files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InputFormat {
+    /// `type,client,tx,amount` rows.
+    #[default]
+    Csv,
+    /// pain.001 or camt.053 XML documents, see `iso20022`.
+    Iso20022,
+    /// OFX bank and credit card statements, see `ofx`.
+    Ofx,
+    /// QIF exports, see `qif`.
+    Qif,
+}
 
-    fn try_from(record: TransactionRecord) -> Result<Self, Self::Error> {
-        match record.kind {
-            TransactionRecordKind::Deposit => {
-                if let Some(amount) = record.amount {
-                    return Transaction::new_deposit(record.client, record.tx, amount);
-                }
-                Err(TransactionValidationError::InvalidAmount)
-            }
-            TransactionRecordKind::Withdrawal => {
-                if let Some(amount) = record.amount {
-                    return Transaction::new_withdrawal(record.client, record.tx, amount);
-                }
-                Err(TransactionValidationError::InvalidAmount)
+impl std::str::FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(InputFormat::Csv),
+            "iso20022" => Ok(InputFormat::Iso20022),
+            "ofx" => Ok(InputFormat::Ofx),
+            "qif" => Ok(InputFormat::Qif),
+            _ => Err(format!("unknown input format: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PrecisionMode {
+    /// Records with too many decimal places are rejected.
+    #[default]
+    Reject,
+    /// Extra decimal places are dropped.
+    Truncate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrecisionPolicy {
+    pub max_decimal_places: u32,
+    pub mode: PrecisionMode,
+}
+
+impl Default for PrecisionPolicy {
+    fn default() -> Self {
+        Self {
+            max_decimal_places: 4,
+            mode: PrecisionMode::default(),
+        }
+    }
+}
+
+impl PrecisionPolicy {
+    fn apply(
+        &self,
+        client: Client,
+        tx: TransactionId,
+        amount: Amount,
+    ) -> Result<Amount, TransactionValidationError> {
+        if amount.normalize().scale() <= self.max_decimal_places {
+            return Ok(amount);
+        }
+        match self.mode {
+            PrecisionMode::Reject => {
+                Err(TransactionValidationError::ExcessivePrecision { client, tx, amount })
             }
+            PrecisionMode::Truncate => Ok(
+                amount.round_dp_with_strategy(self.max_decimal_places, RoundingStrategy::ToZero)
+            ),
+        }
+    }
+}
+
+impl TransactionRecord {
+    pub fn client(&self) -> Client {
+        self.client
+    }
+
+    pub fn tx(&self) -> TransactionId {
+        self.tx
+    }
+
+    pub fn memo(&self) -> Option<&str> {
+        self.memo.as_deref()
+    }
+
+    pub fn batch_id(&self) -> Option<&str> {
+        self.batch_id.as_deref()
+    }
+
+    pub fn tenant(&self) -> Option<&str> {
+        self.tenant.as_deref()
+    }
+
+    /// Input line the record starts on, the header being line 1.
+    pub fn line(&self) -> Option<u64> {
+        self.line
+    }
+
+    /// Why the row of the record fails signature verification; such records mustn't be
+    /// processed.
+    pub fn signature_error(&self) -> Option<SignatureError> {
+        self.signature_error
+    }
+
+    pub fn into_transaction(
+        mut self,
+        precision: &PrecisionPolicy,
+    ) -> Result<Transaction, TransactionValidationError> {
+        if let Some(amount) = self.amount {
+            self.amount = Some(precision.apply(self.client, self.tx, amount)?);
+        }
+        self.into_transaction_unchecked()
+    }
+
+    fn into_transaction_unchecked(self) -> Result<Transaction, TransactionValidationError> {
+        let record = self;
+        let transaction = match record.kind {
+            TransactionRecordKind::Deposit => match record.amount {
+                Some(amount) => Transaction::new_deposit(record.client, record.tx, amount),
+                None => Err(TransactionValidationError::MissingAmount {
+                    client: record.client,
+                    tx: record.tx,
+                }),
+            },
+            TransactionRecordKind::Withdrawal => match record.amount {
+                Some(amount) => Transaction::new_withdrawal(record.client, record.tx, amount),
+                None => Err(TransactionValidationError::MissingAmount {
+                    client: record.client,
+                    tx: record.tx,
+                }),
+            },
             TransactionRecordKind::Dispute => {
                 Ok(Transaction::new_dispute(record.client, record.tx))
             }
@@ -50,11 +230,791 @@ impl std::convert::TryFrom<TransactionRecord> for Transaction {
             TransactionRecordKind::Chargeback => {
                 Ok(Transaction::new_chargeback(record.client, record.tx))
             }
+            TransactionRecordKind::ChargebackReversal => Ok(Transaction::new_chargeback_reversal(
+                record.client,
+                record.tx,
+            )),
+            TransactionRecordKind::Reversal => {
+                Ok(Transaction::new_reversal(record.client, record.tx))
+            }
+            TransactionRecordKind::Hold => match record.amount {
+                Some(amount) => Transaction::new_hold(record.client, record.tx, amount),
+                None => Err(TransactionValidationError::MissingAmount {
+                    client: record.client,
+                    tx: record.tx,
+                }),
+            },
+            TransactionRecordKind::Capture => {
+                Ok(Transaction::new_capture(record.client, record.tx))
+            }
+            TransactionRecordKind::Release => {
+                Ok(Transaction::new_release(record.client, record.tx))
+            }
+            TransactionRecordKind::Adjustment => match record.amount {
+                Some(amount) => Transaction::new_adjustment(
+                    record.client,
+                    record.tx,
+                    amount,
+                    record.reason.unwrap_or_default(),
+                ),
+                None => Err(TransactionValidationError::MissingAmount {
+                    client: record.client,
+                    tx: record.tx,
+                }),
+            },
+            TransactionRecordKind::EscrowHold => match record.amount {
+                Some(amount) => Transaction::new_escrow_hold(
+                    record.client,
+                    record.tx,
+                    amount,
+                    record.escrow.unwrap_or_default(),
+                ),
+                None => Err(TransactionValidationError::MissingAmount {
+                    client: record.client,
+                    tx: record.tx,
+                }),
+            },
+            // the counterparty column of the release says who the escrow is paid out to
+            TransactionRecordKind::EscrowRelease => {
+                Ok(Transaction::new_escrow_release(record.client, record.tx))
+            }
+            TransactionRecordKind::AdvanceTime => {
+                return match record.execute_at {
+                    Some(to) => Ok(Transaction::new_advance_time(record.client, record.tx, to)),
+                    None => Err(TransactionValidationError::MissingTime {
+                        client: record.client,
+                        tx: record.tx,
+                    }),
+                };
+            }
+            // applied as soon as it is read, whatever its `execute_at`
+            TransactionRecordKind::ClosePeriod => {
+                return Ok(Transaction::new_close_period(record.client, record.tx));
+            }
+            // `execute_at` is when the first occurrence is due
+            TransactionRecordKind::Recurring => {
+                let amount = record
+                    .amount
+                    .ok_or(TransactionValidationError::MissingAmount {
+                        client: record.client,
+                        tx: record.tx,
+                    })?;
+                return match (record.interval, record.count) {
+                    (Some(interval), Some(count)) => Transaction::new_recurring(
+                        record.client,
+                        record.tx,
+                        amount,
+                        interval,
+                        count,
+                        record.execute_at,
+                    ),
+                    _ => Err(TransactionValidationError::InvalidRecurrence {
+                        client: record.client,
+                        tx: record.tx,
+                    }),
+                };
+            }
+        };
+        transaction.map(|transaction| {
+            let transaction = transaction
+                .with_memo(record.memo)
+                .with_counterparty(record.counterparty);
+            match record.execute_at {
+                Some(execute_at) => Transaction::new_scheduled(execute_at, transaction),
+                None => transaction,
+            }
+        })
+    }
+}
+
+impl std::convert::TryFrom<TransactionRecord> for Transaction {
+    type Error = TransactionValidationError;
+
+    /// Converts using the default `PrecisionPolicy`.
+    fn try_from(record: TransactionRecord) -> Result<Self, Self::Error> {
+        record.into_transaction(&PrecisionPolicy::default())
+    }
+}
+
+/// How transaction CSV files are laid out. Rows may end with `\n` or `\r\n`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CsvDialect {
+    pub delimiter: u8,
+    /// Whether the first row names the columns. Without one, rows are read by position.
+    pub has_headers: bool,
+    /// Column names by position when there is no header row.
+    pub columns: Vec<String>,
+    pub quote: u8,
+    /// When disabled, quote characters are ordinary data.
+    pub quoting: bool,
+    /// Column names replaced before deserialization, e.g. `customer_id` by `client`.
+    pub renames: HashMap<String, String>,
+    pub amounts: AmountFormat,
+    /// Rows must carry a valid `signature` column when set, see `SignatureKey`.
+    pub signature_key: Option<SignatureKey>,
+}
+
+impl Default for CsvDialect {
+    fn default() -> Self {
+        Self {
+            delimiter: b',',
+            has_headers: true,
+            columns: ["type", "client", "tx", "amount"]
+                .iter()
+                .map(|column| column.to_string())
+                .collect(),
+            quote: b'"',
+            quoting: true,
+            renames: HashMap::new(),
+            amounts: AmountFormat::default(),
+            signature_key: None,
+        }
+    }
+}
+
+impl CsvDialect {
+    pub(crate) fn reader<R: Read>(&self, input: R) -> csv::Reader<R> {
+        csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .delimiter(self.delimiter)
+            // the default terminator counts `\r\n` rows as starting one line early; the `\r` is
+            // trimmed from the last field instead
+            .terminator(csv::Terminator::Any(b'\n'))
+            .has_headers(self.has_headers)
+            // rows without headers may leave out trailing empty columns such as the amount
+            .flexible(!self.has_headers)
+            .quote(self.quote)
+            .quoting(self.quoting)
+            .from_reader(input)
+    }
+
+    /// Column names of the rows read by `rdr`, renamed.
+    pub(crate) fn headers<R: Read>(
+        &self,
+        rdr: &mut csv::Reader<R>,
+    ) -> csv::Result<csv::ByteRecord> {
+        if self.has_headers {
+            Ok(self.rename(rdr.byte_headers()?))
+        } else {
+            Ok(self.rename(&csv::ByteRecord::from(self.columns.clone())))
+        }
+    }
+
+    /// Checks the signature of `row`, unless no `signature_key` is set.
+    pub(crate) fn verify(
+        &self,
+        headers: &csv::ByteRecord,
+        row: &csv::ByteRecord,
+    ) -> Result<(), SignatureError> {
+        match &self.signature_key {
+            Some(key) => key.verify(headers, row),
+            None => Ok(()),
+        }
+    }
+
+    /// Rewrites the amount of `row` in plain notation, unless amounts already are plain.
+    pub(crate) fn localize(
+        &self,
+        headers: &csv::ByteRecord,
+        row: &mut csv::ByteRecord,
+    ) -> Result<(), String> {
+        if self.amounts == AmountFormat::Plain {
+            return Ok(());
+        }
+        let index = match headers.iter().position(|name| name == b"amount") {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+        let amount = match row.get(index) {
+            Some(amount) if !amount.is_empty() => String::from_utf8_lossy(amount),
+            _ => return Ok(()),
+        };
+        let amount = self.amounts.normalize(&amount)?;
+        let mut localized = csv::ByteRecord::with_capacity(row.as_slice().len(), row.len());
+        for (column, field) in row.iter().enumerate() {
+            if column == index {
+                localized.push_field(amount.as_bytes());
+            } else {
+                localized.push_field(field);
+            }
         }
+        localized.set_position(row.position().cloned());
+        *row = localized;
+        Ok(())
+    }
+
+    fn rename(&self, headers: &csv::ByteRecord) -> csv::ByteRecord {
+        headers
+            .iter()
+            .map(|name| {
+                let renamed = std::str::from_utf8(name)
+                    .ok()
+                    .and_then(|name| self.renames.get(name));
+                renamed.map_or(name, |renamed| renamed.as_bytes())
+            })
+            .collect()
     }
 }
 
 pub fn parse_from_file(input_path: PathBuf) -> anyhow::Result<Vec<TransactionRecord>> {
+    Ok(records_from_file(input_path)?.collect())
+}
+
+/// Lazily reads records one by one, silently skipping rows that can't be deserialized.
+pub fn records_from_file(
+    input_path: PathBuf,
+) -> anyhow::Result<impl Iterator<Item = TransactionRecord>> {
+    Ok(records_from_reader(
+        File::open(input_path)?,
+        &CsvDialect::default(),
+    ))
+}
+
+/// Same as `records_from_file`, for any other source such as a remote object.
+pub fn records_from_reader<R: Read>(
+    input: R,
+    dialect: &CsvDialect,
+) -> impl Iterator<Item = TransactionRecord> {
+    let mut rdr = dialect.reader(input);
+    let headers = dialect.headers(&mut rdr).unwrap_or_default();
+    let mut row = csv::ByteRecord::new();
+    let dialect = dialect.clone();
+    std::iter::from_fn(move || next_record(&mut rdr, &headers, &mut row, &dialect))
+}
+
+/// Next row of `rdr` that deserializes into a record, skipping the others.
+fn next_record<R: Read>(
+    rdr: &mut csv::Reader<R>,
+    headers: &csv::ByteRecord,
+    row: &mut csv::ByteRecord,
+    dialect: &CsvDialect,
+) -> Option<TransactionRecord> {
+    loop {
+        match rdr.read_byte_record(row) {
+            Ok(true) => {
+                let signature = dialect.verify(headers, row);
+                if dialect.localize(headers, row).is_err() {
+                    continue;
+                }
+                if let Ok(mut record) = row.deserialize::<TransactionRecord>(Some(headers)) {
+                    record.line = row.position().map(|position| position.line());
+                    record.signature_error = signature.err();
+                    return Some(record);
+                }
+            }
+            Ok(false) => return None,
+            Err(err) if err.is_io_error() => return None,
+            Err(_) => {}
+        }
+    }
+}
+
+/// Like `records_from_file`, but starts reading at `offset` (the beginning of the file when it is
+/// the default) and pairs each record with the offset right after it.
+pub fn records_from_offset(
+    input_path: PathBuf,
+    offset: InputOffset,
+    dialect: &CsvDialect,
+) -> anyhow::Result<impl Iterator<Item = (TransactionRecord, InputOffset)>> {
+    let file = File::open(input_path)?;
+    let mut rdr = dialect.reader(file);
+    let headers = dialect.headers(&mut rdr)?;
+    if offset != InputOffset::default() {
+        rdr.seek(offset.into())?;
+    }
+    let mut row = csv::ByteRecord::new();
+    let dialect = dialect.clone();
+
+    Ok(std::iter::from_fn(move || {
+        let record = next_record(&mut rdr, &headers, &mut row, &dialect)?;
+        Some((record, rdr.position().into()))
+    }))
+}
+
+/// Faster variant of `records_from_file` for very large inputs: the file is mapped into memory
+/// instead of read through syscalls, and every row is deserialized from one reused byte buffer
+/// instead of allocating strings per field.
+///
+/// The input must not be modified while it is being read.
+#[cfg(feature = "native")]
+pub fn records_from_mmap(
+    input_path: PathBuf,
+    dialect: &CsvDialect,
+) -> anyhow::Result<impl Iterator<Item = TransactionRecord>> {
+    let file = File::open(input_path)?;
+    // SAFETY: the mapping is only read, and callers guarantee the file isn't truncated meanwhile
+    let mmap = unsafe { Mmap::map(&file)? };
+    let mut rdr = dialect.reader(std::io::Cursor::new(mmap));
+    let headers = dialect.headers(&mut rdr)?;
+    let mut row = csv::ByteRecord::new();
+    let dialect = dialect.clone();
+
+    Ok(std::iter::from_fn(move || {
+        next_record(&mut rdr, &headers, &mut row, &dialect)
+    }))
+}
+
+/// A row of the input that can't be turned into a transaction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidRecord {
+    /// Line of the input the row starts on, the header being line 1.
+    pub line: u64,
+    pub reason: String,
+}
+
+/// Reads the whole input and reports every row the processing readers would skip, instead of
+/// skipping it.
+pub fn check_records<R: Read>(
+    input: R,
+    precision: &PrecisionPolicy,
+    dialect: &CsvDialect,
+) -> anyhow::Result<Vec<InvalidRecord>> {
+    let mut rdr = dialect.reader(input);
+    let headers = dialect.headers(&mut rdr)?;
+    let mut row = csv::ByteRecord::new();
+    let mut invalid = vec![];
+    loop {
+        let line = rdr.position().line();
+        let reason = match rdr.read_byte_record(&mut row) {
+            Ok(false) => return Ok(invalid),
+            Ok(true) => {
+                if let Err(err) = dialect.verify(&headers, &row) {
+                    invalid.push(InvalidRecord {
+                        line,
+                        reason: format!("invalid signature: {}", err),
+                    });
+                    continue;
+                }
+                let record = dialect.localize(&headers, &mut row).and_then(|()| {
+                    row.deserialize::<TransactionRecord>(Some(&headers))
+                        .map_err(|err| err.to_string())
+                });
+                match record {
+                    Ok(record) => match record.into_transaction(precision) {
+                        Ok(_) => continue,
+                        Err(err) => err.to_string(),
+                    },
+                    Err(reason) => reason,
+                }
+            }
+            Err(err) if err.is_io_error() => return Err(err.into()),
+            Err(err) => err.to_string(),
+        };
+        invalid.push(InvalidRecord { line, reason });
+    }
+}
+
+/// Client ids of the account identifiers found in statement formats (every `InputFormat` but
+/// CSV), read from `account,client` rows.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccountMap(HashMap<String, Client>);
+
+#[derive(Debug, Deserialize)]
+struct AccountMapping {
+    account: String,
+    client: Client,
+}
+
+impl AccountMap {
+    pub fn load<P: AsRef<Path>>(input_path: P) -> anyhow::Result<Self> {
+        let mappings: Vec<AccountMapping> = read_records(input_path)?;
+        Ok(mappings
+            .into_iter()
+            .map(|mapping| (mapping.account, mapping.client))
+            .collect())
+    }
+
+    /// The client mapped to `account`, or `account` itself when it is a client id.
+    pub fn client(&self, account: &str) -> Option<Client> {
+        self.0
+            .get(account)
+            .copied()
+            .or_else(|| account.parse().ok())
+    }
+}
+
+impl FromIterator<(String, Client)> for AccountMap {
+    fn from_iter<I: IntoIterator<Item = (String, Client)>>(iter: I) -> Self {
+        AccountMap(iter.into_iter().collect())
+    }
+}
+
+/// Client and transaction id of a statement entry starting on `line`.
+fn statement_ids(
+    accounts: &AccountMap,
+    line: u64,
+    account: Option<&str>,
+    reference: Option<&str>,
+) -> Result<(Client, TransactionId), InvalidRecord> {
+    let invalid = |reason: String| InvalidRecord { line, reason };
+    let account = account.ok_or_else(|| invalid("entry without account".to_string()))?;
+    let client = accounts
+        .client(account)
+        .ok_or_else(|| invalid(format!("account {} is not mapped to a client", account)))?;
+    let reference = reference.ok_or_else(|| invalid("entry without reference".to_string()))?;
+    let tx = reference
+        .parse()
+        .map_err(|_| invalid(format!("reference {} is not a transaction id", reference)))?;
+    Ok((client, tx))
+}
+
+/// A statement entry with a signed amount: a deposit, or a withdrawal when negative.
+fn signed_record(
+    accounts: &AccountMap,
+    line: u64,
+    account: Option<&str>,
+    reference: Option<&str>,
+    amount: Option<&str>,
+) -> Result<TransactionRecord, InvalidRecord> {
+    let (client, tx) = statement_ids(accounts, line, account, reference)?;
+    let amount = amount.ok_or_else(|| InvalidRecord {
+        line,
+        reason: "entry without amount".to_string(),
+    })?;
+    let amount: Amount = amount.replace(',', "").parse().map_err(|_| InvalidRecord {
+        line,
+        reason: format!("invalid amount {}", amount),
+    })?;
+    let kind = if amount.is_sign_negative() {
+        TransactionRecordKind::Withdrawal
+    } else {
+        TransactionRecordKind::Deposit
+    };
+    Ok(TransactionRecord {
+        kind,
+        client,
+        tx,
+        amount: Some(amount.abs()),
+        memo: None,
+        counterparty: None,
+        batch_id: None,
+        tenant: None,
+        reason: None,
+        escrow: None,
+        execute_at: None,
+        interval: None,
+        count: None,
+        line: Some(line),
+        signature_error: None,
+    })
+}
+
+/// Every entry of a document in a statement format, in document order. Entries that can't be
+/// turned into a record come back as `InvalidRecord`s; a malformed document is an error.
+pub fn read_statement<R: Read>(
+    input: R,
+    format: InputFormat,
+    accounts: &AccountMap,
+) -> anyhow::Result<Vec<Result<TransactionRecord, InvalidRecord>>> {
+    match format {
+        InputFormat::Csv => anyhow::bail!("csv is not a statement format"),
+        InputFormat::Iso20022 => iso20022::read_entries(input, accounts),
+        InputFormat::Ofx => ofx::read_entries(input, accounts),
+        InputFormat::Qif => qif::read_entries(input, accounts),
+    }
+}
+
+/// The records of a statement, skipping entries that can't be read like the CSV readers skip
+/// rows.
+pub fn statement_records<R: Read>(
+    input: R,
+    format: InputFormat,
+    accounts: &AccountMap,
+) -> anyhow::Result<Vec<TransactionRecord>> {
+    Ok(read_statement(input, format, accounts)?
+        .into_iter()
+        .flatten()
+        .collect())
+}
+
+/// Like `check_records`, for statement formats.
+pub fn check_statement<R: Read>(
+    input: R,
+    format: InputFormat,
+    accounts: &AccountMap,
+    precision: &PrecisionPolicy,
+) -> anyhow::Result<Vec<InvalidRecord>> {
+    let mut invalid = vec![];
+    for entry in read_statement(input, format, accounts)? {
+        let record = match entry {
+            Ok(record) => record,
+            Err(record) => {
+                invalid.push(record);
+                continue;
+            }
+        };
+        let line = record.line.unwrap_or_default();
+        if let Err(err) = record.into_transaction(precision) {
+            invalid.push(InvalidRecord {
+                line,
+                reason: err.to_string(),
+            });
+        }
+    }
+    Ok(invalid)
+}
+
+/// Order in which `discover_files` returns the files of a directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FileOrder {
+    #[default]
+    Name,
+    /// Modification time, oldest first.
+    Mtime,
+    /// Date embedded in the file name as `YYYYMMDD` or `YYYY-MM-DD`; files without one go last.
+    Date,
+}
+
+impl std::str::FromStr for FileOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "name" => Ok(FileOrder::Name),
+            "mtime" => Ok(FileOrder::Mtime),
+            "date" => Ok(FileOrder::Date),
+            _ => Err(format!("unknown file order: {}", s)),
+        }
+    }
+}
+
+/// First `YYYYMMDD` or `YYYY-MM-DD` date in the file name, as a sortable number.
+fn embedded_date(path: &Path) -> Option<u32> {
+    let name = path.file_name()?.to_str()?.as_bytes();
+    (0..name.len()).find_map(|start| {
+        let date = match &name[start..] {
+            [y1, y2, y3, y4, b'-', m1, m2, b'-', d1, d2, ..]
+            | [y1, y2, y3, y4, m1, m2, d1, d2, ..] => [y1, y2, y3, y4, m1, m2, d1, d2],
+            _ => return None,
+        };
+        date.iter().try_fold(0, |value, digit| {
+            digit
+                .is_ascii_digit()
+                .then(|| value * 10 + u32::from(**digit - b'0'))
+        })
+    })
+}
+
+/// Transaction files (`*.csv`) directly inside `dir`, in the requested order.
+pub fn discover_files<P: AsRef<Path>>(dir: P, order: FileOrder) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_file() && path.extension().is_some_and(|ext| ext == "csv") {
+            files.push((entry.metadata()?.modified()?, path));
+        }
+    }
+    files.sort_by(|(_, left), (_, right)| left.cmp(right));
+    match order {
+        FileOrder::Name => {}
+        FileOrder::Mtime => files.sort_by_key(|(modified, _)| *modified),
+        FileOrder::Date => {
+            files.sort_by_key(|(_, path)| (embedded_date(path).is_none(), embedded_date(path)))
+        }
+    }
+    Ok(files.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Follows a CSV file that keeps being appended to, like `tail -f`.
+pub struct TailReader {
+    reader: BufReader<File>,
+    dialect: CsvDialect,
+    headers: Option<csv::ByteRecord>,
+    /// Last line read without its terminating newline, still being written.
+    pending: String,
+    read: u64,
+    /// Complete lines read so far.
+    lines: u64,
+}
+
+impl TailReader {
+    pub fn open<P: AsRef<Path>>(input_path: P, dialect: &CsvDialect) -> anyhow::Result<Self> {
+        Ok(Self {
+            reader: BufReader::new(File::open(input_path)?),
+            dialect: dialect.clone(),
+            headers: (!dialect.has_headers)
+                .then(|| dialect.rename(&csv::ByteRecord::from(dialect.columns.clone()))),
+            pending: String::new(),
+            read: 0,
+            lines: 0,
+        })
+    }
+
+    /// Records completed since the previous call. Rows that can't be deserialized are skipped.
+    pub fn poll(&mut self) -> anyhow::Result<Vec<TransactionRecord>> {
+        if self.reader.get_ref().metadata()?.len() < self.read {
+            anyhow::bail!("input was truncated");
+        }
+
+        let mut records = vec![];
+        loop {
+            let read = self.reader.read_line(&mut self.pending)?;
+            self.read += read as u64;
+            if read == 0 || !self.pending.ends_with('\n') {
+                return Ok(records);
+            }
+            let line = std::mem::take(&mut self.pending);
+            self.lines += 1;
+            let Some(mut row) = self.row(&line) else {
+                continue;
+            };
+            match &self.headers {
+                None => self.headers = Some(self.dialect.rename(&row)),
+                Some(headers) => {
+                    let signature = self.dialect.verify(headers, &row);
+                    if self.dialect.localize(headers, &mut row).is_err() {
+                        continue;
+                    }
+                    if let Ok(mut record) = row.deserialize::<TransactionRecord>(Some(headers)) {
+                        record.line = Some(self.lines);
+                        record.signature_error = signature.err();
+                        records.push(record);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Position after the last complete line read, where `seek` continues from.
+    pub fn offset(&self) -> InputOffset {
+        InputOffset {
+            byte: self.read - self.pending.len() as u64,
+            line: self.lines,
+            record: self.lines - u64::from(self.dialect.has_headers),
+        }
+    }
+
+    /// Continues after `offset`, returned by `offset` for the same file, the header row being
+    /// read first.
+    pub fn seek(&mut self, offset: InputOffset) -> anyhow::Result<()> {
+        let mut line = String::new();
+        while self.headers.is_none() {
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 {
+                anyhow::bail!("input has no header row to continue after");
+            }
+            self.headers = self.row(&line).map(|row| self.dialect.rename(&row));
+        }
+        self.reader.seek(SeekFrom::Start(offset.byte))?;
+        self.read = offset.byte;
+        self.lines = offset.line;
+        self.pending.clear();
+        Ok(())
+    }
+
+    fn row(&self, line: &str) -> Option<csv::ByteRecord> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .trim(csv::Trim::All)
+            .delimiter(self.dialect.delimiter)
+            .quote(self.dialect.quote)
+            .quoting(self.dialect.quoting)
+            .from_reader(line.as_bytes());
+        let mut row = csv::ByteRecord::new();
+        rdr.read_byte_record(&mut row)
+            .unwrap_or(false)
+            .then_some(row)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AccountRecord {
+    pub client: Client,
+    pub total: Amount,
+}
+
+/// A row of an account report, as written by `accounts_info_as_csv`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct ReportRecord {
+    pub client: Client,
+    pub available: Amount,
+    pub held: Amount,
+    pub locked: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BalanceRecord {
+    pub client: Client,
+    pub balance: Amount,
+}
+
+/// A row of a corrections file, see `--corrections`: the new amount of deposit or withdrawal
+/// `tx`, or `void` to cancel it.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct CorrectionRecord {
+    pub tx: TransactionId,
+    /// `None` voids the transaction.
+    #[serde(deserialize_with = "corrected_amount")]
+    pub amount: Option<Amount>,
+}
+
+fn corrected_amount<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Amount>, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    if text.eq_ignore_ascii_case("void") {
+        return Ok(None);
+    }
+    text.parse().map(Some).map_err(|err| {
+        de::Error::custom(format!(
+            "amount {:?} is neither a number nor void: {}",
+            text, err
+        ))
+    })
+}
+
+impl CorrectionRecord {
+    /// The correction of `client`'s transaction, with the corrected amount checked against
+    /// `precision` as input amounts are.
+    pub fn into_transaction(
+        self,
+        client: Client,
+        precision: &PrecisionPolicy,
+    ) -> Result<Transaction, TransactionValidationError> {
+        let amount = self
+            .amount
+            .map(|amount| precision.apply(client, self.tx, amount))
+            .transpose()?;
+        Transaction::new_correction(client, self.tx, amount)
+    }
+}
+
+/// A row of a recurrences file, see `--recurring`: `count` deposits of `amount`, or
+/// withdrawals when it is negative, `interval` seconds apart from `start` on.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct RecurrenceRecord {
+    pub client: Client,
+    pub tx: TransactionId,
+    pub amount: Amount,
+    pub interval: u64,
+    pub count: u32,
+    /// Seconds since the Unix epoch, the engine clock's time when empty.
+    #[serde(default)]
+    pub start: Option<u64>,
+}
+
+impl RecurrenceRecord {
+    /// The recurrence, with the amount checked against `precision` as input amounts are.
+    pub fn into_transaction(
+        self,
+        precision: &PrecisionPolicy,
+    ) -> Result<Transaction, TransactionValidationError> {
+        let amount = precision.apply(self.client, self.tx, self.amount)?;
+        Transaction::new_recurring(
+            self.client,
+            self.tx,
+            amount,
+            self.interval,
+            self.count,
+            self.start,
+        )
+    }
+}
+
+pub(crate) fn read_records<T: DeserializeOwned, P: AsRef<Path>>(
+    input_path: P,
+) -> anyhow::Result<Vec<T>> {
     let file = File::open(input_path)?;
     let mut rdr = csv::ReaderBuilder::new()
         .trim(csv::Trim::All)
@@ -62,10 +1022,449 @@ pub fn parse_from_file(input_path: PathBuf) -> anyhow::Result<Vec<TransactionRec
 
     let mut records = vec![];
     for result in rdr.deserialize() {
-        let result: Result<TransactionRecord, _> = result;
-        if let Ok(record) = result {
-            records.push(record);
-        };
+        records.push(result?);
     }
     Ok(records)
 }
+
+pub fn parse_accounts_from_file<P: AsRef<Path>>(
+    input_path: P,
+) -> anyhow::Result<Vec<AccountRecord>> {
+    read_records(input_path)
+}
+
+pub fn parse_report_from_file<P: AsRef<Path>>(input_path: P) -> anyhow::Result<Vec<ReportRecord>> {
+    read_records(input_path)
+}
+
+pub fn parse_balances_from_file<P: AsRef<Path>>(
+    input_path: P,
+) -> anyhow::Result<Vec<BalanceRecord>> {
+    read_records(input_path)
+}
+
+pub fn parse_corrections_from_file<P: AsRef<Path>>(
+    input_path: P,
+) -> anyhow::Result<Vec<CorrectionRecord>> {
+    read_records(input_path)
+}
+
+pub fn parse_recurrences_from_file<P: AsRef<Path>>(
+    input_path: P,
+) -> anyhow::Result<Vec<RecurrenceRecord>> {
+    read_records(input_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::amount;
+    use crate::transactions::TransactionKind;
+
+    fn deposit(amount: Amount) -> TransactionRecord {
+        TransactionRecord {
+            kind: TransactionRecordKind::Deposit,
+            client: 1,
+            tx: 1,
+            amount: Some(amount),
+            memo: None,
+            counterparty: None,
+            batch_id: None,
+            tenant: None,
+            reason: None,
+            escrow: None,
+            execute_at: None,
+            interval: None,
+            count: None,
+            line: None,
+            signature_error: None,
+        }
+    }
+
+    #[test]
+    fn amounts_with_too_many_decimal_places_are_rejected_by_default() {
+        assert!(Transaction::try_from(deposit(amount!(1.2345))).is_ok());
+        assert!(Transaction::try_from(deposit(amount!(1.234500))).is_ok());
+        // fixed point amounts can't hold more than 4 decimal places in the first place
+        #[cfg(not(feature = "fixed-amount"))]
+        assert!(matches!(
+            Transaction::try_from(deposit(amount!(1.23456))),
+            Err(TransactionValidationError::ExcessivePrecision { tx: 1, .. })
+        ));
+
+        let policy = PrecisionPolicy {
+            max_decimal_places: 3,
+            ..PrecisionPolicy::default()
+        };
+        assert!(matches!(
+            deposit(amount!(1.2345)).into_transaction(&policy),
+            Err(TransactionValidationError::ExcessivePrecision { tx: 1, .. })
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "native")]
+    fn mapped_records_match_buffered_ones() {
+        let path = std::env::temp_dir().join(format!("payments-mmap-{}.csv", std::process::id()));
+        std::fs::write(
+            &path,
+            "type, client, tx, amount\ndeposit, 1, 1, 1.5\nbogus, 1, 2, 1.0\n\
+             withdrawal, 2, 3\ndispute, 1, 1,\n",
+        )
+        .unwrap();
+        let summary = |records: Vec<TransactionRecord>| -> Vec<_> {
+            records
+                .into_iter()
+                .map(|record| (record.client, record.tx, record.amount, record.line))
+                .collect()
+        };
+        let buffered = summary(records_from_file(path.clone()).unwrap().collect());
+        let mapped = summary(
+            records_from_mmap(path.clone(), &CsvDialect::default())
+                .unwrap()
+                .collect(),
+        );
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(buffered.len(), 2);
+        assert_eq!(buffered[1].3, Some(5));
+        assert_eq!(mapped, buffered);
+    }
+
+    #[test]
+    fn reading_resumes_after_the_recorded_offset() {
+        let path = std::env::temp_dir().join(format!("payments-offset-{}.csv", std::process::id()));
+        std::fs::write(
+            &path,
+            "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,2.0\ndeposit,1,3,3.0\n",
+        )
+        .unwrap();
+        let (_, offset) =
+            records_from_offset(path.clone(), InputOffset::default(), &CsvDialect::default())
+                .unwrap()
+                .nth(1)
+                .unwrap();
+        let rest: Vec<_> = records_from_offset(path.clone(), offset, &CsvDialect::default())
+            .unwrap()
+            .map(|(record, _)| record.tx)
+            .collect();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(rest, vec![3]);
+    }
+
+    #[test]
+    fn tail_reader_waits_for_complete_lines() {
+        use std::io::Write;
+
+        let path = std::env::temp_dir().join(format!("payments-tail-{}.csv", std::process::id()));
+        let mut file = File::create(&path).unwrap();
+        write!(
+            file,
+            "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,2"
+        )
+        .unwrap();
+        let mut tail = TailReader::open(&path, &CsvDialect::default()).unwrap();
+        let first: Vec<_> = tail.poll().unwrap().iter().map(|r| r.tx).collect();
+        assert_eq!(first, vec![1]);
+        assert!(tail.poll().unwrap().is_empty());
+        let offset = tail.offset();
+        assert_eq!((offset.line, offset.record), (2, 1));
+
+        write!(file, ".5\ndeposit,1,3,3.0\n").unwrap();
+        let next: Vec<_> = tail
+            .poll()
+            .unwrap()
+            .iter()
+            .map(|r| (r.tx, r.amount))
+            .collect();
+        assert_eq!(next, vec![(2, Some(amount!(2.5))), (3, Some(amount!(3.0)))]);
+
+        // a reader continuing after the first record reads the header again
+        let mut resumed = TailReader::open(&path, &CsvDialect::default()).unwrap();
+        resumed.seek(offset).unwrap();
+        let rest: Vec<_> = resumed.poll().unwrap().iter().map(|r| r.tx).collect();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(rest, vec![2, 3]);
+        assert_eq!(resumed.offset(), tail.offset());
+    }
+
+    #[test]
+    fn files_are_ordered_by_embedded_date() {
+        let dir = std::env::temp_dir().join(format!("payments-dir-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in [
+            "b-2021-03-01.csv",
+            "a-20210302.csv",
+            "undated.csv",
+            "c20210228.csv",
+            "notes.txt",
+        ] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        let names = |order| -> Vec<String> {
+            discover_files(&dir, order)
+                .unwrap()
+                .iter()
+                .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+                .collect()
+        };
+        let by_date = names(FileOrder::Date);
+        let by_name = names(FileOrder::Name);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            by_date,
+            vec![
+                "c20210228.csv",
+                "b-2021-03-01.csv",
+                "a-20210302.csv",
+                "undated.csv"
+            ]
+        );
+        assert_eq!(
+            by_name,
+            vec![
+                "a-20210302.csv",
+                "b-2021-03-01.csv",
+                "c20210228.csv",
+                "undated.csv"
+            ]
+        );
+    }
+
+    #[test]
+    fn amounts_with_too_many_decimal_places_can_be_truncated() {
+        let policy = PrecisionPolicy {
+            max_decimal_places: 2,
+            mode: PrecisionMode::Truncate,
+        };
+        let transaction = deposit(amount!(1.239)).into_transaction(&policy).unwrap();
+        assert_eq!(transaction.amount(), Some(amount!(1.23)));
+    }
+
+    #[test]
+    fn invalid_records_are_reported_with_their_line() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,1.0\n\
+                     deposit,1,2\n\
+                     withdrawal,1,3,\n\
+                     refund,1,4,1.0\n\
+                     deposit,1,5,1.00001\n\
+                     dispute,1,1,\n";
+        let invalid = check_records(
+            input.as_bytes(),
+            &PrecisionPolicy::default(),
+            &CsvDialect::default(),
+        )
+        .unwrap();
+        let lines: Vec<u64> = invalid.iter().map(|invalid| invalid.line).collect();
+        assert_eq!(lines, vec![3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn dialects_without_headers_are_read_by_position() {
+        let input = "1;10;deposit;'2.5'\r\n\
+                     1;11;withdrawal;1\r\n\
+                     1;10;dispute\r\n";
+        let dialect = CsvDialect {
+            delimiter: b';',
+            has_headers: false,
+            columns: vec!["client".into(), "tx".into(), "type".into(), "amount".into()],
+            quote: b'\'',
+            quoting: true,
+            renames: HashMap::new(),
+            amounts: AmountFormat::Plain,
+            signature_key: None,
+        };
+        let records: Vec<TransactionRecord> =
+            records_from_reader(input.as_bytes(), &dialect).collect();
+        assert_eq!(
+            records
+                .iter()
+                .map(|record| (record.tx(), record.line()))
+                .collect::<Vec<_>>(),
+            vec![(10, Some(1)), (11, Some(2)), (10, Some(3))]
+        );
+        assert_eq!(records[0].amount, Some(amount!(2.5)));
+        assert!(
+            check_records(input.as_bytes(), &PrecisionPolicy::default(), &dialect)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn columns_are_renamed_before_deserialization() {
+        let input = "txn_type,customer_id,txn_id,value\n\
+                     deposit,3,7,1.5\n";
+        let dialect = CsvDialect {
+            renames: [
+                ("txn_type", "type"),
+                ("customer_id", "client"),
+                ("txn_id", "tx"),
+                ("value", "amount"),
+            ]
+            .into_iter()
+            .map(|(from, to)| (from.to_string(), to.to_string()))
+            .collect(),
+            ..CsvDialect::default()
+        };
+        let records: Vec<TransactionRecord> =
+            records_from_reader(input.as_bytes(), &dialect).collect();
+        assert_eq!(records.len(), 1);
+        assert_eq!((records[0].client(), records[0].tx()), (3, 7));
+        assert_eq!(
+            records_from_reader(input.as_bytes(), &CsvDialect::default()).count(),
+            0
+        );
+    }
+
+    #[test]
+    fn rows_are_verified_against_their_signature() {
+        let key = SignatureKey::new(b"secret");
+        let signed = key.sign(["deposit", "1", "1", "2.5"].map(str::as_bytes));
+        let input = format!(
+            "type,client,tx,amount,signature\n\
+             deposit,1,1,2.5,{signed}\n\
+             deposit,1,1,25,{signed}\n\
+             deposit,1,2,1.0,\n"
+        );
+        let dialect = CsvDialect {
+            signature_key: Some(key),
+            ..CsvDialect::default()
+        };
+        let records: Vec<TransactionRecord> =
+            records_from_reader(input.as_bytes(), &dialect).collect();
+        assert_eq!(
+            records
+                .iter()
+                .map(TransactionRecord::signature_error)
+                .collect::<Vec<_>>(),
+            vec![
+                None,
+                Some(SignatureError::Mismatch),
+                Some(SignatureError::Missing)
+            ]
+        );
+        let invalid =
+            check_records(input.as_bytes(), &PrecisionPolicy::default(), &dialect).unwrap();
+        assert_eq!(
+            invalid
+                .iter()
+                .map(|record| (record.line, record.reason.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    3,
+                    "invalid signature: the signature doesn't match the record"
+                ),
+                (4, "invalid signature: the record is not signed"),
+            ]
+        );
+        // without a key the column is ignored
+        assert!(
+            records_from_reader(input.as_bytes(), &CsvDialect::default())
+                .all(|record| record.signature_error().is_none())
+        );
+    }
+
+    #[test]
+    fn memos_are_carried_to_stored_transactions() {
+        let input = "type,client,tx,amount,memo\n\
+                     deposit,1,1,2.0,INV-1001\n\
+                     deposit,1,2,1.0,\n\
+                     dispute,1,1,,\"chargeback, case 9\"\n";
+        let transactions: Vec<Transaction> =
+            records_from_reader(input.as_bytes(), &CsvDialect::default())
+                .map(|record| Transaction::try_from(record).unwrap())
+                .collect();
+        let memos: Vec<Option<&str>> = transactions.iter().map(Transaction::memo).collect();
+        assert_eq!(memos, vec![Some("INV-1001"), None, None]);
+
+        let input = "type,client,tx,amount,reference\n\
+                     withdrawal,1,3,1.0,PO-7\n";
+        let records: Vec<TransactionRecord> =
+            records_from_reader(input.as_bytes(), &CsvDialect::default()).collect();
+        assert_eq!(records[0].memo(), Some("PO-7"));
+    }
+
+    #[test]
+    fn batch_ids_are_read_from_either_column() {
+        let input = "type,client,tx,amount,batch_id\n\
+                     withdrawal,1,1,5.0,payout-1\n\
+                     deposit,2,2,5.0,payout-1\n\
+                     deposit,3,3,1.0,\n";
+        let records: Vec<TransactionRecord> =
+            records_from_reader(input.as_bytes(), &CsvDialect::default()).collect();
+        let batches: Vec<Option<&str>> = records.iter().map(TransactionRecord::batch_id).collect();
+        assert_eq!(batches, vec![Some("payout-1"), Some("payout-1"), None]);
+
+        let input = "type,client,tx,amount,batch\n\
+                     deposit,1,4,1.0,fees\n";
+        let records: Vec<TransactionRecord> =
+            records_from_reader(input.as_bytes(), &CsvDialect::default()).collect();
+        assert_eq!(records[0].batch_id(), Some("fees"));
+    }
+
+    #[test]
+    fn execute_at_schedules_the_record_and_moves_the_clock() {
+        let input = "type,client,tx,amount,execute_at\n\
+                     deposit,1,1,5.0,1700000000\n\
+                     advance_time,0,2,,1700000060\n\
+                     advance_time,0,3,,\n";
+        let transactions: Vec<Result<Transaction, TransactionValidationError>> =
+            records_from_reader(input.as_bytes(), &CsvDialect::default())
+                .map(|record| record.into_transaction(&PrecisionPolicy::default()))
+                .collect();
+        assert!(matches!(
+            &transactions[0],
+            Ok(Transaction::Scheduled {
+                execute_at: 1700000000,
+                transaction,
+            }) if transaction.kind() == TransactionKind::Deposit
+        ));
+        assert!(matches!(
+            transactions[1],
+            Ok(Transaction::AdvanceTime { to: 1700000060, .. })
+        ));
+        assert!(matches!(
+            transactions[2],
+            Err(TransactionValidationError::MissingTime { tx: 3, .. })
+        ));
+    }
+
+    #[test]
+    fn corrections_set_an_amount_or_void() {
+        let path =
+            std::env::temp_dir().join(format!("payments-corrections-{}.csv", std::process::id()));
+        std::fs::write(&path, "tx,amount\n1, 2.25\n2,VOID\n").unwrap();
+        let corrections = parse_corrections_from_file(&path).unwrap();
+        assert_eq!(
+            corrections,
+            [
+                CorrectionRecord {
+                    tx: 1,
+                    amount: Some(amount!(2.25)),
+                },
+                CorrectionRecord {
+                    tx: 2,
+                    amount: None
+                },
+            ]
+        );
+        let precision = PrecisionPolicy {
+            max_decimal_places: 1,
+            ..PrecisionPolicy::default()
+        };
+        assert!(matches!(
+            corrections[0].into_transaction(7, &precision),
+            Err(TransactionValidationError::ExcessivePrecision {
+                client: 7,
+                tx: 1,
+                ..
+            })
+        ));
+
+        std::fs::write(&path, "tx,amount\n1,\n").unwrap();
+        assert!(parse_corrections_from_file(&path).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
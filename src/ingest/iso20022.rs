@@ -0,0 +1,269 @@
+//! ISO 20022 XML input, as delivered by banking partners instead of CSV.
+//!
+//! - pain.001 (customer credit transfer initiation): every `CdtTrfTxInf` is a withdrawal from
+//!   the debtor account of its `PmtInf`, identified by its `EndToEndId`.
+//! - camt.053 (bank to customer statement): every `Ntry` of a `Stmt` is a deposit (`CRDT`) or a
+//!   withdrawal (`DBIT`) of the statement account, identified by its `NtryRef`. Entries with
+//!   `RvslInd` set are reversals of the entry with the same `NtryRef`.
+//!
+//! Account identifications (`Othr/Id` or `IBAN`) are mapped to clients through an `AccountMap`,
+//! and references must be transaction ids since the engine only knows numeric ids. Namespaces
+//! and message versions are ignored.
+
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use std::io::Read;
+
+use super::{statement_ids, AccountMap, InvalidRecord, TransactionRecord, TransactionRecordKind};
+use crate::transactions::Amount;
+
+/// Fields of a transfer or statement entry, as found in the document.
+#[derive(Debug, Default)]
+struct Entry {
+    line: u64,
+    account: Option<String>,
+    reference: Option<String>,
+    amount: Option<String>,
+    /// `CdtDbtInd`; transfers are always debits.
+    indicator: Option<String>,
+    reversal: bool,
+}
+
+impl Entry {
+    fn into_record(self, accounts: &AccountMap) -> Result<TransactionRecord, InvalidRecord> {
+        let line = self.line;
+        let invalid = |reason: String| InvalidRecord { line, reason };
+        let (client, tx) = statement_ids(
+            accounts,
+            line,
+            self.account.as_deref(),
+            self.reference.as_deref(),
+        )?;
+        let kind = match (self.reversal, self.indicator.as_deref()) {
+            (true, _) => TransactionRecordKind::Reversal,
+            (false, Some("CRDT")) => TransactionRecordKind::Deposit,
+            (false, Some("DBIT")) => TransactionRecordKind::Withdrawal,
+            (false, indicator) => {
+                return Err(invalid(format!(
+                    "unknown credit/debit indicator {}",
+                    indicator.unwrap_or("(none)")
+                )))
+            }
+        };
+        let amount = match (&kind, self.amount) {
+            (TransactionRecordKind::Reversal, _) | (_, None) => None,
+            (_, Some(amount)) => Some(
+                amount
+                    .parse::<Amount>()
+                    .map_err(|_| invalid(format!("invalid amount {}", amount)))?,
+            ),
+        };
+        Ok(TransactionRecord {
+            kind,
+            client,
+            tx,
+            amount,
+            memo: None,
+            counterparty: None,
+            batch_id: None,
+            tenant: None,
+            reason: None,
+            escrow: None,
+            execute_at: None,
+            interval: None,
+            count: None,
+            line: Some(line),
+            signature_error: None,
+        })
+    }
+}
+
+/// Every transfer and statement entry of a pain.001 or camt.053 document, see
+/// `super::read_statement`.
+pub fn read_entries<R: Read>(
+    mut input: R,
+    accounts: &AccountMap,
+) -> anyhow::Result<Vec<Result<TransactionRecord, InvalidRecord>>> {
+    let mut document = String::new();
+    input.read_to_string(&mut document)?;
+    let mut reader = Reader::from_str(&document);
+    reader.config_mut().trim_text(true);
+
+    let mut path: Vec<String> = vec![];
+    let mut account: Option<String> = None;
+    let mut entry: Option<Entry> = None;
+    let mut entries = vec![];
+    let (mut line, mut counted) = (1, 0);
+    loop {
+        let event = reader.read_event()?;
+        // the event ends on the line it started on, except for multi-line text
+        let position = reader.buffer_position() as usize;
+        line += document.as_bytes()[counted..position]
+            .iter()
+            .filter(|byte| **byte == b'\n')
+            .count() as u64;
+        counted = position;
+
+        match event {
+            Event::Start(element) => {
+                let name = element.local_name().as_ref().to_string();
+                match name.as_str() {
+                    "PmtInf" | "Stmt" => account = None,
+                    "CdtTrfTxInf" => {
+                        entry = Some(Entry {
+                            line,
+                            account: account.clone(),
+                            indicator: Some("DBIT".to_string()),
+                            ..Entry::default()
+                        })
+                    }
+                    "Ntry" => {
+                        entry = Some(Entry {
+                            line,
+                            account: account.clone(),
+                            ..Entry::default()
+                        })
+                    }
+                    _ => {}
+                }
+                path.push(name);
+            }
+            Event::End(_) => {
+                if let Some(name) = path.pop() {
+                    if name == "CdtTrfTxInf" || name == "Ntry" {
+                        if let Some(entry) = entry.take() {
+                            entries.push(entry.into_record(accounts));
+                        }
+                    }
+                }
+            }
+            Event::Text(text) => {
+                let text = text.xml10_content().into_owned();
+                let path: Vec<&str> = path.iter().map(String::as_str).collect();
+                match (path.as_slice(), entry.as_mut()) {
+                    ([.., "DbtrAcct", "Id", "Othr", "Id"], _)
+                    | ([.., "DbtrAcct", "Id", "IBAN"], _)
+                    | ([.., "Stmt", "Acct", "Id", "Othr", "Id"], _)
+                    | ([.., "Stmt", "Acct", "Id", "IBAN"], _) => account = Some(text),
+                    ([.., "PmtId", "EndToEndId"], Some(entry))
+                    | ([.., "Ntry", "NtryRef"], Some(entry)) => entry.reference = Some(text),
+                    ([.., "CdtTrfTxInf", "Amt", "InstdAmt"], Some(entry))
+                    | ([.., "Ntry", "Amt"], Some(entry)) => entry.amount = Some(text),
+                    ([.., "Ntry", "CdtDbtInd"], Some(entry)) => entry.indicator = Some(text),
+                    ([.., "Ntry", "RvslInd"], Some(entry)) => entry.reversal = text == "true",
+                    _ => {}
+                }
+            }
+            Event::Eof => match path.last() {
+                Some(open) => anyhow::bail!("document ends inside <{}>", open),
+                None => return Ok(entries),
+            },
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::amount;
+    use crate::transactions::Transaction;
+
+    const PAIN_001: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<Document xmlns="urn:iso:std:iso:20022:tech:xsd:pain.001.001.09">
+  <CstmrCdtTrfInitn>
+    <GrpHdr><MsgId>MSG-1</MsgId></GrpHdr>
+    <PmtInf>
+      <PmtInfId>BATCH-1</PmtInfId>
+      <DbtrAcct><Id><Othr><Id>7</Id></Othr></Id></DbtrAcct>
+      <CdtTrfTxInf>
+        <PmtId><EndToEndId>101</EndToEndId></PmtId>
+        <Amt><InstdAmt Ccy="EUR">12.50</InstdAmt></Amt>
+      </CdtTrfTxInf>
+      <CdtTrfTxInf>
+        <PmtId><EndToEndId>INV-2024</EndToEndId></PmtId>
+        <Amt><InstdAmt Ccy="EUR">1.00</InstdAmt></Amt>
+      </CdtTrfTxInf>
+    </PmtInf>
+  </CstmrCdtTrfInitn>
+</Document>
+"#;
+
+    const CAMT_053: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<Document xmlns="urn:iso:std:iso:20022:tech:xsd:camt.053.001.08">
+  <BkToCstmrStmt>
+    <Stmt>
+      <Acct><Id><Othr><Id>3</Id></Othr></Id></Acct>
+      <Ntry>
+        <NtryRef>1</NtryRef>
+        <Amt Ccy="EUR">100.25</Amt>
+        <CdtDbtInd>CRDT</CdtDbtInd>
+      </Ntry>
+      <Ntry>
+        <NtryRef>2</NtryRef>
+        <Amt Ccy="EUR">40</Amt>
+        <CdtDbtInd>DBIT</CdtDbtInd>
+      </Ntry>
+      <Ntry>
+        <NtryRef>2</NtryRef>
+        <Amt Ccy="EUR">40</Amt>
+        <CdtDbtInd>CRDT</CdtDbtInd>
+        <RvslInd>true</RvslInd>
+      </Ntry>
+    </Stmt>
+  </BkToCstmrStmt>
+</Document>
+"#;
+
+    fn transactions(document: &str) -> Vec<Transaction> {
+        read_entries(document.as_bytes(), &AccountMap::default())
+            .unwrap()
+            .into_iter()
+            .flatten()
+            .map(|record| Transaction::try_from(record).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn credit_transfers_are_withdrawals() {
+        let entries = read_entries(PAIN_001.as_bytes(), &AccountMap::default()).unwrap();
+        assert_eq!(entries.len(), 2);
+        let record = entries[0].as_ref().unwrap();
+        assert_eq!(
+            (record.client(), record.tx(), record.line()),
+            (7, 101, Some(8))
+        );
+        assert!(matches!(
+            transactions(PAIN_001).as_slice(),
+            [Transaction::Withdrawal { amount, .. }] if *amount == amount!(12.50)
+        ));
+        assert_eq!(
+            entries[1].as_ref().unwrap_err(),
+            &InvalidRecord {
+                line: 12,
+                reason: "reference INV-2024 is not a transaction id".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn statement_entries_are_deposits_withdrawals_and_reversals() {
+        assert!(matches!(
+            transactions(CAMT_053).as_slice(),
+            [
+                Transaction::Deposit {
+                    client: 3,
+                    tx: 1,
+                    ..
+                },
+                Transaction::Withdrawal {
+                    client: 3,
+                    tx: 2,
+                    ..
+                },
+                Transaction::Reversal { client: 3, tx: 2 },
+            ]
+        ));
+        assert!(read_entries("<Document><Stmt>".as_bytes(), &AccountMap::default()).is_err());
+    }
+}
@@ -0,0 +1,197 @@
+//! Amounts written for humans rather than machines: `1,234.56`, `1.234,56`, `€ 12,50` or
+//! `-12.50 USD`. They are rewritten to the plain `-1234.56` form the record parser expects.
+
+use serde::Deserialize;
+
+/// How amounts in CSV inputs are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AmountFormat {
+    /// `1234.56`, read as is.
+    #[default]
+    Plain,
+    /// Decimal point, optional `,` thousands separators: `1,234.56`.
+    Point,
+    /// Decimal comma, optional `.` thousands separators: `1.234,56`.
+    Comma,
+    /// Either of the above, guessed per amount. Amounts with a single separator followed by
+    /// exactly three digits, such as `1,234`, are ambiguous and rejected.
+    Auto,
+}
+
+impl std::str::FromStr for AmountFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(AmountFormat::Plain),
+            "point" => Ok(AmountFormat::Point),
+            "comma" => Ok(AmountFormat::Comma),
+            "auto" => Ok(AmountFormat::Auto),
+            _ => Err(format!("unknown amount format: {}", s)),
+        }
+    }
+}
+
+/// Characters grouping digits besides the thousands separator of the format.
+fn is_group_space(c: char) -> bool {
+    matches!(c, ' ' | '\'' | '\u{a0}' | '\u{202f}')
+}
+
+fn is_numeric(c: char) -> bool {
+    c.is_ascii_digit() || c == '.' || c == ',' || c == '-' || c == '+'
+}
+
+impl AmountFormat {
+    /// `raw` in plain notation, without currency symbols or codes around it.
+    pub fn normalize(self, raw: &str) -> Result<String, String> {
+        if self == AmountFormat::Plain {
+            return Ok(raw.to_string());
+        }
+        let invalid = |reason: &str| format!("invalid amount {}: {}", raw, reason);
+        let mut negative = false;
+        let mut text = raw.trim();
+        // the sign may come before or after the currency symbol
+        for _ in 0..2 {
+            if let Some(rest) = text.strip_prefix('-') {
+                negative = !negative;
+                text = rest;
+            } else if let Some(rest) = text.strip_prefix('+') {
+                text = rest;
+            }
+            text = text
+                .trim_start_matches(|c: char| !is_numeric(c))
+                .trim_end_matches(|c: char| !c.is_ascii_digit() && c != '.' && c != ',');
+        }
+        let digits: String = text.chars().filter(|c| !is_group_space(*c)).collect();
+        if digits.is_empty() {
+            return Err(invalid("no digits"));
+        }
+        if let Some(c) = digits
+            .chars()
+            .find(|c| !c.is_ascii_digit() && *c != '.' && *c != ',')
+        {
+            return Err(invalid(&format!("unexpected character {}", c)));
+        }
+
+        let decimal = match self {
+            AmountFormat::Point => Some('.'),
+            AmountFormat::Comma => Some(','),
+            _ => guess_decimal(&digits).map_err(|reason| invalid(&reason))?,
+        };
+        let (whole, fraction) = match decimal.and_then(|decimal| digits.rsplit_once(decimal)) {
+            Some((whole, fraction)) => (whole, Some(fraction)),
+            None => (digits.as_str(), None),
+        };
+        if let Some(fraction) = fraction {
+            if fraction.is_empty() || !fraction.chars().all(|c| c.is_ascii_digit()) {
+                return Err(invalid("misplaced separator in the decimal places"));
+            }
+        }
+        if decimal.is_some_and(|decimal| whole.contains(decimal)) {
+            return Err(invalid("more than one decimal separator"));
+        }
+        let groups: Vec<&str> = whole.split(['.', ',']).collect();
+        let grouped = groups.len() > 1;
+        if grouped
+            && (groups[0].is_empty()
+                || groups[0].len() > 3
+                || groups[1..].iter().any(|group| group.len() != 3))
+        {
+            return Err(invalid(
+                "thousands separators must split the digits in groups of 3",
+            ));
+        }
+
+        let mut plain = String::with_capacity(digits.len() + 1);
+        if negative {
+            plain.push('-');
+        }
+        plain.extend(groups);
+        if plain.trim_start_matches('-').is_empty() {
+            plain.push('0');
+        }
+        if let Some(fraction) = fraction {
+            plain.push('.');
+            plain.push_str(fraction);
+        }
+        Ok(plain)
+    }
+}
+
+/// The decimal separator of an `Auto` amount, `None` when it has no decimal places.
+fn guess_decimal(digits: &str) -> Result<Option<char>, String> {
+    let last = match digits.rfind(['.', ',']) {
+        Some(last) => last,
+        None => return Ok(None),
+    };
+    let separator = digits[last..].chars().next().unwrap_or('.');
+    let other = if separator == '.' { ',' } else { '.' };
+    if digits.contains(other) {
+        // both are used, the last one separates the decimal places
+        return Ok(Some(separator));
+    }
+    if digits.matches(separator).count() > 1 {
+        return Ok(None);
+    }
+    if digits.len() - last - 1 == 3 {
+        return Err(format!(
+            "{} may be a decimal or a thousands separator, set the amount format",
+            separator
+        ));
+    }
+    Ok(Some(separator))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn amounts_are_normalized_per_format() {
+        assert_eq!(
+            AmountFormat::Point.normalize("1,234.56"),
+            Ok("1234.56".into())
+        );
+        assert_eq!(
+            AmountFormat::Comma.normalize("1.234,56"),
+            Ok("1234.56".into())
+        );
+        assert_eq!(AmountFormat::Comma.normalize("€ 12,5"), Ok("12.5".into()));
+        assert_eq!(AmountFormat::Point.normalize("-$1,000"), Ok("-1000".into()));
+        assert_eq!(
+            AmountFormat::Point.normalize("$-3.00 USD"),
+            Ok("-3.00".into())
+        );
+        assert_eq!(
+            AmountFormat::Comma.normalize("1 234 567,8"),
+            Ok("1234567.8".into())
+        );
+        assert_eq!(
+            AmountFormat::Auto.normalize("1.234,56"),
+            Ok("1234.56".into())
+        );
+        assert_eq!(
+            AmountFormat::Auto.normalize("1,234,567"),
+            Ok("1234567".into())
+        );
+        assert_eq!(AmountFormat::Auto.normalize("12,5"), Ok("12.5".into()));
+        assert_eq!(AmountFormat::Auto.normalize(",5"), Ok("0.5".into()));
+    }
+
+    #[test]
+    fn unclear_amounts_are_rejected() {
+        assert_eq!(
+            AmountFormat::Auto.normalize("1,234"),
+            Err(
+                "invalid amount 1,234: , may be a decimal or a thousands separator, set the \
+                 amount format"
+                    .into()
+            )
+        );
+        assert!(AmountFormat::Point.normalize("1,23.5").is_err());
+        assert!(AmountFormat::Point.normalize("1.234.5").is_err());
+        assert!(AmountFormat::Comma.normalize("12x5").is_err());
+        assert!(AmountFormat::Auto.normalize("EUR").is_err());
+    }
+}
@@ -0,0 +1,150 @@
+//! OFX statements, as exported by banks and personal-finance tools.
+//!
+//! Both the SGML flavour (OFX 1.x, leaf elements without closing tags) and the XML one (OFX 2.x)
+//! are read. Every `STMTTRN` is a deposit, or a withdrawal when its `TRNAMT` is negative, of the
+//! account named by the closest preceding `ACCTID`, identified by its `FITID`. Everything else,
+//! `TRNTYPE` included, is ignored.
+
+use std::io::Read;
+
+use super::{signed_record, AccountMap, InvalidRecord, TransactionRecord};
+
+/// Fields of a `STMTTRN`.
+#[derive(Debug, Default)]
+struct Entry {
+    line: u64,
+    account: Option<String>,
+    fitid: Option<String>,
+    amount: Option<String>,
+}
+
+/// Every `STMTTRN` of an OFX document, see `super::read_statement`.
+pub fn read_entries<R: Read>(
+    mut input: R,
+    accounts: &AccountMap,
+) -> anyhow::Result<Vec<Result<TransactionRecord, InvalidRecord>>> {
+    let mut document = String::new();
+    input.read_to_string(&mut document)?;
+
+    let mut account: Option<String> = None;
+    let mut entry: Option<Entry> = None;
+    let mut entries = vec![];
+    let mut line = 1;
+    // the header of OFX 1.x files is not markup; tags start at the first `<`
+    let mut rest = match document.find('<') {
+        Some(start) => {
+            line += newlines(&document[..start]);
+            &document[start + 1..]
+        }
+        None => anyhow::bail!("no OFX markup found"),
+    };
+    loop {
+        let (tag, after) = rest
+            .split_once('>')
+            .ok_or_else(|| anyhow::anyhow!("unterminated tag on line {}", line))?;
+        let (text, next) = after.split_once('<').unwrap_or((after, ""));
+        let value = text.trim();
+        match tag.trim() {
+            "STMTTRN" => {
+                entry = Some(Entry {
+                    line,
+                    account: account.clone(),
+                    ..Entry::default()
+                })
+            }
+            "/STMTTRN" => {
+                if let Some(entry) = entry.take() {
+                    entries.push(signed_record(
+                        accounts,
+                        entry.line,
+                        entry.account.as_deref(),
+                        entry.fitid.as_deref(),
+                        entry.amount.as_deref(),
+                    ));
+                }
+            }
+            "ACCTID" => account = Some(value.to_string()),
+            "FITID" => {
+                if let Some(entry) = entry.as_mut() {
+                    entry.fitid = Some(value.to_string());
+                }
+            }
+            "TRNAMT" => {
+                if let Some(entry) = entry.as_mut() {
+                    entry.amount = Some(value.to_string());
+                }
+            }
+            _ => {}
+        }
+        line += newlines(tag) + newlines(text);
+        if next.is_empty() {
+            return Ok(entries);
+        }
+        rest = next;
+    }
+}
+
+fn newlines(text: &str) -> u64 {
+    text.bytes().filter(|byte| *byte == b'\n').count() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::amount;
+    use crate::transactions::Transaction;
+
+    const SGML: &str = "OFXHEADER:100
+DATA:OFXSGML
+VERSION:102
+
+<OFX>
+<BANKMSGSRSV1><STMTTRNRS><STMTRS>
+<BANKACCTFROM><BANKID>123<ACCTID>CHK-001<ACCTTYPE>CHECKING</BANKACCTFROM>
+<BANKTRANLIST>
+<STMTTRN><TRNTYPE>CREDIT<DTPOSTED>20240102<TRNAMT>250.00<FITID>1001<NAME>Salary</STMTTRN>
+<STMTTRN>
+<TRNTYPE>DEBIT
+<DTPOSTED>20240103
+<TRNAMT>-1,020.5
+<FITID>1002
+</STMTTRN>
+<STMTTRN><TRNTYPE>DEBIT<TRNAMT>-3.00<FITID>A-7</STMTTRN>
+</BANKTRANLIST>
+</STMTRS></STMTTRNRS></BANKMSGSRSV1>
+</OFX>
+";
+
+    #[test]
+    fn transactions_are_signed_by_amount() {
+        let accounts: AccountMap = [("CHK-001".to_string(), 4)].into_iter().collect();
+        let entries = read_entries(SGML.as_bytes(), &accounts).unwrap();
+        assert_eq!(entries.len(), 3);
+        let records: Vec<&TransactionRecord> = entries.iter().flatten().collect();
+        assert_eq!(
+            records
+                .iter()
+                .map(|record| record.line())
+                .collect::<Vec<_>>(),
+            vec![Some(9), Some(10)]
+        );
+        let transactions: Vec<Transaction> = entries
+            .into_iter()
+            .flatten()
+            .map(|record| Transaction::try_from(record).unwrap())
+            .collect();
+        assert!(matches!(
+            transactions.as_slice(),
+            [
+                Transaction::Deposit { client: 4, tx: 1001, amount: deposit, .. },
+                Transaction::Withdrawal { client: 4, tx: 1002, amount: withdrawal, .. },
+            ] if *deposit == amount!(250.00) && *withdrawal == amount!(1020.5)
+        ));
+
+        let entries = read_entries(SGML.as_bytes(), &AccountMap::default()).unwrap();
+        assert_eq!(
+            entries[0].as_ref().unwrap_err().reason,
+            "account CHK-001 is not mapped to a client"
+        );
+    }
+}
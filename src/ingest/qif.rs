@@ -0,0 +1,132 @@
+//! QIF exports of personal-finance tools.
+//!
+//! Records of `!Type:` sections are deposits, or withdrawals when their `T` (or `U`) amount is
+//! negative, identified by their `N` check or reference number. They belong to the account
+//! named by the `N` field of the preceding `!Account` block; QIF has no account id otherwise.
+//! Dates, payees, categories and splits are ignored.
+
+use std::io::{BufRead, BufReader, Read};
+
+use super::{signed_record, AccountMap, InvalidRecord, TransactionRecord};
+
+/// Fields of a record.
+#[derive(Debug, Default)]
+struct Entry {
+    line: u64,
+    number: Option<String>,
+    amount: Option<String>,
+}
+
+/// Every record of a QIF file, see `super::read_statement`.
+pub fn read_entries<R: Read>(
+    input: R,
+    accounts: &AccountMap,
+) -> anyhow::Result<Vec<Result<TransactionRecord, InvalidRecord>>> {
+    let mut account: Option<String> = None;
+    let mut in_account_block = false;
+    let mut entry: Option<Entry> = None;
+    let mut entries = vec![];
+    for (index, text) in BufReader::new(input).lines().enumerate() {
+        let text = text?;
+        let text = text.trim();
+        let line = index as u64 + 1;
+        if let Some(header) = text.strip_prefix('!') {
+            if header.eq_ignore_ascii_case("account") {
+                in_account_block = true;
+                account = None;
+            } else if header.to_ascii_lowercase().starts_with("type:") {
+                in_account_block = false;
+            }
+            continue;
+        }
+        let mut chars = text.chars();
+        let code = match chars.next() {
+            Some(code) => code,
+            None => continue,
+        };
+        let value = chars.as_str().trim();
+        if in_account_block {
+            match code {
+                'N' => account = Some(value.to_string()),
+                '^' => in_account_block = false,
+                _ => {}
+            }
+            continue;
+        }
+        let current = entry.get_or_insert_with(|| Entry {
+            line,
+            ..Entry::default()
+        });
+        match code {
+            'N' => current.number = Some(value.to_string()),
+            'T' | 'U' => current.amount = Some(value.to_string()),
+            '^' => {
+                if let Some(entry) = entry.take() {
+                    entries.push(signed_record(
+                        accounts,
+                        entry.line,
+                        account.as_deref(),
+                        entry.number.as_deref(),
+                        entry.amount.as_deref(),
+                    ));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::amount;
+    use crate::transactions::Transaction;
+
+    const QIF: &str = "!Account
+NEveryday Checking
+TBank
+^
+!Type:Bank
+D01/02/2024
+T1,250.00
+N501
+PEmployer
+^
+D01/03/2024
+T-20.5
+N502
+LGroceries
+^
+D01/04/2024
+T-3.00
+PNo reference
+^
+";
+
+    #[test]
+    fn records_belong_to_the_mapped_account() {
+        let accounts: AccountMap = [("Everyday Checking".to_string(), 9)].into_iter().collect();
+        let entries = read_entries(QIF.as_bytes(), &accounts).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(
+            entries[2].as_ref().unwrap_err(),
+            &InvalidRecord {
+                line: 16,
+                reason: "entry without reference".to_string()
+            }
+        );
+        let transactions: Vec<Transaction> = entries
+            .into_iter()
+            .flatten()
+            .map(|record| Transaction::try_from(record).unwrap())
+            .collect();
+        assert!(matches!(
+            transactions.as_slice(),
+            [
+                Transaction::Deposit { client: 9, tx: 501, amount: deposit, .. },
+                Transaction::Withdrawal { client: 9, tx: 502, amount: withdrawal, .. },
+            ] if *deposit == amount!(1250.00) && *withdrawal == amount!(20.5)
+        ));
+    }
+}
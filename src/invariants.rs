@@ -0,0 +1,258 @@
+//! Cross-checks of the account balances against the stored transactions that produced them.
+//!
+//! The funds held for a client must equal its accepted deposits minus its accepted withdrawals
+//! (captured holds and escrows paid out included) minus the amounts charged back. Reversed
+//! transactions count for nothing, and a reversed chargeback is not counted as charged back.
+//!
+//! Clients purged from a snapshot count with the funds and total their tombstone recorded, so a
+//! discrepancy doesn't disappear with the client, and pruned transactions with the funds the
+//! engine kept of them.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::snapshot::Tombstone;
+use crate::transactions::{saturating_add, Account, Amount, Client, DisputeState, Transaction};
+
+/// Figures the funds of a client, or of all clients, are expected to add up to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Funds {
+    #[serde(with = "crate::amount::text")]
+    pub deposits: Amount,
+    #[serde(with = "crate::amount::text")]
+    pub withdrawals: Amount,
+    #[serde(with = "crate::amount::text")]
+    pub charged_back: Amount,
+    /// Net of the manual adjustments, credits minus debits; missing from snapshots taken
+    /// before adjustments existed.
+    #[serde(default, with = "crate::amount::text")]
+    pub adjustments: Amount,
+}
+
+impl Funds {
+    pub fn expected(&self) -> Amount {
+        saturating_add(
+            saturating_add(
+                saturating_add(self.deposits, -self.withdrawals),
+                -self.charged_back,
+            ),
+            self.adjustments,
+        )
+    }
+
+    pub(crate) fn add(&mut self, other: &Funds) {
+        self.deposits = saturating_add(self.deposits, other.deposits);
+        self.withdrawals = saturating_add(self.withdrawals, other.withdrawals);
+        self.charged_back = saturating_add(self.charged_back, other.charged_back);
+        self.adjustments = saturating_add(self.adjustments, other.adjustments);
+    }
+
+    pub(crate) fn record(&mut self, transaction: &Transaction) {
+        let (amount, dispute, reversed, deposit) = match transaction {
+            Transaction::Deposit {
+                amount,
+                dispute,
+                reversed,
+                ..
+            } => (*amount, *dispute, *reversed, true),
+            Transaction::Withdrawal {
+                amount,
+                dispute,
+                reversed,
+                ..
+            } => (*amount, *dispute, *reversed, false),
+            Transaction::Adjustment { amount, .. } => {
+                self.adjustments = saturating_add(self.adjustments, *amount);
+                return;
+            }
+            // an escrow paid out left the platform like a withdrawal
+            Transaction::EscrowHold {
+                amount,
+                paid_out: true,
+                ..
+            } => (*amount, DisputeState::None, false, false),
+            _ => return,
+        };
+        if reversed {
+            return;
+        }
+        if deposit {
+            self.deposits = saturating_add(self.deposits, amount);
+        } else {
+            self.withdrawals = saturating_add(self.withdrawals, amount);
+        }
+        if dispute == DisputeState::ChargedBack {
+            self.charged_back = saturating_add(self.charged_back, amount);
+        }
+    }
+}
+
+/// Funds that don't add up, for one client or, when `client` is `None`, for all of them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FundsDiscrepancy {
+    pub client: Option<Client>,
+    pub funds: Funds,
+    /// Sum of the account totals.
+    pub actual: Amount,
+}
+
+impl FundsDiscrepancy {
+    pub fn difference(&self) -> Amount {
+        saturating_add(self.actual, -self.funds.expected())
+    }
+}
+
+impl std::fmt::Display for FundsDiscrepancy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.client {
+            Some(client) => write!(f, "client {}", client)?,
+            None => write!(f, "all clients")?,
+        }
+        write!(
+            f,
+            ": total {} but deposits {} - withdrawals {} - charged back {} + adjustments {} = {}, \
+             off by {}",
+            self.actual,
+            self.funds.deposits,
+            self.funds.withdrawals,
+            self.funds.charged_back,
+            self.funds.adjustments,
+            self.funds.expected(),
+            self.difference()
+        )
+    }
+}
+
+/// Clients whose funds don't match their transactions, `tombstones` and `pruned` funds, ordered
+/// by client, followed by the total over all clients when it doesn't match either. Withdrawals
+/// must all be stored, which is not the case in `StorageMode::Compact`.
+pub fn verify_funds<'a, A, T, P, R>(
+    accounts: A,
+    transactions: T,
+    tombstones: P,
+    pruned: R,
+) -> Vec<FundsDiscrepancy>
+where
+    A: IntoIterator<Item = &'a Account>,
+    T: IntoIterator<Item = &'a Transaction>,
+    P: IntoIterator<Item = &'a Tombstone>,
+    R: IntoIterator<Item = (Client, &'a Funds)>,
+{
+    let mut expected: BTreeMap<Client, Funds> = BTreeMap::new();
+    for transaction in transactions {
+        expected
+            .entry(transaction.client())
+            .or_default()
+            .record(transaction);
+    }
+    let mut actual: BTreeMap<Client, Amount> = BTreeMap::new();
+    for account in accounts {
+        actual.insert(account.client(), account.total());
+    }
+    for (client, funds) in pruned {
+        expected.entry(client).or_default().add(funds);
+    }
+    for tombstone in tombstones {
+        expected
+            .entry(tombstone.client)
+            .or_default()
+            .add(&tombstone.funds);
+        let total = actual.entry(tombstone.client).or_insert(Amount::ZERO);
+        *total = saturating_add(*total, tombstone.total);
+    }
+
+    let mut discrepancies = vec![];
+    let mut all = FundsDiscrepancy {
+        client: None,
+        funds: Funds::default(),
+        actual: Amount::ZERO,
+    };
+    let clients: BTreeSet<Client> = expected.keys().chain(actual.keys()).copied().collect();
+    for client in clients {
+        let discrepancy = FundsDiscrepancy {
+            client: Some(client),
+            funds: expected.get(&client).copied().unwrap_or_default(),
+            actual: actual.get(&client).copied().unwrap_or(Amount::ZERO),
+        };
+        all.funds.add(&discrepancy.funds);
+        all.actual = saturating_add(all.actual, discrepancy.actual);
+        if discrepancy.difference() != Amount::ZERO {
+            discrepancies.push(discrepancy);
+        }
+    }
+    if all.difference() != Amount::ZERO {
+        discrepancies.push(all);
+    }
+    discrepancies
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::amount;
+    use crate::snapshot::AccountState;
+    use crate::transactions::PaymentEngine;
+
+    #[test]
+    fn processed_funds_add_up() {
+        let mut engine = PaymentEngine::new();
+        let transactions = [
+            Transaction::new_deposit(1, 1, amount!(10.0)).unwrap(),
+            Transaction::new_deposit(1, 2, amount!(4.0)).unwrap(),
+            Transaction::new_withdrawal(1, 3, amount!(2.5)).unwrap(),
+            Transaction::new_reversal(1, 3),
+            Transaction::new_dispute(1, 2),
+            Transaction::new_chargeback(1, 2),
+            Transaction::new_deposit(2, 4, amount!(3.0)).unwrap(),
+            Transaction::new_hold(2, 5, amount!(1.0)).unwrap(),
+            Transaction::new_capture(2, 5),
+            Transaction::new_dispute(2, 4),
+            Transaction::new_chargeback(2, 4),
+            Transaction::new_chargeback_reversal(2, 4),
+        ];
+        for transaction in transactions {
+            engine.process_transaction(transaction).unwrap();
+        }
+        assert_eq!(
+            verify_funds(engine.accounts_iter(), engine.transactions_iter(), [], []),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn tampered_balances_are_reported() {
+        let mut engine = PaymentEngine::new();
+        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, amount!(10.0)).unwrap());
+        let _ = engine.process_transaction(Transaction::new_deposit(2, 2, amount!(1.0)).unwrap());
+        let mut state = engine.state();
+        for account in state
+            .accounts
+            .iter_mut()
+            .filter(|account| account.client == 1)
+        {
+            *account = AccountState {
+                available: amount!(12.0),
+                ..account.clone()
+            };
+        }
+        engine.restore(state);
+
+        let funds = Funds {
+            deposits: amount!(10.0),
+            ..Funds::default()
+        };
+        let discrepancies =
+            verify_funds(engine.accounts_iter(), engine.transactions_iter(), [], []);
+        assert_eq!(
+            discrepancies[0],
+            FundsDiscrepancy {
+                client: Some(1),
+                funds,
+                actual: amount!(12.0),
+            }
+        );
+        assert_eq!(discrepancies[1].client, None);
+        assert_eq!(discrepancies[1].difference(), amount!(2.0));
+        assert_eq!(discrepancies.len(), 2);
+    }
+}
@@ -0,0 +1,241 @@
+//! Double-entry bookkeeping behind the account balances.
+//!
+//! Every client has three sub-ledgers, `available`, `held` and `escrow`, and money enters and
+//! leaves the platform through a single suspense account. Each balance change is a journal entry
+//! moving an amount from the account it debits to the account it credits, so the entries always
+//! balance: client sub-ledgers are credit-normal (a credit raises the balance), the suspense
+//! account is debit-normal, and its balance equals the sum of all client totals.
+
+use serde::{Deserialize, Serialize};
+
+use crate::transactions::{
+    saturating_add, Account, Amount, Client, TransactionId, TransactionKind,
+    TransactionValidationError,
+};
+
+/// An account of the general ledger.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum LedgerAccount {
+    /// Funds a client can use.
+    Available(Client),
+    /// Funds of a client held by disputes and holds.
+    Held(Client),
+    /// Funds of a client set aside in its escrows.
+    Escrow(Client),
+    /// Counterpart of money entering and leaving the platform.
+    Suspense,
+}
+
+impl LedgerAccount {
+    /// Client owning the sub-ledger, `None` for platform accounts.
+    pub fn client(self) -> Option<Client> {
+        match self {
+            LedgerAccount::Available(client)
+            | LedgerAccount::Held(client)
+            | LedgerAccount::Escrow(client) => Some(client),
+            LedgerAccount::Suspense => None,
+        }
+    }
+}
+
+impl std::fmt::Display for LedgerAccount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LedgerAccount::Available(client) => write!(f, "client:{}:available", client),
+            LedgerAccount::Held(client) => write!(f, "client:{}:held", client),
+            LedgerAccount::Escrow(client) => write!(f, "client:{}:escrow", client),
+            LedgerAccount::Suspense => write!(f, "platform:suspense"),
+        }
+    }
+}
+
+/// A balanced movement of `amount` from `debit` to `credit`, caused by transaction `tx`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub tx: TransactionId,
+    /// Type of the record that caused the movement, e.g. the dispute of a deposit.
+    pub kind: TransactionKind,
+    pub debit: LedgerAccount,
+    pub credit: LedgerAccount,
+    #[serde(with = "crate::amount::text")]
+    pub amount: Amount,
+    /// Periods closed before the entry was posted, see `PaymentEngine::close_period`.
+    #[serde(default)]
+    pub period: u32,
+    /// Engine clock when the entry was posted, in seconds since the Unix epoch; the time a
+    /// scheduled transaction was due for its entries.
+    #[serde(default)]
+    pub time: u64,
+    /// Part of a deposit offsetting a negative available balance, see
+    /// `PaymentEngine::set_recovery`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub recovery: bool,
+}
+
+impl JournalEntry {
+    pub fn new(
+        tx: TransactionId,
+        kind: TransactionKind,
+        debit: LedgerAccount,
+        credit: LedgerAccount,
+        amount: Amount,
+    ) -> Self {
+        Self {
+            tx,
+            kind,
+            debit,
+            credit,
+            amount,
+            period: 0,
+            time: 0,
+            recovery: false,
+        }
+    }
+
+    /// The entry tagged as recovering a negative balance.
+    pub fn as_recovery(self) -> Self {
+        Self {
+            recovery: true,
+            ..self
+        }
+    }
+
+    /// Change of the balance of `account`, following the normal side of each account.
+    pub fn effect(&self, account: LedgerAccount) -> Amount {
+        // a debit raises the suspense account and lowers client sub-ledgers
+        let debited = match account {
+            LedgerAccount::Suspense => self.amount,
+            _ => -self.amount,
+        };
+        match (self.debit == account, self.credit == account) {
+            (true, false) => debited,
+            (false, true) => -debited,
+            _ => Amount::ZERO,
+        }
+    }
+}
+
+/// Journal of every posted entry and the balance of the suspense account. Client balances are
+/// kept by their `Account`, which only changes them when an entry is posted.
+#[derive(Debug, Clone, Default)]
+pub struct Ledger {
+    entries: Vec<JournalEntry>,
+    /// Whether posted entries are kept in `entries`.
+    journal: bool,
+    /// Saturates like the engine metrics instead of rejecting entries.
+    suspense: Amount,
+    /// Stamped on every posted entry.
+    period: u32,
+    time: u64,
+}
+
+impl Ledger {
+    /// Ledger keeping balances only, see `keep_journal`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts or stops keeping journal lines; balances are maintained either way. Kept lines
+    /// stay in memory for as long as the ledger, so only keep them when something reads them.
+    pub fn keep_journal(&mut self, journal: bool) {
+        self.journal = journal;
+    }
+
+    /// Applies `entry` to the sub-ledgers of `account` and the suspense account. Nothing
+    /// changes when a client balance would overflow.
+    pub fn post(
+        &mut self,
+        account: &mut Account,
+        entry: JournalEntry,
+    ) -> Result<(), TransactionValidationError> {
+        let entry = JournalEntry {
+            period: self.period,
+            time: self.time,
+            ..entry
+        };
+        account.apply(&entry)?;
+        self.suspense = saturating_add(self.suspense, entry.effect(LedgerAccount::Suspense));
+        if self.journal {
+            self.entries.push(entry);
+        }
+        Ok(())
+    }
+
+    /// Posted entries in the order they were applied.
+    pub fn entries(&self) -> &[JournalEntry] {
+        &self.entries
+    }
+
+    pub fn suspense(&self) -> Amount {
+        self.suspense
+    }
+
+    pub fn period(&self) -> u32 {
+        self.period
+    }
+
+    pub(crate) fn set_period(&mut self, period: u32) {
+        self.period = period;
+    }
+
+    /// Time stamped on the entries posted from now on.
+    pub(crate) fn time(&self) -> u64 {
+        self.time
+    }
+
+    pub(crate) fn set_time(&mut self, time: u64) {
+        self.time = time;
+    }
+
+    /// Rebuilds a ledger from the journal of a snapshot and the suspense balance it implies.
+    pub(crate) fn restore(&mut self, entries: Vec<JournalEntry>, suspense: Amount) {
+        self.entries = entries;
+        self.suspense = suspense;
+    }
+
+    /// Drops the entries posted after the first `entries` and resets the suspense balance to
+    /// what it was then.
+    pub(crate) fn truncate(&mut self, entries: usize, suspense: Amount) {
+        self.entries.truncate(entries);
+        self.suspense = suspense;
+    }
+
+    /// Takes over the entries and balance of a ledger holding other clients.
+    pub(crate) fn merge(&mut self, other: Ledger) {
+        self.entries.extend(other.entries);
+        self.suspense = saturating_add(self.suspense, other.suspense);
+        self.period = self.period.max(other.period);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::amount;
+
+    #[test]
+    fn entries_move_funds_between_their_accounts() {
+        let entry = JournalEntry::new(
+            1,
+            TransactionKind::Dispute,
+            LedgerAccount::Available(3),
+            LedgerAccount::Held(3),
+            amount!(2.5),
+        );
+        assert_eq!(entry.effect(LedgerAccount::Available(3)), amount!(-2.5));
+        assert_eq!(entry.effect(LedgerAccount::Held(3)), amount!(2.5));
+        assert_eq!(entry.effect(LedgerAccount::Suspense), Amount::ZERO);
+        assert_eq!(entry.effect(LedgerAccount::Held(4)), Amount::ZERO);
+
+        let deposit = JournalEntry::new(
+            2,
+            TransactionKind::Deposit,
+            LedgerAccount::Suspense,
+            LedgerAccount::Available(3),
+            amount!(1),
+        );
+        assert_eq!(deposit.effect(LedgerAccount::Suspense), amount!(1));
+        assert_eq!(deposit.effect(LedgerAccount::Available(3)), amount!(1));
+        assert_eq!(LedgerAccount::Held(3).to_string(), "client:3:held");
+    }
+}
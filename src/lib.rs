@@ -0,0 +1,48 @@
+pub mod actor;
+pub mod amount;
+pub mod anonymize;
+pub mod bloom;
+#[cfg(feature = "native")]
+pub mod cache;
+pub mod checkpoint;
+pub mod clients;
+pub mod config;
+pub mod crdt;
+#[cfg(feature = "tui")]
+pub mod dashboard;
+pub mod dedupe;
+pub mod diagnostics;
+pub mod diff;
+pub mod dlq;
+#[cfg(feature = "native")]
+pub mod encryption;
+pub mod export;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod generate;
+pub mod ingest;
+pub mod invariants;
+pub mod ledger;
+pub mod manifest;
+pub mod metrics;
+pub mod observer;
+pub mod partition;
+pub mod publish;
+pub mod reconcile;
+pub mod remote;
+pub mod risk;
+pub mod rules;
+pub mod schema;
+#[cfg(feature = "native")]
+pub mod server;
+pub mod shared;
+pub mod simulation;
+pub mod snapshot;
+pub mod tenant;
+pub mod testkit;
+pub mod transactions;
+pub mod velocity;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(feature = "native")]
+pub mod webhook;
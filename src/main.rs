@@ -1,39 +1,2759 @@
-use std::io;
-use std::path::PathBuf;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::sync_channel;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use structopt::StructOpt;
 
-mod export;
-mod ingest;
-mod transactions;
-
-use export::accounts_info_as_csv;
-use ingest::parse_from_file;
-use transactions::{PaymentEngine, Transaction};
+use payments::actor::{
+    merged_accounts, merged_journal, merged_metrics, merged_statements, merged_transactions,
+    ActorRouter,
+};
+use payments::cache::RedisCache;
+use payments::checkpoint::Checkpointer;
+use payments::config::Config;
+use payments::crdt::ReplicatedState;
+use payments::dedupe::{DedupeIndex, Seen};
+use payments::diagnostics::{report, set_anonymizer, set_error_format, ErrorEvent, ErrorFormat};
+use payments::diff::{diff_reports, diffs_as_csv};
+use payments::dlq::{self, read_dead_letters, report_dead_letter, write_dead_letters, DeadLetter};
+use payments::encryption::EncryptionKey;
+use payments::export::{
+    journal_as_csv, merchant_stats_as_csv, metrics_as_csv, period_activity_as_csv,
+    period_statements_as_csv, suspicious_activity_as_csv, write_accounts, write_report,
+    write_shards, write_statements, write_transactions, ExportOptions, OutputFormat, Rounding,
+    StatementDate, MAX_PRECISION,
+};
+use payments::generate::{generate, GeneratorOptions};
+use payments::ingest::{
+    check_records, check_statement, discover_files, parse_accounts_from_file,
+    parse_balances_from_file, parse_corrections_from_file, parse_recurrences_from_file,
+    parse_report_from_file, records_from_mmap, records_from_offset, records_from_reader,
+    statement_records, AccountMap, AmountFormat, CsvDialect, FileOrder, InputFormat, PrecisionMode,
+    PrecisionPolicy, SignatureError, TailReader, TransactionRecord,
+};
+use payments::invariants::verify_funds;
+use payments::manifest::Manifest;
+use payments::metrics::{merchant_stats, period_activity, top_accounts, AccountRanking};
+use payments::observer::{Rejection, RejectionLog, SuspiciousActivityLog};
+use payments::partition::split;
+use payments::publish::AccountPublisher;
+use payments::reconcile::{discrepancies_as_csv, reconcile};
+use payments::remote::{is_remote, open_remote};
+use payments::rules::Rules;
+use payments::schema::{account_report_schema, openapi, transaction_record_schema};
+use payments::server::Server;
+use payments::snapshot::{
+    load_snapshot_with_key, save_encrypted_snapshot, save_snapshot, EngineState, InputOffset,
+    Snapshot,
+};
+use payments::tenant::TenantManager;
+use payments::transactions::{
+    Account, Amount, BatchResult, ChargebackAction, Client, PaymentEngine, PeriodLength, Retention,
+    StorageMode, Transaction, TransactionId, TransactionValidationError,
+};
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "payments")]
 struct Opt {
+    /// Same as `payments process <input-path>`
+    input_path: Option<PathBuf>,
+
+    #[structopt(flatten)]
+    engine: EngineOpt,
+
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+}
+
+// Engine settings, accepted both before and after the subcommand. Unset options fall back to
+// the configuration file. (A doc comment here would replace the subcommands' descriptions.)
+#[derive(Debug, StructOpt)]
+struct EngineOpt {
+    /// TOML configuration file; command line options take precedence over it, and
+    /// PAYMENTS_<SECTION>_<KEY> environment variables over the file
+    #[structopt(long)]
+    config: Option<PathBuf>,
+
+    /// Unlock accounts when a charged back deposit is re-credited
+    #[structopt(long)]
+    unfreeze_on_chargeback_reversal: bool,
+
+    /// Number of chargebacks a client may have before --chargeback-action is applied
+    #[structopt(long)]
+    chargeback_threshold: Option<u32>,
+
+    /// What happens once a client exceeds the chargeback threshold: freeze or flag
+    #[structopt(long, possible_values = &["freeze", "flag"])]
+    chargeback_action: Option<ChargebackAction>,
+
+    /// Flag deposits, withdrawals and holds above this amount
+    #[structopt(long)]
+    risk_large_amount: Option<Amount>,
+
+    /// Flag clients making more than this many withdrawals within --risk-withdrawal-window
+    #[structopt(long)]
+    risk_max_withdrawals: Option<usize>,
+
+    /// Number of most recent transactions --risk-max-withdrawals applies to
+    #[structopt(long)]
+    risk_withdrawal_window: Option<usize>,
+
+    /// Reject transactions caught by the risk rules instead of flagging them
+    #[structopt(long)]
+    risk_veto: bool,
+
+    /// Deposits of at most this amount count towards --velocity-small-deposits
+    #[structopt(long)]
+    velocity_small_deposit: Option<Amount>,
+
+    /// Report a withdrawal emptying an account after this many small deposits in a row
+    #[structopt(long)]
+    velocity_small_deposits: Option<usize>,
+
+    /// Report clients opening more than this many disputes within --velocity-dispute-window
+    #[structopt(long)]
+    velocity_max_disputes: Option<usize>,
+
+    /// Minutes of the engine clock --velocity-max-disputes applies to
+    #[structopt(long)]
+    velocity_dispute_window: Option<u64>,
+
+    /// TOML file with validation rules checked before the built-in ones
+    #[structopt(long)]
+    rules: Option<PathBuf>,
+
+    /// File of blocked client ids, one per line; their transactions are rejected as
+    /// client_blocked
+    #[structopt(long)]
+    blocklist: Option<PathBuf>,
+
+    /// File of client,tier rows assigning clients to the account tiers of the --rules file,
+    /// each with its own limits
+    #[structopt(long)]
+    client_tiers: Option<PathBuf>,
+
+    /// CSV file of client attributes loaded before processing, with a client column and any
+    /// of tier, kyc (unknown, pending, verified or rejected), currency, blocked and external_id
+    #[structopt(long)]
+    clients: Option<PathBuf>,
+
+    /// Apply adjustment records, signed manual corrections with a reason code that bypass
+    /// the frozen and funds checks; they are rejected as adjustments_disabled otherwise
+    #[structopt(long)]
+    allow_adjustments: bool,
+
+    /// Credit deposits to the held funds for this many seconds of the engine clock before they
+    /// become available; with --start-time, advance_time records move the clock
+    #[structopt(long)]
+    deposit_hold_secs: Option<u64>,
+
+    /// Offset negative available balances with the next deposits first, even held ones,
+    /// recording the offsetting part as a recovery journal entry
+    #[structopt(long)]
+    recovery: bool,
+
+    /// Number of decimal places in the account report (0-10)
+    #[structopt(long, parse(try_from_str = parse_precision))]
+    precision: Option<u32>,
+
+    /// Rounding applied to the account report: half-up, bankers or truncate
+    #[structopt(long, possible_values = &["half-up", "bankers", "truncate"])]
+    rounding: Option<Rounding>,
+
+    /// Add disputes_open, disputes_total and chargebacks columns to the account report
+    #[structopt(long)]
+    extended_output: bool,
+
+    /// Add an escrowed column, the funds in the escrows of each account, to the account report
+    #[structopt(long)]
+    show_escrow: bool,
+
+    /// Format of the account report and transaction history: csv, parquet with the `parquet`
+    /// feature, or xlsx (report and rejected transactions only) with the `xlsx` feature
+    #[structopt(long, possible_values = &["csv", "parquet", "xlsx"])]
+    output_format: Option<OutputFormat>,
+
+    /// Write the stored deposits, withdrawals and holds with their dispute state to this file
+    #[structopt(long)]
+    transactions_output: Option<PathBuf>,
+
+    /// Write one MT940 statement per client to this directory
+    #[structopt(long)]
+    statements_output: Option<PathBuf>,
+
+    /// Date of the MT940 statements and journal lines as YYYY-MM-DD, today by default
+    #[structopt(long)]
+    statement_date: Option<StatementDate>,
+
+    /// Write the double-entry journal as general-ledger CSV lines to this file
+    #[structopt(long)]
+    journal_out: Option<PathBuf>,
+
+    /// Write the balances of every account at the end of each closed period, with the credits,
+    /// debits and transactions of the period, to this CSV file
+    #[structopt(long)]
+    period_statements: Option<PathBuf>,
+
+    /// Write the suspicious activity detected by the velocity rules to this CSV file
+    #[structopt(long)]
+    sar_report: Option<PathBuf>,
+
+    /// Write the accounts left with negative available funds to this file, in the format of
+    /// the account report
+    #[structopt(long)]
+    recovery_report: Option<PathBuf>,
+
+    /// Replace client ids by pseudonyms keyed with PAYMENTS_OUTPUT_ANONYMIZE_SECRET in the
+    /// reports and error events
+    #[structopt(long)]
+    anonymize: bool,
+
+    /// Write an update message per applied transaction, the client id, a tab and the account
+    /// as JSON, to this file or named pipe
+    #[structopt(long)]
+    publish: Option<PathBuf>,
+
+    /// Milliseconds between two flushes of the published updates instead of one per update
+    #[structopt(long)]
+    publish_interval_ms: Option<u64>,
+
+    /// Process every tenant, as named by the `tenant` column, with an engine of its own and
+    /// write one account report per tenant to this directory
+    #[structopt(long)]
+    tenant_reports: Option<PathBuf>,
+
+    /// Split the account report into this many files of accounts sorted by client, written to
+    /// --shard-dir with an index.csv listing the clients of each file
+    #[structopt(long)]
+    shard_output: Option<usize>,
+
+    /// Directory receiving the files of --shard-output
+    #[structopt(long)]
+    shard_dir: Option<PathBuf>,
+
+    /// File of client,external_id rows; the account report, transaction history and other
+    /// outputs keyed by client then add an external_id column
+    #[structopt(long)]
+    id_map: Option<PathBuf>,
+
+    /// Append the records that can't be processed, with the reason and the transaction, to
+    /// this JSON Lines file, for `payments retry-dlq`
+    #[structopt(long)]
+    dlq: Option<PathBuf>,
+
+    /// Encrypt the snapshots with AES-256-GCM, keyed with PAYMENTS_ENCRYPTION_KEY or --key-file
+    #[structopt(long)]
+    encrypt_snapshots: bool,
+
+    /// Encrypt the account report with AES-256-GCM, keyed with PAYMENTS_ENCRYPTION_KEY or
+    /// --key-file; `payments decrypt` prints it back
+    #[structopt(long)]
+    encrypt_report: bool,
+
+    /// File holding the secret of encrypted snapshots and reports
+    #[structopt(long)]
+    key_file: Option<PathBuf>,
+
+    /// Maximum number of decimal places accepted in input amounts
+    #[structopt(long)]
+    max_decimal_places: Option<u32>,
+
+    /// Truncate input amounts with too many decimal places instead of rejecting them
+    #[structopt(long)]
+    truncate_excess_precision: bool,
+
+    /// Number of worker threads; each owns the accounts of the clients routed to it
+    #[structopt(long)]
+    workers: Option<usize>,
+
+    /// Remember withdrawals by id only; saves memory, but withdrawals can't be disputed
+    #[structopt(long)]
+    compact_withdrawals: bool,
+
+    /// Layout of the input: csv, iso20022 (pain.001 and camt.053 XML documents), ofx or qif
+    #[structopt(long, possible_values = &["csv", "iso20022", "ofx", "qif"])]
+    format: Option<InputFormat>,
+
+    /// CSV file with account,client rows mapping the accounts of statement formats to clients
+    #[structopt(long)]
+    account_map: Option<PathBuf>,
+
+    /// Field separator of CSV inputs, e.g. ';'
+    #[structopt(long)]
+    delimiter: Option<char>,
+
+    /// CSV inputs have no header row; columns are read by position, see --columns
+    #[structopt(long)]
+    no_headers: bool,
+
+    /// Column order of CSV inputs without headers [default: type,client,tx,amount]
+    #[structopt(long, require_delimiter = true)]
+    columns: Option<Vec<String>>,
+
+    /// CSV columns to rename before reading, as from=to pairs, e.g. customer_id=client
+    #[structopt(long, require_delimiter = true, parse(try_from_str = parse_rename))]
+    rename_columns: Vec<(String, String)>,
+
+    /// Notation of CSV amounts: plain (1234.56), point (1,234.56), comma (1.234,56) or auto;
+    /// all but plain also strip currency symbols and codes
+    #[structopt(long, possible_values = &["plain", "point", "comma", "auto"])]
+    amount_format: Option<AmountFormat>,
+
+    /// Quote character of CSV inputs
+    #[structopt(long)]
+    quote: Option<char>,
+
+    /// Treat quote characters in CSV inputs as ordinary data
+    #[structopt(long)]
+    no_quoting: bool,
+
+    /// Only apply the first N input records, reporting balances as they were right after record
+    /// N (records are counted from 1, header excluded), or with @T, stop once the simulated
+    /// clock of --start-time passes T seconds since the Unix epoch
+    #[structopt(long, parse(try_from_str = parse_as_of))]
+    as_of: Option<AsOf>,
+
+    /// Silently skip deposits, withdrawals and holds repeating a record already seen in this run
+    /// or in the runs that kept --dedupe-index; records reusing an id are still reported
+    #[structopt(long)]
+    skip_duplicates: bool,
+
+    /// After the input, apply the `tx,amount` rows of this file: each sets the amount of a
+    /// stored deposit or withdrawal, or voids it with `void`, and is posted to the journal as a
+    /// correction
+    #[structopt(long)]
+    corrections: Option<PathBuf>,
+
+    /// JSON manifest of the input with its number of rows and SHA-256: the run fails when the
+    /// input doesn't match it, and exits with status 6 when it changed while being processed
+    #[structopt(long)]
+    manifest: Option<PathBuf>,
+
+    /// Start a simulated clock at this time, in seconds since the Unix epoch: records with an
+    /// execute_at column are then applied as advance_time records move the clock past them,
+    /// rather than as the system clock does
+    #[structopt(long)]
+    start_time: Option<u64>,
+
+    /// Before the input, expand the `client,tx,amount,interval,count,start` rows of this file
+    /// into count deposits of amount, or withdrawals when it is negative, interval seconds
+    /// apart from start on (the clock's time when empty), with ids from 4026531840 on
+    #[structopt(long)]
+    recurring: Option<PathBuf>,
+
+    /// Close a period whenever the clock moves into the next day, week or month, as
+    /// close_period records do: journal lines after it carry the next period number
+    #[structopt(long, possible_values = &["daily", "weekly", "monthly"])]
+    period: Option<PeriodLength>,
+
+    /// Keep the records seen by --skip-duplicates in this file across runs
+    #[structopt(long)]
+    dedupe_index: Option<PathBuf>,
+
+    /// Read and parse the input on a separate thread
+    #[structopt(long)]
+    pipeline: bool,
+
+    /// Memory-map the input instead of reading it through a buffer (the file must not change
+    /// while it is processed)
+    #[structopt(long)]
+    fast_io: bool,
+
+    /// Periodically save the engine state and input offset to this file
+    #[structopt(long)]
+    snapshot: Option<PathBuf>,
+
+    /// Number of processed records between two snapshots
+    #[structopt(long)]
+    snapshot_every: Option<u64>,
+
+    /// Seconds between two snapshots of serve and watch, which are taken in the background
+    #[structopt(long)]
+    snapshot_interval: Option<u64>,
+
+    /// Number of snapshots serve and watch keep, the older ones at <snapshot>.1, <snapshot>.2…
+    #[structopt(long)]
+    keep_snapshots: Option<usize>,
+
+    /// Check transaction ids against a bloom filter sized for this many transactions before
+    /// looking them up, speeding up inputs with few duplicates
+    #[structopt(long)]
+    bloom_filter: Option<usize>,
+
+    /// Continue from the state and input offset saved in --snapshot (or the configuration)
+    #[structopt(long)]
+    resume: bool,
+
+    /// Id of this engine among replicas processing the same clients, recorded in --snapshot
+    /// so that `merge --replicated` can combine the snapshots
+    #[structopt(long)]
+    replica: Option<String>,
+
+    /// After processing, check that the account totals add up to the accepted deposits minus
+    /// the accepted withdrawals and chargebacks, and exit with status 5 listing the clients
+    /// that don't
+    #[structopt(long)]
+    verify_invariants: bool,
+
+    /// Show a live dashboard on stderr while processing (needs the `tui` feature)
+    #[structopt(long)]
+    tui: bool,
+
+    /// How skipped records and other problems are reported on stderr: text (log warnings) or
+    /// json (one object per line with code, tx, client, reason and line)
+    #[structopt(long, possible_values = &["text", "json"])]
+    errors_format: Option<ErrorFormat>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Process a transaction file and print the account report
+    Process {
+        input_path: PathBuf,
+
+        #[structopt(flatten)]
+        engine: EngineOpt,
+    },
+    /// Check a transaction file and list the records that can't be processed
+    Validate {
+        input_path: PathBuf,
+
+        #[structopt(flatten)]
+        engine: EngineOpt,
+    },
+    /// Split a transaction file into client-disjoint shards, listing them on stdout
+    Split {
+        input_path: PathBuf,
+
+        /// Number of shards
+        #[structopt(long)]
+        partitions: u32,
+
+        /// Directory receiving the shards, named after the input with the partition number
+        #[structopt(long, default_value = ".")]
+        out_dir: PathBuf,
+
+        #[structopt(flatten)]
+        engine: EngineOpt,
+    },
+    /// Accept transactions and answer account queries over HTTP
+    Serve {
+        /// Address to listen on
+        #[structopt(long, default_value = "127.0.0.1:8080")]
+        listen: String,
+
+        /// Keep every account in Redis at this host:port, written through after each applied
+        /// transaction
+        #[structopt(long)]
+        redis: Option<String>,
+
+        /// Start of the Redis keys, followed by a colon and the client id
+        #[structopt(long, default_value = "payments:account")]
+        redis_prefix: String,
+
+        #[structopt(flatten)]
+        engine: EngineOpt,
+    },
+    /// Write a synthetic transaction file to stdout
+    Gen {
+        /// Number of distinct clients
+        #[structopt(long, default_value = "100")]
+        clients: Client,
+
+        /// Number of records
+        #[structopt(long, default_value = "1000")]
+        transactions: u64,
+
+        /// Seed of the generator; the same seed always produces the same file
+        #[structopt(long, default_value = "0")]
+        seed: u64,
+    },
+    /// Process a transaction file, or read the state of --snapshot when none is given, and print
+    /// engine metrics instead of the account report
+    Stats {
+        input_path: Option<PathBuf>,
+
+        /// Print volume and chargebacks per counterparty instead of the engine metrics
+        #[structopt(long)]
+        by_merchant: bool,
+
+        /// Print volume, deposit and withdrawal totals, disputes and the chargeback rate per
+        /// day, week or month of the engine clock instead of the engine metrics; scheduled
+        /// records count in the period they were due
+        #[structopt(
+            long,
+            possible_values = &["day", "week", "month"],
+            parse(try_from_str = parse_group_by)
+        )]
+        group_by: Option<PeriodLength>,
+
+        /// Print the account report of this many accounts, the largest by --by first, instead
+        /// of the engine metrics
+        #[structopt(long)]
+        top: Option<usize>,
+
+        /// What --top ranks accounts by: available, held, total (the default) or chargebacks
+        #[structopt(
+            long,
+            requires = "top",
+            possible_values = &["available", "held", "total", "chargebacks"]
+        )]
+        by: Option<AccountRanking>,
+
+        #[structopt(flatten)]
+        engine: EngineOpt,
+    },
+    /// Compare an engine account report against an external balance file
+    Reconcile {
+        engine_output: PathBuf,
+        bank_statement: PathBuf,
+
+        /// Maximum absolute difference between balances that is not reported
+        #[structopt(long, default_value = "0")]
+        tolerance: Amount,
+    },
+    /// Combine engine snapshots taken over disjoint sets of clients and print the account report
+    Merge {
+        #[structopt(required = true, min_values = 1)]
+        snapshots: Vec<PathBuf>,
+
+        /// The snapshots were taken by --replica engines that may share clients, but processed
+        /// disjoint sets of transactions; only the account report is written
+        #[structopt(long)]
+        replicated: bool,
+
+        #[structopt(flatten)]
+        engine: EngineOpt,
+    },
+    /// Remove a client's account, transactions and journal entries from the --snapshot file,
+    /// leave a tombstone in their place and print it
+    PurgeClient {
+        client: Client,
+
+        #[structopt(flatten)]
+        engine: EngineOpt,
+    },
+    /// Drop settled or old transactions from the --snapshot file; their ids are kept so they
+    /// are still rejected as duplicates, but they can no longer be disputed
+    Prune {
+        /// Drop transactions more than this many ids below the newest one
+        #[structopt(long)]
+        older_than: Option<TransactionId>,
+
+        /// Drop reversed, resolved, charged back and released transactions
+        #[structopt(long)]
+        resolved: bool,
+
+        #[structopt(flatten)]
+        engine: EngineOpt,
+    },
+    /// Compare two account reports and print the clients whose accounts differ
+    Diff { old: PathBuf, new: PathBuf },
+    /// Print the plaintext of an encrypted account report or snapshot
+    Decrypt {
+        path: PathBuf,
+
+        #[structopt(flatten)]
+        engine: EngineOpt,
+    },
+    /// Print the JSON Schema of the input records or the account report, or the OpenAPI
+    /// document of `serve`
+    Schema {
+        #[structopt(possible_values = &["record", "report", "openapi"])]
+        document: String,
+
+        #[structopt(flatten)]
+        engine: EngineOpt,
+    },
+    /// Process every transaction file (*.csv) of a directory into one engine
+    ProcessDir {
+        dir: PathBuf,
+
+        /// Order in which files are processed: name, mtime or date (embedded in the file name)
+        #[structopt(long, default_value = "name", possible_values = &["name", "mtime", "date"])]
+        order: FileOrder,
+
+        /// Move fully processed files into a processed/ folder inside the directory
+        #[structopt(long)]
+        move_processed: bool,
+
+        #[structopt(flatten)]
+        engine: EngineOpt,
+    },
+    /// Apply the transactions of a dead-letter queue (--dlq) to the state of the --snapshot file,
+    /// keep the records still failing in the queue and print the account report
+    RetryDlq {
+        path: PathBuf,
+
+        #[structopt(flatten)]
+        engine: EngineOpt,
+    },
+    /// Process records as they are appended to a file, printing the account report periodically
+    Watch {
+        input_path: PathBuf,
+
+        /// Seconds between two account reports; a report is only printed after changes
+        #[structopt(long, default_value = "10")]
+        interval: u64,
+
+        #[structopt(flatten)]
+        engine: EngineOpt,
+    },
+}
+
+impl Command {
+    fn engine_options(&self) -> Option<&EngineOpt> {
+        match self {
+            Command::Process { engine, .. }
+            | Command::Validate { engine, .. }
+            | Command::Split { engine, .. }
+            | Command::Serve { engine, .. }
+            | Command::Stats { engine, .. }
+            | Command::Merge { engine, .. }
+            | Command::PurgeClient { engine, .. }
+            | Command::Prune { engine, .. }
+            | Command::ProcessDir { engine, .. }
+            | Command::Watch { engine, .. }
+            | Command::RetryDlq { engine, .. }
+            | Command::Decrypt { engine, .. }
+            | Command::Schema { engine, .. } => Some(engine),
+            Command::Gen { .. } | Command::Reconcile { .. } | Command::Diff { .. } => None,
+        }
+    }
+}
+
+fn parse_precision(src: &str) -> Result<u32, String> {
+    let precision: u32 = src.parse().map_err(|err| format!("{}", err))?;
+    if precision > MAX_PRECISION {
+        return Err(format!("precision must be at most {}", MAX_PRECISION));
+    }
+    Ok(precision)
+}
+
+fn parse_group_by(src: &str) -> Result<PeriodLength, String> {
+    match src {
+        "day" => Ok(PeriodLength::Daily),
+        "week" => Ok(PeriodLength::Weekly),
+        "month" => Ok(PeriodLength::Monthly),
+        _ => Err(format!("unknown period: {}", src)),
+    }
+}
+
+/// Point of the input `--as-of` stops at.
+#[derive(Debug, Clone, Copy)]
+enum AsOf {
+    Records(u64),
+    Time(u64),
+}
+
+fn parse_as_of(src: &str) -> Result<AsOf, String> {
+    match src.strip_prefix('@') {
+        Some(time) => time
+            .parse()
+            .map(AsOf::Time)
+            .map_err(|_| format!("expected @ and seconds since the Unix epoch, not {}", src)),
+        None => src.parse().map(AsOf::Records).map_err(|_| {
+            format!(
+                "expected a number of records or @ and a timestamp, not {}",
+                src
+            )
+        }),
+    }
+}
+
+fn parse_rename(src: &str) -> Result<(String, String), String> {
+    match src.split_once('=') {
+        Some((from, to)) if !from.is_empty() && !to.is_empty() => {
+            Ok((from.to_string(), to.to_string()))
+        }
+        _ => Err(format!("expected from=to, not {}", src)),
+    }
+}
+
+/// Exit status telling callers the report only covers part of the input.
+const EXIT_PARTIAL: i32 = 3;
+
+/// Exit status of `validate` when some records can't be processed.
+const EXIT_INVALID: i32 = 4;
+
+/// Exit status of `diff` when the reports differ, as with diff(1).
+const EXIT_DIFFERENT: i32 = 1;
+
+/// Exit status of `--verify-invariants` when the account totals don't match the transactions.
+const EXIT_INVARIANT: i32 = 5;
+
+/// Exit status telling callers the input no longer matched its `--manifest` once processed.
+const EXIT_CHANGED: i32 = 6;
+
+/// Number of parsed transactions buffered between the reader thread and the engine.
+const PIPELINE_DEPTH: usize = 4096;
+
+/// A parsed transaction and where it was read from.
+#[derive(Clone)]
+struct InputTransaction {
+    transaction: Transaction,
+    /// Line the record starts on.
+    line: Option<u64>,
+    /// Position right after the record.
+    offset: InputOffset,
+    /// Batch of the record, shared by the consecutive records with the same `batch_id`.
+    batch: Option<Arc<BatchTag>>,
+    tenant: Option<Arc<str>>,
+}
+
+/// Records sharing a `batch_id` on consecutive lines, applied all or nothing by `apply_all`.
+struct BatchTag {
+    id: Box<str>,
+    /// Set when one of its records can't be parsed, is dropped as a conflicting duplicate or
+    /// belongs to another tenant, so that none of the others are applied either.
+    invalid: AtomicBool,
+}
+
+impl BatchTag {
+    /// `current` when the record belongs to it as well, a new tag otherwise.
+    fn of(record: &TransactionRecord, current: Option<Arc<BatchTag>>) -> Option<Arc<BatchTag>> {
+        let id = record.batch_id()?;
+        match current {
+            Some(current) if *current.id == *id => Some(current),
+            _ => Some(Arc::new(BatchTag {
+                id: id.into(),
+                invalid: AtomicBool::new(false),
+            })),
+        }
+    }
+
+    fn invalidate(&self) {
+        self.invalid.store(true, Ordering::SeqCst);
+    }
+}
+
+struct SnapshotOptions {
+    path: PathBuf,
+    every: u64,
+    replica: Option<String>,
+    /// Key the snapshots are encrypted with, if they are.
+    key: Option<EncryptionKey>,
+}
+
+/// How `read_transactions` reads and parses its input.
+struct ReadOptions {
+    precision: PrecisionPolicy,
+    format: InputFormat,
+    accounts: AccountMap,
+    dialect: CsvDialect,
+    pipeline: bool,
+    fast_io: bool,
+    /// Number of records to read at most.
+    as_of: Option<u64>,
+    /// Time of the simulated clock the input stops at, see `InputConfig::as_of_time`.
+    as_of_time: Option<u64>,
+}
+
+impl ReadOptions {
+    fn new(config: &Config) -> anyhow::Result<Self> {
+        Ok(Self {
+            precision: config.precision_policy(),
+            format: config.input.format,
+            accounts: config.account_map()?,
+            dialect: config.csv_dialect(),
+            pipeline: config.input.pipeline,
+            fast_io: config.input.fast_io,
+            as_of: config.input.as_of,
+            as_of_time: config.input.as_of_time,
+        })
+    }
+}
+
+/// Transactions of the input. Offsets are only tracked for local CSV files read without
+/// `fast_io`.
+fn read_transactions(
     input_path: PathBuf,
+    start: InputOffset,
+    options: ReadOptions,
+) -> anyhow::Result<Box<dyn Iterator<Item = InputTransaction>>> {
+    let location = input_path.to_string_lossy();
+    let dialect = &options.dialect;
+    let records: Box<dyn Iterator<Item = (TransactionRecord, InputOffset)> + Send> =
+        if options.format != InputFormat::Csv {
+            Box::new(
+                statement_records(open_input(&input_path)?, options.format, &options.accounts)?
+                    .into_iter()
+                    .map(|record| (record, InputOffset::default())),
+            )
+        } else if is_remote(&location) {
+            Box::new(
+                records_from_reader(open_remote(&location)?, dialect)
+                    .map(|record| (record, InputOffset::default())),
+            )
+        } else if options.fast_io {
+            Box::new(
+                records_from_mmap(input_path, dialect)?
+                    .map(|record| (record, InputOffset::default())),
+            )
+        } else {
+            Box::new(records_from_offset(input_path, start, dialect)?)
+        };
+    let records = records.take(options.as_of.map_or(usize::MAX, |records| {
+        usize::try_from(records).unwrap_or(usize::MAX)
+    }));
+    let precision = options.precision;
+    let mut current: Option<Arc<BatchTag>> = None;
+    let mut tenant: Option<Arc<str>> = None;
+    let transactions = records.filter_map(move |(record, offset)| {
+        let line = record.line();
+        let memo = record.memo().map(str::to_string);
+        current = BatchTag::of(&record, current.take());
+        if tenant.as_deref() != record.tenant() {
+            tenant = record.tenant().map(Arc::from);
+        }
+        if let Some(err) = record.signature_error() {
+            report_dead_letter(
+                unverified(&record, err),
+                None,
+                current.as_deref().map(|batch| &*batch.id),
+            );
+            if let Some(batch) = &current {
+                batch.invalidate();
+            }
+            return None;
+        }
+        match record.into_transaction(&precision) {
+            Ok(transaction) => Some(InputTransaction {
+                transaction,
+                line,
+                offset,
+                batch: current.clone(),
+                tenant: tenant.clone(),
+            }),
+            Err(err) => {
+                report_dead_letter(
+                    ErrorEvent::unparsable(&err)
+                        .with_line(line)
+                        .with_memo(memo.as_deref()),
+                    None,
+                    current.as_deref().map(|batch| &*batch.id),
+                );
+                if let Some(batch) = &current {
+                    batch.invalidate();
+                }
+                None
+            }
+        }
+    });
+    // the clock only moves with advance_time records, so the one passing the time is the last
+    let mut passed = false;
+    let transactions = transactions.map_while(move |mut input| {
+        if passed {
+            return None;
+        }
+        if let (Some(until), Transaction::AdvanceTime { to, .. }) =
+            (options.as_of_time, &mut input.transaction)
+        {
+            if *to > until {
+                *to = until;
+                passed = true;
+            }
+        }
+        Some(input)
+    });
+    if !options.pipeline {
+        return Ok(Box::new(transactions));
+    }
+
+    let (sender, receiver) = sync_channel(PIPELINE_DEPTH);
+    thread::spawn(move || {
+        for transaction in transactions {
+            if sender.send(transaction).is_err() {
+                break;
+            }
+        }
+    });
+    Ok(Box::new(receiver.into_iter()))
 }
 
-fn main() -> anyhow::Result<()> {
-    env_logger::init();
-    let opt = Opt::from_args();
-    let mut payment_engine = PaymentEngine::new();
-    for record in parse_from_file(opt.input_path)? {
-        match Transaction::try_from(record) {
-            Ok(transaction) => {
-                if let Err(err) = payment_engine.process_transaction(transaction) {
-                    log::warn!("unable to process transaction: {}", err);
+/// A record whose signature doesn't verify, reported apart from those that can't be parsed.
+fn unverified(record: &TransactionRecord, err: SignatureError) -> ErrorEvent {
+    ErrorEvent::new("invalid_signature", "unable to verify record", err)
+        .with_transaction(record.client(), record.tx())
+        .with_line(record.line())
+        .with_memo(record.memo())
+}
+
+/// The recurrences of the file at `path`, reporting the ones that can't be turned into a
+/// transaction.
+fn read_recurrences(
+    path: &Path,
+    precision: &PrecisionPolicy,
+) -> anyhow::Result<Vec<InputTransaction>> {
+    let mut recurrences = vec![];
+    for recurrence in parse_recurrences_from_file(path)? {
+        match recurrence.into_transaction(precision) {
+            Ok(transaction) => recurrences.push(InputTransaction {
+                transaction,
+                line: None,
+                offset: InputOffset::default(),
+                batch: None,
+                tenant: None,
+            }),
+            Err(err) => report_dead_letter(ErrorEvent::unparsable(&err), None, None),
+        }
+    }
+    Ok(recurrences)
+}
+
+/// Processes a transaction, reporting it when it is rejected.
+fn apply(payment_engine: &mut PaymentEngine, transaction: Transaction, line: Option<u64>) {
+    let memo = transaction.memo().map(str::to_string);
+    let retry = dlq::enabled().then(|| transaction.clone());
+    if let Err(err) = payment_engine.process_transaction(transaction) {
+        report_dead_letter(
+            ErrorEvent::rejected(&err)
+                .with_line(line)
+                .with_memo(memo.as_deref()),
+            retry,
+            None,
+        );
+    }
+}
+
+/// Applies the corrections file at `path`, each correction by the engine storing its
+/// transaction, and reports the corrections of unknown transactions and the rejected ones.
+fn apply_corrections(
+    engines: &mut [PaymentEngine],
+    path: &Path,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let precision = config.precision_policy();
+    for correction in parse_corrections_from_file(path)? {
+        let tx = correction.tx;
+        let stored = engines.iter_mut().find_map(|engine| {
+            let client = engine.get_transaction(tx)?.client();
+            Some((engine, client))
+        });
+        let (engine, client) = match stored {
+            Some(stored) => stored,
+            None => {
+                report(ErrorEvent::new(
+                    "correction_unknown",
+                    "unable to apply correction",
+                    format!("transaction {} is not stored", tx),
+                ));
+                continue;
+            }
+        };
+        match correction.into_transaction(client, &precision) {
+            Ok(transaction) => apply(engine, transaction, None),
+            Err(err) => report_dead_letter(ErrorEvent::rejected(&err), None, None),
+        }
+    }
+    Ok(())
+}
+
+/// Applies `transactions` in order, each batch of them all or nothing, and calls `applied` after
+/// every record outside a batch and every batch with the engine, the position right after it
+/// and its number of records.
+fn apply_all<F>(
+    payment_engine: &mut PaymentEngine,
+    transactions: impl Iterator<Item = InputTransaction>,
+    mut applied: F,
+) where
+    F: FnMut(&PaymentEngine, InputOffset, u64),
+{
+    let mut transactions = transactions.peekable();
+    while let Some(input) = transactions.next() {
+        let batch = match &input.batch {
+            Some(batch) => Arc::clone(batch),
+            None => {
+                apply(payment_engine, input.transaction, input.line);
+                applied(payment_engine, input.offset, 1);
+                continue;
+            }
+        };
+        let mut records = vec![input];
+        while let Some(input) = transactions.next_if(|next| {
+            next.batch
+                .as_ref()
+                .is_some_and(|next| Arc::ptr_eq(next, &batch))
+        }) {
+            records.push(input);
+        }
+        let offset = records[records.len() - 1].offset;
+        let count = records.len() as u64;
+        apply_batch(payment_engine, &batch, records);
+        applied(payment_engine, offset, count);
+    }
+}
+
+/// Processes the records of a batch all or nothing, reporting the record that was rejected and
+/// the batch.
+fn apply_batch(
+    payment_engine: &mut PaymentEngine,
+    batch: &BatchTag,
+    records: Vec<InputTransaction>,
+) {
+    let first_line = records[0].line;
+    if batch.invalid.load(Ordering::SeqCst) {
+        let event = ErrorEvent::new(
+            "batch_rejected",
+            "unable to process batch",
+            format!(
+                "batch {} has an invalid record, none of its records were applied",
+                batch.id
+            ),
+        );
+        dead_letter_batch(&event, batch, &records, None);
+        report(event.with_line(first_line));
+        return;
+    }
+    let retry = dlq::enabled().then(|| records.clone());
+    let lines: Vec<(Option<u64>, Option<String>)> = records
+        .iter()
+        .map(|input| (input.line, input.transaction.memo().map(str::to_string)))
+        .collect();
+    let transactions = records.into_iter().map(|input| input.transaction).collect();
+    if let BatchResult::Rejected { index, error } = payment_engine.process_batch(transactions) {
+        let (line, memo) = &lines[index];
+        let rejected = ErrorEvent::rejected(&error)
+            .with_line(*line)
+            .with_memo(memo.as_deref());
+        let event = ErrorEvent::new(
+            "batch_rejected",
+            "unable to process batch",
+            format!(
+                "a record of batch {} was rejected, none of its {} records were applied",
+                batch.id,
+                lines.len()
+            ),
+        );
+        if let Some(records) = &retry {
+            dead_letter_batch(&event, batch, records, Some((index, &rejected)));
+        }
+        report(rejected);
+        report(event.with_line(first_line));
+    }
+}
+
+/// Pushes every record of a batch that wasn't applied to the dead-letter queue, with the reason
+/// `event` of the batch or, for the record at the index of `rejected`, the reason it was
+/// rejected for.
+fn dead_letter_batch(
+    event: &ErrorEvent,
+    batch: &BatchTag,
+    records: &[InputTransaction],
+    rejected: Option<(usize, &ErrorEvent)>,
+) {
+    if !dlq::enabled() {
+        return;
+    }
+    for (index, input) in records.iter().enumerate() {
+        let reason = match rejected {
+            Some((rejected, reason)) if rejected == index => reason.clone(),
+            _ => event.clone().with_line(input.line),
+        };
+        dlq::push(
+            DeadLetter::new(&reason, Some(input.transaction.clone())).with_batch(Some(&batch.id)),
+        );
+    }
+}
+
+/// Reports a record of a batch that is skipped because batches can't be applied atomically by
+/// `mode`.
+fn reject_batched(line: Option<u64>, batch: &str, mode: &str, transaction: Option<Transaction>) {
+    report_dead_letter(
+        ErrorEvent::new(
+            "batch_unsupported",
+            "unable to process batch",
+            format!("batch {} can't be applied atomically {}", batch, mode),
+        )
+        .with_line(line),
+        transaction,
+        Some(batch),
+    );
+}
+
+/// Whether `input` goes on to the engine: replays are counted in `replays` and dropped, records
+/// reusing a known id are reported as conflicting and dropped.
+fn admit(index: &mut DedupeIndex, input: &InputTransaction, replays: &mut u64) -> bool {
+    let transaction = &input.transaction;
+    match index.check(transaction) {
+        Seen::New => true,
+        Seen::Replay => {
+            *replays += 1;
+            false
+        }
+        Seen::Conflict => {
+            if let Some(batch) = &input.batch {
+                batch.invalidate();
+            }
+            let err = TransactionValidationError::ConflictingDuplicate {
+                client: transaction.client(),
+                tx: transaction.tx(),
+            };
+            report_dead_letter(
+                ErrorEvent::rejected(&err)
+                    .with_line(input.line)
+                    .with_memo(transaction.memo()),
+                dlq::enabled().then(|| transaction.clone()),
+                input.batch.as_deref().map(|batch| &*batch.id),
+            );
+            false
+        }
+    }
+}
+
+fn process_in_parallel<F: Fn() -> PaymentEngine>(
+    transactions: impl Iterator<Item = InputTransaction>,
+    workers: usize,
+    make_engine: F,
+) -> Vec<PaymentEngine> {
+    let router = ActorRouter::with_engines(workers, make_engine);
+    for input in transactions {
+        match &input.batch {
+            Some(batch) => reject_batched(
+                input.line,
+                &batch.id,
+                "with --workers",
+                Some(input.transaction),
+            ),
+            None => router.route(input.transaction),
+        }
+    }
+    router.finish()
+}
+
+fn take_snapshot(snapshots: &SnapshotOptions, payment_engine: &PaymentEngine, offset: InputOffset) {
+    let snapshot = Snapshot {
+        replica: snapshots.replica.clone(),
+        offset,
+        engine: payment_engine.state(),
+    };
+    let saved = match &snapshots.key {
+        Some(key) => save_encrypted_snapshot(&snapshots.path, &snapshot, key),
+        None => save_snapshot(&snapshots.path, &snapshot),
+    };
+    if let Err(err) = saved {
+        report(ErrorEvent::new(
+            "snapshot_failed",
+            "unable to save snapshot",
+            err,
+        ));
+    }
+}
+
+/// Called after every processed record with the engine and whether processing is done.
+type Progress = Box<dyn FnMut(&PaymentEngine, bool)>;
+
+#[cfg(feature = "tui")]
+fn dashboard(payment_engine: &mut PaymentEngine) -> anyhow::Result<Progress> {
+    use std::io::IsTerminal;
+
+    if !io::stderr().is_terminal() {
+        anyhow::bail!("--tui draws on stderr, which is not a terminal");
+    }
+    let mut dashboard = payments::dashboard::Dashboard::new();
+    payment_engine.subscribe(dashboard.observer());
+    Ok(Box::new(move |engine, done| {
+        if done {
+            dashboard.finish(engine)
+        } else {
+            dashboard.record(engine)
+        }
+    }))
+}
+
+#[cfg(not(feature = "tui"))]
+fn dashboard(_payment_engine: &mut PaymentEngine) -> anyhow::Result<Progress> {
+    anyhow::bail!("built without tui support, enable the `tui` feature")
+}
+
+fn process(
+    transactions: impl Iterator<Item = InputTransaction>,
+    mut payment_engine: PaymentEngine,
+    mut offset: InputOffset,
+    snapshots: Option<SnapshotOptions>,
+    mut progress: Option<Progress>,
+) -> PaymentEngine {
+    let mut since_snapshot = 0;
+    apply_all(
+        &mut payment_engine,
+        transactions,
+        |engine, applied, records| {
+            if let Some(progress) = &mut progress {
+                progress(engine, false);
+            }
+            offset = applied;
+            since_snapshot += records;
+            if let Some(snapshots) = &snapshots {
+                if since_snapshot >= snapshots.every {
+                    take_snapshot(snapshots, engine, offset);
+                    since_snapshot = 0;
                 }
             }
-            Err(err) => {
-                log::warn!("unable to parse transaction: {}", err);
+        },
+    );
+    if let Some(snapshots) = &snapshots {
+        take_snapshot(snapshots, &payment_engine, offset);
+    }
+    if let Some(progress) = &mut progress {
+        progress(&payment_engine, true);
+    }
+    payment_engine
+}
+
+/// Writes the stored transactions to the `transactions` output file, if one is configured.
+fn write_history<'a>(
+    transactions: impl IntoIterator<Item = &'a Transaction>,
+    options: &ExportOptions,
+    config: &Config,
+) {
+    let path = match &config.output.transactions {
+        Some(path) => path,
+        None => return,
+    };
+    let result = fs::File::create(path)
+        .map_err(|err| err.into())
+        .and_then(|file| write_transactions(transactions, io::BufWriter::new(file), options));
+    if let Err(err) = result {
+        report(ErrorEvent::new(
+            "write_failed",
+            "unable to write transaction history",
+            err,
+        ));
+    }
+}
+
+/// Writes the MT940 statements of every account to the `statements` directory, if one is
+/// configured.
+fn write_client_statements(engines: &[PaymentEngine], config: &Config) {
+    let dir = match &config.output.statements {
+        Some(dir) => dir,
+        None => return,
+    };
+    if let Err(err) = write_statements(
+        merged_accounts(engines),
+        merged_transactions(engines),
+        dir,
+        &config.statement_options(),
+        &config.export_options(),
+    ) {
+        report(ErrorEvent::new(
+            "write_failed",
+            "unable to write statements",
+            err,
+        ));
+    }
+}
+
+/// Writes the journal entries of every engine to the `journal` file, if one is configured.
+fn write_journal(engines: &[PaymentEngine], config: &Config) {
+    let path = match &config.output.journal {
+        Some(path) => path,
+        None => return,
+    };
+    let result = fs::File::create(path)
+        .map_err(|err| err.into())
+        .and_then(|file| {
+            journal_as_csv(
+                merged_journal(engines),
+                config.statement_options().date,
+                io::BufWriter::new(file),
+                &config.export_options(),
+            )
+        });
+    if let Err(err) = result {
+        report(ErrorEvent::new(
+            "write_failed",
+            "unable to write journal",
+            err,
+        ));
+    }
+}
+
+/// Writes the statements of the closed periods of every engine to the `period_statements`
+/// file, if one is configured.
+fn write_period_statements(engines: &[PaymentEngine], options: &ExportOptions, config: &Config) {
+    let path = match &config.output.period_statements {
+        Some(path) => path,
+        None => return,
+    };
+    let result = fs::File::create(path)
+        .map_err(|err| err.into())
+        .and_then(|file| {
+            period_statements_as_csv(
+                merged_statements(engines),
+                io::BufWriter::new(file),
+                options,
+            )
+        });
+    if let Err(err) = result {
+        report(ErrorEvent::new(
+            "write_failed",
+            "unable to write period statements",
+            err,
+        ));
+    }
+}
+
+/// Writes the activity collected by `log` to the `suspicious_activity` file, if configured.
+fn write_suspicious_activity(
+    log: &SuspiciousActivityLog,
+    options: &ExportOptions,
+    config: &Config,
+) {
+    let path = match &config.output.suspicious_activity {
+        Some(path) => path,
+        None => return,
+    };
+    let result = fs::File::create(path)
+        .map_err(|err| err.into())
+        .and_then(|file| {
+            suspicious_activity_as_csv(&log.activity(), io::BufWriter::new(file), options)
+        });
+    if let Err(err) = result {
+        report(ErrorEvent::new(
+            "write_failed",
+            "unable to write suspicious activity",
+            err,
+        ));
+    }
+}
+
+/// Writes the accounts of every engine left with negative available funds to the
+/// `recovery_report` file, if configured.
+fn write_recovery_report(engines: &[PaymentEngine], options: &ExportOptions, config: &Config) {
+    let path = match &config.output.recovery_report {
+        Some(path) => path,
+        None => return,
+    };
+    let result = fs::File::create(path)
+        .map_err(|err| err.into())
+        .and_then(|file| {
+            write_accounts(
+                merged_accounts(engines)
+                    .into_iter()
+                    .filter(|account| account.is_negative()),
+                io::BufWriter::new(file),
+                options,
+            )
+        });
+    if let Err(err) = result {
+        report(ErrorEvent::new(
+            "write_failed",
+            "unable to write recovery report",
+            err,
+        ));
+    }
+}
+
+/// Flushes the published updates, reporting a failure to write them.
+fn finish_publishing(publisher: Option<&AccountPublisher>) {
+    if let Some(Err(err)) = publisher.map(AccountPublisher::finish) {
+        report(ErrorEvent::new(
+            "publish_failed",
+            "unable to publish account updates",
+            err,
+        ));
+    }
+}
+
+/// Command line options override the configuration file.
+fn apply_cli(opt: &EngineOpt, config: &mut Config) {
+    if opt.unfreeze_on_chargeback_reversal {
+        config.disputes.unfreeze_on_chargeback_reversal = true;
+    }
+    if let Some(threshold) = opt.chargeback_threshold {
+        config.disputes.chargeback_threshold = threshold;
+    }
+    if let Some(action) = opt.chargeback_action {
+        config.disputes.chargeback_action = action;
+    }
+    if opt.risk_large_amount.is_some() {
+        config.risk.large_amount = opt.risk_large_amount;
+    }
+    if opt.risk_max_withdrawals.is_some() {
+        config.risk.max_withdrawals = opt.risk_max_withdrawals;
+    }
+    if let Some(window) = opt.risk_withdrawal_window {
+        config.risk.withdrawal_window = window;
+    }
+    if opt.risk_veto {
+        config.risk.veto = true;
+    }
+    if opt.velocity_small_deposit.is_some() {
+        config.velocity.small_deposit = opt.velocity_small_deposit;
+    }
+    if let Some(count) = opt.velocity_small_deposits {
+        config.velocity.small_deposits = count;
+    }
+    if opt.velocity_max_disputes.is_some() {
+        config.velocity.max_disputes = opt.velocity_max_disputes;
+    }
+    if let Some(minutes) = opt.velocity_dispute_window {
+        config.velocity.dispute_window_minutes = minutes;
+    }
+    if opt.rules.is_some() {
+        config.limits.rules = opt.rules.clone();
+    }
+    if opt.blocklist.is_some() {
+        config.limits.blocklist = opt.blocklist.clone();
+    }
+    if opt.client_tiers.is_some() {
+        config.limits.client_tiers = opt.client_tiers.clone();
+    }
+    if opt.clients.is_some() {
+        config.limits.clients = opt.clients.clone();
+    }
+    if opt.allow_adjustments {
+        config.limits.allow_adjustments = true;
+    }
+    if opt.deposit_hold_secs.is_some() {
+        config.limits.deposit_hold_secs = opt.deposit_hold_secs;
+    }
+    if opt.recovery {
+        config.limits.recovery = true;
+    }
+    if let Some(precision) = opt.precision {
+        config.output.precision = precision;
+    }
+    if let Some(rounding) = opt.rounding {
+        config.output.rounding = rounding;
+    }
+    if opt.extended_output {
+        config.output.extended = true;
+    }
+    if opt.show_escrow {
+        config.output.show_escrow = true;
+    }
+    if let Some(format) = opt.output_format {
+        config.output.format = format;
+    }
+    if let Some(path) = &opt.transactions_output {
+        config.output.transactions = Some(path.clone());
+    }
+    if let Some(path) = &opt.statements_output {
+        config.output.statements = Some(path.clone());
+    }
+    if opt.statement_date.is_some() {
+        config.output.statement_date = opt.statement_date;
+    }
+    if let Some(path) = &opt.journal_out {
+        config.output.journal = Some(path.clone());
+    }
+    if let Some(path) = &opt.period_statements {
+        config.output.period_statements = Some(path.clone());
+    }
+    if let Some(path) = &opt.sar_report {
+        config.output.suspicious_activity = Some(path.clone());
+    }
+    if let Some(path) = &opt.recovery_report {
+        config.output.recovery_report = Some(path.clone());
+    }
+    if opt.anonymize {
+        config.output.anonymize = true;
+    }
+    if opt.publish.is_some() {
+        config.output.publish = opt.publish.clone();
+    }
+    if opt.publish_interval_ms.is_some() {
+        config.output.publish_interval_ms = opt.publish_interval_ms;
+    }
+    if let Some(dir) = &opt.tenant_reports {
+        config.output.tenant_reports = Some(dir.clone());
+    }
+    if opt.shard_output.is_some() {
+        config.output.shards = opt.shard_output;
+    }
+    if opt.shard_dir.is_some() {
+        config.output.shard_dir = opt.shard_dir.clone();
+    }
+    if opt.id_map.is_some() {
+        config.output.id_map = opt.id_map.clone();
+    }
+    if opt.dlq.is_some() {
+        config.output.dead_letters = opt.dlq.clone();
+    }
+    if opt.encrypt_snapshots {
+        config.encryption.snapshots = true;
+    }
+    if opt.encrypt_report {
+        config.encryption.reports = true;
+    }
+    if opt.key_file.is_some() {
+        config.encryption.key_file = opt.key_file.clone();
+    }
+    if let Some(places) = opt.max_decimal_places {
+        config.input.max_decimal_places = places;
+    }
+    if opt.truncate_excess_precision {
+        config.input.precision_mode = PrecisionMode::Truncate;
+    }
+    if let Some(workers) = opt.workers {
+        config.storage.workers = workers;
+    }
+    if opt.compact_withdrawals {
+        config.storage.mode = StorageMode::Compact;
+    }
+    if opt.pipeline {
+        config.input.pipeline = true;
+    }
+    if let Some(format) = opt.format {
+        config.input.format = format;
+    }
+    if let Some(path) = &opt.account_map {
+        config.input.accounts = Some(path.clone());
+    }
+    if let Some(delimiter) = opt.delimiter {
+        config.input.delimiter = delimiter;
+    }
+    if opt.no_headers {
+        config.input.headers = false;
+    }
+    if let Some(columns) = &opt.columns {
+        config.input.columns = columns.clone();
+    }
+    for (from, to) in &opt.rename_columns {
+        config.input.rename.insert(from.clone(), to.clone());
+    }
+    if let Some(format) = opt.amount_format {
+        config.input.amount_format = format;
+    }
+    if let Some(quote) = opt.quote {
+        config.input.quote = quote;
+    }
+    if opt.no_quoting {
+        config.input.quoting = false;
+    }
+    if opt.fast_io {
+        config.input.fast_io = true;
+    }
+    match opt.as_of {
+        Some(AsOf::Records(records)) => config.input.as_of = Some(records),
+        Some(AsOf::Time(time)) => config.input.as_of_time = Some(time),
+        None => {}
+    }
+    if opt.skip_duplicates {
+        config.input.skip_duplicates = true;
+    }
+    if opt.start_time.is_some() {
+        config.input.start_time = opt.start_time;
+    }
+    if opt.corrections.is_some() {
+        config.input.corrections = opt.corrections.clone();
+    }
+    if opt.manifest.is_some() {
+        config.input.manifest = opt.manifest.clone();
+    }
+    if opt.recurring.is_some() {
+        config.input.recurring = opt.recurring.clone();
+    }
+    if opt.period.is_some() {
+        config.input.period = opt.period;
+    }
+    if opt.dedupe_index.is_some() {
+        config.storage.dedupe_index = opt.dedupe_index.clone();
+    }
+    if opt.snapshot.is_some() {
+        config.storage.snapshot = opt.snapshot.clone();
+    }
+    if let Some(every) = opt.snapshot_every {
+        config.storage.snapshot_every = every;
+    }
+    if opt.snapshot_interval.is_some() {
+        config.storage.snapshot_interval_secs = opt.snapshot_interval;
+    }
+    if let Some(keep) = opt.keep_snapshots {
+        config.storage.keep_snapshots = keep;
+    }
+    if opt.bloom_filter.is_some() {
+        config.storage.bloom_filter = opt.bloom_filter;
+    }
+    if opt.replica.is_some() {
+        config.storage.replica = opt.replica.clone();
+    }
+    if let Some(format) = opt.errors_format {
+        config.logging.errors_format = format;
+    }
+}
+
+/// Loads the configuration, then applies the options given before the subcommand and those
+/// given after it, in that order.
+fn load_config(global: &EngineOpt, local: Option<&EngineOpt>) -> anyhow::Result<Config> {
+    let path = local
+        .and_then(|local| local.config.as_deref())
+        .or(global.config.as_deref());
+    let mut config = Config::load(path)?;
+    apply_cli(global, &mut config);
+    if let Some(local) = local {
+        apply_cli(local, &mut config);
+    }
+    config.validate()?;
+    Ok(config)
+}
+
+/// Sets a flag once SIGINT or SIGTERM is received.
+fn interrupt_flag() -> anyhow::Result<Arc<AtomicBool>> {
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let handler_flag = Arc::clone(&interrupted);
+    ctrlc::set_handler(move || handler_flag.store(true, Ordering::SeqCst))?;
+    Ok(interrupted)
+}
+
+fn process_dir(
+    dir: &Path,
+    order: FileOrder,
+    move_processed: bool,
+    mut payment_engine: PaymentEngine,
+    publisher: Option<AccountPublisher>,
+    rules: Option<&Rules>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let interrupted = interrupt_flag()?;
+    let processed_dir = dir.join("processed");
+    for path in discover_files(dir, order)? {
+        log::info!("processing {}", path.display());
+        let stop = Arc::clone(&interrupted);
+        let options = ReadOptions {
+            precision: config.precision_policy(),
+            format: InputFormat::Csv,
+            accounts: AccountMap::default(),
+            dialect: config.csv_dialect(),
+            pipeline: false,
+            fast_io: false,
+            as_of: None,
+            as_of_time: None,
+        };
+        let transactions = read_transactions(path.clone(), InputOffset::default(), options)?
+            .take_while(move |_| !stop.load(Ordering::SeqCst));
+        apply_all(&mut payment_engine, transactions, |_, _, _| {});
+        // a partially processed file stays where it is
+        if interrupted.load(Ordering::SeqCst) {
+            break;
+        }
+        if move_processed {
+            fs::create_dir_all(&processed_dir)?;
+            if let Some(name) = path.file_name() {
+                fs::rename(&path, processed_dir.join(name))?;
+            }
+        }
+    }
+
+    let options = config.export_options().with_profiles(rules);
+    print_accounts(config, payment_engine.accounts_iter(), &[], &options);
+    finish_publishing(publisher.as_ref());
+    if interrupted.load(Ordering::SeqCst) {
+        report(ErrorEvent::new(
+            "interrupted",
+            "",
+            "interrupted, the account report is incomplete",
+        ));
+        process::exit(EXIT_PARTIAL);
+    }
+    Ok(())
+}
+
+/// How often a watched file is checked for new records.
+const WATCH_POLL: Duration = Duration::from_millis(200);
+
+fn watch(
+    mut tail: TailReader,
+    interval: Duration,
+    mut payment_engine: PaymentEngine,
+    publisher: Option<AccountPublisher>,
+    precision: PrecisionPolicy,
+    export_options: ExportOptions,
+    mut checkpointer: Option<Checkpointer>,
+) -> anyhow::Result<()> {
+    let interrupted = interrupt_flag()?;
+    let mut last_report = Instant::now();
+    let mut changed = false;
+    while !interrupted.load(Ordering::SeqCst) {
+        let records = tail.poll()?;
+        if records.is_empty() {
+            thread::sleep(WATCH_POLL);
+        }
+        if let Some(checkpointer) = &mut checkpointer {
+            checkpointer.record(records.len() as u64);
+        }
+        for record in records {
+            changed = true;
+            let line = record.line();
+            if let Some(err) = record.signature_error() {
+                report_dead_letter(unverified(&record, err), None, None);
+                continue;
+            }
+            if let Some(batch) = record.batch_id() {
+                reject_batched(line, batch, "while watching", None);
+                continue;
+            }
+            match record.into_transaction(&precision) {
+                Ok(transaction) => apply(&mut payment_engine, transaction, line),
+                Err(err) => {
+                    report_dead_letter(ErrorEvent::unparsable(&err).with_line(line), None, None)
+                }
+            }
+        }
+        if let Some(checkpointer) = &mut checkpointer {
+            if checkpointer.is_due() {
+                checkpointer.checkpoint(&payment_engine, tail.offset());
+            }
+        }
+        if changed && last_report.elapsed() >= interval {
+            if let Err(err) = write_accounts(
+                payment_engine.accounts_iter(),
+                io::stdout(),
+                &export_options,
+            ) {
+                report(ErrorEvent::new(
+                    "write_failed",
+                    "unable to write report",
+                    err,
+                ));
             }
+            changed = false;
+            last_report = Instant::now();
         }
     }
-    if let Err(err) = accounts_info_as_csv(payment_engine.get_accounts(), io::stdout()) {
-        log::warn!("unable to write csv: {}", err);
+    finish_publishing(publisher.as_ref());
+    if let Some(checkpointer) = checkpointer {
+        checkpointer.finish(&payment_engine, tail.offset());
+    }
+    if !changed {
+        return Ok(());
+    }
+    if let Err(err) = write_accounts(
+        payment_engine.accounts_iter(),
+        io::stdout(),
+        &export_options,
+    ) {
+        report(ErrorEvent::new(
+            "write_failed",
+            "unable to write report",
+            err,
+        ));
+    }
+    Ok(())
+}
+
+/// Processes the records of every tenant with an engine of its own and writes one account
+/// report per tenant to `dir`.
+fn process_tenants(input_path: &Path, dir: &Path, config: &Config) -> anyhow::Result<()> {
+    let rules = config.load_rules()?;
+    let interrupted = interrupt_flag()?;
+    let stop = Arc::clone(&interrupted);
+    let mut tenants = TenantManager::with_engines(|| config.engine(rules.as_ref()));
+    let mut transactions = read_transactions(
+        input_path.to_path_buf(),
+        InputOffset::default(),
+        ReadOptions::new(config)?,
+    )?
+    .take_while(move |_| !stop.load(Ordering::SeqCst))
+    .peekable();
+    while let Some(input) = transactions.next() {
+        let tenant = input.tenant.clone();
+        let mut records = vec![input];
+        while let Some(input) = transactions.next_if(|next| next.tenant == tenant) {
+            records.push(input);
+        }
+        // a batch is applied by the engine of a single tenant
+        if let (Some(last), Some(next)) = (
+            records.last().and_then(|input| input.batch.as_ref()),
+            transactions.peek().and_then(|input| input.batch.as_ref()),
+        ) {
+            if Arc::ptr_eq(last, next) {
+                last.invalidate();
+            }
+        }
+        let engine = match tenant.as_deref().map(|tenant| tenants.engine_mut(tenant)) {
+            Some(Ok(engine)) => engine,
+            Some(Err(err)) => {
+                for input in records {
+                    let transaction = &input.transaction;
+                    report(
+                        ErrorEvent::new("invalid_tenant", "unable to process transaction", &err)
+                            .with_transaction(transaction.client(), transaction.tx())
+                            .with_line(input.line),
+                    );
+                }
+                continue;
+            }
+            None => {
+                for input in records {
+                    let transaction = &input.transaction;
+                    report(
+                        ErrorEvent::new(
+                            "missing_tenant",
+                            "unable to process transaction",
+                            "the record has no tenant",
+                        )
+                        .with_transaction(transaction.client(), transaction.tx())
+                        .with_line(input.line),
+                    );
+                }
+                continue;
+            }
+        };
+        apply_all(engine, records.into_iter(), |_, _, _| {});
+    }
+
+    fs::create_dir_all(dir)?;
+    let options = config.export_options().with_profiles(rules.as_ref());
+    let extension = match options.format {
+        OutputFormat::Parquet => "parquet",
+        _ => "csv",
+    };
+    for (tenant, engine) in tenants.iter() {
+        let path = dir.join(format!("{}.{}", tenant, extension));
+        let written = fs::File::create(&path)
+            .map_err(|err| err.into())
+            .and_then(|file| {
+                write_report(
+                    merged_accounts(std::slice::from_ref(engine)),
+                    &[],
+                    io::BufWriter::new(file),
+                    &options,
+                )
+            });
+        if let Err(err) = written {
+            report(ErrorEvent::new(
+                "write_failed",
+                "unable to write tenant report",
+                err,
+            ));
+        }
+    }
+    log::info!(
+        "wrote the reports of {} tenants to {}",
+        tenants.len(),
+        dir.display()
+    );
+    if interrupted.load(Ordering::SeqCst) {
+        report(ErrorEvent::new(
+            "interrupted",
+            "",
+            "interrupted, the tenant reports are incomplete",
+        ));
+        process::exit(EXIT_PARTIAL);
+    }
+    Ok(())
+}
+
+fn process_file(
+    input_path: &Path,
+    config: &Config,
+    resume: bool,
+    verify_invariants: bool,
+    tui: bool,
+) -> anyhow::Result<()> {
+    let manifest = match &config.input.manifest {
+        Some(path) => Some(verify_manifest(input_path, path, config)?),
+        None => None,
+    };
+    if let Some(dir) = &config.output.tenant_reports {
+        if verify_invariants || tui {
+            anyhow::bail!(
+                "--verify-invariants and --tui follow a single engine, they can't be combined \
+                 with --tenant-reports"
+            );
+        }
+        process_tenants(input_path, dir, config)?;
+        check_unchanged(input_path, manifest.as_ref(), config);
+        return Ok(());
+    }
+    open_dead_letters(config)?;
+    let rules = config.load_rules()?;
+    // only workbooks list rejected transactions, there's no need to collect them otherwise
+    let rejections = RejectionLog::default();
+    let suspicious = SuspiciousActivityLog::default();
+    let publisher = config.publisher()?;
+    let make_engine = || {
+        let mut engine = config.engine(rules.as_ref());
+        if config.output.format == OutputFormat::Xlsx {
+            engine.subscribe(Box::new(rejections.clone()));
+        }
+        if config.output.suspicious_activity.is_some() {
+            engine.subscribe(Box::new(suspicious.clone()));
+        }
+        if let Some(publisher) = &publisher {
+            engine.subscribe(Box::new(publisher.clone()));
+        }
+        engine
+    };
+    let workers = config.storage.workers;
+    // SIGINT/SIGTERM stop ingesting, the report then covers what was processed so far
+    let interrupted = interrupt_flag()?;
+    let stop = Arc::clone(&interrupted);
+
+    if resume && config.storage.snapshot.is_none() {
+        anyhow::bail!("--resume needs a --snapshot file");
+    }
+    if resume && config.input.as_of.is_some() {
+        anyhow::bail!("--as-of counts records from the start of the input, it can't --resume");
+    }
+    if tui && workers > 1 {
+        anyhow::bail!("--tui follows a single engine, it can't be combined with --workers");
+    }
+    if verify_invariants && config.storage.mode == StorageMode::Compact {
+        anyhow::bail!(
+            "--verify-invariants needs every withdrawal, which compact mode doesn't keep"
+        );
+    }
+    if config.storage.snapshot.is_some()
+        && (workers > 1
+            || config.input.fast_io
+            || config.input.format != InputFormat::Csv
+            || is_remote(&input_path.to_string_lossy()))
+    {
+        anyhow::bail!(
+            "--snapshot can't be combined with --workers, --fast-io, non-csv formats or remote \
+             inputs"
+        );
+    }
+    let mut payment_engine = make_engine();
+    let progress = if tui {
+        Some(dashboard(&mut payment_engine)?)
+    } else {
+        None
+    };
+    let mut start = InputOffset::default();
+    if let (true, Some(path)) = (resume, &config.storage.snapshot) {
+        if path.exists() {
+            let snapshot = load_state(path, config)?;
+            if snapshot.replica.is_some() && snapshot.replica != config.storage.replica {
+                anyhow::bail!(
+                    "{} was taken by replica {}, resume it with the same --replica",
+                    path.display(),
+                    snapshot.replica.unwrap_or_default()
+                );
+            }
+            payment_engine.restore(snapshot.engine);
+            start = snapshot.offset;
+        } else {
+            report(ErrorEvent::new(
+                "snapshot_missing",
+                "",
+                "no snapshot to resume from, starting from the beginning",
+            ));
+        }
+    }
+    let key = if config.encryption.snapshots {
+        config.encryption_key()?
+    } else {
+        None
+    };
+    let snapshots = config.storage.snapshot.clone().map(|path| SnapshotOptions {
+        path,
+        every: config.storage.snapshot_every.max(1),
+        replica: config.storage.replica.clone(),
+        key,
+    });
+
+    let mut dedupe = match (config.input.skip_duplicates, &config.storage.dedupe_index) {
+        (true, Some(path)) => Some(DedupeIndex::load(path)?),
+        (true, None) => Some(DedupeIndex::default()),
+        (false, _) => None,
+    };
+    let mut replays = 0;
+
+    let recurrences = match &config.input.recurring {
+        Some(path) => read_recurrences(path, &config.precision_policy())?,
+        None => vec![],
+    };
+    let transactions = recurrences
+        .into_iter()
+        .chain(read_transactions(
+            input_path.to_path_buf(),
+            start,
+            ReadOptions::new(config)?,
+        )?)
+        .take_while(move |_| !stop.load(Ordering::SeqCst))
+        .filter(|input| {
+            dedupe
+                .as_mut()
+                .is_none_or(|index| admit(index, input, &mut replays))
+        });
+    let mut engines = if workers > 1 {
+        process_in_parallel(transactions, workers, make_engine)
+    } else {
+        vec![process(
+            transactions,
+            payment_engine,
+            start,
+            snapshots,
+            progress,
+        )]
+    };
+    if let Some(path) = &config.input.corrections {
+        apply_corrections(&mut engines, path, config)?;
+    }
+    let options = config.export_options().with_profiles(rules.as_ref());
+    print_accounts(
+        config,
+        merged_accounts(&engines),
+        &rejections.rejections(),
+        &options,
+    );
+    write_history(merged_transactions(&engines), &options, config);
+    write_client_statements(&engines, config);
+    write_journal(&engines, config);
+    write_period_statements(&engines, &options, config);
+    write_suspicious_activity(&suspicious, &options, config);
+    write_recovery_report(&engines, &options, config);
+    finish_publishing(publisher.as_ref());
+    if let Some(index) = dedupe {
+        log::info!("skipped {} replayed records", replays);
+        if let Some(path) = &config.storage.dedupe_index {
+            if let Err(err) = index.save(path) {
+                report(ErrorEvent::new(
+                    "dedupe_index_failed",
+                    "unable to save dedupe index",
+                    err,
+                ));
+            }
+        }
+    }
+    check_unchanged(input_path, manifest.as_ref(), config);
+    if verify_invariants && !verify(&engines) {
+        process::exit(EXIT_INVARIANT);
+    }
+    if interrupted.load(Ordering::SeqCst) {
+        report(ErrorEvent::new(
+            "interrupted",
+            "",
+            "interrupted, the account report is incomplete",
+        ));
+        process::exit(EXIT_PARTIAL);
+    }
+    Ok(())
+}
+
+/// Checks the input against the manifest at `path` and returns the manifest.
+fn verify_manifest(input_path: &Path, path: &Path, config: &Config) -> anyhow::Result<Manifest> {
+    if is_remote(&input_path.to_string_lossy()) {
+        anyhow::bail!("--manifest needs a local input file");
+    }
+    let manifest = Manifest::load(path)
+        .map_err(|err| anyhow::anyhow!("unable to read {}: {}", path.display(), err))?;
+    let actual = Manifest::of_file(input_path, &config.csv_dialect())?;
+    manifest.check(&actual).map_err(|err| {
+        anyhow::anyhow!(
+            "{} doesn't match {}: {}",
+            input_path.display(),
+            path.display(),
+            err
+        )
+    })?;
+    Ok(manifest)
+}
+
+/// Exits with `EXIT_CHANGED` when the input no longer matches `manifest` after processing, so
+/// that the outputs aren't trusted.
+fn check_unchanged(input_path: &Path, manifest: Option<&Manifest>, config: &Config) {
+    let Some(manifest) = manifest else {
+        return;
+    };
+    let unchanged = Manifest::of_file(input_path, &config.csv_dialect())
+        .map_err(|err| err.to_string())
+        .and_then(|actual| manifest.check(&actual).map_err(|err| err.to_string()));
+    if let Err(reason) = unchanged {
+        report(ErrorEvent::new(
+            "input_changed",
+            "input changed while it was processed",
+            reason,
+        ));
+        process::exit(EXIT_CHANGED);
+    }
+}
+
+/// Background snapshots of `serve` and `watch`, when a snapshot file is configured.
+fn checkpointer(config: &Config) -> Option<Checkpointer> {
+    let path = config.storage.snapshot.clone()?;
+    let save_config = config.clone();
+    let checkpointer = Checkpointer::start(
+        path,
+        config.storage.keep_snapshots,
+        config.storage.replica.clone(),
+        Box::new(move |path, snapshot| save_state(path, snapshot, &save_config)),
+    )
+    .every(config.storage.snapshot_every);
+    Some(match config.storage.snapshot_interval_secs {
+        Some(seconds) => checkpointer.with_interval(Duration::from_secs(seconds)),
+        None => checkpointer,
+    })
+}
+
+/// Loads the snapshot at `path`, decrypting it with the configured key when it is encrypted.
+fn load_state(path: &Path, config: &Config) -> anyhow::Result<Snapshot> {
+    load_snapshot_with_key(path, config.encryption_key()?.as_ref())
+}
+
+/// Saves `snapshot` at `path`, encrypted when `[encryption] snapshots` is set.
+fn save_state(path: &Path, snapshot: &Snapshot, config: &Config) -> anyhow::Result<()> {
+    match config.encryption_key()? {
+        Some(key) if config.encryption.snapshots => save_encrypted_snapshot(path, snapshot, &key),
+        _ => save_snapshot(path, snapshot),
+    }
+}
+
+/// Writes the account report `write` produces to stdout, encrypted when `[encryption] reports`
+/// is set.
+fn print_report<F>(config: &Config, write: F)
+where
+    F: FnOnce(&mut (dyn Write + Send)) -> Result<(), Box<dyn std::error::Error>>,
+{
+    let written = if config.encryption.reports {
+        let mut plaintext = vec![];
+        write(&mut plaintext).and_then(|()| {
+            let key = config
+                .encryption_key()?
+                .expect("validated to be configured");
+            io::stdout().write_all(&key.encrypt(&plaintext)?)?;
+            Ok(())
+        })
+    } else {
+        write(&mut io::stdout())
+    };
+    if let Err(err) = written {
+        report(ErrorEvent::new(
+            "write_failed",
+            "unable to write report",
+            err,
+        ));
+    }
+}
+
+/// Prints the account report, or writes it as `[output] shards` files when configured.
+fn print_accounts<'a, I>(
+    config: &Config,
+    accounts: I,
+    rejections: &[Rejection],
+    options: &ExportOptions,
+) where
+    I: IntoIterator<Item = &'a Account>,
+{
+    let (Some(shards), Some(dir)) = (config.output.shards, &config.output.shard_dir) else {
+        print_report(config, |output| {
+            write_report(accounts, rejections, output, options)
+        });
+        return;
+    };
+    let accounts: Vec<&Account> = accounts.into_iter().collect();
+    match write_shards(&accounts, shards, dir, options) {
+        Ok(written) => log::info!(
+            "wrote {} accounts in {} shards to {}",
+            accounts.len(),
+            written.len(),
+            dir.display()
+        ),
+        Err(err) => report(ErrorEvent::new(
+            "write_failed",
+            "unable to write sharded report",
+            err,
+        )),
+    }
+}
+
+/// Reports every client whose funds don't match its transactions, returns whether all do.
+fn verify(engines: &[PaymentEngine]) -> bool {
+    let discrepancies = verify_funds(
+        merged_accounts(engines),
+        merged_transactions(engines),
+        engines.iter().flat_map(|engine| engine.tombstones()),
+        engines.iter().flat_map(|engine| engine.pruned_funds()),
+    );
+    for discrepancy in &discrepancies {
+        let event = ErrorEvent::new("invariant_violated", "funds don't add up", discrepancy);
+        report(match discrepancy.client {
+            Some(client) => event.with_client(client),
+            None => event,
+        });
+    }
+    discrepancies.is_empty()
+}
+
+/// Purges `client` from the configured snapshot, which is rewritten in place.
+fn purge_client(client: Client, config: &Config) -> anyhow::Result<()> {
+    let path = config
+        .storage
+        .snapshot
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("purge-client needs a --snapshot file"))?;
+    let mut snapshot = load_state(path, config)?;
+    let purged_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    let tombstone = snapshot
+        .engine
+        .purge_client(client, purged_at)
+        .ok_or_else(|| anyhow::anyhow!("client {} is not in {}", client, path.display()))?;
+    save_state(path, &snapshot, config)?;
+    let mut output = io::stdout().lock();
+    serde_json::to_writer(&mut output, &tombstone)?;
+    writeln!(output)?;
+    Ok(())
+}
+
+/// Drops the transactions `retention` selects from the configured snapshot, which is
+/// rewritten in place.
+fn prune(retention: Retention, config: &Config) -> anyhow::Result<()> {
+    if retention == Retention::default() {
+        anyhow::bail!("nothing to prune, pass --older-than or --resolved");
+    }
+    let path = config
+        .storage
+        .snapshot
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("prune needs a --snapshot file"))?;
+    let snapshot = load_state(path, config)?;
+    let mut payment_engine = PaymentEngine::new();
+    payment_engine.restore(snapshot.engine);
+    let pruned = payment_engine.prune(retention);
+    save_state(
+        path,
+        &Snapshot {
+            replica: snapshot.replica,
+            offset: snapshot.offset,
+            engine: payment_engine.state(),
+        },
+        config,
+    )?;
+    let mut output = io::stdout().lock();
+    serde_json::to_writer(
+        &mut output,
+        &serde_json::json!({
+            "pruned": pruned,
+            "kept": payment_engine.transactions_iter().count(),
+        }),
+    )?;
+    writeln!(output)?;
+    Ok(())
+}
+
+/// A local file or remote object.
+fn open_input(input_path: &Path) -> anyhow::Result<Box<dyn io::Read + Send>> {
+    let location = input_path.to_string_lossy();
+    if is_remote(&location) {
+        open_remote(&location)
+    } else {
+        Ok(Box::new(fs::File::open(input_path)?))
+    }
+}
+
+fn validate(input_path: &Path, config: &Config) -> anyhow::Result<()> {
+    let input = open_input(input_path)?;
+    let precision = config.precision_policy();
+    let invalid = match config.input.format {
+        InputFormat::Csv => check_records(input, &precision, &config.csv_dialect())?,
+        format => check_statement(input, format, &config.account_map()?, &precision)?,
+    };
+    let mut wtr = csv::Writer::from_writer(io::stdout());
+    wtr.write_record(["line", "reason"])?;
+    for record in &invalid {
+        wtr.write_record([record.line.to_string(), record.reason.clone()])?;
+    }
+    wtr.flush()?;
+    if !invalid.is_empty() {
+        process::exit(EXIT_INVALID);
+    }
+    Ok(())
+}
+
+fn split_input(
+    input_path: &Path,
+    partitions: u32,
+    out_dir: &Path,
+    config: &Config,
+) -> anyhow::Result<()> {
+    if partitions == 0 {
+        anyhow::bail!("--partitions must be at least 1");
+    }
+    if config.input.format != InputFormat::Csv {
+        anyhow::bail!("only csv inputs can be split");
+    }
+    let stem = input_path
+        .file_stem()
+        .map_or("input".into(), |stem| stem.to_string_lossy());
+    fs::create_dir_all(out_dir)?;
+    let paths: Vec<PathBuf> = (0..partitions)
+        .map(|partition| out_dir.join(format!("{}.{}.csv", stem, partition)))
+        .collect();
+    let mut shards = paths
+        .iter()
+        .map(|path| Ok(io::BufWriter::new(fs::File::create(path)?)))
+        .collect::<io::Result<Vec<_>>>()?;
+    let summary = split(open_input(input_path)?, &config.csv_dialect(), &mut shards)?;
+    for shard in shards.iter_mut() {
+        shard.flush()?;
+    }
+    for record in &summary.invalid {
+        report(
+            ErrorEvent::new("invalid_record", "unable to split record", &record.reason)
+                .with_line(Some(record.line)),
+        );
+    }
+
+    let mut wtr = csv::Writer::from_writer(io::stdout());
+    wtr.write_record(["partition", "path", "records"])?;
+    for (partition, (path, records)) in paths.iter().zip(&summary.records).enumerate() {
+        wtr.write_record([
+            partition.to_string(),
+            path.display().to_string(),
+            records.to_string(),
+        ])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+fn stats(
+    input_path: Option<&Path>,
+    by_merchant: bool,
+    group_by: Option<PeriodLength>,
+    top: Option<(usize, AccountRanking)>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    if by_merchant && config.storage.mode == StorageMode::Compact {
+        anyhow::bail!("--by-merchant needs every withdrawal, it can't be used in compact mode");
+    }
+    if by_merchant && group_by.is_some() {
+        anyhow::bail!("--by-merchant and --group-by can't be combined");
+    }
+    if group_by.is_some() && config.storage.mode == StorageMode::Compact {
+        anyhow::bail!("--group-by reads the journal, which isn't kept in compact mode");
+    }
+    if top.is_some() && (by_merchant || group_by.is_some()) {
+        anyhow::bail!("--top can't be combined with --by-merchant or --group-by");
+    }
+    let rules = config.load_rules()?;
+    let make_engine = || {
+        let mut engine = config.engine(rules.as_ref());
+        engine.keep_journal(group_by.is_some());
+        engine
+    };
+    let engines = match input_path {
+        Some(input_path) => {
+            let transactions = read_transactions(
+                input_path.to_path_buf(),
+                InputOffset::default(),
+                ReadOptions::new(config)?,
+            )?;
+            if config.storage.workers > 1 {
+                process_in_parallel(transactions, config.storage.workers, make_engine)
+            } else {
+                let mut payment_engine = make_engine();
+                apply_all(&mut payment_engine, transactions, |_, _, _| {});
+                vec![payment_engine]
+            }
+        }
+        None => {
+            let path = config.storage.snapshot.as_ref().ok_or_else(|| {
+                anyhow::anyhow!("stats needs an input file or a --snapshot to read")
+            })?;
+            let mut payment_engine = make_engine();
+            payment_engine.restore(load_state(path, config)?.engine);
+            vec![payment_engine]
+        }
+    };
+    let written = if let Some((count, by)) = top {
+        let mut options = config.export_options().with_profiles(rules.as_ref());
+        // the ranked figure is part of the report
+        options.extended |= by == AccountRanking::Chargebacks;
+        write_accounts(
+            top_accounts(merged_accounts(&engines), by, count),
+            io::stdout(),
+            &options,
+        )
+    } else if by_merchant {
+        let stats = merchant_stats(engines.iter().flat_map(PaymentEngine::transactions_iter));
+        merchant_stats_as_csv(&stats, io::stdout(), &config.export_options())
+    } else if let Some(length) = group_by {
+        let activity = period_activity(merged_journal(&engines), length);
+        period_activity_as_csv(&activity, io::stdout(), &config.export_options())
+    } else {
+        metrics_as_csv(
+            &merged_metrics(&engines),
+            io::stdout(),
+            &config.export_options(),
+        )
+    };
+    if let Err(err) = written {
+        report(ErrorEvent::new("write_failed", "unable to write csv", err));
+    }
+    Ok(())
+}
+
+fn merge(snapshots: &[PathBuf], config: &Config) -> anyhow::Result<()> {
+    let rules = config.load_rules()?;
+    let mut payment_engine = config.engine(rules.as_ref());
+    for path in snapshots {
+        let mut engine = config.engine(rules.as_ref());
+        engine.restore(load_state(path, config)?.engine);
+        payment_engine
+            .merge(engine)
+            .map_err(|err| anyhow::anyhow!("unable to merge {}: {}", path.display(), err))?;
+    }
+    let options = config.export_options().with_profiles(rules.as_ref());
+    print_accounts(config, payment_engine.accounts_iter(), &[], &options);
+    write_history(payment_engine.transactions_iter(), &options, config);
+    write_client_statements(std::slice::from_ref(&payment_engine), config);
+    write_journal(std::slice::from_ref(&payment_engine), config);
+    write_period_statements(std::slice::from_ref(&payment_engine), &options, config);
+    Ok(())
+}
+
+/// Opens the `dead_letters` queue, if one is configured.
+fn open_dead_letters(config: &Config) -> anyhow::Result<()> {
+    if let Some(path) = &config.output.dead_letters {
+        dlq::open(path)
+            .map_err(|err| anyhow::anyhow!("unable to open {}: {}", path.display(), err))?;
+    }
+    Ok(())
+}
+
+/// Applies the dead letters at `path` to the configured snapshot, both rewritten in place, and
+/// prints the account report.
+fn retry_dead_letters(path: &Path, config: &Config) -> anyhow::Result<()> {
+    let snapshot_path = config
+        .storage
+        .snapshot
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("retry-dlq needs the --snapshot file of the run"))?;
+    let rules = config.load_rules()?;
+    let mut snapshot = load_state(snapshot_path, config)?;
+    let mut payment_engine = config.engine(rules.as_ref());
+    payment_engine.restore(snapshot.engine);
+    let letters = read_dead_letters(path)?;
+    let count = letters.len();
+    let failing = dlq::retry(&mut payment_engine, letters);
+    log::info!(
+        "applied {} of {} dead letters, {} still failing",
+        count - failing.len(),
+        count,
+        failing.len()
+    );
+    snapshot.engine = payment_engine.state();
+    save_state(snapshot_path, &snapshot, config)?;
+    write_dead_letters(path, &failing)?;
+    let options = config.export_options().with_profiles(rules.as_ref());
+    print_accounts(config, payment_engine.accounts_iter(), &[], &options);
+    Ok(())
+}
+
+/// Merges the accounts of the snapshots of replicas, in any order and with any snapshot
+/// repeated, and prints the account report.
+fn merge_replicas(snapshots: &[PathBuf], config: &Config) -> anyhow::Result<()> {
+    if config.output.transactions.is_some()
+        || config.output.statements.is_some()
+        || config.output.journal.is_some()
+        || config.output.period_statements.is_some()
+    {
+        anyhow::bail!(
+            "replicas only merge accounts, transactions, statements and journals can't be written"
+        );
+    }
+    let mut merged = ReplicatedState::default();
+    for path in snapshots {
+        let snapshot = load_state(path, config)?;
+        let replica = snapshot.replica.ok_or_else(|| {
+            anyhow::anyhow!("{} wasn't taken by a --replica engine", path.display())
+        })?;
+        merged.merge(&ReplicatedState::of(&replica, &snapshot.engine.accounts));
+    }
+    let mut payment_engine = PaymentEngine::new();
+    payment_engine.restore(EngineState {
+        accounts: merged.account_states(),
+        ..EngineState::default()
+    });
+    print_accounts(
+        config,
+        payment_engine.accounts_iter(),
+        &[],
+        &config.export_options(),
+    );
+    Ok(())
+}
+
+fn serve(
+    listen: &str,
+    redis: Option<(&str, &str)>,
+    config: &Config,
+    resume: bool,
+) -> anyhow::Result<()> {
+    if redis.is_some() && config.output.anonymize {
+        anyhow::bail!("--redis keys accounts by client id, it can't be combined with --anonymize");
+    }
+    let rules = config.load_rules()?;
+    let interrupted = interrupt_flag()?;
+    let mut payment_engine = config.engine(rules.as_ref());
+    if let (true, Some(path)) = (resume, &config.storage.snapshot) {
+        if path.exists() {
+            payment_engine.restore(load_state(path, config)?.engine);
+        }
+    }
+    let publisher = config.publisher()?;
+    if let Some(publisher) = &publisher {
+        payment_engine.subscribe(Box::new(publisher.clone()));
+    }
+    if let Some((address, prefix)) = redis {
+        payment_engine.subscribe(Box::new(RedisCache::new(address, prefix)));
+    }
+    let webhooks = config.webhooks()?;
+    if let Some(webhooks) = &webhooks {
+        payment_engine.subscribe(Box::new(webhooks.clone()));
+    }
+    let mut server = Server::bind(
+        listen,
+        payment_engine,
+        config.precision_policy(),
+        config.export_options().with_profiles(rules.as_ref()),
+    )?
+    .with_auth(config.authenticator()?)
+    .with_limits(config.server_limits());
+    if let Some((address, _)) = redis {
+        let address = address.to_string();
+        server = server.with_readiness_check(
+            "redis",
+            Box::new(move || RedisCache::ping(&address).map_err(|err| err.to_string())),
+        );
+    }
+    if let Some(checkpointer) = checkpointer(config) {
+        server = server.with_snapshots(checkpointer);
+    }
+    if let Some(address) = server.local_addr() {
+        log::info!("listening on {}", address);
+    }
+    let payment_engine = server.run(&interrupted)?;
+    finish_publishing(publisher.as_ref());
+    // notifications still waiting are delivered or dead-lettered before exiting
+    if let Some(webhooks) = &webhooks {
+        webhooks.finish();
+    }
+    // the final state is printed like at the end of any other run
+    print_accounts(
+        config,
+        payment_engine.accounts_iter(),
+        &[],
+        &config.export_options(),
+    );
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    let opt = Opt::from_args();
+    let local = opt.cmd.as_ref().and_then(Command::engine_options);
+    let config = load_config(&opt.engine, local)?;
+    // RUST_LOG still takes precedence over the configured level
+    let level = config.logging.level.as_deref().unwrap_or("error");
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(level)).init();
+    set_error_format(config.logging.errors_format);
+    set_anonymizer(config.anonymizer());
+    let resume = opt.engine.resume || local.is_some_and(|local| local.resume);
+    let verify_invariants =
+        opt.engine.verify_invariants || local.is_some_and(|local| local.verify_invariants);
+    let tui = opt.engine.tui || local.is_some_and(|local| local.tui);
+
+    match (&opt.cmd, &opt.input_path) {
+        (Some(Command::Process { input_path, .. }), _) | (None, Some(input_path)) => {
+            process_file(input_path, &config, resume, verify_invariants, tui)?
+        }
+        (Some(Command::Validate { input_path, .. }), _) => validate(input_path, &config)?,
+        (
+            Some(Command::Serve {
+                listen,
+                redis,
+                redis_prefix,
+                ..
+            }),
+            _,
+        ) => {
+            let redis = redis
+                .as_deref()
+                .map(|address| (address, redis_prefix.as_str()));
+            serve(listen, redis, &config, resume)?
+        }
+        (
+            Some(Command::Gen {
+                clients,
+                transactions,
+                seed,
+            }),
+            _,
+        ) => {
+            let options = GeneratorOptions {
+                clients: *clients,
+                transactions: *transactions,
+                seed: *seed,
+            };
+            if let Err(err) = generate(&options, io::stdout()) {
+                report(ErrorEvent::new("write_failed", "unable to write csv", err));
+            }
+        }
+        (
+            Some(Command::Stats {
+                input_path,
+                by_merchant,
+                group_by,
+                top,
+                by,
+                ..
+            }),
+            _,
+        ) => stats(
+            input_path.as_deref(),
+            *by_merchant,
+            *group_by,
+            top.map(|count| (count, by.unwrap_or_default())),
+            &config,
+        )?,
+        (
+            Some(Command::Split {
+                input_path,
+                partitions,
+                out_dir,
+                ..
+            }),
+            _,
+        ) => split_input(input_path, *partitions, out_dir, &config)?,
+        (
+            Some(Command::Merge {
+                snapshots,
+                replicated: true,
+                ..
+            }),
+            _,
+        ) => merge_replicas(snapshots, &config)?,
+        (Some(Command::Merge { snapshots, .. }), _) => merge(snapshots, &config)?,
+        (Some(Command::PurgeClient { client, .. }), _) => purge_client(*client, &config)?,
+        (Some(Command::RetryDlq { path, .. }), _) => retry_dead_letters(path, &config)?,
+        (
+            Some(Command::Prune {
+                older_than,
+                resolved,
+                ..
+            }),
+            _,
+        ) => {
+            let retention = Retention {
+                older_than: older_than.or(config.storage.prune_older_than),
+                resolved: *resolved || config.storage.prune_resolved,
+            };
+            prune(retention, &config)?
+        }
+        (Some(Command::Schema { document, .. }), _) => {
+            let precision = config.precision_policy();
+            let options = config.export_options();
+            let schema = match document.as_str() {
+                "record" => transaction_record_schema(&precision),
+                "report" => account_report_schema(&options),
+                _ => openapi(&precision, &options),
+            };
+            let mut output = io::stdout().lock();
+            serde_json::to_writer_pretty(&mut output, &schema)?;
+            writeln!(output)?;
+        }
+        (
+            Some(Command::Reconcile {
+                engine_output,
+                bank_statement,
+                tolerance,
+            }),
+            _,
+        ) => {
+            let accounts = parse_accounts_from_file(engine_output)?;
+            let balances = parse_balances_from_file(bank_statement)?;
+            let discrepancies = reconcile(&accounts, &balances, *tolerance);
+            if let Err(err) = discrepancies_as_csv(discrepancies, io::stdout()) {
+                report(ErrorEvent::new("write_failed", "unable to write csv", err));
+            }
+        }
+        (Some(Command::Decrypt { path, .. }), _) => {
+            let key = config.encryption_key()?.ok_or_else(|| {
+                anyhow::anyhow!("decrypt needs a key, set PAYMENTS_ENCRYPTION_KEY or --key-file")
+            })?;
+            let plaintext = key.decrypt(&fs::read(path)?)?;
+            io::stdout().write_all(&plaintext)?;
+        }
+        (Some(Command::Diff { old, new }), _) => {
+            let old = parse_report_from_file(old)?;
+            let new = parse_report_from_file(new)?;
+            let diffs = diff_reports(&old, &new);
+            if let Err(err) = diffs_as_csv(&diffs, io::stdout()) {
+                report(ErrorEvent::new("write_failed", "unable to write csv", err));
+            }
+            if !diffs.is_empty() {
+                process::exit(EXIT_DIFFERENT);
+            }
+        }
+        (
+            Some(Command::ProcessDir {
+                dir,
+                order,
+                move_processed,
+                ..
+            }),
+            _,
+        ) => {
+            open_dead_letters(&config)?;
+            let rules = config.load_rules()?;
+            let publisher = config.publisher()?;
+            let mut payment_engine = config.engine(rules.as_ref());
+            if let Some(publisher) = &publisher {
+                payment_engine.subscribe(Box::new(publisher.clone()));
+            }
+            process_dir(
+                dir,
+                *order,
+                *move_processed,
+                payment_engine,
+                publisher,
+                rules.as_ref(),
+                &config,
+            )?
+        }
+        (
+            Some(Command::Watch {
+                input_path,
+                interval,
+                ..
+            }),
+            _,
+        ) => {
+            if config.encryption.reports {
+                anyhow::bail!("watch prints a report after every change, they can't be encrypted");
+            }
+            open_dead_letters(&config)?;
+            let rules = config.load_rules()?;
+            let publisher = config.publisher()?;
+            let mut payment_engine = config.engine(rules.as_ref());
+            if let Some(publisher) = &publisher {
+                payment_engine.subscribe(Box::new(publisher.clone()));
+            }
+            let mut tail = TailReader::open(input_path, &config.csv_dialect())?;
+            if let (true, Some(path)) = (resume, &config.storage.snapshot) {
+                if path.exists() {
+                    let snapshot = load_state(path, &config)?;
+                    payment_engine.restore(snapshot.engine);
+                    tail.seek(snapshot.offset)?;
+                }
+            }
+            watch(
+                tail,
+                Duration::from_secs(*interval),
+                payment_engine,
+                publisher,
+                config.precision_policy(),
+                config.export_options().with_profiles(rules.as_ref()),
+                checkpointer(&config),
+            )?
+        }
+        (None, None) => {
+            Opt::clap().print_help()?;
+            println!();
+        }
     }
     Ok(())
 }
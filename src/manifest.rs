@@ -0,0 +1,143 @@
+//! Sidecar files describing an input file, so that truncated or altered inputs are caught before
+//! they are processed, see `--manifest`.
+//!
+//! A manifest is a JSON object such as `{"rows": 1000, "sha256": "9f86d0…"}`: the number of
+//! records of the file, its header row excluded, and the hex SHA-256 of the whole file.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+use crate::ingest::CsvDialect;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    pub rows: u64,
+    pub sha256: String,
+}
+
+/// How a file differs from its manifest.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ManifestMismatch {
+    #[error("{actual} rows instead of {expected}, the file looks truncated")]
+    Truncated { expected: u64, actual: u64 },
+    #[error("{actual} rows instead of {expected}")]
+    Rows { expected: u64, actual: u64 },
+    #[error("SHA-256 {actual} instead of {expected}, the file was altered")]
+    Checksum { expected: String, actual: String },
+}
+
+impl Manifest {
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    /// Manifest of the file at `path`, its rows read as `dialect` says.
+    pub fn of_file<P: AsRef<Path>>(path: P, dialect: &CsvDialect) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut hasher = Sha256::new();
+        let mut file = BufReader::new(File::open(path)?);
+        let mut buffer = [0; 64 * 1024];
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+        }
+        let sha256 = hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+
+        let mut rdr = dialect.reader(File::open(path)?);
+        let mut row = csv::ByteRecord::new();
+        let mut rows = 0;
+        loop {
+            match rdr.read_byte_record(&mut row) {
+                Ok(true) => rows += 1,
+                Ok(false) => break,
+                Err(err) if err.is_io_error() => return Err(err.into()),
+                // malformed rows are rows all the same, the readers report them
+                Err(_) => rows += 1,
+            }
+        }
+        Ok(Self { rows, sha256 })
+    }
+
+    /// Compares `actual`, the manifest of a file, against this one. Missing rows are told apart
+    /// from other changes.
+    pub fn check(&self, actual: &Manifest) -> Result<(), ManifestMismatch> {
+        if actual.rows < self.rows {
+            return Err(ManifestMismatch::Truncated {
+                expected: self.rows,
+                actual: actual.rows,
+            });
+        }
+        if actual.rows != self.rows {
+            return Err(ManifestMismatch::Rows {
+                expected: self.rows,
+                actual: actual.rows,
+            });
+        }
+        if !actual.sha256.eq_ignore_ascii_case(self.sha256.trim()) {
+            return Err(ManifestMismatch::Checksum {
+                expected: self.sha256.clone(),
+                actual: actual.sha256.clone(),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn truncated_and_altered_files_are_told_apart() {
+        let path =
+            std::env::temp_dir().join(format!("payments-manifest-{}.csv", std::process::id()));
+        let dialect = CsvDialect::default();
+        fs::write(
+            &path,
+            "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,2.0\n",
+        )
+        .unwrap();
+        let manifest = Manifest::of_file(&path, &dialect).unwrap();
+        assert_eq!(manifest.rows, 2);
+        assert_eq!(
+            manifest.sha256,
+            format!("{:x}", Sha256::digest(fs::read(&path).unwrap().as_slice()))
+        );
+        assert_eq!(manifest.check(&manifest), Ok(()));
+
+        fs::write(&path, "type,client,tx,amount\ndeposit,1,1,1.0\n").unwrap();
+        let truncated = Manifest::of_file(&path, &dialect).unwrap();
+        fs::write(
+            &path,
+            "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,9.0\n",
+        )
+        .unwrap();
+        let altered = Manifest::of_file(&path, &dialect).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            manifest.check(&truncated),
+            Err(ManifestMismatch::Truncated {
+                expected: 2,
+                actual: 1
+            })
+        );
+        assert!(matches!(
+            manifest.check(&altered),
+            Err(ManifestMismatch::Checksum { .. })
+        ));
+    }
+}
@@ -0,0 +1,350 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::ledger::JournalEntry;
+use crate::transactions::{
+    saturating_add, Account, Amount, DisputeState, PeriodLength, Transaction, TransactionKind,
+};
+
+/// Snapshot of engine wide figures returned by `PaymentEngine::metrics`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EngineMetrics {
+    /// Successfully applied transactions per type.
+    pub applied: HashMap<TransactionKind, u64>,
+    pub rejected: u64,
+    /// Rejections of clients on the blocklist, included in `rejected`.
+    pub blocked: u64,
+    /// Scheduled transactions not due yet.
+    pub scheduled: u64,
+    pub total_available: Amount,
+    pub total_held: Amount,
+    pub total_escrowed: Amount,
+    pub open_disputes: u64,
+    pub frozen_accounts: u64,
+    pub flagged_accounts: u64,
+}
+
+impl EngineMetrics {
+    pub fn applied(&self, kind: TransactionKind) -> u64 {
+        self.applied.get(&kind).copied().unwrap_or(0)
+    }
+
+    /// Adds up figures of engines holding disjoint sets of accounts.
+    pub fn merge(&mut self, other: &EngineMetrics) {
+        for (kind, count) in other.applied.iter() {
+            *self.applied.entry(*kind).or_insert(0) += count;
+        }
+        self.rejected += other.rejected;
+        self.blocked += other.blocked;
+        self.scheduled += other.scheduled;
+        self.total_available = saturating_add(self.total_available, other.total_available);
+        self.total_held = saturating_add(self.total_held, other.total_held);
+        self.total_escrowed = saturating_add(self.total_escrowed, other.total_escrowed);
+        self.open_disputes += other.open_disputes;
+        self.frozen_accounts += other.frozen_accounts;
+        self.flagged_accounts += other.flagged_accounts;
+    }
+}
+
+/// Activity of one counterparty over the stored deposits and withdrawals, captured holds
+/// included. Pending and released holds are not counted.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MerchantStats {
+    pub counterparty: String,
+    pub transactions: u64,
+    pub volume: Amount,
+    /// Transactions charged back, even when the chargeback was reversed later.
+    pub chargebacks: u64,
+    pub chargeback_volume: Amount,
+}
+
+impl MerchantStats {
+    /// Share of the transactions that were charged back, between 0 and 1.
+    pub fn chargeback_rate(&self) -> f64 {
+        if self.transactions == 0 {
+            return 0.0;
+        }
+        self.chargebacks as f64 / self.transactions as f64
+    }
+}
+
+/// Figures per counterparty, ordered by name. Transactions without a counterparty are left out.
+pub fn merchant_stats<'a, I>(transactions: I) -> Vec<MerchantStats>
+where
+    I: IntoIterator<Item = &'a Transaction>,
+{
+    let mut merchants: BTreeMap<&str, MerchantStats> = BTreeMap::new();
+    for transaction in transactions {
+        let (amount, dispute) = match transaction {
+            Transaction::Deposit {
+                amount, dispute, ..
+            }
+            | Transaction::Withdrawal {
+                amount, dispute, ..
+            } => (*amount, *dispute),
+            _ => continue,
+        };
+        let counterparty = match transaction.counterparty() {
+            Some(counterparty) => counterparty,
+            None => continue,
+        };
+        let stats = merchants
+            .entry(counterparty)
+            .or_insert_with(|| MerchantStats {
+                counterparty: counterparty.to_string(),
+                ..MerchantStats::default()
+            });
+        stats.transactions += 1;
+        stats.volume = saturating_add(stats.volume, amount);
+        if matches!(
+            dispute,
+            DisputeState::ChargedBack | DisputeState::ChargebackReversed
+        ) {
+            stats.chargebacks += 1;
+            stats.chargeback_volume = saturating_add(stats.chargeback_volume, amount);
+        }
+    }
+    merchants.into_values().collect()
+}
+
+/// What `top_accounts` ranks accounts by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AccountRanking {
+    Available,
+    Held,
+    #[default]
+    Total,
+    Chargebacks,
+}
+
+impl std::str::FromStr for AccountRanking {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "available" => Ok(AccountRanking::Available),
+            "held" => Ok(AccountRanking::Held),
+            "total" => Ok(AccountRanking::Total),
+            "chargebacks" => Ok(AccountRanking::Chargebacks),
+            _ => Err(format!("unknown ranking: {}", s)),
+        }
+    }
+}
+
+/// The `count` accounts with the largest balance or the most chargebacks, largest first and
+/// by client among equals.
+pub fn top_accounts<'a, I>(accounts: I, by: AccountRanking, count: usize) -> Vec<&'a Account>
+where
+    I: IntoIterator<Item = &'a Account>,
+{
+    let mut accounts: Vec<&Account> = accounts.into_iter().collect();
+    accounts.sort_by(|left, right| {
+        let order = match by {
+            AccountRanking::Available => right.available().cmp(&left.available()),
+            AccountRanking::Held => right.held().cmp(&left.held()),
+            AccountRanking::Total => right.total().cmp(&left.total()),
+            AccountRanking::Chargebacks => right.chargebacks().cmp(&left.chargebacks()),
+        };
+        order.then(left.client().cmp(&right.client()))
+    });
+    accounts.truncate(count);
+    accounts
+}
+
+/// Activity of one calendar period, from the journal entries posted in it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PeriodActivity {
+    /// First day of the period, see `PeriodLength::label`.
+    pub period: String,
+    /// Deposits and withdrawals, captured holds included.
+    pub transactions: u64,
+    pub deposits: Amount,
+    pub withdrawals: Amount,
+    /// Disputes opened, including reopened ones.
+    pub disputes: u64,
+    pub chargebacks: u64,
+}
+
+impl PeriodActivity {
+    /// Deposits and withdrawals together.
+    pub fn volume(&self) -> Amount {
+        saturating_add(self.deposits, self.withdrawals)
+    }
+
+    /// Chargebacks per deposit or withdrawal of the period, between 0 and 1 unless
+    /// transactions of earlier periods are charged back.
+    pub fn chargeback_rate(&self) -> f64 {
+        if self.transactions == 0 {
+            return 0.0;
+        }
+        self.chargebacks as f64 / self.transactions as f64
+    }
+}
+
+/// Figures per day, week or month the entries were posted in, oldest first. Periods without
+/// entries are left out.
+pub fn period_activity<'a, I>(entries: I, length: PeriodLength) -> Vec<PeriodActivity>
+where
+    I: IntoIterator<Item = &'a JournalEntry>,
+{
+    let mut periods: BTreeMap<u64, PeriodActivity> = BTreeMap::new();
+    for entry in entries {
+        let activity = periods
+            .entry(length.key(entry.time))
+            .or_insert_with(|| PeriodActivity {
+                period: length.label(entry.time),
+                ..PeriodActivity::default()
+            });
+        match entry.kind {
+            TransactionKind::Deposit => {
+                activity.transactions += 1;
+                activity.deposits = saturating_add(activity.deposits, entry.amount);
+            }
+            TransactionKind::Withdrawal | TransactionKind::Capture => {
+                activity.transactions += 1;
+                activity.withdrawals = saturating_add(activity.withdrawals, entry.amount);
+            }
+            TransactionKind::Dispute => activity.disputes += 1,
+            TransactionKind::Chargeback => activity.chargebacks += 1,
+            _ => {}
+        }
+    }
+    periods.into_values().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::amount;
+    use crate::transactions::{Clock, PaymentEngine};
+
+    #[test]
+    fn merchants_are_aggregated_with_their_chargebacks() {
+        let mut engine = PaymentEngine::new();
+        let merchant = |name: &str| Some(name.to_string());
+        for (tx, name) in [(1, "shop"), (2, "shop"), (3, "arcade"), (4, "shop")] {
+            let deposit = Transaction::new_deposit(1, tx, amount!(10))
+                .unwrap()
+                .with_counterparty(merchant(name));
+            engine.process_transaction(deposit).unwrap();
+        }
+        engine
+            .process_transaction(Transaction::new_deposit(1, 5, amount!(1)).unwrap())
+            .unwrap();
+        engine
+            .process_transaction(
+                Transaction::new_withdrawal(1, 6, amount!(2.5))
+                    .unwrap()
+                    .with_counterparty(merchant("shop")),
+            )
+            .unwrap();
+        engine
+            .process_transaction(Transaction::new_dispute(1, 2))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::new_chargeback(1, 2))
+            .unwrap();
+
+        let stats = merchant_stats(engine.transactions_iter());
+        assert_eq!(
+            stats,
+            vec![
+                MerchantStats {
+                    counterparty: "arcade".to_string(),
+                    transactions: 1,
+                    volume: amount!(10),
+                    ..MerchantStats::default()
+                },
+                MerchantStats {
+                    counterparty: "shop".to_string(),
+                    transactions: 4,
+                    volume: amount!(32.5),
+                    chargebacks: 1,
+                    chargeback_volume: amount!(10),
+                },
+            ]
+        );
+        assert_eq!(stats[1].chargeback_rate(), 0.25);
+    }
+
+    #[test]
+    fn top_accounts_are_the_largest_first() {
+        let mut engine = PaymentEngine::new();
+        for (client, amount) in [
+            (1, amount!(5)),
+            (2, amount!(20)),
+            (3, amount!(5)),
+            (4, amount!(1)),
+        ] {
+            engine
+                .process_transaction(
+                    Transaction::new_deposit(client, client.into(), amount).unwrap(),
+                )
+                .unwrap();
+        }
+        engine
+            .process_transaction(Transaction::new_dispute(2, 2))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::new_chargeback(2, 2))
+            .unwrap();
+
+        let clients = |by| -> Vec<u16> {
+            top_accounts(engine.accounts_iter(), by, 3)
+                .iter()
+                .map(|account| account.client())
+                .collect()
+        };
+        assert_eq!(clients(AccountRanking::Available), vec![1, 3, 4]);
+        assert_eq!(clients(AccountRanking::Chargebacks), vec![2, 1, 3]);
+        assert_eq!(
+            top_accounts(engine.accounts_iter(), AccountRanking::Total, 10).len(),
+            4
+        );
+    }
+
+    #[test]
+    fn activity_is_grouped_by_the_period_entries_were_posted_in() {
+        let mut engine = PaymentEngine::new();
+        engine.keep_journal(true);
+        // 2024-03-31T23:00:00Z
+        engine.set_clock(Clock::Simulated(1_711_926_000));
+        engine
+            .process_transaction(Transaction::new_deposit(1, 1, amount!(10)).unwrap())
+            .unwrap();
+        engine
+            .process_transaction(Transaction::new_withdrawal(1, 2, amount!(4)).unwrap())
+            .unwrap();
+        engine.advance_time(1_711_929_600);
+        engine
+            .process_transaction(Transaction::new_dispute(1, 1))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::new_chargeback(1, 1))
+            .unwrap();
+
+        let months = period_activity(engine.ledger().entries(), PeriodLength::Monthly);
+        assert_eq!(
+            months,
+            vec![
+                PeriodActivity {
+                    period: "2024-03".to_string(),
+                    transactions: 2,
+                    deposits: amount!(10),
+                    withdrawals: amount!(4),
+                    ..PeriodActivity::default()
+                },
+                PeriodActivity {
+                    period: "2024-04".to_string(),
+                    disputes: 1,
+                    chargebacks: 1,
+                    ..PeriodActivity::default()
+                },
+            ]
+        );
+        assert_eq!(months[0].volume(), amount!(14));
+        let weeks = period_activity(engine.ledger().entries(), PeriodLength::Weekly);
+        assert_eq!(weeks.len(), 2);
+        assert_eq!(weeks[0].period, "2024-03-25");
+        assert_eq!(weeks[1].period, "2024-04-01");
+    }
+}
@@ -0,0 +1,96 @@
+use std::sync::{Arc, Mutex};
+
+use crate::transactions::{
+    Account, Client, TransactionId, TransactionKind, TransactionValidationError,
+};
+use crate::velocity::SuspiciousActivity;
+
+/// Callbacks fired by `PaymentEngine` after the corresponding change has been applied.
+/// Every method defaults to doing nothing, so observers only implement what they need.
+pub trait EngineObserver: Send {
+    fn on_account_created(&mut self, _account: &Account) {}
+
+    fn on_dispute_opened(&mut self, _tx: TransactionId, _account: &Account) {}
+
+    fn on_dispute_resolved(&mut self, _tx: TransactionId, _account: &Account) {}
+
+    fn on_chargeback(&mut self, _tx: TransactionId, _account: &Account) {}
+
+    fn on_account_frozen(&mut self, _account: &Account) {}
+
+    /// An account unfrozen by `PaymentEngine::unfreeze`.
+    fn on_account_unfrozen(&mut self, _account: &Account) {}
+
+    /// Every applied transaction, after the callbacks above, with the account of its client.
+    fn on_applied(&mut self, _kind: TransactionKind, _tx: TransactionId, _account: &Account) {}
+
+    /// A pattern of `VelocityRules` completed by the transaction just applied, after
+    /// `on_applied`.
+    fn on_suspicious_activity(&mut self, _activity: &SuspiciousActivity) {}
+
+    fn on_rejected(
+        &mut self,
+        _client: Client,
+        _tx: TransactionId,
+        _error: &TransactionValidationError,
+    ) {
+    }
+}
+
+/// A transaction refused by the engine, as reported to `EngineObserver::on_rejected`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rejection {
+    pub client: Client,
+    pub tx: TransactionId,
+    pub code: &'static str,
+    pub reason: String,
+}
+
+/// Collects the rejections of every engine it is subscribed to; clones share the same list.
+#[derive(Debug, Clone, Default)]
+pub struct RejectionLog(Arc<Mutex<Vec<Rejection>>>);
+
+impl RejectionLog {
+    /// Rejections so far, sorted by transaction id.
+    pub fn rejections(&self) -> Vec<Rejection> {
+        let mut rejections = self.0.lock().unwrap().clone();
+        rejections.sort_by_key(|rejection| rejection.tx);
+        rejections
+    }
+}
+
+impl EngineObserver for RejectionLog {
+    fn on_rejected(
+        &mut self,
+        client: Client,
+        tx: TransactionId,
+        error: &TransactionValidationError,
+    ) {
+        self.0.lock().unwrap().push(Rejection {
+            client,
+            tx,
+            code: error.code(),
+            reason: error.to_string(),
+        });
+    }
+}
+
+/// Collects the suspicious activity detected by every engine it is subscribed to; clones
+/// share the same list.
+#[derive(Debug, Clone, Default)]
+pub struct SuspiciousActivityLog(Arc<Mutex<Vec<SuspiciousActivity>>>);
+
+impl SuspiciousActivityLog {
+    /// Activity so far, sorted by detection time, then by client.
+    pub fn activity(&self) -> Vec<SuspiciousActivity> {
+        let mut activity = self.0.lock().unwrap().clone();
+        activity.sort_by_key(|activity| (activity.time, activity.client));
+        activity
+    }
+}
+
+impl EngineObserver for SuspiciousActivityLog {
+    fn on_suspicious_activity(&mut self, activity: &SuspiciousActivity) {
+        self.0.lock().unwrap().push(activity.clone());
+    }
+}
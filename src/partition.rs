@@ -0,0 +1,164 @@
+//! Client-disjoint shards of an input, for processing on different machines, see
+//! `payments split`.
+//!
+//! Clients are assigned with jump consistent hashing (Lamping and Veach) of a fixed mix of the
+//! client id, so the assignment is the same on every machine and build, and going from `n` to
+//! `n + 1` partitions only moves the clients that end up in the new one.
+
+use std::io::{Read, Write};
+
+use crate::ingest::{CsvDialect, InvalidRecord, TransactionRecord};
+use crate::transactions::Client;
+
+/// Partition of `client` among `partitions`, in `0..partitions`.
+pub fn partition_of(client: Client, partitions: u32) -> u32 {
+    assert!(partitions > 0, "there must be at least one partition");
+    let mut key = mix(u64::from(client));
+    let mut bucket: i64 = -1;
+    let mut next: i64 = 0;
+    while next < i64::from(partitions) {
+        bucket = next;
+        key = key.wrapping_mul(2862933555777941757).wrapping_add(1);
+        next = ((bucket + 1) as f64 * ((1u64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+    }
+    bucket as u32
+}
+
+/// splitmix64 finalizer, spreading consecutive client ids over the whole key space.
+fn mix(mut key: u64) -> u64 {
+    key = (key ^ (key >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    key = (key ^ (key >> 27)).wrapping_mul(0x94d049bb133111eb);
+    key ^ (key >> 31)
+}
+
+/// What `split` wrote.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SplitSummary {
+    /// Rows written to every shard, by partition.
+    pub records: Vec<u64>,
+    /// Rows left out because no client could be read from them.
+    pub invalid: Vec<InvalidRecord>,
+}
+
+/// Copies every row of `input` to the shard of its client, one writer per partition. Rows are
+/// written as read, in their original order, after the header row when the input has one, so
+/// that each shard is an input in the same dialect.
+pub fn split<R, W>(input: R, dialect: &CsvDialect, shards: &mut [W]) -> anyhow::Result<SplitSummary>
+where
+    R: Read,
+    W: Write,
+{
+    let partitions = u32::try_from(shards.len())?;
+    if partitions == 0 {
+        anyhow::bail!("there must be at least one partition");
+    }
+    let mut rdr = dialect.reader(input);
+    let headers = dialect.headers(&mut rdr)?;
+    let mut writers: Vec<csv::Writer<&mut W>> = shards
+        .iter_mut()
+        .map(|shard| {
+            csv::WriterBuilder::new()
+                .delimiter(dialect.delimiter)
+                .quote(dialect.quote)
+                .flexible(true)
+                .from_writer(shard)
+        })
+        .collect();
+    if dialect.has_headers {
+        let original = rdr.byte_headers()?.clone();
+        for writer in writers.iter_mut() {
+            writer.write_byte_record(&original)?;
+        }
+    }
+
+    let mut summary = SplitSummary {
+        records: vec![0; writers.len()],
+        invalid: vec![],
+    };
+    let mut row = csv::ByteRecord::new();
+    loop {
+        let line = rdr.position().line();
+        match rdr.read_byte_record(&mut row) {
+            Ok(false) => break,
+            Ok(true) => {
+                let mut localized = row.clone();
+                let client = dialect.localize(&headers, &mut localized).and_then(|()| {
+                    localized
+                        .deserialize::<TransactionRecord>(Some(&headers))
+                        .map(|record| record.client())
+                        .map_err(|err| err.to_string())
+                });
+                match client {
+                    Ok(client) => {
+                        let partition = partition_of(client, partitions) as usize;
+                        writers[partition].write_byte_record(&row)?;
+                        summary.records[partition] += 1;
+                    }
+                    Err(reason) => summary.invalid.push(InvalidRecord { line, reason }),
+                }
+            }
+            Err(err) if err.is_io_error() => return Err(err.into()),
+            Err(err) => summary.invalid.push(InvalidRecord {
+                line,
+                reason: err.to_string(),
+            }),
+        }
+    }
+    for writer in writers.iter_mut() {
+        writer.flush()?;
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partitions_are_stable_and_move_few_clients() {
+        // fixed across builds and machines
+        let assigned: Vec<u32> = (1..=8).map(|client| partition_of(client, 4)).collect();
+        assert_eq!(assigned, [0, 3, 0, 1, 0, 2, 3, 0]);
+
+        let clients = 0..=Client::MAX;
+        let moved = clients
+            .clone()
+            .filter(|client| partition_of(*client, 8) != partition_of(*client, 9))
+            .count();
+        // about a ninth of the clients move to the new partition, none between old ones
+        assert!(moved < 65536 / 8, "{} clients moved", moved);
+        assert!(clients
+            .filter(|client| partition_of(*client, 8) != partition_of(*client, 9))
+            .all(|client| partition_of(client, 9) == 8));
+    }
+
+    #[test]
+    fn rows_go_to_the_shard_of_their_client() {
+        let input = "type,client,tx,amount,memo\n\
+                     deposit,1,1,2.0,\"a, b\"\n\
+                     deposit,2,2,1.0,\n\
+                     deposit,x,3,1.0,\n\
+                     dispute,1,1,,\n";
+        let mut shards: Vec<Vec<u8>> = vec![vec![]; 4];
+        let summary = split(input.as_bytes(), &CsvDialect::default(), &mut shards).unwrap();
+        assert_eq!(summary.invalid.len(), 1);
+        assert_eq!(summary.invalid[0].line, 4);
+
+        let first = partition_of(1, 4) as usize;
+        let second = partition_of(2, 4) as usize;
+        assert_ne!(first, second);
+        assert_eq!(
+            String::from_utf8(shards[first].clone()).unwrap(),
+            "type,client,tx,amount,memo\n\
+             deposit,1,1,2.0,\"a, b\"\n\
+             dispute,1,1,,\n"
+        );
+        assert_eq!(summary.records[first], 2);
+        assert_eq!(summary.records[second], 1);
+        // empty shards still name the columns
+        let empty = (0..4)
+            .find(|shard| ![first, second].contains(shard))
+            .unwrap();
+        assert_eq!(shards[empty], b"type,client,tx,amount,memo\n");
+    }
+}
@@ -0,0 +1,174 @@
+//! Stream of account updates for downstream read models, see `--publish`.
+//!
+//! Every applied transaction produces one message: the client id, a tab and a JSON object with
+//! the transaction (`tx` and `type`) and the new state of the client's account. That is the
+//! keyed layout `kcat -P -K '\t'` reads, so a named pipe into kcat publishes the updates to a
+//! topic with the client as message key, which keeps the updates of one client in order.
+
+use serde::Serialize;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+use crate::observer::EngineObserver;
+use crate::snapshot::AccountState;
+use crate::transactions::{Account, TransactionId, TransactionKind};
+
+#[derive(Serialize)]
+struct Update<'a> {
+    tx: TransactionId,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    account: &'a AccountState,
+}
+
+struct Sink {
+    writer: Box<dyn Write + Send>,
+    /// Whether `writer` is flushed after every message rather than periodically.
+    flush_each: bool,
+    /// First write error, the following messages are dropped.
+    error: Option<io::Error>,
+}
+
+impl Sink {
+    fn publish(&mut self, kind: TransactionKind, tx: TransactionId, account: &Account) {
+        if self.error.is_some() {
+            return;
+        }
+        let account = AccountState::from(account);
+        let update = Update {
+            tx,
+            kind: kind.name(),
+            account: &account,
+        };
+        let result = serde_json::to_string(&update)
+            .map_err(io::Error::from)
+            .and_then(|json| writeln!(self.writer, "{}\t{}", account.client, json));
+        let result = match result {
+            Ok(()) if self.flush_each => self.writer.flush(),
+            result => result,
+        };
+        if let Err(err) = result {
+            self.error = Some(err);
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.error.is_none() {
+            if let Err(err) = self.writer.flush() {
+                self.error = Some(err);
+            }
+        }
+    }
+}
+
+/// Observer writing an update message for every applied transaction of the engines it is
+/// subscribed to; clones share the same writer.
+#[derive(Clone)]
+pub struct AccountPublisher(Arc<Mutex<Sink>>);
+
+impl AccountPublisher {
+    /// Publishes to `writer`, flushing it after every message or, with an `interval`, from a
+    /// background thread that often, so that consumers see updates in groups. The thread stops
+    /// once every clone of the publisher is dropped.
+    pub fn new(writer: Box<dyn Write + Send>, interval: Option<Duration>) -> Self {
+        let sink = Arc::new(Mutex::new(Sink {
+            writer,
+            flush_each: interval.is_none(),
+            error: None,
+        }));
+        if let Some(interval) = interval {
+            let sink = Arc::downgrade(&sink);
+            thread::spawn(move || flush_every(sink, interval));
+        }
+        Self(sink)
+    }
+
+    /// Flushes the messages still buffered and returns the first write error, if any.
+    pub fn finish(&self) -> io::Result<()> {
+        let mut sink = self.0.lock().unwrap();
+        sink.flush();
+        match sink.error.take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+fn flush_every(sink: Weak<Mutex<Sink>>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        match sink.upgrade() {
+            Some(sink) => sink.lock().unwrap().flush(),
+            None => return,
+        }
+    }
+}
+
+impl EngineObserver for AccountPublisher {
+    fn on_applied(&mut self, kind: TransactionKind, tx: TransactionId, account: &Account) {
+        self.0.lock().unwrap().publish(kind, tx, account);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::amount;
+    use crate::transactions::{PaymentEngine, Transaction};
+
+    /// Writer whose output stays readable after it was handed to the publisher.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn applied_transactions_are_published_keyed_by_client() {
+        let output = Shared::default();
+        let publisher = AccountPublisher::new(Box::new(output.clone()), None);
+        let mut engine = PaymentEngine::new();
+        engine.subscribe(Box::new(publisher.clone()));
+        for transaction in [
+            Transaction::new_deposit(1, 1, amount!(10.0)).unwrap(),
+            Transaction::new_withdrawal(2, 2, amount!(1.0)).unwrap(),
+            Transaction::new_deposit(2, 3, amount!(4.0)).unwrap(),
+            Transaction::new_dispute(1, 1),
+        ] {
+            let _ = engine.process_transaction(transaction);
+        }
+        publisher.finish().unwrap();
+
+        let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        let messages: Vec<(&str, serde_json::Value)> = output
+            .lines()
+            .map(|line| {
+                let (key, json) = line.split_once('\t').unwrap();
+                (key, serde_json::from_str(json).unwrap())
+            })
+            .collect();
+        let keys: Vec<&str> = messages.iter().map(|(key, _)| *key).collect();
+        assert_eq!(keys, ["1", "2", "1"]);
+        let (_, dispute) = &messages[2];
+        assert_eq!(dispute["tx"], 1);
+        assert_eq!(dispute["type"], "dispute");
+        assert_eq!(dispute["account"]["client"], 1);
+        assert_eq!(
+            dispute["account"]["held"]
+                .as_str()
+                .unwrap()
+                .parse::<f64>()
+                .unwrap(),
+            10.0
+        );
+    }
+}
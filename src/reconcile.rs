@@ -0,0 +1,186 @@
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io;
+
+use crate::ingest::{AccountRecord, BalanceRecord};
+use crate::transactions::{Amount, Client};
+
+#[derive(Debug, PartialEq)]
+pub enum Discrepancy {
+    MissingInStatement {
+        client: Client,
+        engine: Amount,
+    },
+    MissingInEngine {
+        client: Client,
+        statement: Amount,
+    },
+    Mismatch {
+        client: Client,
+        engine: Amount,
+        statement: Amount,
+    },
+}
+
+impl Discrepancy {
+    fn client(&self) -> Client {
+        match self {
+            Discrepancy::MissingInStatement { client, .. }
+            | Discrepancy::MissingInEngine { client, .. }
+            | Discrepancy::Mismatch { client, .. } => *client,
+        }
+    }
+}
+
+impl Serialize for Discrepancy {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let (issue, engine, statement) = match self {
+            Discrepancy::MissingInStatement { engine, .. } => {
+                ("missing_in_statement", Some(*engine), None)
+            }
+            Discrepancy::MissingInEngine { statement, .. } => {
+                ("missing_in_engine", None, Some(*statement))
+            }
+            Discrepancy::Mismatch {
+                engine, statement, ..
+            } => ("mismatch", Some(*engine), Some(*statement)),
+        };
+        let difference = match (engine, statement) {
+            (Some(engine), Some(statement)) => Some(engine - statement),
+            _ => None,
+        };
+
+        let mut state = serializer.serialize_struct("Discrepancy", 5)?;
+        state.serialize_field("client", &self.client())?;
+        state.serialize_field("issue", issue)?;
+        state.serialize_field("engine", &engine.map(|amount| amount.round_dp(4)))?;
+        state.serialize_field("statement", &statement.map(|amount| amount.round_dp(4)))?;
+        state.serialize_field("difference", &difference.map(|amount| amount.round_dp(4)))?;
+        state.end()
+    }
+}
+
+/// Compares the totals computed by the engine against an external balance file.
+/// Differences within `tolerance` (inclusive) are not reported.
+pub fn reconcile(
+    accounts: &[AccountRecord],
+    balances: &[BalanceRecord],
+    tolerance: Amount,
+) -> Vec<Discrepancy> {
+    let engine: BTreeMap<Client, Amount> = accounts
+        .iter()
+        .map(|account| (account.client, account.total))
+        .collect();
+    let statement: BTreeMap<Client, Amount> = balances
+        .iter()
+        .map(|balance| (balance.client, balance.balance))
+        .collect();
+
+    let mut discrepancies = vec![];
+    for (client, engine_total) in engine.iter() {
+        match statement.get(client) {
+            Some(statement_total) => {
+                if (*engine_total - *statement_total).abs() > tolerance {
+                    discrepancies.push(Discrepancy::Mismatch {
+                        client: *client,
+                        engine: *engine_total,
+                        statement: *statement_total,
+                    });
+                }
+            }
+            None => discrepancies.push(Discrepancy::MissingInStatement {
+                client: *client,
+                engine: *engine_total,
+            }),
+        }
+    }
+    for (client, statement_total) in statement.iter() {
+        if !engine.contains_key(client) {
+            discrepancies.push(Discrepancy::MissingInEngine {
+                client: *client,
+                statement: *statement_total,
+            });
+        }
+    }
+    discrepancies.sort_by_key(|discrepancy| discrepancy.client());
+    discrepancies
+}
+
+pub fn discrepancies_as_csv<W: io::Write>(
+    discrepancies: Vec<Discrepancy>,
+    output: W,
+) -> Result<(), Box<dyn Error>> {
+    let mut wtr = csv::Writer::from_writer(output);
+    for discrepancy in discrepancies {
+        wtr.serialize(discrepancy)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::amount;
+
+    fn account(client: Client, total: Amount) -> AccountRecord {
+        AccountRecord { client, total }
+    }
+
+    fn balance(client: Client, balance: Amount) -> BalanceRecord {
+        BalanceRecord { client, balance }
+    }
+
+    #[test]
+    fn matching_balances_produce_no_discrepancies() {
+        let result = reconcile(
+            &[account(1, amount!(10.0)), account(2, amount!(5.5))],
+            &[balance(2, amount!(5.5)), balance(1, amount!(10.0))],
+            amount!(0.0),
+        );
+        assert!(result.is_empty());
+    }
+
+    #[test]
+    fn missing_clients_are_reported_on_both_sides() {
+        let result = reconcile(
+            &[account(1, amount!(10.0))],
+            &[balance(2, amount!(5.0))],
+            amount!(0.0),
+        );
+        assert_eq!(
+            result,
+            vec![
+                Discrepancy::MissingInStatement {
+                    client: 1,
+                    engine: amount!(10.0)
+                },
+                Discrepancy::MissingInEngine {
+                    client: 2,
+                    statement: amount!(5.0)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn mismatches_beyond_tolerance_are_reported() {
+        let result = reconcile(
+            &[account(1, amount!(10.0)), account(2, amount!(10.0))],
+            &[balance(1, amount!(10.01)), balance(2, amount!(10.02))],
+            amount!(0.01),
+        );
+        assert_eq!(
+            result,
+            vec![Discrepancy::Mismatch {
+                client: 2,
+                engine: amount!(10.0),
+                statement: amount!(10.02)
+            }]
+        );
+    }
+}
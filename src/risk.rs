@@ -0,0 +1,150 @@
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+
+use crate::transactions::{Account, Amount, Client, Transaction};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RiskDecision {
+    Allow,
+    /// Apply the transaction, but mark the client's account as flagged.
+    Flag(String),
+    /// Reject the transaction before it touches any balance.
+    Veto(String),
+}
+
+/// Hook consulted by `PaymentEngine` before every transaction is applied.
+/// `account` is `None` when the client has no account yet.
+pub trait RiskScorer: Send {
+    fn score(&mut self, transaction: &Transaction, account: Option<&Account>) -> RiskDecision;
+}
+
+#[derive(Debug, Default)]
+pub struct NoopRiskScorer;
+
+impl RiskScorer for NoopRiskScorer {
+    fn score(&mut self, _transaction: &Transaction, _account: Option<&Account>) -> RiskDecision {
+        RiskDecision::Allow
+    }
+}
+
+/// Heuristics used by `RulesRiskScorer`. Every rule is disabled unless configured.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RiskRules {
+    /// Deposits, withdrawals and holds above this amount are reported.
+    pub large_amount: Option<Amount>,
+    /// More than this many withdrawals by one client within `withdrawal_window` are reported.
+    pub max_withdrawals: Option<usize>,
+    /// Number of most recent transactions (across all clients) `max_withdrawals` applies to.
+    pub withdrawal_window: usize,
+    /// Veto offending transactions instead of only flagging them.
+    pub veto: bool,
+}
+
+impl Default for RiskRules {
+    fn default() -> Self {
+        Self {
+            large_amount: None,
+            max_withdrawals: None,
+            withdrawal_window: 100,
+            veto: false,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RulesRiskScorer {
+    rules: RiskRules,
+    seen: usize,
+    withdrawals: HashMap<Client, VecDeque<usize>>,
+}
+
+impl RulesRiskScorer {
+    pub fn new(rules: RiskRules) -> Self {
+        Self {
+            rules,
+            ..Self::default()
+        }
+    }
+
+    fn decide(&self, reason: String) -> RiskDecision {
+        if self.rules.veto {
+            RiskDecision::Veto(reason)
+        } else {
+            RiskDecision::Flag(reason)
+        }
+    }
+}
+
+impl RiskScorer for RulesRiskScorer {
+    fn score(&mut self, transaction: &Transaction, _account: Option<&Account>) -> RiskDecision {
+        self.seen += 1;
+
+        if let (Some(limit), Some(amount)) = (self.rules.large_amount, transaction.amount()) {
+            if amount > limit {
+                return self.decide(format!("amount {} exceeds {}", amount, limit));
+            }
+        }
+
+        if let (Some(max_withdrawals), Transaction::Withdrawal { client, .. }) =
+            (self.rules.max_withdrawals, transaction)
+        {
+            let window_start = self.seen.saturating_sub(self.rules.withdrawal_window);
+            let recent = self.withdrawals.entry(*client).or_default();
+            while recent.front().is_some_and(|seen| *seen <= window_start) {
+                recent.pop_front();
+            }
+            recent.push_back(self.seen);
+            if recent.len() > max_withdrawals {
+                let count = recent.len();
+                return self.decide(format!(
+                    "{} withdrawals within the last {} transactions",
+                    count, self.rules.withdrawal_window
+                ));
+            }
+        }
+
+        RiskDecision::Allow
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::amount;
+
+    #[test]
+    fn large_amounts_are_flagged() {
+        let mut scorer = RulesRiskScorer::new(RiskRules {
+            large_amount: Some(amount!(1000.0)),
+            ..RiskRules::default()
+        });
+        let small = Transaction::new_deposit(1, 1, amount!(1000.0)).unwrap();
+        let large = Transaction::new_deposit(1, 2, amount!(1000.01)).unwrap();
+        assert_eq!(scorer.score(&small, None), RiskDecision::Allow);
+        assert!(matches!(scorer.score(&large, None), RiskDecision::Flag(_)));
+    }
+
+    #[test]
+    fn rapid_withdrawals_are_vetoed_within_window() {
+        let mut scorer = RulesRiskScorer::new(RiskRules {
+            max_withdrawals: Some(2),
+            withdrawal_window: 3,
+            veto: true,
+            ..RiskRules::default()
+        });
+        let withdrawal = |tx| Transaction::new_withdrawal(1, tx, amount!(1.0)).unwrap();
+        assert_eq!(scorer.score(&withdrawal(1), None), RiskDecision::Allow);
+        assert_eq!(scorer.score(&withdrawal(2), None), RiskDecision::Allow);
+        assert!(matches!(
+            scorer.score(&withdrawal(3), None),
+            RiskDecision::Veto(_)
+        ));
+
+        // another client's activity pushes the old withdrawals out of the window
+        let other = Transaction::new_deposit(2, 4, amount!(1.0)).unwrap();
+        assert_eq!(scorer.score(&other, None), RiskDecision::Allow);
+        assert_eq!(scorer.score(&other, None), RiskDecision::Allow);
+        assert_eq!(scorer.score(&withdrawal(5), None), RiskDecision::Allow);
+    }
+}
@@ -0,0 +1,440 @@
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use crate::clients::{ClientProfiles, KycStatus};
+use crate::transactions::{Amount, Client, Transaction, TransactionValidationError};
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AmountBounds {
+    pub min: Option<Amount>,
+    pub max: Option<Amount>,
+}
+
+impl AmountBounds {
+    fn contains(&self, amount: Amount) -> bool {
+        self.min.is_none_or(|min| amount >= min) && self.max.is_none_or(|max| amount <= max)
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AmountRules {
+    pub deposit: Option<AmountBounds>,
+    pub withdrawal: Option<AmountBounds>,
+    pub hold: Option<AmountBounds>,
+}
+
+/// Limits of the clients of an account tier, on top of the rules every client is held to.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TierLimits {
+    pub deposit: Option<AmountBounds>,
+    pub withdrawal: Option<AmountBounds>,
+    /// Most funds the account may hold; deposits going over it are rejected.
+    pub max_balance: Option<Amount>,
+    /// Seconds of the engine clock after a deposit during which it can be disputed. Not
+    /// enforced in compact mode, which keeps no journal to tell when deposits were made.
+    pub dispute_window: Option<u64>,
+    /// Funds the clients of the tier must keep available, over the global reserve.
+    pub reserve: Option<Amount>,
+}
+
+/// Operator defined validation rules, evaluated by the engine before its built-in checks.
+///
+/// ```toml
+/// allow_clients = [1, 2, 3]
+/// deny_clients = [2]
+/// frozen_exceptions = [3]
+/// reserve = 10.0
+///
+/// [amounts.withdrawal]
+/// max = 500.0
+///
+/// [tiers.basic]
+/// max_balance = 1000.0
+/// dispute_window = 7776000
+/// reserve = 50.0
+///
+/// [tiers.basic.withdrawal]
+/// max = 200.0
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rules {
+    /// When not empty, only these clients may transact.
+    #[serde(default)]
+    pub allow_clients: HashSet<Client>,
+    #[serde(default)]
+    pub deny_clients: HashSet<Client>,
+    /// Sanctioned or closed customers, usually read with `load_blocklist`. Unlike denied
+    /// clients, their transactions are counted separately and fail with `ClientBlocked`.
+    #[serde(default)]
+    pub blocked_clients: HashSet<Client>,
+    /// Clients that can still withdraw (and hold/capture funds) while their account is locked.
+    #[serde(default)]
+    pub frozen_exceptions: HashSet<Client>,
+    #[serde(default)]
+    pub amounts: AmountRules,
+    /// Limits per account tier, by tier name.
+    #[serde(default)]
+    pub tiers: HashMap<String, TierLimits>,
+    /// Only clients whose identity is verified may withdraw, see `ClientProfile::kyc`.
+    #[serde(default)]
+    pub require_kyc: bool,
+    /// Funds every client must keep available: withdrawals and holds taking the available
+    /// funds below it are rejected with `ReserveViolation`. See `reserve` for the reserves of
+    /// tiers and clients.
+    #[serde(default)]
+    pub reserve: Option<Amount>,
+    /// Attributes of the clients, usually read with `ClientProfiles::load` and
+    /// `load_client_tiers` through `set_profiles` and `assign_tiers`. Clients without a tier are
+    /// only held to the rules above.
+    #[serde(skip)]
+    pub clients: ClientProfiles,
+}
+
+impl Rules {
+    pub fn check(&self, transaction: &Transaction) -> Result<(), TransactionValidationError> {
+        let client = transaction.client();
+        let profile = self.clients.get(client);
+        if self.blocked_clients.contains(&client) || profile.is_some_and(|profile| profile.blocked)
+        {
+            return Err(TransactionValidationError::ClientBlocked {
+                client,
+                tx: transaction.tx(),
+            });
+        }
+        if self.deny_clients.contains(&client)
+            || (!self.allow_clients.is_empty() && !self.allow_clients.contains(&client))
+        {
+            return Err(TransactionValidationError::ClientNotAllowed {
+                client,
+                tx: transaction.tx(),
+            });
+        }
+
+        if self.require_kyc
+            && matches!(transaction, Transaction::Withdrawal { .. })
+            && profile.is_none_or(|profile| profile.kyc != KycStatus::Verified)
+        {
+            return Err(TransactionValidationError::KycRequired {
+                client,
+                tx: transaction.tx(),
+            });
+        }
+
+        let bounds = match transaction {
+            Transaction::Deposit { .. } => self.amounts.deposit.as_ref(),
+            Transaction::Withdrawal { .. } => self.amounts.withdrawal.as_ref(),
+            Transaction::Hold { .. } => self.amounts.hold.as_ref(),
+            _ => None,
+        };
+        let tier_bounds = self
+            .tier_limits(client)
+            .and_then(|limits| match transaction {
+                Transaction::Deposit { .. } => limits.deposit.as_ref(),
+                Transaction::Withdrawal { .. } => limits.withdrawal.as_ref(),
+                _ => None,
+            });
+        if let Some(amount) = transaction.amount() {
+            if !bounds
+                .into_iter()
+                .chain(tier_bounds)
+                .all(|bounds| bounds.contains(amount))
+            {
+                return Err(TransactionValidationError::AmountOutOfBounds {
+                    client,
+                    tx: transaction.tx(),
+                    amount,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Name of the tier `client` belongs to, if any.
+    pub fn tier(&self, client: Client) -> Option<&str> {
+        self.clients.get(client)?.tier.as_deref()
+    }
+
+    /// Limits of the tier `client` belongs to, if any.
+    pub fn tier_limits(&self, client: Client) -> Option<&TierLimits> {
+        self.tier(client).and_then(|tier| self.tiers.get(tier))
+    }
+
+    /// Reserve `client` must keep available: the one of its profile, else the one of its tier,
+    /// else the global one.
+    pub fn reserve(&self, client: Client) -> Option<Amount> {
+        self.clients
+            .get(client)
+            .and_then(|profile| profile.reserve)
+            .or_else(|| self.tier_limits(client).and_then(|limits| limits.reserve))
+            .or(self.reserve)
+    }
+
+    /// Assigns tiers to clients, failing on a tier without limits.
+    pub fn assign_tiers(&mut self, client_tiers: HashMap<Client, String>) -> anyhow::Result<()> {
+        for (client, tier) in &client_tiers {
+            self.check_tier(*client, tier)?;
+        }
+        for (client, tier) in client_tiers {
+            self.clients.entry(client).tier = Some(tier);
+        }
+        Ok(())
+    }
+
+    /// Sets the external ids of clients.
+    pub fn assign_external_ids(&mut self, external_ids: HashMap<Client, String>) {
+        for (client, id) in external_ids {
+            self.clients.entry(client).external_id = Some(id);
+        }
+    }
+
+    /// Takes over the attributes of `profiles`, failing on a tier without limits.
+    pub fn set_profiles(&mut self, profiles: ClientProfiles) -> anyhow::Result<()> {
+        for (client, profile) in profiles.iter() {
+            if let Some(tier) = &profile.tier {
+                self.check_tier(client, tier)?;
+            }
+        }
+        self.clients.extend(profiles);
+        Ok(())
+    }
+
+    fn check_tier(&self, client: Client, tier: &str) -> anyhow::Result<()> {
+        if !self.tiers.contains_key(tier) {
+            anyhow::bail!(
+                "client {} is in tier {:?}, which has no limits",
+                client,
+                tier
+            );
+        }
+        Ok(())
+    }
+
+    pub fn is_frozen_exception(&self, client: Client) -> bool {
+        self.frozen_exceptions.contains(&client)
+    }
+}
+
+pub fn load_rules<P: AsRef<Path>>(path: P) -> anyhow::Result<Rules> {
+    let content = fs::read_to_string(path)?;
+    Ok(toml::from_str(&content)?)
+}
+
+/// Client ids of a blocklist file, one per line. Blank lines and `#` comments are ignored.
+pub fn load_blocklist<P: AsRef<Path>>(path: P) -> anyhow::Result<HashSet<Client>> {
+    let content = fs::read_to_string(path)?;
+    parse_blocklist(&content)
+}
+
+/// `client,tier` rows of a client attributes file, one per line. Blank lines and `#` comments
+/// are ignored.
+pub fn load_client_tiers<P: AsRef<Path>>(path: P) -> anyhow::Result<HashMap<Client, String>> {
+    let content = fs::read_to_string(path)?;
+    parse_client_tiers(&content)
+}
+
+fn parse_client_tiers(content: &str) -> anyhow::Result<HashMap<Client, String>> {
+    let mut tiers = HashMap::new();
+    for (index, line) in content.lines().enumerate() {
+        let row = line.split('#').next().unwrap_or_default().trim();
+        if row.is_empty() {
+            continue;
+        }
+        let (id, tier) = row
+            .split_once(',')
+            .map(|(id, tier)| (id.trim(), tier.trim()))
+            .filter(|(_, tier)| !tier.is_empty())
+            .ok_or_else(|| anyhow::anyhow!("line {}: expected client,tier", index + 1))?;
+        let client = id.parse().map_err(|err| {
+            anyhow::anyhow!("line {}: invalid client id {:?}: {}", index + 1, id, err)
+        })?;
+        tiers.insert(client, tier.to_string());
+    }
+    Ok(tiers)
+}
+
+fn parse_blocklist(content: &str) -> anyhow::Result<HashSet<Client>> {
+    let mut clients = HashSet::new();
+    for (index, line) in content.lines().enumerate() {
+        let id = line.split('#').next().unwrap_or_default().trim();
+        if id.is_empty() {
+            continue;
+        }
+        let client = id.parse().map_err(|err| {
+            anyhow::anyhow!("line {}: invalid client id {:?}: {}", index + 1, id, err)
+        })?;
+        clients.insert(client);
+    }
+    Ok(clients)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::amount;
+    use crate::clients::ClientProfile;
+
+    fn rules() -> Rules {
+        toml::from_str(
+            r#"
+            allow_clients = [1, 2, 3]
+            deny_clients = [2]
+            frozen_exceptions = [3]
+
+            [amounts.deposit]
+            min = 1.0
+
+            [amounts.withdrawal]
+            max = 500.0
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn clients_outside_allowlist_or_on_denylist_are_rejected() {
+        let rules = rules();
+        let deposit = |client| Transaction::new_deposit(client, 1, amount!(10.0)).unwrap();
+        assert!(rules.check(&deposit(1)).is_ok());
+        assert!(matches!(
+            rules.check(&deposit(2)),
+            Err(TransactionValidationError::ClientNotAllowed { client: 2, .. })
+        ));
+        assert!(matches!(
+            rules.check(&deposit(4)),
+            Err(TransactionValidationError::ClientNotAllowed { client: 4, .. })
+        ));
+        assert!(rules.check(&Transaction::new_dispute(4, 1)).is_err());
+    }
+
+    #[test]
+    fn blocked_clients_are_rejected_before_other_rules() {
+        let mut rules = rules();
+        rules.blocked_clients = parse_blocklist("# closed\n3\n\n 2 # sanctioned\n").unwrap();
+        let deposit = |client| Transaction::new_deposit(client, 1, amount!(10.0)).unwrap();
+        assert!(rules.check(&deposit(1)).is_ok());
+        assert!(matches!(
+            rules.check(&deposit(2)),
+            Err(TransactionValidationError::ClientBlocked { client: 2, .. })
+        ));
+        assert!(matches!(
+            rules.check(&Transaction::new_dispute(3, 1)),
+            Err(TransactionValidationError::ClientBlocked { client: 3, .. })
+        ));
+        assert!(parse_blocklist("1\nseven\n")
+            .unwrap_err()
+            .to_string()
+            .starts_with("line 2: "));
+    }
+
+    #[test]
+    fn amounts_outside_bounds_are_rejected() {
+        let rules = rules();
+        assert!(rules
+            .check(&Transaction::new_deposit(1, 1, amount!(0.5)).unwrap())
+            .is_err());
+        assert!(rules
+            .check(&Transaction::new_deposit(1, 1, amount!(10000.0)).unwrap())
+            .is_ok());
+        assert!(rules
+            .check(&Transaction::new_withdrawal(1, 2, amount!(500.0)).unwrap())
+            .is_ok());
+        assert!(matches!(
+            rules.check(&Transaction::new_withdrawal(1, 2, amount!(500.01)).unwrap()),
+            Err(TransactionValidationError::AmountOutOfBounds { tx: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn tiers_add_their_own_bounds() {
+        let mut rules: Rules = toml::from_str(
+            r#"
+            [amounts.withdrawal]
+            max = 500.0
+
+            [tiers.basic.withdrawal]
+            max = 100.0
+
+            [tiers.premium]
+            max_balance = 1000000.0
+            "#,
+        )
+        .unwrap();
+        let tiers = parse_client_tiers("client,tier # header\n1, basic\n2,premium\n").unwrap_err();
+        assert!(tiers.to_string().starts_with("line 1: invalid client id"));
+        let tiers = parse_client_tiers("1, basic\n\n2,premium\n").unwrap();
+        rules.assign_tiers(tiers).unwrap();
+        assert!(rules
+            .assign_tiers(HashMap::from([(3, "gold".to_string())]))
+            .is_err());
+
+        let withdrawal = |client| Transaction::new_withdrawal(client, 1, amount!(150.0)).unwrap();
+        assert!(matches!(
+            rules.check(&withdrawal(1)),
+            Err(TransactionValidationError::AmountOutOfBounds { client: 1, .. })
+        ));
+        assert!(rules.check(&withdrawal(2)).is_ok());
+        assert!(rules.check(&withdrawal(3)).is_ok());
+        assert_eq!(rules.tier(1), Some("basic"));
+        assert!(rules.tier_limits(2).unwrap().max_balance.is_some());
+        assert!(rules.tier_limits(3).is_none());
+    }
+
+    #[test]
+    fn client_profiles_block_and_require_verified_identities() {
+        let mut rules = Rules {
+            require_kyc: true,
+            ..Rules::default()
+        };
+        let profile = |kyc, blocked| ClientProfile {
+            kyc,
+            blocked,
+            ..ClientProfile::default()
+        };
+        rules
+            .set_profiles(
+                [
+                    (1, profile(KycStatus::Verified, false)),
+                    (2, profile(KycStatus::Pending, false)),
+                    (3, profile(KycStatus::Verified, true)),
+                ]
+                .into_iter()
+                .collect(),
+            )
+            .unwrap();
+
+        let withdrawal = |client| Transaction::new_withdrawal(client, 1, amount!(1.0)).unwrap();
+        assert!(rules.check(&withdrawal(1)).is_ok());
+        assert!(matches!(
+            rules.check(&withdrawal(2)),
+            Err(TransactionValidationError::KycRequired { client: 2, .. })
+        ));
+        // unknown clients aren't verified either
+        assert!(rules.check(&withdrawal(4)).is_err());
+        assert!(rules
+            .check(&Transaction::new_deposit(2, 1, amount!(1.0)).unwrap())
+            .is_ok());
+        assert!(matches!(
+            rules.check(&Transaction::new_deposit(3, 1, amount!(1.0)).unwrap()),
+            Err(TransactionValidationError::ClientBlocked { client: 3, .. })
+        ));
+
+        let tiered = ClientProfile {
+            tier: Some("gold".to_string()),
+            ..ClientProfile::default()
+        };
+        assert!(rules
+            .set_profiles([(5, tiered)].into_iter().collect())
+            .is_err());
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(toml::from_str::<Rules>("deny = [1]").is_err());
+    }
+}
@@ -0,0 +1,599 @@
+//! JSON Schema (draft 2020-12) of the input records and the account report, and an OpenAPI
+//! 3.1 document of the HTTP server, for integrators generating clients.
+//!
+//! The schemas describe one CSV row each, with the columns as properties.
+
+use serde_json::{json, Value};
+
+use crate::export::ExportOptions;
+use crate::ingest::PrecisionPolicy;
+use crate::transactions::{Client, TransactionId, TransactionKind, MAX_OCCURRENCES};
+
+const DIALECT: &str = "https://json-schema.org/draft/2020-12/schema";
+
+/// A row of the transaction input.
+pub fn transaction_record_schema(precision: &PrecisionPolicy) -> Value {
+    // corrections come from a corrections file, not from the input
+    let kinds: Vec<&str> = TransactionKind::ALL
+        .iter()
+        .filter(|kind| **kind != TransactionKind::Correction)
+        .map(|kind| kind.name())
+        .collect();
+    json!({
+        "$schema": DIALECT,
+        "title": "TransactionRecord",
+        "description": "A row of the transaction input, columns `type,client,tx,amount` \
+                        followed by the optional ones",
+        "type": "object",
+        "properties": {
+            "type": { "type": "string", "enum": kinds },
+            "client": { "type": "integer", "minimum": 0, "maximum": Client::MAX },
+            "tx": { "type": "integer", "minimum": 0, "maximum": TransactionId::MAX },
+            "amount": {
+                "type": ["number", "null"],
+                "description": format!(
+                    "Required by deposits, withdrawals, holds, escrow holds, adjustments and \
+                     recurring records, ignored otherwise; positive except for adjustments and \
+                     recurring records, which are signed; at most {} decimal places",
+                    precision.max_decimal_places
+                ),
+            },
+            "memo": {
+                "type": ["string", "null"],
+                "description": "Free-text reference, also read from a `reference` column",
+            },
+            "counterparty": {
+                "type": ["string", "null"],
+                "description": "Merchant or other party, also read from a `merchant` column; \
+                                an escrow release with a counterparty pays the escrow out to it",
+            },
+            "batch_id": {
+                "type": ["string", "null"],
+                "description": "Consecutive records with the same batch id are applied all or \
+                                nothing, also read from a `batch` column",
+            },
+            "tenant": {
+                "type": ["string", "null"],
+                "description": "Partner program the record belongs to, required with \
+                                per-tenant reports",
+            },
+            "reason": {
+                "type": ["string", "null"],
+                "description": "Reason code, required by adjustments",
+            },
+            "escrow": {
+                "type": ["string", "null"],
+                "description": "Name of the escrow, required by escrow holds",
+            },
+            "execute_at": {
+                "type": ["integer", "null"],
+                "minimum": 0,
+                "description": "Seconds since the Unix epoch before which the record is held \
+                                back; the time the clock moves to for advance_time, which \
+                                requires it; the first occurrence of a recurring record",
+            },
+            "interval": {
+                "type": ["integer", "null"],
+                "minimum": 0,
+                "description": "Seconds between the occurrences, required by recurring records",
+            },
+            "count": {
+                "type": ["integer", "null"],
+                "minimum": 1,
+                "maximum": MAX_OCCURRENCES,
+                "description": "Occurrences, required by recurring records",
+            },
+        },
+        "required": ["type", "client", "tx"],
+    })
+}
+
+/// A row of the account report, with the columns `options` add.
+pub fn account_report_schema(options: &ExportOptions) -> Value {
+    let amount = |description: &str| {
+        let description = format!(
+            "{}, rounded to {} decimal places",
+            description, options.precision
+        );
+        json!({ "type": "number", "description": description })
+    };
+    let count = || json!({ "type": "integer", "minimum": 0 });
+    let mut properties = json!({
+        "client": { "type": "integer", "minimum": 0, "maximum": Client::MAX },
+        "available": amount("Funds the client can use"),
+        "held": amount("Funds held by disputes and holds"),
+        "total": amount("Available, held and escrowed funds"),
+        "locked": { "type": "boolean", "description": "Frozen by a chargeback" },
+    });
+    let mut required = vec!["client", "available", "held", "total", "locked"];
+    if options.show_external_ids() {
+        // listed next to the client id
+        let description = "Id of the client in downstream systems, null for clients without one";
+        properties["external_id"] =
+            json!({ "type": ["string", "null"], "description": description });
+        required.insert(1, "external_id");
+    }
+    let mut extra = vec![];
+    if options.show_flags {
+        extra.push(("flagged", json!({ "type": "boolean" })));
+    }
+    if options.extended {
+        extra.push(("disputes_open", count()));
+        extra.push(("disputes_total", count()));
+        extra.push(("chargebacks", count()));
+    }
+    if options.show_tiers() {
+        let tier = "Account tier of the client, empty for clients without one";
+        extra.push(("tier", json!({ "type": "string", "description": tier })));
+    }
+    if options.show_reserves() {
+        extra.push((
+            "reserve",
+            amount("Funds the client must keep available, 0 for clients without a reserve"),
+        ));
+    }
+    if options.show_escrow {
+        extra.push(("escrowed", amount("Funds in the escrows of the account")));
+    }
+    for (column, schema) in extra {
+        properties[column] = schema;
+        required.push(column);
+    }
+    json!({
+        "$schema": DIALECT,
+        "title": "AccountReportRow",
+        "description": "A row of the account report, one per client sorted by client",
+        "type": "object",
+        "properties": properties,
+        "required": required,
+    })
+}
+
+/// The routes of `Server`, with bodies in the formats described by the schemas above.
+pub fn openapi(precision: &PrecisionPolicy, options: &ExportOptions) -> Value {
+    let mut record = transaction_record_schema(precision);
+    let mut report = account_report_schema(options);
+    for schema in [&mut record, &mut report] {
+        if let Some(schema) = schema.as_object_mut() {
+            schema.remove("$schema");
+        }
+    }
+    let csv = |reference: &str, description: &str| {
+        json!({
+            "description": description,
+            "content": {
+                "text/csv": {
+                    "schema": {
+                        "type": "array",
+                        "items": { "$ref": format!("#/components/schemas/{}", reference) },
+                    },
+                },
+            },
+        })
+    };
+    let text = |description: &str| {
+        json!({
+            "description": description,
+            "content": { "text/plain": { "schema": { "type": "string" } } },
+        })
+    };
+    let batch_failure = |description: &str| {
+        json!({
+            "description": description,
+            "content": {
+                "application/json": {
+                    "schema": { "$ref": "#/components/schemas/BatchFailure" },
+                },
+            },
+        })
+    };
+    let retry_after = |description: &str| {
+        let mut response = text(description);
+        response["headers"] = json!({
+            "Retry-After": {
+                "description": "Seconds to wait before retrying",
+                "schema": { "type": "integer", "minimum": 1 },
+            },
+        });
+        response
+    };
+    let readiness = |description: &str| {
+        json!({
+            "description": description,
+            "content": {
+                "application/json": {
+                    "schema": { "$ref": "#/components/schemas/Readiness" },
+                },
+            },
+        })
+    };
+    let throttled = retry_after("The caller or all callers together submit too often");
+    // every route names the least role its token needs
+    let role = |role: &str| json!([{ "bearer": [role] }]);
+    let mut document = json!({
+        "openapi": "3.1.0",
+        "info": {
+            "title": "payments",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": {
+            "/transactions": {
+                "post": {
+                    "summary": "Process transaction records",
+                    "security": role("submit"),
+                    "requestBody": csv("TransactionRecord", "CSV records with a header row"),
+                    "responses": {
+                        "200": {
+                            "description": "Number of applied, rejected and unparsable records",
+                            "content": {
+                                "application/json": {
+                                    "schema": { "$ref": "#/components/schemas/SubmitResult" },
+                                },
+                            },
+                        },
+                        "400": text("The body can't be read"),
+                        "429": throttled.clone(),
+                    },
+                },
+            },
+            "/batches": {
+                "post": {
+                    "summary": "Apply all transaction records or none of them",
+                    "security": role("submit"),
+                    "requestBody": csv("TransactionRecord", "CSV records with a header row"),
+                    "responses": {
+                        "200": {
+                            "description": "Number of applied records",
+                            "content": {
+                                "application/json": {
+                                    "schema": {
+                                        "type": "object",
+                                        "properties": {
+                                            "applied": { "type": "integer", "minimum": 0 },
+                                        },
+                                        "required": ["applied"],
+                                    },
+                                },
+                            },
+                        },
+                        "400": batch_failure("A record can't be parsed, nothing was applied"),
+                        "422": batch_failure("A record was rejected, nothing was applied"),
+                        "429": throttled,
+                    },
+                },
+            },
+            "/accounts": {
+                "get": {
+                    "summary": "Account report",
+                    "security": role("read"),
+                    "responses": {
+                        "200": csv("AccountReportRow", "Every account, with a header row"),
+                    },
+                },
+            },
+            "/accounts/{client}": {
+                "get": {
+                    "summary": "Report of a single account",
+                    "security": role("read"),
+                    "parameters": [{
+                        "name": "client",
+                        "in": "path",
+                        "required": true,
+                        "schema": { "type": "integer", "minimum": 0, "maximum": Client::MAX },
+                    }],
+                    "responses": {
+                        "200": csv("AccountReportRow", "The account, with a header row"),
+                        "400": text("Invalid client id"),
+                        "404": text("Unknown client"),
+                    },
+                },
+            },
+            "/metrics": {
+                "get": {
+                    "summary": "Engine metrics",
+                    "security": role("admin"),
+                    "responses": {
+                        "200": csv("Metric", "`metric,value` rows with a header row"),
+                    },
+                },
+            },
+            "/healthz": {
+                "get": {
+                    "summary": "Liveness of the server",
+                    "responses": {
+                        "200": {
+                            "description": "Time since the server started",
+                            "content": {
+                                "application/json": {
+                                    "schema": { "$ref": "#/components/schemas/Health" },
+                                },
+                            },
+                        },
+                    },
+                },
+            },
+            "/readyz": {
+                "get": {
+                    "summary": "Readiness of the server",
+                    "responses": {
+                        "200": readiness("Dependencies are reachable and requests are served"),
+                        "503": readiness("A dependency is unreachable, the request queue is \
+                                          full or the last snapshot couldn't be saved"),
+                    },
+                },
+            },
+            "/accounts/{client}/unfreeze": {
+                "post": {
+                    "summary": "Lift the freeze of an account",
+                    "security": role("admin"),
+                    "parameters": [{
+                        "name": "client",
+                        "in": "path",
+                        "required": true,
+                        "schema": { "type": "integer", "minimum": 0, "maximum": Client::MAX },
+                    }],
+                    "responses": {
+                        "200": {
+                            "description": "Whether the account was frozen",
+                            "content": {
+                                "application/json": {
+                                    "schema": {
+                                        "type": "object",
+                                        "properties": {
+                                            "client": { "type": "integer", "minimum": 0 },
+                                            "unfrozen": { "type": "boolean" },
+                                        },
+                                        "required": ["client", "unfrozen"],
+                                    },
+                                },
+                            },
+                        },
+                        "400": text("Invalid client id"),
+                        "404": text("Unknown client"),
+                    },
+                },
+            },
+        },
+        "components": {
+            "securitySchemes": {
+                "bearer": {
+                    "type": "http",
+                    "scheme": "bearer",
+                    "description": "A static token or an HS256 JWT with a `role` claim, only \
+                        required when the server is configured with tokens. Roles are `read`, \
+                        `submit` and `admin`, each allowing what the ones before it allow.",
+                },
+            },
+            "schemas": {
+                "TransactionRecord": record,
+                "AccountReportRow": report,
+                "SubmitResult": {
+                    "type": "object",
+                    "properties": {
+                        "applied": { "type": "integer", "minimum": 0 },
+                        "rejected": { "type": "integer", "minimum": 0 },
+                        "invalid": { "type": "integer", "minimum": 0 },
+                    },
+                    "required": ["applied", "rejected", "invalid"],
+                },
+                "BatchFailure": {
+                    "type": "object",
+                    "properties": {
+                        "applied": { "const": 0 },
+                        "line": {
+                            "type": ["integer", "null"],
+                            "description": "Input line of the first failing record",
+                        },
+                        "code": { "type": "string" },
+                        "reason": { "type": "string" },
+                    },
+                    "required": ["applied", "line", "code", "reason"],
+                },
+                "Health": {
+                    "type": "object",
+                    "properties": {
+                        "status": { "const": "alive" },
+                        "uptime_seconds": { "type": "integer", "minimum": 0 },
+                    },
+                    "required": ["status", "uptime_seconds"],
+                },
+                "Readiness": {
+                    "type": "object",
+                    "properties": {
+                        "ready": { "type": "boolean" },
+                        "checks": {
+                            "type": "object",
+                            "description": "`ok` or the reason it failed, per dependency",
+                            "additionalProperties": { "type": "string" },
+                        },
+                        "queue": {
+                            "type": "object",
+                            "properties": {
+                                "waiting": { "type": "integer", "minimum": 0 },
+                                "capacity": { "type": "integer", "minimum": 1 },
+                            },
+                            "required": ["waiting", "capacity"],
+                        },
+                        "snapshot": {
+                            "type": ["object", "null"],
+                            "description": "Null when the server takes no snapshots",
+                            "properties": {
+                                "age_seconds": { "type": ["integer", "null"] },
+                                "error": { "type": ["string", "null"] },
+                            },
+                            "required": ["age_seconds", "error"],
+                        },
+                    },
+                    "required": ["ready", "checks", "queue", "snapshot"],
+                },
+                "Metric": {
+                    "type": "object",
+                    "properties": {
+                        "metric": { "type": "string" },
+                        "value": { "type": "string" },
+                    },
+                    "required": ["metric", "value"],
+                },
+            },
+        },
+    });
+    let refusals = [
+        ("401", text("Missing, invalid or expired bearer token")),
+        (
+            "403",
+            text("The role of the token doesn't allow the request"),
+        ),
+        ("503", retry_after("Too many requests are waiting")),
+    ];
+    if let Some(paths) = document["paths"].as_object_mut() {
+        for operation in paths.values_mut().filter_map(Value::as_object_mut) {
+            // probes need no token
+            for operation in operation
+                .values_mut()
+                .filter(|operation| operation.get("security").is_some())
+            {
+                for (status, response) in &refusals {
+                    operation["responses"][status] = response.clone();
+                }
+            }
+        }
+    }
+    document
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::amount;
+    use crate::export::accounts_info_as_csv;
+    use crate::ingest::TransactionRecord;
+    use crate::transactions::{PaymentEngine, Transaction};
+    use serde::de::{self, Deserialize, Visitor};
+    use std::collections::BTreeSet;
+
+    /// Deserializer recording the fields a derived `Deserialize` of a struct asks for.
+    struct Fields<'a>(&'a mut &'static [&'static str]);
+
+    impl<'de> de::Deserializer<'de> for Fields<'_> {
+        type Error = de::value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+            Err(de::Error::custom("not a struct"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _: &'static str,
+            fields: &'static [&'static str],
+            _: V,
+        ) -> Result<V::Value, Self::Error> {
+            *self.0 = fields;
+            Err(de::Error::custom("fields recorded"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+            option unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier
+            ignored_any
+        }
+    }
+
+    fn properties(schema: &Value) -> BTreeSet<&str> {
+        schema["properties"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect()
+    }
+
+    #[test]
+    fn schemas_list_the_columns_the_formats_have() {
+        let mut fields: &[&str] = &[];
+        let _ = TransactionRecord::deserialize(Fields(&mut fields));
+        let record = transaction_record_schema(&PrecisionPolicy::default());
+        let columns = properties(&record);
+        assert!(fields.len() > columns.len());
+        for field in fields {
+            // aliases are named in the description of the column they stand for
+            let alias = format!("`{}`", field);
+            assert!(
+                columns.contains(field)
+                    || columns
+                        .iter()
+                        .any(|column| record["properties"][column]["description"]
+                            .as_str()
+                            .is_some_and(|description| description.contains(&alias))),
+                "{} is not described",
+                field
+            );
+        }
+        assert!(columns.iter().all(|column| fields.contains(column)));
+
+        let mut engine = PaymentEngine::new();
+        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, amount!(1.0)).unwrap());
+        let plain = ExportOptions::default();
+        let everything = ExportOptions {
+            show_flags: true,
+            extended: true,
+            show_escrow: true,
+            tiers: [(1, "gold".to_string())].into_iter().collect(),
+            external_ids: [(1, "C-1".to_string())].into_iter().collect(),
+            default_reserve: Some(amount!(1.0)),
+            ..ExportOptions::default()
+        };
+        for options in [plain, everything] {
+            let mut output = vec![];
+            accounts_info_as_csv(engine.accounts_iter(), &mut output, &options).unwrap();
+            let output = String::from_utf8(output).unwrap();
+            let header: Vec<&str> = output.lines().next().unwrap().split(',').collect();
+            let schema = account_report_schema(&options);
+            // required in column order
+            assert_eq!(schema["required"], json!(header));
+            assert_eq!(properties(&schema), header.into_iter().collect());
+        }
+    }
+
+    #[test]
+    fn schemas_follow_the_formats() {
+        let record = transaction_record_schema(&PrecisionPolicy::default());
+        let kinds = record["properties"]["type"]["enum"].as_array().unwrap();
+        assert_eq!(kinds.len(), TransactionKind::ALL.len() - 1);
+        assert!(!kinds.contains(&json!("correction")));
+        assert!(kinds.contains(&json!("chargeback_reversal")));
+        assert_eq!(record["properties"]["client"]["maximum"], 65535);
+
+        let plain = account_report_schema(&ExportOptions::default());
+        assert_eq!(plain["required"].as_array().unwrap().len(), 5);
+        let extended = account_report_schema(&ExportOptions {
+            extended: true,
+            ..ExportOptions::default()
+        });
+        assert_eq!(extended["required"][7], "chargebacks");
+        assert_eq!(extended["properties"]["disputes_open"]["minimum"], 0);
+
+        let document = openapi(&PrecisionPolicy::default(), &ExportOptions::default());
+        assert_eq!(
+            document["paths"]["/accounts/{client}"]["get"]["responses"]["404"]["description"],
+            "Unknown client"
+        );
+        let unfreeze = &document["paths"]["/accounts/{client}/unfreeze"]["post"];
+        assert_eq!(unfreeze["security"][0]["bearer"][0], "admin");
+        assert!(unfreeze["responses"]["403"].is_object());
+        assert!(unfreeze["responses"]["429"].is_null());
+        assert!(document["paths"]["/readyz"]["get"]["responses"]["401"].is_null());
+        assert_eq!(
+            document["paths"]["/transactions"]["post"]["responses"]["429"]["headers"]
+                ["Retry-After"]["schema"]["minimum"],
+            1
+        );
+        let components = &document["components"]["schemas"];
+        assert_eq!(components["TransactionRecord"]["required"][0], "type");
+        assert!(components["AccountReportRow"].get("$schema").is_none());
+        assert_eq!(
+            document["paths"]["/batches"]["post"]["responses"]["422"]["content"]
+                ["application/json"]["schema"]["$ref"],
+            "#/components/schemas/BatchFailure"
+        );
+    }
+}
@@ -0,0 +1,695 @@
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tiny_http::{Header, Method, Request, Response};
+
+use crate::checkpoint::Checkpointer;
+use crate::diagnostics::{report, ErrorEvent};
+use crate::export::{accounts_info_as_csv, metrics_as_csv, ExportOptions};
+use crate::ingest::{records_from_reader, CsvDialect, PrecisionPolicy};
+use crate::snapshot::InputOffset;
+use crate::transactions::{BatchResult, Client, PaymentEngine};
+
+mod auth;
+mod limit;
+
+pub use auth::{AuthError, Authenticator, Role};
+pub use limit::{RateLimiter, ServerLimits};
+
+/// How long `Server::run` waits for a request before checking whether it should stop.
+const POLL: Duration = Duration::from_millis(200);
+
+/// HTTP front end of a `PaymentEngine`. Requests are handled one at a time, in arrival order,
+/// while a thread receives them into a queue bounded by `ServerLimits::queue`.
+///
+/// - `POST /transactions` processes a CSV body in the input format (header included) and
+///   answers with JSON counts of applied, rejected and unparsable records
+/// - `POST /batches` applies all records of such a body or none of them, see
+///   `PaymentEngine::process_batch`
+/// - `GET /accounts` returns the account report, `GET /accounts/<client>` a single account
+/// - `GET /metrics` returns the engine metrics as `metric,value` rows
+/// - `POST /accounts/<client>/unfreeze` lifts the freeze of an account, see
+///   `PaymentEngine::unfreeze`
+/// - `GET /healthz` answers as long as requests are served, `GET /readyz` reports the
+///   `with_readiness_check` checks, the requests waiting and the age of the last snapshot, with
+///   a 503 when a check fails, the queue is full or the last snapshot couldn't be saved
+///
+/// With an `Authenticator` set by `with_auth`, requests need a bearer token whose role allows
+/// them: `Role::Read` for the account routes, `Role::Submit` to post transactions and batches,
+/// `Role::Admin` for the metrics and unfreezing. The health routes are open to probes.
+///
+/// Submissions beyond the rates of the `ServerLimits` set by `with_limits` are answered with a
+/// 429, and requests beyond its queue with a 503, both with a `Retry-After` header. Bodies
+/// larger than its `max_body` are answered with a 413.
+pub struct Server {
+    http: Arc<tiny_http::Server>,
+    engine: PaymentEngine,
+    precision: PrecisionPolicy,
+    export_options: ExportOptions,
+    auth: Authenticator,
+    limits: ServerLimits,
+    rate: Option<RateLimiter>,
+    client_rate: Option<RateLimiter>,
+    /// Requests received but not served yet.
+    waiting: Arc<AtomicUsize>,
+    started: Instant,
+    checks: Vec<(String, ReadinessCheck)>,
+    checkpointer: Option<Checkpointer>,
+}
+
+/// Status, content type and body of a response.
+type Reply = (u16, &'static str, Vec<u8>);
+
+/// A dependency `/readyz` checks, e.g. that a storage backend is reachable, failing with the
+/// reason it isn't.
+pub type ReadinessCheck = Box<dyn FnMut() -> Result<(), String>>;
+
+impl Server {
+    pub fn bind(
+        address: &str,
+        engine: PaymentEngine,
+        precision: PrecisionPolicy,
+        export_options: ExportOptions,
+    ) -> anyhow::Result<Self> {
+        let http = tiny_http::Server::http(address).map_err(|err| anyhow::anyhow!(err))?;
+        Ok(Server {
+            http: Arc::new(http),
+            engine,
+            precision,
+            export_options,
+            auth: Authenticator::new(),
+            limits: ServerLimits::default(),
+            rate: None,
+            client_rate: None,
+            waiting: Arc::new(AtomicUsize::new(0)),
+            started: Instant::now(),
+            checks: vec![],
+            checkpointer: None,
+        })
+    }
+
+    pub fn with_auth(mut self, auth: Authenticator) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    pub fn with_limits(mut self, limits: ServerLimits) -> Self {
+        self.rate = limits.rate.map(RateLimiter::new);
+        self.client_rate = limits.client_rate.map(RateLimiter::new);
+        self.limits = limits;
+        self
+    }
+
+    pub fn with_readiness_check(mut self, name: &str, check: ReadinessCheck) -> Self {
+        self.checks.push((name.to_string(), check));
+        self
+    }
+
+    /// Snapshots the engine as `checkpointer` says, with the transactions applied by the
+    /// submissions, and a last time when the server stops.
+    pub fn with_snapshots(mut self, checkpointer: Checkpointer) -> Self {
+        self.checkpointer = Some(checkpointer);
+        self
+    }
+
+    pub fn local_addr(&self) -> Option<std::net::SocketAddr> {
+        self.http.server_addr().to_ip()
+    }
+
+    /// Serves requests until `stop` is set, then hands the engine back. Requests still queued
+    /// then are dropped unanswered.
+    pub fn run(mut self, stop: &AtomicBool) -> anyhow::Result<PaymentEngine> {
+        let (sender, requests) = mpsc::sync_channel(self.limits.queue);
+        let http = Arc::clone(&self.http);
+        let waiting = Arc::clone(&self.waiting);
+        thread::scope(|scope| {
+            let receiving = scope.spawn(move || -> std::io::Result<()> {
+                while !stop.load(Ordering::SeqCst) {
+                    let Some(request) = http.recv_timeout(POLL)? else {
+                        continue;
+                    };
+                    waiting.fetch_add(1, Ordering::SeqCst);
+                    if let Err(TrySendError::Full(request)) = sender.try_send(request) {
+                        waiting.fetch_sub(1, Ordering::SeqCst);
+                        let busy = text(503, "too many requests waiting".to_string());
+                        respond(request, busy, Some(Duration::from_secs(1)));
+                    }
+                }
+                Ok(())
+            });
+            while !stop.load(Ordering::SeqCst) && !receiving.is_finished() {
+                if let Ok(request) = requests.recv_timeout(POLL) {
+                    self.waiting.fetch_sub(1, Ordering::SeqCst);
+                    self.serve(request);
+                }
+                // snapshots are also due while no requests come in
+                if let Some(checkpointer) = &mut self.checkpointer {
+                    if checkpointer.is_due() {
+                        checkpointer.checkpoint(&self.engine, InputOffset::default());
+                    }
+                }
+            }
+            receiving
+                .join()
+                .expect("the receiving thread doesn't panic")
+        })?;
+        if let Some(checkpointer) = self.checkpointer.take() {
+            checkpointer.finish(&self.engine, InputOffset::default());
+        }
+        Ok(self.engine)
+    }
+
+    fn serve(&mut self, mut request: Request) {
+        let (reply, retry_after) = self.handle(&mut request);
+        respond(request, reply, retry_after);
+    }
+
+    /// The reply to `request`, with the time the caller should wait before retrying when it
+    /// was throttled.
+    fn handle(&mut self, request: &mut Request) -> (Reply, Option<Duration>) {
+        let authorization = request
+            .headers()
+            .iter()
+            .find(|header| header.field.equiv("Authorization"))
+            .map(|header| header.value.to_string());
+        if let Err(refusal) =
+            self.authorize(request.method(), request.url(), authorization.as_deref())
+        {
+            return (refusal, None);
+        }
+        // tokens are only told apart from addresses once they were checked
+        let caller = match (authorization, request.remote_addr()) {
+            (Some(token), _) if self.auth.is_enabled() => token,
+            (_, Some(address)) => address.ip().to_string(),
+            (_, None) => String::new(),
+        };
+        if let Err(wait) = self.throttle(request.method(), request.url(), &caller, Instant::now()) {
+            return (text(429, "rate limit exceeded".to_string()), Some(wait));
+        }
+        let length = request.body_length();
+        let reply = match read_body(request.as_reader(), length, self.limits.max_body) {
+            Ok(body) => self.reply(request.method(), request.url(), &body),
+            Err(refusal) => refusal,
+        };
+        (reply, None)
+    }
+
+    /// Takes a submission of `caller` from the rate limits, or tells how long until it may
+    /// submit again. Only the submission routes are limited.
+    fn throttle(
+        &mut self,
+        method: &Method,
+        url: &str,
+        caller: &str,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        if !matches!(
+            (method, segments(url).as_slice()),
+            (Method::Post, ["transactions"] | ["batches"])
+        ) {
+            return Ok(());
+        }
+        if let Some(limiter) = &mut self.client_rate {
+            limiter.check(caller, now)?;
+        }
+        if let Some(limiter) = &mut self.rate {
+            limiter.check("", now)?;
+        }
+        Ok(())
+    }
+
+    /// Checks the bearer token of a request against the role its route needs, when the server
+    /// has tokens. Unknown routes need `Role::Admin`, so that they don't tell anyone else which
+    /// routes exist.
+    fn authorize(
+        &self,
+        method: &Method,
+        url: &str,
+        authorization: Option<&str>,
+    ) -> Result<(), Reply> {
+        if !self.auth.is_enabled() {
+            return Ok(());
+        }
+        let required = match (method, segments(url).as_slice()) {
+            (Method::Get, ["healthz"] | ["readyz"]) => return Ok(()),
+            (Method::Get, ["accounts"] | ["accounts", _]) => Role::Read,
+            (Method::Post, ["transactions"] | ["batches"]) => Role::Submit,
+            _ => Role::Admin,
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        match self.auth.authorize(authorization, required, now) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(text(err.status(), err.to_string())),
+        }
+    }
+
+    fn reply(&mut self, method: &Method, url: &str, body: &[u8]) -> Reply {
+        match (method, segments(url).as_slice()) {
+            (Method::Post, ["transactions"]) => self.submit(body),
+            (Method::Post, ["batches"]) => self.submit_batch(body),
+            (Method::Get, ["accounts"]) => self.csv(|engine, output, options| {
+                accounts_info_as_csv(engine.accounts_iter(), output, options)
+            }),
+            (Method::Get, ["accounts", client]) => match client.parse::<Client>() {
+                Ok(client) if self.engine.get_account(client).is_some() => {
+                    self.csv(|engine, output, options| {
+                        accounts_info_as_csv(engine.get_account(client), output, options)
+                    })
+                }
+                Ok(_) => text(404, "unknown client".to_string()),
+                Err(_) => text(400, "invalid client id".to_string()),
+            },
+            (Method::Get, ["metrics"]) => self
+                .csv(|engine, output, options| metrics_as_csv(&engine.metrics(), output, options)),
+            (Method::Get, ["healthz"]) => {
+                let health = serde_json::json!({
+                    "status": "alive",
+                    "uptime_seconds": self.started.elapsed().as_secs(),
+                });
+                (200, "application/json", health.to_string().into_bytes())
+            }
+            (Method::Get, ["readyz"]) => self.readiness(),
+            (Method::Post, ["accounts", client, "unfreeze"]) => match client.parse::<Client>() {
+                Ok(client) => match self.engine.unfreeze(client) {
+                    Some(unfrozen) => {
+                        let answer = serde_json::json!({ "client": client, "unfrozen": unfrozen });
+                        (200, "application/json", answer.to_string().into_bytes())
+                    }
+                    None => text(404, "unknown client".to_string()),
+                },
+                Err(_) => text(400, "invalid client id".to_string()),
+            },
+            (
+                _,
+                ["transactions"]
+                | ["batches"]
+                | ["accounts"]
+                | ["accounts", _]
+                | ["metrics"]
+                | ["accounts", _, "unfreeze"]
+                | ["healthz"]
+                | ["readyz"],
+            ) => text(405, "method not allowed".to_string()),
+            _ => text(404, "not found".to_string()),
+        }
+    }
+
+    fn submit(&mut self, body: &[u8]) -> Reply {
+        let (mut applied, mut rejected, mut invalid) = (0, 0, 0);
+        for record in records_from_reader(body, &CsvDialect::default()) {
+            let line = record.line();
+            let memo = record.memo().map(str::to_string);
+            match record.into_transaction(&self.precision) {
+                Ok(transaction) => match self.engine.process_transaction(transaction) {
+                    Ok(()) => applied += 1,
+                    Err(err) => {
+                        report(
+                            ErrorEvent::rejected(&err)
+                                .with_line(line)
+                                .with_memo(memo.as_deref()),
+                        );
+                        rejected += 1;
+                    }
+                },
+                Err(err) => {
+                    report(
+                        ErrorEvent::unparsable(&err)
+                            .with_line(line)
+                            .with_memo(memo.as_deref()),
+                    );
+                    invalid += 1;
+                }
+            }
+        }
+        self.count_applied(applied);
+        let counts = serde_json::json!({
+            "applied": applied,
+            "rejected": rejected,
+            "invalid": invalid,
+        });
+        (200, "application/json", counts.to_string().into_bytes())
+    }
+
+    /// Nothing is applied when a record can't be parsed or is rejected; the answer then names
+    /// the first such record by its line.
+    fn submit_batch(&mut self, body: &[u8]) -> Reply {
+        let mut transactions = vec![];
+        let mut lines = vec![];
+        for record in records_from_reader(body, &CsvDialect::default()) {
+            let line = record.line();
+            match record.into_transaction(&self.precision) {
+                Ok(transaction) => {
+                    transactions.push(transaction);
+                    lines.push(line);
+                }
+                Err(err) => {
+                    let failure = batch_failure(line, err.code(), &err);
+                    return (400, "application/json", failure);
+                }
+            }
+        }
+        match self.engine.process_batch(transactions) {
+            BatchResult::Applied(applied) => {
+                self.count_applied(applied as u64);
+                let counts = serde_json::json!({ "applied": applied });
+                (200, "application/json", counts.to_string().into_bytes())
+            }
+            BatchResult::Rejected { index, error } => {
+                report(ErrorEvent::rejected(&error).with_line(lines[index]));
+                let failure = batch_failure(lines[index], error.code(), &error);
+                (422, "application/json", failure)
+            }
+        }
+    }
+
+    fn readiness(&mut self) -> Reply {
+        let mut ready = true;
+        let mut checks = serde_json::Map::new();
+        for (name, check) in &mut self.checks {
+            let status = check().err().unwrap_or_else(|| "ok".to_string());
+            ready &= status == "ok";
+            checks.insert(name.clone(), status.into());
+        }
+        let waiting = self.waiting.load(Ordering::SeqCst);
+        ready &= waiting < self.limits.queue;
+        let snapshot = self.checkpointer.as_ref().map(|checkpointer| {
+            let status = checkpointer.status();
+            ready &= status.error.is_none();
+            serde_json::json!({
+                "age_seconds": status.last.map(|last| last.elapsed().as_secs()),
+                "error": status.error,
+            })
+        });
+        let readiness = serde_json::json!({
+            "ready": ready,
+            "checks": checks,
+            "queue": { "waiting": waiting, "capacity": self.limits.queue },
+            "snapshot": snapshot,
+        });
+        let status = if ready { 200 } else { 503 };
+        (
+            status,
+            "application/json",
+            readiness.to_string().into_bytes(),
+        )
+    }
+
+    /// Counts `applied` transactions towards the next snapshot, taking it when it is due.
+    fn count_applied(&mut self, applied: u64) {
+        if let Some(checkpointer) = &mut self.checkpointer {
+            checkpointer.record(applied);
+            if checkpointer.is_due() {
+                checkpointer.checkpoint(&self.engine, InputOffset::default());
+            }
+        }
+    }
+
+    fn csv<F>(&self, write: F) -> Reply
+    where
+        F: FnOnce(
+            &PaymentEngine,
+            &mut Vec<u8>,
+            &ExportOptions,
+        ) -> Result<(), Box<dyn std::error::Error>>,
+    {
+        let mut output = vec![];
+        match write(&self.engine, &mut output, &self.export_options) {
+            Ok(()) => (200, "text/csv", output),
+            Err(err) => text(500, format!("unable to write csv: {}", err)),
+        }
+    }
+}
+
+fn batch_failure(line: Option<u64>, code: &str, reason: &dyn std::fmt::Display) -> Vec<u8> {
+    let failure = serde_json::json!({
+        "applied": 0,
+        "line": line,
+        "code": code,
+        "reason": reason.to_string(),
+    });
+    failure.to_string().into_bytes()
+}
+
+/// Reads a body of at most `max` bytes. One announcing a larger `length` is refused before
+/// anything is read, and one without a length is cut off after `max` bytes.
+fn read_body(reader: impl Read, length: Option<usize>, max: usize) -> Result<Vec<u8>, Reply> {
+    let too_large = || text(413, format!("request body larger than {} bytes", max));
+    if length.is_some_and(|length| length > max) {
+        return Err(too_large());
+    }
+    let mut body = vec![];
+    reader
+        .take(max as u64 + 1)
+        .read_to_end(&mut body)
+        .map_err(|err| text(400, format!("unable to read request body: {}", err)))?;
+    if body.len() > max {
+        return Err(too_large());
+    }
+    Ok(body)
+}
+
+fn respond(request: Request, reply: Reply, retry_after: Option<Duration>) {
+    let (status, content_type, body) = reply;
+    let header = Header::from_bytes("Content-Type", content_type)
+        .expect("content types are valid header values");
+    let mut response = Response::from_data(body)
+        .with_status_code(status)
+        .with_header(header);
+    if status == 401 {
+        let challenge = Header::from_bytes("WWW-Authenticate", "Bearer")
+            .expect("the challenge is a valid header value");
+        response = response.with_header(challenge);
+    }
+    if let Some(wait) = retry_after {
+        // whole seconds, rounded up so that retrying then succeeds
+        let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+        let retry = Header::from_bytes("Retry-After", seconds.max(1).to_string())
+            .expect("numbers are valid header values");
+        response = response.with_header(retry);
+    }
+    if let Err(err) = request.respond(response) {
+        report(ErrorEvent::new(
+            "response_failed",
+            "unable to send response",
+            err,
+        ));
+    }
+}
+
+/// The path of `url` split at its slashes.
+fn segments(url: &str) -> Vec<&str> {
+    let path = url.split('?').next().unwrap_or(url);
+    path.trim_matches('/').split('/').collect()
+}
+
+fn text(status: u16, message: String) -> Reply {
+    (status, "text/plain", message.into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server() -> Server {
+        Server::bind(
+            "127.0.0.1:0",
+            PaymentEngine::new(),
+            PrecisionPolicy::default(),
+            ExportOptions::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn submitted_transactions_show_up_in_the_report() {
+        let mut server = server();
+        let (status, _, body) = server.reply(
+            &Method::Post,
+            "/transactions",
+            b"type,client,tx,amount\n\
+              deposit,1,1,2.0\n\
+              withdrawal,1,2,5.0\n\
+              deposit,2,3,-1.0\n",
+        );
+        assert_eq!(status, 200);
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            r#"{"applied":1,"invalid":1,"rejected":1}"#
+        );
+
+        let (status, _, body) = server.reply(&Method::Get, "/accounts/1", b"");
+        assert_eq!(status, 200);
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            "client,available,held,total,locked\n1,2.0,0.0,2.0,false\n"
+        );
+        assert_eq!(server.reply(&Method::Get, "/accounts/2", b"").0, 404);
+        assert_eq!(server.reply(&Method::Get, "/accounts/x", b"").0, 400);
+        assert_eq!(server.reply(&Method::Delete, "/accounts", b"").0, 405);
+        assert_eq!(server.reply(&Method::Get, "/nope", b"").0, 404);
+    }
+
+    #[test]
+    fn batches_apply_all_records_or_none() {
+        let mut server = server();
+        let (status, _, body) = server.reply(
+            &Method::Post,
+            "/batches",
+            b"type,client,tx,amount\n\
+              deposit,1,1,5.0\n\
+              withdrawal,1,2,2.0\n\
+              withdrawal,1,3,4.0\n",
+        );
+        assert_eq!(status, 422);
+        let failure: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(failure["applied"], 0);
+        assert_eq!(failure["line"], 4);
+        assert_eq!(failure["code"], "insufficient_funds");
+        assert_eq!(server.reply(&Method::Get, "/accounts/1", b"").0, 404);
+
+        let (status, _, body) = server.reply(
+            &Method::Post,
+            "/batches",
+            b"type,client,tx,amount\ndeposit,1,1,5.0\nwithdrawal,1,2,2.0\n",
+        );
+        assert_eq!(status, 200);
+        assert_eq!(String::from_utf8(body).unwrap(), r#"{"applied":2}"#);
+        assert_eq!(server.reply(&Method::Get, "/batches", b"").0, 405);
+    }
+
+    #[test]
+    fn tokens_need_the_role_of_their_route() {
+        let server = server().with_auth(
+            Authenticator::new()
+                .with_token("reader", Role::Read)
+                .with_token("operator", Role::Admin),
+        );
+        let status = |method: &Method, url: &str, token: Option<&str>| {
+            let authorization = token.map(|token| format!("Bearer {}", token));
+            match server.authorize(method, url, authorization.as_deref()) {
+                Ok(()) => 200,
+                Err((status, _, _)) => status,
+            }
+        };
+        assert_eq!(status(&Method::Get, "/accounts/1", None), 401);
+        assert_eq!(status(&Method::Get, "/accounts/1", Some("nope")), 401);
+        assert_eq!(status(&Method::Get, "/accounts/1", Some("reader")), 200);
+        assert_eq!(status(&Method::Post, "/transactions", Some("reader")), 403);
+        assert_eq!(status(&Method::Get, "/metrics", Some("reader")), 403);
+        assert_eq!(status(&Method::Post, "/batches", Some("operator")), 200);
+        assert_eq!(
+            status(&Method::Post, "/accounts/1/unfreeze", Some("operator")),
+            200
+        );
+        assert_eq!(status(&Method::Get, "/nope", Some("reader")), 403);
+    }
+
+    #[test]
+    fn submissions_are_throttled_per_caller_and_overall() {
+        let mut server = server().with_limits(ServerLimits {
+            rate: Some(3.0),
+            client_rate: Some(2.0),
+            ..ServerLimits::default()
+        });
+        let now = Instant::now();
+        let mut submit = |caller: &str| server.throttle(&Method::Post, "/batches", caller, now);
+        assert_eq!(submit("a"), Ok(()));
+        assert_eq!(submit("a"), Ok(()));
+        assert_eq!(submit("a"), Err(Duration::from_millis(500)));
+        assert_eq!(submit("b"), Ok(()));
+        // b is within its own rate, but the overall one is used up
+        assert!(submit("b").is_err());
+        assert_eq!(server.throttle(&Method::Get, "/accounts", "a", now), Ok(()));
+    }
+
+    #[test]
+    fn bodies_beyond_the_limit_are_refused() {
+        assert_eq!(
+            read_body(&b"deposit"[..], Some(7), 8),
+            Ok(b"deposit".to_vec())
+        );
+        assert_eq!(read_body(&b"deposit,1"[..], Some(9), 8).unwrap_err().0, 413);
+        // without a length, e.g. when chunked, the body is only read up to the limit
+        assert_eq!(read_body(&b"deposit,1"[..], None, 8).unwrap_err().0, 413);
+        assert_eq!(read_body(&b"deposit"[..], None, 8), Ok(b"deposit".to_vec()));
+    }
+
+    #[test]
+    fn readiness_reports_checks_and_snapshots() {
+        let saved = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&saved);
+        let checkpointer = Checkpointer::start(
+            std::env::temp_dir().join(format!("payments-served-{}", std::process::id())),
+            1,
+            None,
+            Box::new(move |path, _| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                std::fs::write(path, "")?;
+                Ok(())
+            }),
+        )
+        .every(2);
+        let mut server = server()
+            .with_auth(Authenticator::new().with_token("operator", Role::Admin))
+            .with_readiness_check("redis", Box::new(|| Err("connection refused".to_string())))
+            .with_snapshots(checkpointer);
+        assert!(server.authorize(&Method::Get, "/healthz", None).is_ok());
+        assert!(server.authorize(&Method::Get, "/readyz", None).is_ok());
+        assert_eq!(server.reply(&Method::Get, "/healthz", b"").0, 200);
+
+        let (status, _, body) = server.reply(&Method::Get, "/readyz", b"");
+        assert_eq!(status, 503);
+        let readiness: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(readiness["checks"]["redis"], "connection refused");
+        assert_eq!(readiness["queue"]["capacity"], 1024);
+        assert!(readiness["snapshot"]["age_seconds"].is_null());
+
+        server.reply(
+            &Method::Post,
+            "/transactions",
+            b"type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,1.0\ndeposit,1,3,1.0\n",
+        );
+        let checkpointer = server.checkpointer.take().unwrap();
+        let status = checkpointer.finish(&server.engine, InputOffset::default());
+        assert_eq!(saved.load(Ordering::SeqCst), 1);
+        assert!(status.last.is_some());
+        std::fs::remove_file(
+            std::env::temp_dir().join(format!("payments-served-{}", std::process::id())),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn admins_unfreeze_accounts() {
+        let mut server = server();
+        server.reply(
+            &Method::Post,
+            "/transactions",
+            b"type,client,tx,amount
+deposit,1,1,5.0
+dispute,1,1,
+chargeback,1,1,
+",
+        );
+        let (status, _, body) = server.reply(&Method::Post, "/accounts/1/unfreeze", b"");
+        assert_eq!(status, 200);
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            r#"{"client":1,"unfrozen":true}"#
+        );
+        assert!(!server.engine.get_account(1).unwrap().frozen());
+        assert_eq!(
+            server.reply(&Method::Post, "/accounts/2/unfreeze", b"").0,
+            404
+        );
+        assert_eq!(
+            server.reply(&Method::Get, "/accounts/1/unfreeze", b"").0,
+            405
+        );
+    }
+}
@@ -0,0 +1,267 @@
+//! Bearer tokens of `payments serve`, see `[server]`. Requests carry
+//! `Authorization: Bearer <token>`, the token being one of the static tokens of the `tokens`
+//! file or a JWT signed with HS256 under `jwt_secret`, its `role` claim naming its role. The
+//! `exp` and `nbf` claims of a JWT, in seconds since the epoch, are checked when present.
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::ingest::read_records;
+
+/// What a token may do, each role allowing what the roles before it allow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Reading balances.
+    Read,
+    /// Submitting transactions and batches.
+    Submit,
+    /// Operating the engine: metrics and unfreezing accounts.
+    Admin,
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Role::Read => "read",
+            Role::Submit => "submit",
+            Role::Admin => "admin",
+        })
+    }
+}
+
+/// Why a request is refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum AuthError {
+    #[error("missing bearer token")]
+    Missing,
+    #[error("invalid bearer token")]
+    Invalid,
+    #[error("expired bearer token")]
+    Expired,
+    #[error("the {role} role doesn't allow this request, it needs the {required} role")]
+    Forbidden { role: Role, required: Role },
+}
+
+impl AuthError {
+    /// HTTP status of the refusal: 403 for a valid token lacking the role, 401 otherwise.
+    pub fn status(&self) -> u16 {
+        match self {
+            AuthError::Forbidden { .. } => 403,
+            _ => 401,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenRecord {
+    token: String,
+    role: Role,
+}
+
+#[derive(Deserialize)]
+struct JwtHeader {
+    alg: String,
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    role: Role,
+    exp: Option<u64>,
+    nbf: Option<u64>,
+}
+
+/// The tokens a server accepts and their roles. Without any, every request is served.
+#[derive(Clone, Default)]
+pub struct Authenticator {
+    /// Roles of the static tokens by the SHA-256 of the token, so that looking a token up
+    /// doesn't leak it through timing.
+    tokens: HashMap<[u8; 32], Role>,
+    jwt_secret: Option<Vec<u8>>,
+}
+
+impl std::fmt::Debug for Authenticator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Authenticator")
+            .field("tokens", &self.tokens.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Authenticator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_token(mut self, token: &str, role: Role) -> Self {
+        self.tokens.insert(Sha256::digest(token).into(), role);
+        self
+    }
+
+    /// Adds the static tokens of the CSV file at `path`, with `token` and `role` columns.
+    pub fn with_tokens_file<P: AsRef<Path>>(self, path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let records: Vec<TokenRecord> = read_records(path)
+            .map_err(|err| anyhow::anyhow!("unable to read {}: {}", path.display(), err))?;
+        Ok(records.into_iter().fold(self, |auth, record| {
+            auth.with_token(&record.token, record.role)
+        }))
+    }
+
+    /// Accepts JWTs signed with HS256 under `secret`.
+    pub fn with_jwt_secret(mut self, secret: &[u8]) -> Self {
+        self.jwt_secret = Some(secret.to_vec());
+        self
+    }
+
+    /// Whether requests need a token at all.
+    pub fn is_enabled(&self) -> bool {
+        !self.tokens.is_empty() || self.jwt_secret.is_some()
+    }
+
+    /// Role of the token of the `Authorization` header value, `now` being the time in seconds
+    /// since the epoch.
+    pub fn authenticate(&self, authorization: Option<&str>, now: u64) -> Result<Role, AuthError> {
+        let (scheme, token) = authorization
+            .and_then(|value| value.trim().split_once(' '))
+            .ok_or(AuthError::Missing)?;
+        if !scheme.eq_ignore_ascii_case("bearer") {
+            return Err(AuthError::Missing);
+        }
+        let token = token.trim();
+        if let Some(role) = self.tokens.get::<[u8; 32]>(&Sha256::digest(token).into()) {
+            return Ok(*role);
+        }
+        match &self.jwt_secret {
+            Some(secret) => verify_jwt(secret, token, now),
+            None => Err(AuthError::Invalid),
+        }
+    }
+
+    /// Like `authenticate`, but also refuses tokens whose role is below `required`.
+    pub fn authorize(
+        &self,
+        authorization: Option<&str>,
+        required: Role,
+        now: u64,
+    ) -> Result<Role, AuthError> {
+        let role = self.authenticate(authorization, now)?;
+        if role < required {
+            return Err(AuthError::Forbidden { role, required });
+        }
+        Ok(role)
+    }
+}
+
+fn verify_jwt(secret: &[u8], token: &str, now: u64) -> Result<Role, AuthError> {
+    let mut parts = token.split('.');
+    let (Some(header), Some(payload), Some(signature), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(AuthError::Invalid);
+    };
+    // only HS256, so that a token can't pick `none` or a key of its own
+    if decode_part::<JwtHeader>(header)?.alg != "HS256" {
+        return Err(AuthError::Invalid);
+    }
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| AuthError::Invalid)?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(header.as_bytes());
+    mac.update(b".");
+    mac.update(payload.as_bytes());
+    mac.verify_slice(&signature)
+        .map_err(|_| AuthError::Invalid)?;
+
+    let claims: Claims = decode_part(payload)?;
+    if claims.exp.is_some_and(|exp| now >= exp) {
+        return Err(AuthError::Expired);
+    }
+    if claims.nbf.is_some_and(|nbf| now < nbf) {
+        return Err(AuthError::Invalid);
+    }
+    Ok(claims.role)
+}
+
+fn decode_part<T: DeserializeOwned>(part: &str) -> Result<T, AuthError> {
+    let json = URL_SAFE_NO_PAD
+        .decode(part)
+        .map_err(|_| AuthError::Invalid)?;
+    serde_json::from_slice(&json).map_err(|_| AuthError::Invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jwt(secret: &[u8], header: &str, claims: &str) -> String {
+        let signing_input = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(header),
+            URL_SAFE_NO_PAD.encode(claims)
+        );
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+        mac.update(signing_input.as_bytes());
+        let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+        format!("Bearer {}.{}", signing_input, signature)
+    }
+
+    #[test]
+    fn static_tokens_and_signed_jwts_grant_their_role() {
+        let auth = Authenticator::new()
+            .with_token("reader", Role::Read)
+            .with_token("operator", Role::Admin)
+            .with_jwt_secret(b"secret");
+        let hs256 = r#"{"alg":"HS256","typ":"JWT"}"#;
+
+        assert_eq!(auth.authenticate(Some("Bearer reader"), 0), Ok(Role::Read));
+        assert_eq!(
+            auth.authenticate(Some("bearer  operator"), 0),
+            Ok(Role::Admin)
+        );
+        assert_eq!(auth.authenticate(None, 0), Err(AuthError::Missing));
+        assert_eq!(
+            auth.authenticate(Some("Basic reader"), 0),
+            Err(AuthError::Missing)
+        );
+        assert_eq!(
+            auth.authenticate(Some("Bearer nope"), 0),
+            Err(AuthError::Invalid)
+        );
+        assert_eq!(
+            auth.authorize(Some("Bearer reader"), Role::Submit, 0),
+            Err(AuthError::Forbidden {
+                role: Role::Read,
+                required: Role::Submit
+            })
+        );
+        assert_eq!(
+            auth.authorize(Some("Bearer operator"), Role::Submit, 0),
+            Ok(Role::Admin)
+        );
+
+        let submitter = jwt(b"secret", hs256, r#"{"role":"submit","exp":100}"#);
+        assert_eq!(auth.authenticate(Some(&submitter), 99), Ok(Role::Submit));
+        assert_eq!(
+            auth.authenticate(Some(&submitter), 100),
+            Err(AuthError::Expired)
+        );
+        let early = jwt(b"secret", hs256, r#"{"role":"read","nbf":100}"#);
+        assert_eq!(auth.authenticate(Some(&early), 99), Err(AuthError::Invalid));
+        for forged in [
+            jwt(b"other", hs256, r#"{"role":"admin"}"#),
+            jwt(b"secret", r#"{"alg":"none"}"#, r#"{"role":"admin"}"#),
+            jwt(b"secret", hs256, r#"{"role":"root"}"#),
+        ] {
+            assert_eq!(auth.authenticate(Some(&forged), 0), Err(AuthError::Invalid));
+        }
+        assert!(!Authenticator::new().is_enabled());
+    }
+}
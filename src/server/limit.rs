@@ -0,0 +1,107 @@
+//! Rate limits on the submission routes of `Server`, see `[server]`.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Callers the per-caller limiter keeps before it forgets those whose bucket is full again.
+const MAX_CALLERS: usize = 10_000;
+
+/// Limits of `Server` on submissions and on the requests it keeps waiting.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ServerLimits {
+    /// Submissions per second over all callers.
+    pub rate: Option<f64>,
+    /// Submissions per second of a single caller: its bearer token when the server has tokens,
+    /// its IP address otherwise.
+    pub client_rate: Option<f64>,
+    /// Requests received but not served yet; further requests are answered with a 503 until
+    /// the server catches up.
+    pub queue: usize,
+    /// Bytes a request body may have; larger ones are answered with a 413.
+    pub max_body: usize,
+}
+
+impl Default for ServerLimits {
+    fn default() -> Self {
+        Self {
+            rate: None,
+            client_rate: None,
+            queue: 1024,
+            max_body: 16 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets refilled at `rate` tokens per second, holding a second's worth of tokens, one
+/// at least, so that short bursts pass.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    rate: f64,
+    buckets: HashMap<String, Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            buckets: HashMap::new(),
+        }
+    }
+
+    fn capacity(&self) -> f64 {
+        self.rate.max(1.0)
+    }
+
+    /// Takes a token from the bucket of `key`, or tells how long until one is available.
+    pub fn check(&mut self, key: &str, now: Instant) -> Result<(), Duration> {
+        let (rate, capacity) = (self.rate, self.capacity());
+        if self.buckets.len() >= MAX_CALLERS && !self.buckets.contains_key(key) {
+            self.buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < capacity
+            });
+        }
+        let bucket = self.buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_refill_at_the_rate() {
+        let mut limiter = RateLimiter::new(2.0);
+        let start = Instant::now();
+        assert_eq!(limiter.check("a", start), Ok(()));
+        assert_eq!(limiter.check("a", start), Ok(()));
+        assert_eq!(limiter.check("a", start), Err(Duration::from_millis(500)));
+        // callers have buckets of their own
+        assert_eq!(limiter.check("b", start), Ok(()));
+        assert_eq!(
+            limiter.check("a", start + Duration::from_millis(500)),
+            Ok(())
+        );
+
+        let mut slow = RateLimiter::new(0.5);
+        assert_eq!(slow.check("a", start), Ok(()));
+        assert_eq!(slow.check("a", start), Err(Duration::from_secs(2)));
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+
+use crate::metrics::EngineMetrics;
+use crate::transactions::{
+    Account, Client, PaymentEngine, Transaction, TransactionId, TransactionKind,
+    TransactionValidationError, FIRST_GENERATED_ID,
+};
+
+/// `PaymentEngine` usable from many threads at once.
+///
+/// Clients are spread over independently locked shards, so transactions of different clients
+/// proceed in parallel while transactions of a single client are applied one at a time, in the
+/// order they are submitted. Transaction ids stay unique across all shards.
+pub struct SharedPaymentEngine {
+    shards: Vec<Mutex<PaymentEngine>>,
+    ids: TransactionIds,
+}
+
+/// Transaction ids introduced by deposits, withdrawals and holds, shared by engines that
+/// each only know their own clients' transactions.
+#[derive(Debug, Default)]
+pub(crate) struct TransactionIds {
+    owners: Mutex<HashMap<TransactionId, Client>>,
+}
+
+impl TransactionIds {
+    fn owners(&self) -> MutexGuard<'_, HashMap<TransactionId, Client>> {
+        self.owners
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Claims the id of a transaction creating a new transaction id. Returns whether the id
+    /// was newly claimed, in which case it must be released if the transaction fails.
+    pub(crate) fn claim(
+        &self,
+        transaction: &Transaction,
+    ) -> Result<bool, TransactionValidationError> {
+        if !matches!(
+            transaction.kind(),
+            TransactionKind::Deposit
+                | TransactionKind::Withdrawal
+                | TransactionKind::Hold
+                | TransactionKind::Adjustment
+                | TransactionKind::EscrowHold
+        ) {
+            return Ok(false);
+        }
+        let client = transaction.client();
+        let tx = transaction.tx();
+        match self.owners().entry(tx) {
+            Entry::Occupied(owner) if *owner.get() != client => {
+                Err(TransactionValidationError::ConflictingDuplicate { client, tx })
+            }
+            Entry::Occupied(_) => Ok(false),
+            Entry::Vacant(entry) => {
+                entry.insert(client);
+                Ok(true)
+            }
+        }
+    }
+
+    pub(crate) fn release(&self, tx: TransactionId) {
+        self.owners().remove(&tx);
+    }
+}
+
+impl SharedPaymentEngine {
+    pub fn new(shard_count: usize) -> Self {
+        Self::with_engines(shard_count, PaymentEngine::new)
+    }
+
+    /// Builds every shard with `make_engine`, which lets callers configure policies,
+    /// rules, risk scorers and observers per shard.
+    pub fn with_engines<F>(shard_count: usize, make_engine: F) -> Self
+    where
+        F: Fn() -> PaymentEngine,
+    {
+        let shard_count = shard_count.max(1);
+        Self {
+            shards: (0..shard_count)
+                .map(|shard| {
+                    let mut engine = make_engine();
+                    engine.set_generated_ids(
+                        FIRST_GENERATED_ID + shard as TransactionId,
+                        shard_count as TransactionId,
+                    );
+                    Mutex::new(engine)
+                })
+                .collect(),
+            ids: TransactionIds::default(),
+        }
+    }
+
+    fn shard(&self, client: Client) -> MutexGuard<'_, PaymentEngine> {
+        let shard = &self.shards[client as usize % self.shards.len()];
+        shard
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn process_transaction(
+        &self,
+        transaction: Transaction,
+    ) -> Result<(), TransactionValidationError> {
+        // clock moves and period ends concern every client
+        if let Transaction::AdvanceTime { .. } | Transaction::ClosePeriod { .. } = transaction {
+            for shard in &self.shards {
+                shard
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .process_transaction(transaction.clone())?;
+            }
+            return Ok(());
+        }
+        let client = transaction.client();
+        let tx = transaction.tx();
+        // A shard only knows its own transaction ids, so ids introduced by another
+        // client's shard are claimed here first.
+        let claimed = self.ids.claim(&transaction)?;
+        let result = self.shard(client).process_transaction(transaction);
+        if result.is_err() && claimed {
+            self.ids.release(tx);
+        }
+        result
+    }
+
+    pub fn get_account(&self, client: Client) -> Option<Account> {
+        self.shard(client).get_account(client).copied()
+    }
+
+    /// Accounts of every shard, sorted by client.
+    pub fn get_accounts(&self) -> Vec<Account> {
+        let mut accounts = vec![];
+        for shard in self.shards.iter() {
+            let shard = shard
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            accounts.extend(shard.iter_accounts().copied());
+        }
+        accounts.sort_by_key(|account| account.client());
+        accounts
+    }
+
+    pub fn metrics(&self) -> EngineMetrics {
+        let mut metrics = EngineMetrics::default();
+        for shard in self.shards.iter() {
+            let shard = shard
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            metrics.merge(&shard.metrics());
+        }
+        metrics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::amount;
+    use std::thread;
+
+    #[test]
+    fn clients_are_processed_in_parallel() {
+        let engine = SharedPaymentEngine::new(4);
+        thread::scope(|scope| {
+            for client in 1..=8u16 {
+                let engine = &engine;
+                scope.spawn(move || {
+                    for i in 0..100u32 {
+                        let tx = client as u32 * 1000 + i;
+                        let deposit = Transaction::new_deposit(client, tx, amount!(1.0)).unwrap();
+                        engine.process_transaction(deposit).unwrap();
+                    }
+                    let withdrawal = Transaction::new_withdrawal(
+                        client,
+                        client as u32 * 1000 + 999,
+                        amount!(40.0),
+                    )
+                    .unwrap();
+                    engine.process_transaction(withdrawal).unwrap();
+                });
+            }
+        });
+
+        let accounts = engine.get_accounts();
+        assert_eq!(accounts.len(), 8);
+        for account in accounts {
+            assert_eq!(account.available(), amount!(60.0));
+        }
+        assert_eq!(engine.metrics().applied(TransactionKind::Deposit), 800);
+    }
+
+    #[test]
+    fn transaction_ids_are_unique_across_shards() {
+        let engine = SharedPaymentEngine::new(2);
+        engine
+            .process_transaction(Transaction::new_deposit(1, 1, amount!(10.0)).unwrap())
+            .unwrap();
+        let result =
+            engine.process_transaction(Transaction::new_deposit(2, 1, amount!(10.0)).unwrap());
+        assert!(matches!(
+            result,
+            Err(TransactionValidationError::ConflictingDuplicate { tx: 1, .. })
+        ));
+        assert!(engine.get_account(2).is_none());
+
+        // failed transactions don't keep their id
+        let result =
+            engine.process_transaction(Transaction::new_withdrawal(2, 2, amount!(10.0)).unwrap());
+        assert!(result.is_err());
+        engine
+            .process_transaction(Transaction::new_deposit(1, 2, amount!(10.0)).unwrap())
+            .unwrap();
+        assert_eq!(engine.get_account(1).unwrap().available(), amount!(20.0));
+    }
+}
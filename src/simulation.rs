@@ -0,0 +1,24 @@
+//! Outcome of `PaymentEngine::simulate`, a dry run of a batch of transactions.
+
+use crate::transactions::{Account, Client, TransactionValidationError};
+
+/// What a batch would do to the engine it was simulated against.
+#[derive(Debug, Clone, Default)]
+pub struct SimulationReport {
+    /// Accounts of every client the batch involves as they would end up, sorted by client.
+    /// Accounts the batch would not create and which don't exist yet are left out.
+    pub accounts: Vec<Account>,
+    /// Transactions of the batch that would be refused, in batch order.
+    pub rejections: Vec<TransactionValidationError>,
+    /// Number of transactions that would be applied.
+    pub applied: u64,
+}
+
+impl SimulationReport {
+    pub fn account(&self, client: Client) -> Option<&Account> {
+        self.accounts
+            .binary_search_by_key(&client, Account::client)
+            .ok()
+            .map(|index| &self.accounts[index])
+    }
+}
@@ -0,0 +1,412 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+use crate::crdt::AccountCounters;
+#[cfg(feature = "native")]
+use crate::encryption::{is_encrypted, EncryptionKey};
+use crate::invariants::Funds;
+use crate::ledger::JournalEntry;
+use crate::transactions::{
+    saturating_add, Amount, Client, Transaction, TransactionId, TransactionKind,
+};
+
+/// Balances and flags of one account.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccountState {
+    pub client: Client,
+    #[serde(with = "crate::amount::text")]
+    pub available: Amount,
+    #[serde(with = "crate::amount::text")]
+    pub held: Amount,
+    /// Missing from snapshots taken before escrows existed.
+    #[serde(default, with = "crate::amount::text")]
+    pub escrowed: Amount,
+    pub frozen: bool,
+    pub chargebacks: u32,
+    pub flagged: bool,
+    /// Missing from snapshots taken before the counters existed.
+    #[serde(default)]
+    pub disputes_open: u32,
+    #[serde(default)]
+    pub disputes_total: u32,
+    /// Missing from snapshots taken before replicas could be merged, see `crdt`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub counters: Option<AccountCounters>,
+}
+
+/// Everything `PaymentEngine` needs to carry on where it stopped. Policies, rules, risk
+/// scorer state and observers are not part of it.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct EngineState {
+    pub accounts: Vec<AccountState>,
+    pub transactions: Vec<Transaction>,
+    /// Withdrawals only remembered by id (see `StorageMode::Compact`).
+    pub settled: Vec<TransactionId>,
+    /// Journal entries in posting order; empty in snapshots taken before the ledger existed.
+    #[serde(default)]
+    pub journal: Vec<JournalEntry>,
+    pub applied: Vec<(TransactionKind, u64)>,
+    pub rejected: u64,
+    /// Part of `rejected`; missing from snapshots taken before blocklists existed.
+    #[serde(default)]
+    pub blocked: u64,
+    /// Clients removed by `purge_client`, oldest first.
+    #[serde(default)]
+    pub tombstones: Vec<Tombstone>,
+    /// Accepted funds of the transactions dropped by `PaymentEngine::prune`, per client.
+    #[serde(default)]
+    pub pruned: Vec<(Client, Funds)>,
+    /// Time of a simulated clock, `None` for the system clock.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clock: Option<u64>,
+    /// Scheduled transactions not due yet with their time, in the order they are applied.
+    #[serde(default)]
+    pub scheduled: Vec<(u64, Transaction)>,
+    /// Id the next transaction expanded from a recurrence gets, `None` before the first one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_generated: Option<TransactionId>,
+    /// Periods closed so far, see `PaymentEngine::close_period`.
+    #[serde(default)]
+    pub period: u32,
+    /// Calendar period the current one falls in when periods are closed automatically.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub period_key: Option<u64>,
+    /// Counters of the accounts when the current period started.
+    #[serde(default)]
+    pub period_start: Vec<(Client, AccountCounters)>,
+    /// Statements of the closed periods not written out yet.
+    #[serde(default)]
+    pub statements: Vec<PeriodStatement>,
+    /// Deposits whose funds are still held for the deposit hold period, in the order they are
+    /// released.
+    #[serde(default)]
+    pub clearing: Vec<ClearingDeposit>,
+}
+
+/// What was removed with a purged client, kept for good so the platform totals still add up.
+/// It holds amounts and counts only, no transaction ids, memos or counterparties.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Tombstone {
+    pub client: Client,
+    /// Seconds since the Unix epoch.
+    pub purged_at: u64,
+    /// Number of stored transactions removed.
+    pub transactions: u64,
+    /// Number of journal entries removed.
+    pub journal_entries: u64,
+    /// Accepted funds of the removed transactions.
+    pub funds: Funds,
+    /// Total of the removed account.
+    #[serde(with = "crate::amount::text")]
+    pub total: Amount,
+}
+
+/// Deposit credited to the held funds of its client until `release_at`, see
+/// `PaymentEngine::set_deposit_hold`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClearingDeposit {
+    pub client: Client,
+    pub tx: TransactionId,
+    #[serde(with = "crate::amount::text")]
+    pub amount: Amount,
+    /// Seconds since the Unix epoch.
+    pub release_at: u64,
+}
+
+/// Balances of an account at the end of a period, with the funds that came in and went out of
+/// its available balance and the transactions applied to it during the period.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PeriodStatement {
+    /// Number of the period, the first one being 0.
+    pub period: u32,
+    pub client: Client,
+    #[serde(with = "crate::amount::text")]
+    pub available: Amount,
+    #[serde(with = "crate::amount::text")]
+    pub held: Amount,
+    #[serde(with = "crate::amount::text")]
+    pub escrowed: Amount,
+    pub locked: bool,
+    #[serde(with = "crate::amount::text")]
+    pub credits: Amount,
+    #[serde(with = "crate::amount::text")]
+    pub debits: Amount,
+    pub transactions: u64,
+}
+
+impl PeriodStatement {
+    pub fn total(&self) -> Amount {
+        saturating_add(saturating_add(self.available, self.held), self.escrowed)
+    }
+}
+
+impl EngineState {
+    /// Removes the account, stored transactions and journal entries of `client`, records a
+    /// `Tombstone` in their place and returns it. `None` when the state knows nothing of the
+    /// client. Ids of compact withdrawals are kept, they don't say whose they were.
+    pub fn purge_client(&mut self, client: Client, purged_at: u64) -> Option<Tombstone> {
+        let mut tombstone = Tombstone {
+            client,
+            purged_at,
+            transactions: 0,
+            journal_entries: 0,
+            funds: Funds::default(),
+            total: Amount::ZERO,
+        };
+        let mut known = false;
+        self.accounts.retain(|account| {
+            if account.client != client {
+                return true;
+            }
+            known = true;
+            tombstone.total = saturating_add(
+                saturating_add(account.available, account.held),
+                account.escrowed,
+            );
+            false
+        });
+        self.transactions.retain(|transaction| {
+            if transaction.client() != client {
+                return true;
+            }
+            tombstone.transactions += 1;
+            tombstone.funds.record(transaction);
+            false
+        });
+        let journal = self.journal.len();
+        self.journal.retain(|entry| {
+            entry.debit.client() != Some(client) && entry.credit.client() != Some(client)
+        });
+        tombstone.journal_entries = (journal - self.journal.len()) as u64;
+        self.pruned.retain(|(pruned, funds)| {
+            if *pruned != client {
+                return true;
+            }
+            known = true;
+            tombstone.funds.add(funds);
+            false
+        });
+        self.period_start.retain(|(started, _)| *started != client);
+        self.statements
+            .retain(|statement| statement.client != client);
+        self.clearing.retain(|deposit| deposit.client != client);
+        if !known && tombstone.transactions == 0 && tombstone.journal_entries == 0 {
+            return None;
+        }
+        self.tombstones.push(tombstone.clone());
+        Some(tombstone)
+    }
+}
+
+/// Position in the input file, as tracked by the csv reader.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InputOffset {
+    pub byte: u64,
+    pub line: u64,
+    pub record: u64,
+}
+
+impl From<&csv::Position> for InputOffset {
+    fn from(position: &csv::Position) -> Self {
+        Self {
+            byte: position.byte(),
+            line: position.line(),
+            record: position.record(),
+        }
+    }
+}
+
+impl From<InputOffset> for csv::Position {
+    fn from(offset: InputOffset) -> Self {
+        let mut position = csv::Position::new();
+        position
+            .set_byte(offset.byte)
+            .set_line(offset.line)
+            .set_record(offset.record);
+        position
+    }
+}
+
+/// Engine state together with the input offset right after the last record it reflects.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Replica that took the snapshot, for `payments merge --replicated`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replica: Option<String>,
+    pub offset: InputOffset,
+    pub engine: EngineState,
+}
+
+/// Writes the snapshot next to `path` first and then moves it into place, so a crash never
+/// leaves a half written snapshot behind.
+pub fn save_snapshot<P: AsRef<Path>>(path: P, snapshot: &Snapshot) -> anyhow::Result<()> {
+    replace(path.as_ref(), |writer| {
+        Ok(serde_json::to_writer(writer, snapshot)?)
+    })
+}
+
+/// Same as `save_snapshot`, encrypting the snapshot with `key`.
+#[cfg(feature = "native")]
+pub fn save_encrypted_snapshot<P: AsRef<Path>>(
+    path: P,
+    snapshot: &Snapshot,
+    key: &EncryptionKey,
+) -> anyhow::Result<()> {
+    let sealed = key.encrypt(&serde_json::to_vec(snapshot)?)?;
+    replace(path.as_ref(), |writer| Ok(writer.write_all(&sealed)?))
+}
+
+fn replace<F>(path: &Path, write: F) -> anyhow::Result<()>
+where
+    F: FnOnce(&mut BufWriter<File>) -> anyhow::Result<()>,
+{
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+
+    let mut writer = BufWriter::new(File::create(&partial)?);
+    write(&mut writer)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    fs::rename(&partial, path)?;
+    Ok(())
+}
+
+pub fn load_snapshot<P: AsRef<Path>>(path: P) -> anyhow::Result<Snapshot> {
+    let reader = BufReader::new(File::open(path)?);
+    Ok(serde_json::from_reader(reader)?)
+}
+
+/// Loads a snapshot saved by `save_snapshot` or, decrypting it with `key`, by
+/// `save_encrypted_snapshot`.
+#[cfg(feature = "native")]
+pub fn load_snapshot_with_key<P: AsRef<Path>>(
+    path: P,
+    key: Option<&EncryptionKey>,
+) -> anyhow::Result<Snapshot> {
+    let path = path.as_ref();
+    let data = fs::read(path)?;
+    if !is_encrypted(&data) {
+        return Ok(serde_json::from_slice(&data)?);
+    }
+    let key =
+        key.ok_or_else(|| anyhow::anyhow!("{} is encrypted, configure its key", path.display()))?;
+    Ok(serde_json::from_slice(&key.decrypt(&data)?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::amount;
+    use crate::transactions::{PaymentEngine, TransactionValidationError};
+
+    #[test]
+    fn restored_engine_continues_where_it_stopped() {
+        let mut engine = PaymentEngine::new();
+        let _ =
+            engine.process_transaction(Transaction::new_deposit(1, 1, amount!(10.1234)).unwrap());
+        let _ = engine.process_transaction(Transaction::new_deposit(2, 2, amount!(5.0)).unwrap());
+        let _ = engine.process_transaction(Transaction::new_dispute(2, 2));
+
+        let path =
+            std::env::temp_dir().join(format!("payments-snapshot-{}.json", std::process::id()));
+        let offset = InputOffset {
+            byte: 42,
+            line: 4,
+            record: 3,
+        };
+        save_snapshot(
+            &path,
+            &Snapshot {
+                replica: None,
+                offset,
+                engine: engine.state(),
+            },
+        )
+        .unwrap();
+        let snapshot = load_snapshot(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(snapshot.offset, offset);
+
+        let mut restored = PaymentEngine::new();
+        restored.restore(snapshot.engine);
+        assert_eq!(
+            restored.get_account(1).unwrap().available(),
+            amount!(10.1234)
+        );
+        assert_eq!(restored.get_account(2).unwrap().held(), amount!(5.0));
+        assert!(matches!(
+            restored.process_transaction(Transaction::new_deposit(1, 1, amount!(1.0)).unwrap()),
+            Err(TransactionValidationError::ConflictingDuplicate { tx: 1, .. })
+        ));
+        restored
+            .process_transaction(Transaction::new_chargeback(2, 2))
+            .unwrap();
+        assert!(restored.get_account(2).unwrap().frozen());
+        assert_eq!(restored.metrics().rejected, 1);
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn encrypted_snapshots_need_their_key() {
+        let mut engine = PaymentEngine::new();
+        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, amount!(3.0)).unwrap());
+        let snapshot = Snapshot {
+            engine: engine.state(),
+            ..Snapshot::default()
+        };
+        let key = EncryptionKey::new(b"secret");
+        let path = std::env::temp_dir().join(format!(
+            "payments-encrypted-snapshot-{}.json",
+            std::process::id()
+        ));
+
+        save_encrypted_snapshot(&path, &snapshot, &key).unwrap();
+        assert!(load_snapshot(&path).is_err());
+        assert!(load_snapshot_with_key(&path, None).is_err());
+        let loaded = load_snapshot_with_key(&path, Some(&key)).unwrap();
+        assert_eq!(loaded.engine.accounts.len(), 1);
+        // plain snapshots load whether a key is given or not
+        save_snapshot(&path, &snapshot).unwrap();
+        assert!(load_snapshot_with_key(&path, Some(&key)).is_ok());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn purged_clients_leave_a_tombstone() {
+        let mut engine = PaymentEngine::new();
+        engine.keep_journal(true);
+        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, amount!(10.0)).unwrap());
+        let _ =
+            engine.process_transaction(Transaction::new_withdrawal(1, 2, amount!(4.0)).unwrap());
+        let _ = engine.process_transaction(Transaction::new_deposit(2, 3, amount!(1.0)).unwrap());
+        let mut state = engine.state();
+
+        assert_eq!(state.purge_client(3, 60), None);
+        let tombstone = state.purge_client(1, 60).unwrap();
+        assert_eq!(tombstone.transactions, 2);
+        assert_eq!(tombstone.journal_entries, 2);
+        assert_eq!(tombstone.total, amount!(6.0));
+        assert_eq!(tombstone.funds.expected(), amount!(6.0));
+        assert_eq!(state.accounts.len(), 1);
+        assert_eq!(state.transactions.len(), 1);
+        assert_eq!(state.journal.len(), 1);
+
+        let mut restored = PaymentEngine::new();
+        restored.restore(state);
+        assert!(restored.get_account(1).is_none());
+        assert_eq!(restored.tombstones(), [tombstone]);
+        assert_eq!(restored.ledger().suspense(), amount!(1.0));
+        assert_eq!(
+            crate::invariants::verify_funds(
+                restored.accounts_iter(),
+                restored.transactions_iter(),
+                restored.tombstones(),
+                restored.pruned_funds(),
+            ),
+            vec![]
+        );
+        assert_eq!(restored.state().tombstones.len(), 1);
+    }
+}
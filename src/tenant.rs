@@ -0,0 +1,156 @@
+//! Isolated engines for the partner programs whose records share an input, see
+//! `--tenant-reports`.
+//!
+//! Every tenant has an engine of its own, so client and transaction ids of different tenants
+//! never collide: client 1 of one program and client 1 of another are different accounts.
+
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+use crate::transactions::{PaymentEngine, Transaction, TransactionValidationError};
+
+/// Longest tenant id; ids name the per-tenant report files.
+pub const MAX_TENANT_LEN: usize = 64;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum TenantError {
+    #[error(
+        "tenant id {0:?} must be 1 to {} letters, digits, '-' or '_'",
+        MAX_TENANT_LEN
+    )]
+    InvalidTenant(String),
+
+    #[error(transparent)]
+    Rejected(#[from] TransactionValidationError),
+}
+
+/// Engines keyed by tenant id, each created on the first transaction of its tenant.
+pub struct TenantManager<'a> {
+    engines: BTreeMap<String, PaymentEngine>,
+    make_engine: Box<dyn Fn() -> PaymentEngine + 'a>,
+}
+
+impl Default for TenantManager<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> TenantManager<'a> {
+    pub fn new() -> Self {
+        Self::with_engines(PaymentEngine::new)
+    }
+
+    /// Builds the engine of every tenant with `make_engine`, which lets callers configure
+    /// policies, rules, risk scorers and observers as for a single engine.
+    pub fn with_engines<F>(make_engine: F) -> Self
+    where
+        F: Fn() -> PaymentEngine + 'a,
+    {
+        Self {
+            engines: BTreeMap::new(),
+            make_engine: Box::new(make_engine),
+        }
+    }
+
+    /// Engine of `tenant`, created when the tenant is new.
+    pub fn engine_mut(&mut self, tenant: &str) -> Result<&mut PaymentEngine, TenantError> {
+        if !is_valid_tenant(tenant) {
+            return Err(TenantError::InvalidTenant(tenant.to_string()));
+        }
+        if !self.engines.contains_key(tenant) {
+            self.engines
+                .insert(tenant.to_string(), (self.make_engine)());
+        }
+        Ok(self.engines.get_mut(tenant).unwrap())
+    }
+
+    pub fn get(&self, tenant: &str) -> Option<&PaymentEngine> {
+        self.engines.get(tenant)
+    }
+
+    pub fn process_transaction(
+        &mut self,
+        tenant: &str,
+        transaction: Transaction,
+    ) -> Result<(), TenantError> {
+        Ok(self.engine_mut(tenant)?.process_transaction(transaction)?)
+    }
+
+    /// Tenants with their engines, ordered by tenant id.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &PaymentEngine)> {
+        self.engines
+            .iter()
+            .map(|(tenant, engine)| (tenant.as_str(), engine))
+    }
+
+    pub fn len(&self) -> usize {
+        self.engines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.engines.is_empty()
+    }
+
+    pub fn into_engines(self) -> BTreeMap<String, PaymentEngine> {
+        self.engines
+    }
+}
+
+fn is_valid_tenant(tenant: &str) -> bool {
+    !tenant.is_empty()
+        && tenant.len() <= MAX_TENANT_LEN
+        && tenant
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::amount;
+
+    #[test]
+    fn tenants_have_separate_namespaces() {
+        let mut tenants = TenantManager::new();
+        for tenant in ["acme", "globex"] {
+            tenants
+                .process_transaction(
+                    tenant,
+                    Transaction::new_deposit(1, 1, amount!(5.0)).unwrap(),
+                )
+                .unwrap();
+        }
+        tenants
+            .process_transaction(
+                "acme",
+                Transaction::new_withdrawal(1, 2, amount!(2.0)).unwrap(),
+            )
+            .unwrap();
+        assert!(matches!(
+            tenants.process_transaction(
+                "globex",
+                Transaction::new_deposit(2, 1, amount!(1.0)).unwrap()
+            ),
+            Err(TenantError::Rejected(
+                TransactionValidationError::ConflictingDuplicate { tx: 1, .. }
+            ))
+        ));
+        assert_eq!(
+            tenants.process_transaction("../acme", Transaction::new_dispute(1, 1)),
+            Err(TenantError::InvalidTenant("../acme".to_string()))
+        );
+
+        let tenant_ids: Vec<&str> = tenants.iter().map(|(tenant, _)| tenant).collect();
+        assert_eq!(tenant_ids, ["acme", "globex"]);
+        let available = |tenant| {
+            tenants
+                .get(tenant)
+                .and_then(|engine| engine.get_account(1))
+                .unwrap()
+                .available()
+        };
+        assert_eq!(available("acme"), amount!(3.0));
+        assert_eq!(available("globex"), amount!(5.0));
+    }
+}
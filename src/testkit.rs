@@ -0,0 +1,289 @@
+//! Scenarios of transactions and of the state they should leave behind, for tests of the
+//! engine, ours and those of library users:
+//!
+//! ```
+//! use payments::testkit::Scenario;
+//!
+//! Scenario::new()
+//!     .deposit(1, 1, "100")
+//!     .dispute(1, 1)
+//!     .expect_available(1, "0")
+//!     .expect_held(1, "100")
+//!     .chargeback(1, 1)
+//!     .expect_total(1, "0")
+//!     .expect_frozen(1)
+//!     .withdraw(1, 2, "10")
+//!     .expect_err();
+//! ```
+//!
+//! Every step is applied as soon as it is added, and every expectation is checked right away,
+//! panicking at the line of the test that broke it. The engine runs on a simulated clock
+//! starting at 0, so a scenario replays the same way every time.
+
+use crate::amount::Amount;
+use crate::transactions::{
+    Account, Client, Clock, DisputeState, PaymentEngine, Transaction, TransactionId,
+    TransactionValidationError,
+};
+
+pub struct Scenario {
+    engine: PaymentEngine,
+    /// Outcome of the last transaction, `None` before the first one.
+    last: Option<Result<(), TransactionValidationError>>,
+    steps: usize,
+}
+
+impl Default for Scenario {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scenario {
+    pub fn new() -> Self {
+        Self::with_engine(PaymentEngine::new())
+    }
+
+    /// Scenario on `engine`, set up with the policies, rules or storage mode under test. Its
+    /// clock is set to a simulated clock starting at 0.
+    pub fn with_engine(mut engine: PaymentEngine) -> Self {
+        engine.set_clock(Clock::Simulated(0));
+        Self {
+            engine,
+            last: None,
+            steps: 0,
+        }
+    }
+
+    /// Processes `transaction`; its outcome is what `expect_ok` and `expect_err` look at.
+    pub fn apply(mut self, transaction: Transaction) -> Self {
+        self.last = Some(self.engine.process_transaction(transaction));
+        self.steps += 1;
+        self
+    }
+
+    /// Processes the transaction of `transaction`, or records its error as the outcome when
+    /// it can't be built, e.g. for amounts that aren't positive.
+    fn try_apply(mut self, transaction: Result<Transaction, TransactionValidationError>) -> Self {
+        match transaction {
+            Ok(transaction) => self.apply(transaction),
+            Err(err) => {
+                self.last = Some(Err(err));
+                self.steps += 1;
+                self
+            }
+        }
+    }
+
+    #[track_caller]
+    pub fn deposit(self, client: Client, tx: TransactionId, amount: &str) -> Self {
+        let amount = parse(amount);
+        self.try_apply(Transaction::new_deposit(client, tx, amount))
+    }
+
+    #[track_caller]
+    pub fn withdraw(self, client: Client, tx: TransactionId, amount: &str) -> Self {
+        let amount = parse(amount);
+        self.try_apply(Transaction::new_withdrawal(client, tx, amount))
+    }
+
+    #[track_caller]
+    pub fn hold(self, client: Client, tx: TransactionId, amount: &str) -> Self {
+        let amount = parse(amount);
+        self.try_apply(Transaction::new_hold(client, tx, amount))
+    }
+
+    pub fn dispute(self, client: Client, tx: TransactionId) -> Self {
+        self.apply(Transaction::new_dispute(client, tx))
+    }
+
+    pub fn resolve(self, client: Client, tx: TransactionId) -> Self {
+        self.apply(Transaction::new_resolve(client, tx))
+    }
+
+    pub fn chargeback(self, client: Client, tx: TransactionId) -> Self {
+        self.apply(Transaction::new_chargeback(client, tx))
+    }
+
+    pub fn reverse(self, client: Client, tx: TransactionId) -> Self {
+        self.apply(Transaction::new_reversal(client, tx))
+    }
+
+    pub fn reverse_chargeback(self, client: Client, tx: TransactionId) -> Self {
+        self.apply(Transaction::new_chargeback_reversal(client, tx))
+    }
+
+    pub fn capture(self, client: Client, tx: TransactionId) -> Self {
+        self.apply(Transaction::new_capture(client, tx))
+    }
+
+    pub fn release(self, client: Client, tx: TransactionId) -> Self {
+        self.apply(Transaction::new_release(client, tx))
+    }
+
+    /// Expects the last transaction to be applied, or ignored as a replay.
+    #[track_caller]
+    pub fn expect_ok(self) -> Self {
+        match &self.last {
+            Some(Ok(())) => self,
+            Some(Err(err)) => panic!("step {} was rejected: {}", self.steps, err),
+            None => panic!("no transaction to expect anything of"),
+        }
+    }
+
+    /// Expects the last transaction to be rejected, for any reason.
+    #[track_caller]
+    pub fn expect_err(self) -> Self {
+        match &self.last {
+            Some(Err(_)) => self,
+            Some(Ok(())) => panic!("step {} was applied", self.steps),
+            None => panic!("no transaction to expect anything of"),
+        }
+    }
+
+    /// Expects the last transaction to be rejected with `expected`.
+    #[track_caller]
+    pub fn expect_error(self, expected: TransactionValidationError) -> Self {
+        match &self.last {
+            Some(Err(err)) if *err == expected => self,
+            Some(Err(err)) => panic!("step {} was rejected with {:?}", self.steps, err),
+            Some(Ok(())) => panic!("step {} was applied", self.steps),
+            None => panic!("no transaction to expect anything of"),
+        }
+    }
+
+    #[track_caller]
+    pub fn expect_available(self, client: Client, amount: &str) -> Self {
+        let available = self.account(client).available();
+        self.expect_amount(client, "available", available, amount)
+    }
+
+    #[track_caller]
+    pub fn expect_held(self, client: Client, amount: &str) -> Self {
+        let held = self.account(client).held();
+        self.expect_amount(client, "held", held, amount)
+    }
+
+    #[track_caller]
+    pub fn expect_total(self, client: Client, amount: &str) -> Self {
+        let total = self.account(client).total();
+        self.expect_amount(client, "total", total, amount)
+    }
+
+    #[track_caller]
+    pub fn expect_frozen(self, client: Client) -> Self {
+        assert!(
+            self.account(client).frozen(),
+            "after step {}, client {} isn't frozen",
+            self.steps,
+            client
+        );
+        self
+    }
+
+    #[track_caller]
+    pub fn expect_not_frozen(self, client: Client) -> Self {
+        assert!(
+            !self.account(client).frozen(),
+            "after step {}, client {} is frozen",
+            self.steps,
+            client
+        );
+        self
+    }
+
+    #[track_caller]
+    pub fn expect_no_account(self, client: Client) -> Self {
+        assert!(
+            self.engine.get_account(client).is_none(),
+            "after step {}, client {} has an account",
+            self.steps,
+            client
+        );
+        self
+    }
+
+    /// Expects the stored deposit or withdrawal `tx` to be in the `expected` dispute state.
+    #[track_caller]
+    pub fn expect_dispute(self, tx: TransactionId, expected: DisputeState) -> Self {
+        let state = match self.engine.get_transaction(tx) {
+            Some(Transaction::Deposit { dispute, .. })
+            | Some(Transaction::Withdrawal { dispute, .. }) => *dispute,
+            _ => panic!(
+                "after step {}, no deposit or withdrawal {} is stored",
+                self.steps, tx
+            ),
+        };
+        assert_eq!(
+            state, expected,
+            "after step {}, transaction {} is in the wrong dispute state",
+            self.steps, tx
+        );
+        self
+    }
+
+    pub fn engine(&self) -> &PaymentEngine {
+        &self.engine
+    }
+
+    /// Gives access to the engine between steps, e.g. to change its settings.
+    pub fn engine_mut(&mut self) -> &mut PaymentEngine {
+        &mut self.engine
+    }
+
+    pub fn into_engine(self) -> PaymentEngine {
+        self.engine
+    }
+
+    #[track_caller]
+    fn account(&self, client: Client) -> &Account {
+        self.engine.get_account(client).unwrap_or_else(|| {
+            panic!(
+                "after step {}, client {} has no account",
+                self.steps, client
+            )
+        })
+    }
+
+    #[track_caller]
+    fn expect_amount(self, client: Client, name: &str, actual: Amount, expected: &str) -> Self {
+        assert_eq!(
+            actual,
+            parse(expected),
+            "after step {}, the {} funds of client {} differ",
+            self.steps,
+            name,
+            client
+        );
+        self
+    }
+}
+
+#[track_caller]
+fn parse(amount: &str) -> Amount {
+    amount
+        .parse()
+        .unwrap_or_else(|err| panic!("invalid amount {:?}: {}", amount, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[should_panic(expected = "after step 2, the held funds of client 1 differ")]
+    fn broken_expectations_name_their_step() {
+        Scenario::new()
+            .deposit(1, 1, "100")
+            .dispute(1, 1)
+            .expect_held(1, "99.5");
+    }
+
+    #[test]
+    fn invalid_transactions_are_rejected_steps() {
+        Scenario::new()
+            .deposit(1, 1, "-5")
+            .expect_err()
+            .expect_no_account(1);
+    }
+}
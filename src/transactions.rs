@@ -1,51 +1,463 @@
-use rust_decimal::Decimal;
-use rust_decimal_macros::dec;
-use serde::ser::{Serialize, SerializeStruct, Serializer};
-use std::collections::HashMap;
+use roaring::RoaringBitmap;
+use rustc_hash::FxHashMap;
+use serde::ser::{SerializeStruct, Serializer};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::ops::RangeInclusive;
 use thiserror::Error;
 
+use crate::bloom::BloomFilter;
+use crate::clients::ClientProfile;
+use crate::crdt::AccountCounters;
+use crate::diagnostics::{report, ErrorEvent};
+use crate::invariants::Funds;
+use crate::ledger::{JournalEntry, Ledger, LedgerAccount};
+use crate::metrics::EngineMetrics;
+use crate::observer::EngineObserver;
+use crate::risk::{NoopRiskScorer, RiskDecision, RiskScorer};
+use crate::rules::Rules;
+use crate::simulation::SimulationReport;
+use crate::snapshot::{AccountState, ClearingDeposit, EngineState, PeriodStatement, Tombstone};
+use crate::velocity::{VelocityDetector, VelocityRules};
+
 pub type Client = u16;
 pub type TransactionId = u32;
-pub type Amount = Decimal;
+pub use crate::amount::Amount;
 
-#[derive(Error, Debug)]
+/// Why a transaction was not applied. Every variant names the client and transaction involved;
+/// `code` and `number` identify the variant and never change meaning.
+#[derive(Error, Debug, Clone, PartialEq)]
 pub enum TransactionValidationError {
-    #[error("amount must be greater that 0.0")]
-    InvalidAmount,
+    #[error("amount {amount} of transaction {tx} must be greater than 0")]
+    InvalidAmount {
+        client: Client,
+        tx: TransactionId,
+        amount: Amount,
+    },
+
+    #[error("transaction {tx} already processed")]
+    Duplicate { client: Client, tx: TransactionId },
+
+    #[error(
+        "insufficient funds for transaction {tx}: {requested} requested, {available} available"
+    )]
+    InsufficientFunds {
+        client: Client,
+        tx: TransactionId,
+        requested: Amount,
+        available: Amount,
+    },
+
+    #[error("client {client} has no account")]
+    MissingAccount { client: Client, tx: TransactionId },
+
+    #[error(
+        "transaction {tx} is unknown to client {client} or can't be used in its current state"
+    )]
+    InvalidTransaction { client: Client, tx: TransactionId },
+
+    #[error("transaction {tx} was charged back and can't be disputed again")]
+    DisputeChargeback { client: Client, tx: TransactionId },
+
+    #[error("account of client {client} is frozen")]
+    FrozenAccount { client: Client, tx: TransactionId },
+
+    #[error("transaction {tx} already reversed")]
+    Reversed { client: Client, tx: TransactionId },
+
+    #[error("transaction {tx} rejected by risk checks: {reason}")]
+    RiskVeto {
+        client: Client,
+        tx: TransactionId,
+        reason: String,
+    },
+
+    #[error("client {client} not allowed to transact")]
+    ClientNotAllowed { client: Client, tx: TransactionId },
+
+    #[error("amount {amount} of transaction {tx} outside of the allowed bounds")]
+    AmountOutOfBounds {
+        client: Client,
+        tx: TransactionId,
+        amount: Amount,
+    },
+
+    #[error("amount {amount} of transaction {tx} has too many decimal places")]
+    ExcessivePrecision {
+        client: Client,
+        tx: TransactionId,
+        amount: Amount,
+    },
+
+    #[error(
+        "transaction {tx} would take the balance of client {client} out of representable range"
+    )]
+    ArithmeticOverflow { client: Client, tx: TransactionId },
+
+    #[error("transaction {tx} needs an amount")]
+    MissingAmount { client: Client, tx: TransactionId },
+
+    #[error("client {client} is blocked")]
+    ClientBlocked { client: Client, tx: TransactionId },
+
+    #[error("transaction {tx} already processed with another type, client or amount")]
+    ConflictingDuplicate { client: Client, tx: TransactionId },
+
+    #[error("adjustment {tx} needs a reason code")]
+    MissingReason { client: Client, tx: TransactionId },
+
+    #[error("adjustment {tx} rejected, adjustments are not allowed")]
+    AdjustmentsDisabled { client: Client, tx: TransactionId },
+
+    #[error("escrow hold {tx} needs an escrow name")]
+    MissingEscrow { client: Client, tx: TransactionId },
+
+    #[error("advance_time {tx} needs an execute_at time")]
+    MissingTime { client: Client, tx: TransactionId },
+
+    #[error("transaction id {tx} is reserved for expanded recurrences")]
+    ReservedId { client: Client, tx: TransactionId },
+
+    #[error(
+        "recurrence {tx} needs an interval and between 1 and {} occurrences, within the \
+         ids left for expanded recurrences",
+        MAX_OCCURRENCES
+    )]
+    InvalidRecurrence { client: Client, tx: TransactionId },
+
+    #[error("deposit {tx} would take client {client} over the balance limit {limit} of its tier")]
+    BalanceLimitExceeded {
+        client: Client,
+        tx: TransactionId,
+        limit: Amount,
+    },
+
+    #[error("dispute of transaction {tx} outside of the dispute window of the client's tier")]
+    DisputeWindowExpired { client: Client, tx: TransactionId },
+
+    #[error("withdrawal {tx} rejected, client {client} has no verified identity")]
+    KycRequired { client: Client, tx: TransactionId },
+
+    #[error("transaction {tx} would take client {client} below its reserve {reserve}")]
+    ReserveViolation {
+        client: Client,
+        tx: TransactionId,
+        reserve: Amount,
+    },
+}
+
+impl TransactionValidationError {
+    /// Stable identifier of the error, for consumers that branch on it.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::InvalidAmount { .. } => "invalid_amount",
+            Self::Duplicate { .. } => "duplicate",
+            Self::InsufficientFunds { .. } => "insufficient_funds",
+            Self::MissingAccount { .. } => "missing_account",
+            Self::InvalidTransaction { .. } => "invalid_transaction",
+            Self::DisputeChargeback { .. } => "dispute_chargeback",
+            Self::FrozenAccount { .. } => "frozen_account",
+            Self::Reversed { .. } => "reversed",
+            Self::RiskVeto { .. } => "risk_veto",
+            Self::ClientNotAllowed { .. } => "client_not_allowed",
+            Self::AmountOutOfBounds { .. } => "amount_out_of_bounds",
+            Self::ExcessivePrecision { .. } => "excessive_precision",
+            Self::ArithmeticOverflow { .. } => "arithmetic_overflow",
+            Self::MissingAmount { .. } => "missing_amount",
+            Self::ClientBlocked { .. } => "client_blocked",
+            Self::ConflictingDuplicate { .. } => "conflicting_duplicate",
+            Self::MissingReason { .. } => "missing_reason",
+            Self::AdjustmentsDisabled { .. } => "adjustments_disabled",
+            Self::MissingEscrow { .. } => "missing_escrow",
+            Self::MissingTime { .. } => "missing_time",
+            Self::ReservedId { .. } => "reserved_id",
+            Self::InvalidRecurrence { .. } => "invalid_recurrence",
+            Self::BalanceLimitExceeded { .. } => "balance_limit_exceeded",
+            Self::DisputeWindowExpired { .. } => "dispute_window_expired",
+            Self::KycRequired { .. } => "kyc_required",
+            Self::ReserveViolation { .. } => "reserve_violation",
+        }
+    }
+
+    /// Numeric counterpart of `code`. Numbers of removed variants are not reused.
+    pub fn number(&self) -> u16 {
+        match self {
+            Self::InvalidAmount { .. } => 1,
+            Self::Duplicate { .. } => 2,
+            Self::InsufficientFunds { .. } => 3,
+            Self::MissingAccount { .. } => 4,
+            Self::InvalidTransaction { .. } => 5,
+            Self::DisputeChargeback { .. } => 6,
+            Self::FrozenAccount { .. } => 7,
+            Self::Reversed { .. } => 8,
+            Self::RiskVeto { .. } => 9,
+            Self::ClientNotAllowed { .. } => 10,
+            Self::AmountOutOfBounds { .. } => 11,
+            Self::ExcessivePrecision { .. } => 12,
+            Self::ArithmeticOverflow { .. } => 13,
+            Self::MissingAmount { .. } => 14,
+            Self::ClientBlocked { .. } => 15,
+            Self::ConflictingDuplicate { .. } => 16,
+            Self::MissingReason { .. } => 17,
+            Self::AdjustmentsDisabled { .. } => 18,
+            Self::MissingEscrow { .. } => 19,
+            Self::MissingTime { .. } => 20,
+            Self::ReservedId { .. } => 21,
+            Self::InvalidRecurrence { .. } => 22,
+            Self::BalanceLimitExceeded { .. } => 23,
+            Self::DisputeWindowExpired { .. } => 24,
+            Self::KycRequired { .. } => 25,
+            Self::ReserveViolation { .. } => 26,
+        }
+    }
+
+    pub fn client(&self) -> Client {
+        match self {
+            Self::InvalidAmount { client, .. }
+            | Self::Duplicate { client, .. }
+            | Self::InsufficientFunds { client, .. }
+            | Self::MissingAccount { client, .. }
+            | Self::InvalidTransaction { client, .. }
+            | Self::DisputeChargeback { client, .. }
+            | Self::FrozenAccount { client, .. }
+            | Self::Reversed { client, .. }
+            | Self::RiskVeto { client, .. }
+            | Self::ClientNotAllowed { client, .. }
+            | Self::AmountOutOfBounds { client, .. }
+            | Self::ExcessivePrecision { client, .. }
+            | Self::ArithmeticOverflow { client, .. }
+            | Self::MissingAmount { client, .. }
+            | Self::ClientBlocked { client, .. }
+            | Self::ConflictingDuplicate { client, .. }
+            | Self::MissingReason { client, .. }
+            | Self::AdjustmentsDisabled { client, .. }
+            | Self::MissingEscrow { client, .. }
+            | Self::MissingTime { client, .. }
+            | Self::ReservedId { client, .. }
+            | Self::InvalidRecurrence { client, .. }
+            | Self::BalanceLimitExceeded { client, .. }
+            | Self::DisputeWindowExpired { client, .. }
+            | Self::KycRequired { client, .. }
+            | Self::ReserveViolation { client, .. } => *client,
+        }
+    }
+
+    pub fn tx(&self) -> TransactionId {
+        match self {
+            Self::InvalidAmount { tx, .. }
+            | Self::Duplicate { tx, .. }
+            | Self::InsufficientFunds { tx, .. }
+            | Self::MissingAccount { tx, .. }
+            | Self::InvalidTransaction { tx, .. }
+            | Self::DisputeChargeback { tx, .. }
+            | Self::FrozenAccount { tx, .. }
+            | Self::Reversed { tx, .. }
+            | Self::RiskVeto { tx, .. }
+            | Self::ClientNotAllowed { tx, .. }
+            | Self::AmountOutOfBounds { tx, .. }
+            | Self::ExcessivePrecision { tx, .. }
+            | Self::ArithmeticOverflow { tx, .. }
+            | Self::MissingAmount { tx, .. }
+            | Self::ClientBlocked { tx, .. }
+            | Self::ConflictingDuplicate { tx, .. }
+            | Self::MissingReason { tx, .. }
+            | Self::AdjustmentsDisabled { tx, .. }
+            | Self::MissingEscrow { tx, .. }
+            | Self::MissingTime { tx, .. }
+            | Self::ReservedId { tx, .. }
+            | Self::InvalidRecurrence { tx, .. }
+            | Self::BalanceLimitExceeded { tx, .. }
+            | Self::DisputeWindowExpired { tx, .. }
+            | Self::KycRequired { tx, .. }
+            | Self::ReserveViolation { tx, .. } => *tx,
+        }
+    }
+}
 
-    #[error("transaction already processed")]
-    Duplicate(TransactionId),
+/// Why two engines can't be combined with `PaymentEngine::merge`.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum MergeError {
+    #[error("client {0} has an account in both engines")]
+    ConflictingClient(Client),
 
-    #[error("insufficient funds")]
-    InsufficientFunds,
+    #[error("transaction {0} is known to both engines")]
+    ConflictingTransaction(TransactionId),
+}
+
+/// Why `PaymentEngine::rollback_to` or `PaymentEngine::release` can't use a savepoint.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum SavepointError {
+    #[error("savepoint {0} was released, rolled back past or dropped by restore, merge or prune")]
+    Closed(u64),
+}
+
+/// Point an engine can be returned to with `PaymentEngine::rollback_to`, taken with
+/// `PaymentEngine::savepoint`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Savepoint {
+    id: u64,
+}
+
+/// Outcome of `PaymentEngine::process_batch`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BatchResult {
+    /// Every transaction of the batch was applied, this many of them; identical replays of
+    /// stored transactions are skipped and not counted.
+    Applied(usize),
+    /// Nothing was applied because the transaction at `index` in the batch was rejected.
+    Rejected {
+        index: usize,
+        error: TransactionValidationError,
+    },
+}
 
-    #[error("missing funds")]
-    MissingAccount,
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DisputeState {
+    None,
+    Open,
+    Resolved,
+    ChargedBack,
+    ChargebackReversed,
+}
+
+impl DisputeState {
+    pub fn name(self) -> &'static str {
+        match self {
+            DisputeState::None => "none",
+            DisputeState::Open => "open",
+            DisputeState::Resolved => "resolved",
+            DisputeState::ChargedBack => "charged_back",
+            DisputeState::ChargebackReversed => "chargeback_reversed",
+        }
+    }
+
+    /// Single source of truth for the dispute lifecycle: returns the state the
+    /// transaction ends up in, or the error explaining why the move is not allowed.
+    fn transition(
+        self,
+        next: DisputeState,
+        client: Client,
+        tx: TransactionId,
+    ) -> Result<DisputeState, TransactionValidationError> {
+        match (self, next) {
+            (DisputeState::None, DisputeState::Open)
+            | (DisputeState::Resolved, DisputeState::Open)
+            | (DisputeState::Open, DisputeState::Resolved)
+            | (DisputeState::Open, DisputeState::ChargedBack)
+            | (DisputeState::ChargedBack, DisputeState::ChargebackReversed) => Ok(next),
+            (DisputeState::Open, DisputeState::Open)
+            | (DisputeState::ChargedBack, DisputeState::ChargedBack)
+            | (DisputeState::ChargebackReversed, DisputeState::ChargebackReversed) => {
+                Err(TransactionValidationError::Duplicate { client, tx })
+            }
+            (DisputeState::ChargedBack, DisputeState::Open) => {
+                Err(TransactionValidationError::DisputeChargeback { client, tx })
+            }
+            _ => Err(TransactionValidationError::InvalidTransaction { client, tx }),
+        }
+    }
+}
 
-    #[error("invalid transaction")]
-    InvalidTransaction(TransactionId),
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum TransactionKind {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    Reversal,
+    ChargebackReversal,
+    Hold,
+    Capture,
+    Release,
+    Correction,
+    Adjustment,
+    EscrowHold,
+    EscrowRelease,
+    AdvanceTime,
+    Recurring,
+    ClosePeriod,
+}
 
-    #[error("invalid transaction")]
-    DisputeChargeback(TransactionId),
+impl TransactionKind {
+    pub const ALL: [TransactionKind; 17] = [
+        TransactionKind::Deposit,
+        TransactionKind::Withdrawal,
+        TransactionKind::Dispute,
+        TransactionKind::Resolve,
+        TransactionKind::Chargeback,
+        TransactionKind::Reversal,
+        TransactionKind::ChargebackReversal,
+        TransactionKind::Hold,
+        TransactionKind::Capture,
+        TransactionKind::Release,
+        TransactionKind::Correction,
+        TransactionKind::Adjustment,
+        TransactionKind::EscrowHold,
+        TransactionKind::EscrowRelease,
+        TransactionKind::AdvanceTime,
+        TransactionKind::Recurring,
+        TransactionKind::ClosePeriod,
+    ];
 
-    #[error("frozen account")]
-    FrozenAccount,
+    /// The `type` used for this kind in the CSV input.
+    pub fn name(self) -> &'static str {
+        match self {
+            TransactionKind::Deposit => "deposit",
+            TransactionKind::Withdrawal => "withdrawal",
+            TransactionKind::Dispute => "dispute",
+            TransactionKind::Resolve => "resolve",
+            TransactionKind::Chargeback => "chargeback",
+            TransactionKind::Reversal => "reversal",
+            TransactionKind::ChargebackReversal => "chargeback_reversal",
+            TransactionKind::Hold => "hold",
+            TransactionKind::Capture => "capture",
+            TransactionKind::Release => "release",
+            TransactionKind::Correction => "correction",
+            TransactionKind::Adjustment => "adjustment",
+            TransactionKind::EscrowHold => "escrow_hold",
+            TransactionKind::EscrowRelease => "escrow_release",
+            TransactionKind::AdvanceTime => "advance_time",
+            TransactionKind::Recurring => "recurring",
+            TransactionKind::ClosePeriod => "close_period",
+        }
+    }
 }
 
+/// Serialized for engine snapshots only; the CSV input is read through `TransactionRecord`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Transaction {
     Deposit {
         client: Client,
         tx: TransactionId,
+        #[serde(with = "crate::amount::text")]
         amount: Amount,
-        dispute: bool,
-        chargeback: bool,
+        dispute: DisputeState,
+        reversed: bool,
+        /// Free-text reference from the input, kept for tracing only.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        memo: Option<Box<str>>,
+        /// Merchant or other party on the other side, for per-counterparty statistics.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        counterparty: Option<Box<str>>,
+        /// Time of the engine clock it was applied at, which the dispute window of a tier is
+        /// counted from. Not known for deposits stored by older snapshots.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        posted: Option<u64>,
     },
     Withdrawal {
         client: Client,
         tx: TransactionId,
+        #[serde(with = "crate::amount::text")]
         amount: Amount,
-        dispute: bool,
-        chargeback: bool,
+        dispute: DisputeState,
+        reversed: bool,
+        /// Free-text reference from the input, kept for tracing only.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        memo: Option<Box<str>>,
+        /// Merchant or other party on the other side, for per-counterparty statistics.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        counterparty: Option<Box<str>>,
     },
     Dispute {
         client: Client,
@@ -59,6 +471,158 @@ pub enum Transaction {
         client: Client,
         tx: TransactionId,
     },
+    Reversal {
+        client: Client,
+        tx: TransactionId,
+    },
+    ChargebackReversal {
+        client: Client,
+        tx: TransactionId,
+    },
+    Hold {
+        client: Client,
+        tx: TransactionId,
+        #[serde(with = "crate::amount::text")]
+        amount: Amount,
+        released: bool,
+        /// Free-text reference from the input, kept for tracing only.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        memo: Option<Box<str>>,
+        /// Merchant or other party on the other side, for per-counterparty statistics.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        counterparty: Option<Box<str>>,
+    },
+    Capture {
+        client: Client,
+        tx: TransactionId,
+    },
+    Release {
+        client: Client,
+        tx: TransactionId,
+    },
+    /// New amount of a stored deposit or withdrawal, or `None` to void it, from a corrections
+    /// file.
+    Correction {
+        client: Client,
+        tx: TransactionId,
+        amount: Option<Amount>,
+    },
+    /// Manual credit (positive `amount`) or debit (negative) posted by operations, see
+    /// `PaymentEngine::set_allow_adjustments`.
+    Adjustment {
+        client: Client,
+        tx: TransactionId,
+        #[serde(with = "crate::amount::text")]
+        amount: Amount,
+        /// Reason code, e.g. `goodwill`.
+        reason: Box<str>,
+        /// Free-text reference from the input, kept for tracing only.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        memo: Option<Box<str>>,
+        /// Merchant or other party on the other side, for per-counterparty statistics.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        counterparty: Option<Box<str>>,
+    },
+    /// Funds set aside in the named escrow of the account, apart from the funds held by
+    /// disputes and holds, until an `EscrowRelease` of the same `tx`.
+    EscrowHold {
+        client: Client,
+        tx: TransactionId,
+        #[serde(with = "crate::amount::text")]
+        amount: Amount,
+        /// Name of the escrow, e.g. an order or a deal; a client can have several.
+        escrow: Box<str>,
+        released: bool,
+        /// Released to `counterparty` rather than back to the client.
+        paid_out: bool,
+        /// Free-text reference from the input, kept for tracing only.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        memo: Option<Box<str>>,
+        /// Party the funds are paid out to, from the hold or from the release.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        counterparty: Option<Box<str>>,
+    },
+    /// Releases the escrow hold `tx` back to the client, or pays it out to `counterparty`
+    /// when set.
+    EscrowRelease {
+        client: Client,
+        tx: TransactionId,
+        counterparty: Option<Box<str>>,
+    },
+    /// `transaction`, applied once the engine clock reaches `execute_at`, in seconds since the
+    /// Unix epoch (see `PaymentEngine::set_clock`). It has the type, client, id and amount of
+    /// `transaction`.
+    Scheduled {
+        execute_at: u64,
+        transaction: Box<Transaction>,
+    },
+    /// Moves the clock of the engine forward to `to`, applying the scheduled transactions that
+    /// are then due.
+    AdvanceTime {
+        client: Client,
+        tx: TransactionId,
+        to: u64,
+    },
+    /// `count` deposits of `amount`, or withdrawals when it is negative, `interval` seconds
+    /// apart from `start` on, the engine clock's time when not set. The engine schedules them
+    /// with ids of its own, see `FIRST_GENERATED_ID`.
+    Recurring {
+        client: Client,
+        tx: TransactionId,
+        #[serde(with = "crate::amount::text")]
+        amount: Amount,
+        interval: u64,
+        count: u32,
+        start: Option<u64>,
+    },
+    /// Ends the current period, see `PaymentEngine::close_period`. Client and id are not used.
+    ClosePeriod {
+        client: Client,
+        tx: TransactionId,
+    },
+}
+
+/// Ids from this one on are given to the transactions expanded from recurrences; records
+/// creating a transaction can't use them.
+pub const FIRST_GENERATED_ID: TransactionId = 0xF000_0000;
+
+/// Occurrences a recurrence may have at most.
+pub const MAX_OCCURRENCES: u32 = 10_000;
+
+/// Rejects taking `amount` out of the available funds of `account` when that leaves less than
+/// the reserve of its client, see `Rules::reserve`.
+fn check_reserve(
+    account: &Account,
+    rules: &Rules,
+    tx: TransactionId,
+    amount: Amount,
+) -> Result<(), TransactionValidationError> {
+    match rules.reserve(account.client) {
+        Some(reserve)
+            if account
+                .available
+                .checked_sub(amount)
+                .is_none_or(|left| left < reserve) =>
+        {
+            Err(TransactionValidationError::ReserveViolation {
+                client: account.client,
+                tx,
+                reserve,
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Rejects input that moves funds under an id from the range kept for recurrences.
+fn check_not_reserved(transaction: &Transaction) -> Result<(), TransactionValidationError> {
+    if transaction.amount().is_some() && transaction.tx() >= FIRST_GENERATED_ID {
+        return Err(TransactionValidationError::ReservedId {
+            client: transaction.client(),
+            tx: transaction.tx(),
+        });
+    }
+    Ok(())
 }
 
 impl Transaction {
@@ -67,15 +631,18 @@ impl Transaction {
         tx: TransactionId,
         amount: Amount,
     ) -> Result<Self, TransactionValidationError> {
-        if amount <= dec!(0.0) {
-            return Err(TransactionValidationError::InvalidAmount);
+        if amount <= Amount::ZERO {
+            return Err(TransactionValidationError::InvalidAmount { client, tx, amount });
         };
         let transaction = Self::Deposit {
             client,
             tx,
             amount,
-            dispute: false,
-            chargeback: false,
+            dispute: DisputeState::None,
+            reversed: false,
+            memo: None,
+            counterparty: None,
+            posted: None,
         };
         Ok(transaction)
     }
@@ -85,16 +652,18 @@ impl Transaction {
         tx: TransactionId,
         amount: Amount,
     ) -> Result<Self, TransactionValidationError> {
-        if amount <= dec!(0.0) {
-            return Err(TransactionValidationError::InvalidAmount);
+        if amount <= Amount::ZERO {
+            return Err(TransactionValidationError::InvalidAmount { client, tx, amount });
         };
 
         let transaction = Self::Withdrawal {
             client,
             tx,
             amount,
-            dispute: false,
-            chargeback: false,
+            dispute: DisputeState::None,
+            reversed: false,
+            memo: None,
+            counterparty: None,
         };
         Ok(transaction)
     }
@@ -109,707 +678,4954 @@ impl Transaction {
     pub fn new_chargeback(client: Client, tx: TransactionId) -> Self {
         Self::Chargeback { client, tx }
     }
-}
 
-#[derive(Debug, Clone, Copy)]
-pub struct Account {
-    client: Client,
-    available: Amount,
-    held: Amount,
-    frozen: bool,
-}
+    pub fn new_reversal(client: Client, tx: TransactionId) -> Self {
+        Self::Reversal { client, tx }
+    }
 
-impl Account {
-    fn new(client: Client) -> Self {
-        Self {
+    pub fn new_chargeback_reversal(client: Client, tx: TransactionId) -> Self {
+        Self::ChargebackReversal { client, tx }
+    }
+
+    pub fn new_hold(
+        client: Client,
+        tx: TransactionId,
+        amount: Amount,
+    ) -> Result<Self, TransactionValidationError> {
+        if amount <= Amount::ZERO {
+            return Err(TransactionValidationError::InvalidAmount { client, tx, amount });
+        };
+
+        let transaction = Self::Hold {
             client,
-            available: dec!(0.0),
-            held: dec!(0.0),
-            frozen: false,
-        }
+            tx,
+            amount,
+            released: false,
+            memo: None,
+            counterparty: None,
+        };
+        Ok(transaction)
     }
 
-    fn total_funds(&self) -> Decimal {
-        self.available + self.held
+    pub fn new_capture(client: Client, tx: TransactionId) -> Self {
+        Self::Capture { client, tx }
     }
-}
 
-impl Serialize for Account {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        let mut state = serializer.serialize_struct("Account", 4)?;
-        state.serialize_field("client", &self.client)?;
-        state.serialize_field("available", &self.available.round_dp(4))?;
-        state.serialize_field("held", &self.held.round_dp(4))?;
-        state.serialize_field("total", &self.total_funds().round_dp(4))?;
-        state.serialize_field("locked", &self.frozen)?;
-        state.end()
+    pub fn new_release(client: Client, tx: TransactionId) -> Self {
+        Self::Release { client, tx }
     }
-}
 
-pub struct PaymentEngine {
-    accounts: HashMap<Client, Account>,
-    transactions: HashMap<TransactionId, Transaction>,
-}
+    /// Credits `amount` to the client, or debits it when negative, for `reason`.
+    pub fn new_adjustment(
+        client: Client,
+        tx: TransactionId,
+        amount: Amount,
+        reason: String,
+    ) -> Result<Self, TransactionValidationError> {
+        if amount == Amount::ZERO {
+            return Err(TransactionValidationError::InvalidAmount { client, tx, amount });
+        }
+        let reason = reason.trim();
+        if reason.is_empty() {
+            return Err(TransactionValidationError::MissingReason { client, tx });
+        }
+        Ok(Self::Adjustment {
+            client,
+            tx,
+            amount,
+            reason: reason.into(),
+            memo: None,
+            counterparty: None,
+        })
+    }
 
-impl PaymentEngine {
-    pub fn new() -> Self {
-        Self {
-            accounts: HashMap::new(),
-            transactions: HashMap::new(),
+    /// Moves `amount` from the available funds of the client into the escrow named `escrow`.
+    pub fn new_escrow_hold(
+        client: Client,
+        tx: TransactionId,
+        amount: Amount,
+        escrow: String,
+    ) -> Result<Self, TransactionValidationError> {
+        if amount <= Amount::ZERO {
+            return Err(TransactionValidationError::InvalidAmount { client, tx, amount });
+        }
+        let escrow = escrow.trim();
+        if escrow.is_empty() {
+            return Err(TransactionValidationError::MissingEscrow { client, tx });
         }
+        Ok(Self::EscrowHold {
+            client,
+            tx,
+            amount,
+            escrow: escrow.into(),
+            released: false,
+            paid_out: false,
+            memo: None,
+            counterparty: None,
+        })
     }
 
-    pub fn get_accounts(&self) -> Vec<Account> {
-        let mut acc: Vec<Account> = self.accounts.values().cloned().collect();
-        acc.sort_by_key(|acc| acc.client);
-        acc
+    /// Releases the escrow hold `tx` back to the client; see `with_counterparty` to pay it out.
+    pub fn new_escrow_release(client: Client, tx: TransactionId) -> Self {
+        Self::EscrowRelease {
+            client,
+            tx,
+            counterparty: None,
+        }
     }
 
-    fn process_deposit(&mut self, deposit: Transaction) -> Result<(), TransactionValidationError> {
-        if let Transaction::Deposit {
-            tx, client, amount, ..
-        } = deposit
-        {
-            if self.transactions.contains_key(&tx) {
-                return Err(TransactionValidationError::Duplicate(tx));
-            }
+    /// Applies `transaction` once the engine clock reaches `execute_at`.
+    pub fn new_scheduled(execute_at: u64, transaction: Transaction) -> Self {
+        Self::Scheduled {
+            execute_at,
+            transaction: Box::new(transaction),
+        }
+    }
 
-            let account = self
-                .accounts
-                .entry(client)
-                .or_insert_with(|| Account::new(client));
+    pub fn new_advance_time(client: Client, tx: TransactionId, to: u64) -> Self {
+        Self::AdvanceTime { client, tx, to }
+    }
 
-            account.available += amount;
-            self.transactions.insert(tx, deposit);
+    pub fn new_close_period(client: Client, tx: TransactionId) -> Self {
+        Self::ClosePeriod { client, tx }
+    }
+
+    /// `count` deposits of `amount`, or withdrawals of its opposite when negative, `interval`
+    /// seconds apart from `start` on.
+    pub fn new_recurring(
+        client: Client,
+        tx: TransactionId,
+        amount: Amount,
+        interval: u64,
+        count: u32,
+        start: Option<u64>,
+    ) -> Result<Self, TransactionValidationError> {
+        if amount == Amount::ZERO {
+            return Err(TransactionValidationError::InvalidAmount { client, tx, amount });
         }
-        Ok(())
+        if count == 0 || count > MAX_OCCURRENCES || (count > 1 && interval == 0) {
+            return Err(TransactionValidationError::InvalidRecurrence { client, tx });
+        }
+        Ok(Self::Recurring {
+            client,
+            tx,
+            amount,
+            interval,
+            count,
+            start,
+        })
     }
 
-    fn process_withdrawal(
-        &mut self,
-        withdrawal: Transaction,
-    ) -> Result<(), TransactionValidationError> {
-        if let Transaction::Withdrawal {
-            tx, client, amount, ..
-        } = withdrawal
-        {
-            if self.transactions.contains_key(&tx) {
-                return Err(TransactionValidationError::Duplicate(tx));
-            }
-            let account = match self.accounts.get_mut(&client) {
-                Some(account) => account,
-                None => {
-                    return Err(TransactionValidationError::MissingAccount);
-                }
-            };
-            if account.frozen {
-                return Err(TransactionValidationError::FrozenAccount);
-            }
-            if account.available < amount {
-                return Err(TransactionValidationError::InsufficientFunds);
+    /// Sets the amount of the deposit or withdrawal `tx` to `amount`, or voids it when `None`.
+    pub fn new_correction(
+        client: Client,
+        tx: TransactionId,
+        amount: Option<Amount>,
+    ) -> Result<Self, TransactionValidationError> {
+        match amount {
+            Some(amount) if amount <= Amount::ZERO => {
+                Err(TransactionValidationError::InvalidAmount { client, tx, amount })
             }
-            account.available -= amount;
-            self.transactions.insert(tx, withdrawal);
+            amount => Ok(Self::Correction { client, tx, amount }),
         }
+    }
 
-        Ok(())
+    /// Attaches the free-text `memo` of the input record. Only deposits, withdrawals and holds
+    /// are stored, the other kinds drop it.
+    pub fn with_memo(mut self, memo: Option<String>) -> Self {
+        if let Self::Deposit { memo: slot, .. }
+        | Self::Withdrawal { memo: slot, .. }
+        | Self::Hold { memo: slot, .. }
+        | Self::Adjustment { memo: slot, .. }
+        | Self::EscrowHold { memo: slot, .. } = &mut self
+        {
+            *slot = memo.map(String::into_boxed_str);
+        }
+        self
     }
 
-    fn process_dispute(
-        &mut self,
-        tx: TransactionId,
-        dispute_client: Client,
-    ) -> Result<(), TransactionValidationError> {
-        match self.transactions.get(&tx) {
-            Some(transaction) => match transaction {
-                Transaction::Deposit {
-                    client,
-                    tx,
-                    dispute,
-                    chargeback,
-                    ..
-                }
-                | Transaction::Withdrawal {
-                    client,
-                    tx,
-                    dispute,
-                    chargeback,
-                    ..
-                } => {
-                    if *client != dispute_client {
-                        return Err(TransactionValidationError::InvalidTransaction(*tx));
-                    };
+    /// Attaches the counterparty of the input record, dropped like memos by the kinds that aren't
+    /// stored except escrow releases, which pay the escrow out to it.
+    pub fn with_counterparty(mut self, counterparty: Option<String>) -> Self {
+        if let Self::Deposit {
+            counterparty: slot, ..
+        }
+        | Self::Withdrawal {
+            counterparty: slot, ..
+        }
+        | Self::Hold {
+            counterparty: slot, ..
+        }
+        | Self::Adjustment {
+            counterparty: slot, ..
+        }
+        | Self::EscrowHold {
+            counterparty: slot, ..
+        } = &mut self
+        {
+            *slot = counterparty.map(String::into_boxed_str);
+        } else if let Self::EscrowRelease {
+            counterparty: slot, ..
+        } = &mut self
+        {
+            *slot = counterparty.map(String::into_boxed_str);
+        }
+        self
+    }
 
-                    if *chargeback {
-                        return Err(TransactionValidationError::DisputeChargeback(*tx));
-                    }
-                    if *dispute {
-                        return Err(TransactionValidationError::Duplicate(*tx));
-                    }
-                    if !self.accounts.contains_key(client) {
-                        return Err(TransactionValidationError::MissingAccount);
-                    };
-                }
-                _ => {}
-            },
-            None => {
-                return Err(TransactionValidationError::InvalidTransaction(tx));
-            }
-        };
+    /// Counterparty of a deposit, withdrawal, hold, adjustment or escrow.
+    pub fn counterparty(&self) -> Option<&str> {
+        match self {
+            Self::Deposit { counterparty, .. }
+            | Self::Withdrawal { counterparty, .. }
+            | Self::Hold { counterparty, .. }
+            | Self::Adjustment { counterparty, .. }
+            | Self::EscrowHold { counterparty, .. }
+            | Self::EscrowRelease { counterparty, .. } => counterparty.as_deref(),
+            Self::Scheduled { transaction, .. } => transaction.counterparty(),
+            _ => None,
+        }
+    }
 
-        if let Some(Transaction::Deposit {
-            client,
-            dispute,
-            amount,
-            ..
-        }) = self.transactions.get_mut(&tx)
-        {
-            if let Some(account) = self.accounts.get_mut(client) {
-                *dispute = true;
-                account.available -= *amount;
-                account.held += *amount;
-            }
+    /// Memo of a deposit, withdrawal, hold, adjustment or escrow hold; it plays no part in
+    /// balances.
+    pub fn memo(&self) -> Option<&str> {
+        match self {
+            Self::Deposit { memo, .. }
+            | Self::Withdrawal { memo, .. }
+            | Self::Hold { memo, .. }
+            | Self::Adjustment { memo, .. }
+            | Self::EscrowHold { memo, .. } => memo.as_deref(),
+            Self::Scheduled { transaction, .. } => transaction.memo(),
+            _ => None,
         }
-        if let Some(Transaction::Withdrawal {
-            client,
-            dispute,
-            amount,
-            ..
-        }) = self.transactions.get_mut(&tx)
-        {
-            if let Some(account) = self.accounts.get_mut(client) {
-                *dispute = true;
-                account.available -= -*amount;
-                account.held += -*amount;
-            }
+    }
+
+    /// Reason code of an adjustment.
+    pub fn reason(&self) -> Option<&str> {
+        match self {
+            Self::Adjustment { reason, .. } => Some(reason),
+            _ => None,
         }
-        Ok(())
     }
 
-    fn process_resolve(
-        &mut self,
-        tx: TransactionId,
-        resolve_client: Client,
-    ) -> Result<(), TransactionValidationError> {
-        if !self.transactions.contains_key(&tx) {
-            return Err(TransactionValidationError::InvalidTransaction(tx));
+    /// Name of the escrow of an escrow hold.
+    pub fn escrow(&self) -> Option<&str> {
+        match self {
+            Self::EscrowHold { escrow, .. } => Some(escrow),
+            _ => None,
         }
+    }
 
-        match self.transactions.get_mut(&tx) {
-            Some(transaction) => match transaction {
-                Transaction::Deposit {
-                    client,
-                    tx,
-                    dispute,
-                    chargeback,
-                    ..
-                }
-                | Transaction::Withdrawal {
-                    client,
-                    tx,
-                    dispute,
-                    chargeback,
-                    ..
-                } => {
-                    if *client != resolve_client {
-                        return Err(TransactionValidationError::InvalidTransaction(*tx));
-                    };
-                    if !*dispute {
-                        return Err(TransactionValidationError::InvalidTransaction(*tx));
-                    }
-                    if *chargeback {
-                        return Err(TransactionValidationError::InvalidTransaction(*tx));
-                    }
-                }
-                _ => {}
-            },
-            None => return Err(TransactionValidationError::InvalidTransaction(tx)),
-        };
+    pub fn kind(&self) -> TransactionKind {
+        match self {
+            Self::Deposit { .. } => TransactionKind::Deposit,
+            Self::Withdrawal { .. } => TransactionKind::Withdrawal,
+            Self::Dispute { .. } => TransactionKind::Dispute,
+            Self::Resolve { .. } => TransactionKind::Resolve,
+            Self::Chargeback { .. } => TransactionKind::Chargeback,
+            Self::Reversal { .. } => TransactionKind::Reversal,
+            Self::ChargebackReversal { .. } => TransactionKind::ChargebackReversal,
+            Self::Hold { .. } => TransactionKind::Hold,
+            Self::Capture { .. } => TransactionKind::Capture,
+            Self::Release { .. } => TransactionKind::Release,
+            Self::Correction { .. } => TransactionKind::Correction,
+            Self::Adjustment { .. } => TransactionKind::Adjustment,
+            Self::EscrowHold { .. } => TransactionKind::EscrowHold,
+            Self::EscrowRelease { .. } => TransactionKind::EscrowRelease,
+            Self::Scheduled { transaction, .. } => transaction.kind(),
+            Self::AdvanceTime { .. } => TransactionKind::AdvanceTime,
+            Self::Recurring { .. } => TransactionKind::Recurring,
+            Self::ClosePeriod { .. } => TransactionKind::ClosePeriod,
+        }
+    }
 
-        if let Some(Transaction::Deposit {
-            client,
-            amount,
-            dispute,
-            ..
-        }) = self.transactions.get_mut(&tx)
-        {
-            if let Some(account) = self.accounts.get_mut(client) {
-                account.available += *amount;
-                account.held -= *amount;
-                *dispute = false;
-            } else {
-                return Err(TransactionValidationError::MissingAccount);
-            }
+    pub fn client(&self) -> Client {
+        match self {
+            Self::Deposit { client, .. }
+            | Self::Withdrawal { client, .. }
+            | Self::Dispute { client, .. }
+            | Self::Resolve { client, .. }
+            | Self::Chargeback { client, .. }
+            | Self::Reversal { client, .. }
+            | Self::ChargebackReversal { client, .. }
+            | Self::Hold { client, .. }
+            | Self::Capture { client, .. }
+            | Self::Release { client, .. }
+            | Self::Correction { client, .. }
+            | Self::Adjustment { client, .. }
+            | Self::EscrowHold { client, .. }
+            | Self::EscrowRelease { client, .. }
+            | Self::AdvanceTime { client, .. }
+            | Self::Recurring { client, .. }
+            | Self::ClosePeriod { client, .. } => *client,
+            Self::Scheduled { transaction, .. } => transaction.client(),
         }
+    }
 
-        if let Some(Transaction::Withdrawal {
-            client,
-            amount,
-            dispute,
-            ..
-        }) = self.transactions.get_mut(&tx)
-        {
-            if let Some(account) = self.accounts.get_mut(client) {
-                account.available += -*amount;
-                account.held -= -*amount;
-                *dispute = false;
-            } else {
-                return Err(TransactionValidationError::MissingAccount);
-            }
+    pub fn tx(&self) -> TransactionId {
+        match self {
+            Self::Deposit { tx, .. }
+            | Self::Withdrawal { tx, .. }
+            | Self::Dispute { tx, .. }
+            | Self::Resolve { tx, .. }
+            | Self::Chargeback { tx, .. }
+            | Self::Reversal { tx, .. }
+            | Self::ChargebackReversal { tx, .. }
+            | Self::Hold { tx, .. }
+            | Self::Capture { tx, .. }
+            | Self::Release { tx, .. }
+            | Self::Correction { tx, .. }
+            | Self::Adjustment { tx, .. }
+            | Self::EscrowHold { tx, .. }
+            | Self::EscrowRelease { tx, .. }
+            | Self::AdvanceTime { tx, .. }
+            | Self::Recurring { tx, .. }
+            | Self::ClosePeriod { tx, .. } => *tx,
+            Self::Scheduled { transaction, .. } => transaction.tx(),
         }
-        Ok(())
     }
 
-    fn process_chargeback(
-        &mut self,
-        tx: TransactionId,
-        chargeback_client: Client,
-    ) -> Result<(), TransactionValidationError> {
-        if !self.transactions.contains_key(&tx) {
-            return Err(TransactionValidationError::InvalidTransaction(tx));
+    /// Amount carried by the record itself; records referring to another transaction have none.
+    pub fn amount(&self) -> Option<Amount> {
+        match self {
+            Self::Deposit { amount, .. }
+            | Self::Withdrawal { amount, .. }
+            | Self::Hold { amount, .. }
+            | Self::Adjustment { amount, .. }
+            | Self::EscrowHold { amount, .. }
+            | Self::Recurring { amount, .. } => Some(*amount),
+            Self::Scheduled { transaction, .. } => transaction.amount(),
+            _ => None,
         }
+    }
+}
 
-        match self.transactions.get_mut(&tx) {
-            Some(transaction) => match transaction {
-                Transaction::Deposit {
-                    client,
-                    tx,
-                    dispute,
-                    chargeback,
-                    ..
-                }
-                | Transaction::Withdrawal {
-                    client,
-                    tx,
-                    dispute,
-                    chargeback,
-                    ..
-                } => {
-                    if *client != chargeback_client {
-                        return Err(TransactionValidationError::InvalidTransaction(*tx));
-                    };
-                    if *chargeback {
-                        return Err(TransactionValidationError::Duplicate(*tx));
-                    }
-                    if !*dispute {
-                        return Err(TransactionValidationError::InvalidTransaction(*tx));
-                    }
-                }
-                _ => {}
-            },
-            None => return Err(TransactionValidationError::InvalidTransaction(tx)),
-        };
+#[derive(Debug, Clone, Copy)]
+pub struct Account {
+    client: Client,
+    /// Balances of the `LedgerAccount::Available`, `LedgerAccount::Held` and
+    /// `LedgerAccount::Escrow` sub-ledgers of the client, kept up to date by `Ledger::post`.
+    available: Amount,
+    held: Amount,
+    escrowed: Amount,
+    frozen: bool,
+    chargebacks: u32,
+    flagged: bool,
+    disputes_open: u32,
+    disputes_total: u32,
+    /// What this engine contributed to the account, for replicas to merge (see `crdt`).
+    counters: AccountCounters,
+}
 
-        if let Some(Transaction::Deposit {
+impl Account {
+    fn new(client: Client) -> Self {
+        Self {
             client,
-            amount,
-            chargeback,
-            ..
-        }) = self.transactions.get_mut(&tx)
-        {
-            if let Some(account) = self.accounts.get_mut(client) {
-                account.held -= *amount;
-                account.frozen = true;
-                *chargeback = true;
-            } else {
-                return Err(TransactionValidationError::MissingAccount);
+            available: Amount::ZERO,
+            held: Amount::ZERO,
+            escrowed: Amount::ZERO,
+            frozen: false,
+            chargebacks: 0,
+            flagged: false,
+            disputes_open: 0,
+            disputes_total: 0,
+            counters: AccountCounters::default(),
+        }
+    }
+
+    fn total_funds(&self) -> Amount {
+        self.available + self.held + self.escrowed
+    }
+
+    pub fn client(&self) -> Client {
+        self.client
+    }
+
+    pub fn available(&self) -> Amount {
+        self.available
+    }
+
+    pub fn held(&self) -> Amount {
+        self.held
+    }
+
+    /// Funds in the escrows of the account, all of them together.
+    pub fn escrowed(&self) -> Amount {
+        self.escrowed
+    }
+
+    /// Available, held and escrowed funds.
+    pub fn total(&self) -> Amount {
+        self.total_funds()
+    }
+
+    pub fn frozen(&self) -> bool {
+        self.frozen
+    }
+
+    /// Whether the available funds are below zero, as after the dispute of a spent deposit.
+    pub fn is_negative(&self) -> bool {
+        self.available < Amount::ZERO
+    }
+
+    pub fn chargebacks(&self) -> u32 {
+        self.chargebacks
+    }
+
+    pub fn flagged(&self) -> bool {
+        self.flagged
+    }
+
+    /// Disputes neither resolved nor charged back yet.
+    pub fn disputes_open(&self) -> u32 {
+        self.disputes_open
+    }
+
+    /// Disputes ever opened, including reopened ones.
+    pub fn disputes_total(&self) -> u32 {
+        self.disputes_total
+    }
+
+    /// Monotonic counters of the credits and debits behind the balances, and of the applied
+    /// transactions.
+    pub fn counters(&self) -> AccountCounters {
+        self.counters
+    }
+
+    /// Applies a journal entry to the sub-ledgers of this account; `available`, `held` and
+    /// `escrowed` only ever change this way. Nothing changes when a balance or the total funds
+    /// would overflow.
+    pub(crate) fn apply(&mut self, entry: &JournalEntry) -> Result<(), TransactionValidationError> {
+        let overflow = TransactionValidationError::ArithmeticOverflow {
+            client: self.client,
+            tx: entry.tx,
+        };
+        let available_effect = entry.effect(LedgerAccount::Available(self.client));
+        let held_effect = entry.effect(LedgerAccount::Held(self.client));
+        let escrow_effect = entry.effect(LedgerAccount::Escrow(self.client));
+        let available = self
+            .available
+            .checked_add(available_effect)
+            .ok_or(overflow.clone())?;
+        let held = self.held.checked_add(held_effect).ok_or(overflow.clone())?;
+        let escrowed = self
+            .escrowed
+            .checked_add(escrow_effect)
+            .ok_or(overflow.clone())?;
+        available
+            .checked_add(held)
+            .and_then(|funds| funds.checked_add(escrowed))
+            .ok_or(overflow)?;
+        self.available = available;
+        self.held = held;
+        self.escrowed = escrowed;
+        self.counters
+            .record(available_effect, held_effect, escrow_effect);
+        Ok(())
+    }
+
+    fn register_chargeback(&mut self, policy: &DisputePolicy) {
+        self.chargebacks += 1;
+        if self.chargebacks > policy.chargeback_threshold {
+            match policy.chargeback_action {
+                ChargebackAction::Freeze => self.frozen = true,
+                ChargebackAction::Flag => self.flagged = true,
             }
         }
+    }
+}
 
-        if let Some(Transaction::Withdrawal {
-            client,
-            amount,
-            chargeback,
-            ..
-        }) = self.transactions.get_mut(&tx)
-        {
-            if let Some(account) = self.accounts.get_mut(client) {
-                account.held -= *amount;
-                account.frozen = true;
-                *chargeback = true;
-            } else {
-                return Err(TransactionValidationError::MissingAccount);
+impl Serialize for Account {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("Account", 4)?;
+        state.serialize_field("client", &self.client)?;
+        state.serialize_field("available", &self.available.round_dp(4))?;
+        state.serialize_field("held", &self.held.round_dp(4))?;
+        state.serialize_field("total", &self.total_funds().round_dp(4))?;
+        state.serialize_field("locked", &self.frozen)?;
+        state.end()
+    }
+}
+
+/// What happens to an account once its chargebacks exceed the threshold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChargebackAction {
+    #[default]
+    Freeze,
+    Flag,
+}
+
+impl std::str::FromStr for ChargebackAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "freeze" => Ok(ChargebackAction::Freeze),
+            "flag" => Ok(ChargebackAction::Flag),
+            _ => Err(format!("unknown chargeback action: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DisputePolicy {
+    /// Unlock the account once a charged back deposit is re-credited after representment.
+    pub unfreeze_on_chargeback_reversal: bool,
+    /// Number of chargebacks a client may have before `chargeback_action` is applied.
+    /// The default of 0 freezes the account on the very first chargeback.
+    pub chargeback_threshold: u32,
+    pub chargeback_action: ChargebackAction,
+}
+
+/// How much of each transaction the engine keeps around.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageMode {
+    /// Every deposit, withdrawal and hold is stored.
+    #[default]
+    Full,
+    /// Withdrawals (including captured holds) are only remembered by id, so they can no longer
+    /// be disputed or reversed, but take a fraction of the memory. No journal lines are kept.
+    Compact,
+}
+
+/// Time scheduled transactions are applied by, see `Transaction::Scheduled`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Clock {
+    /// Time of the system, read whenever a scheduled transaction may be due.
+    #[default]
+    System,
+    /// Seconds since the Unix epoch, only moved by `PaymentEngine::advance_time`.
+    Simulated(u64),
+}
+
+/// Calendar periods the engine closes by itself as its clock moves, see
+/// `PaymentEngine::set_period_length`. Days are UTC days and weeks start on Monday.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PeriodLength {
+    Daily,
+    Weekly,
+    Monthly,
+}
+
+impl PeriodLength {
+    /// Number of the period `time` falls in, counted from the Unix epoch.
+    pub fn key(self, time: u64) -> u64 {
+        let days = time / 86_400;
+        match self {
+            PeriodLength::Daily => days,
+            // 1970-01-01 was a Thursday
+            PeriodLength::Weekly => (days + 3) / 7,
+            PeriodLength::Monthly => {
+                let (year, month, _) = civil_date(days);
+                year * 12 + month - 1
             }
         }
-        Ok(())
     }
 
-    pub fn process_transaction(
-        &mut self,
-        transaction: Transaction,
-    ) -> Result<(), TransactionValidationError> {
-        match transaction {
-            Transaction::Deposit { .. } => {
-                self.process_deposit(transaction)?;
+    /// First day of the period `time` falls in, as `YYYY-MM-DD`, or `YYYY-MM` for months.
+    pub fn label(self, time: u64) -> String {
+        let days = time / 86_400;
+        match self {
+            PeriodLength::Daily => {
+                let (year, month, day) = civil_date(days);
+                format!("{:04}-{:02}-{:02}", year, month, day)
             }
-            Transaction::Withdrawal { .. } => {
-                self.process_withdrawal(transaction)?;
+            PeriodLength::Weekly => {
+                let monday = (days + 3) / 7 * 7;
+                let (year, month, day) = civil_date(monday.saturating_sub(3));
+                format!("{:04}-{:02}-{:02}", year, month, day)
             }
-            Transaction::Dispute { tx, client, .. } => {
-                self.process_dispute(tx, client)?;
+            PeriodLength::Monthly => {
+                let (year, month, _) = civil_date(days);
+                format!("{:04}-{:02}", year, month)
             }
-            Transaction::Resolve { tx, client, .. } => {
-                self.process_resolve(tx, client)?;
+        }
+    }
+}
+
+impl std::str::FromStr for PeriodLength {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "daily" => Ok(PeriodLength::Daily),
+            "weekly" => Ok(PeriodLength::Weekly),
+            "monthly" => Ok(PeriodLength::Monthly),
+            _ => Err(format!("unknown period: {}", s)),
+        }
+    }
+}
+
+/// Year, month and day of the month (both from 1) of the day `days` after 1970-01-01, in the
+/// proleptic Gregorian calendar.
+fn civil_date(days: u64) -> (u64, u64, u64) {
+    // shifted to 0000-03-01, so that leap days end the year
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Which stored transactions `PaymentEngine::prune` drops. Open disputes, holds that are
+/// neither captured nor released and unreleased escrow holds are always kept.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Retention {
+    /// Drops transactions more than this many ids below the newest known id. Records carry no
+    /// timestamps, so the age of a transaction is told by its id.
+    pub older_than: Option<TransactionId>,
+    /// Drops transactions whose outcome is settled: reversed, resolved, charged back or
+    /// released. Charged back transactions can no longer be reversed afterwards.
+    pub resolved: bool,
+}
+
+impl Retention {
+    fn drops(&self, transaction: &Transaction, newest: TransactionId) -> bool {
+        let (dispute, finished) = match transaction {
+            Transaction::Deposit {
+                dispute, reversed, ..
             }
-            Transaction::Chargeback { tx, client, .. } => {
-                self.process_chargeback(tx, client)?;
+            | Transaction::Withdrawal {
+                dispute, reversed, ..
+            } => (*dispute, *reversed || *dispute != DisputeState::None),
+            Transaction::Hold { released, .. } | Transaction::EscrowHold { released, .. } => {
+                (DisputeState::None, *released)
             }
+            _ => return false,
+        };
+        if dispute == DisputeState::Open
+            || matches!(
+                transaction,
+                Transaction::Hold {
+                    released: false,
+                    ..
+                } | Transaction::EscrowHold {
+                    released: false,
+                    ..
+                }
+            )
+        {
+            return false;
         }
-        Ok(())
+        let old = self
+            .older_than
+            .is_some_and(|age| newest - transaction.tx() > age);
+        old || (self.resolved && finished)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Accounts per client id above which `accounts_in` walks the ids instead of sorting.
+const SCAN_RATIO: usize = 8;
+
+/// Iterator of `PaymentEngine::accounts_in`.
+enum SortedAccounts<'a> {
+    Scan {
+        accounts: &'a FxHashMap<Client, Account>,
+        clients: RangeInclusive<Client>,
+    },
+    Sorted(std::vec::IntoIter<&'a Account>),
+}
+
+impl<'a> Iterator for SortedAccounts<'a> {
+    type Item = &'a Account;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            SortedAccounts::Scan { accounts, clients } => {
+                clients.find_map(|client| accounts.get(&client))
+            }
+            SortedAccounts::Sorted(accounts) => accounts.next(),
+        }
+    }
+}
+
+pub struct PaymentEngine {
+    accounts: FxHashMap<Client, Account>,
+    transactions: FxHashMap<TransactionId, Transaction>,
+    storage_mode: StorageMode,
+    /// Ids of withdrawals not kept in `transactions` in `StorageMode::Compact`.
+    settled: RoaringBitmap,
+    /// Every balance change, see `Ledger`.
+    ledger: Ledger,
+    dispute_policy: DisputePolicy,
+    risk_scorer: Box<dyn RiskScorer>,
+    rules: Rules,
+    observers: Vec<Box<dyn EngineObserver>>,
+    applied: HashMap<TransactionKind, u64>,
+    rejected: u64,
+    /// Rejected transactions of clients on the blocklist.
+    blocked: u64,
+    /// Clients purged from the snapshots this engine was restored from.
+    tombstones: Vec<Tombstone>,
+    /// Accepted funds of the transactions dropped by `prune`, per client.
+    pruned: FxHashMap<Client, Funds>,
+    /// State replaced by the most recently applied transactions, newest last.
+    undo: VecDeque<Undo>,
+    /// Number of applied transactions `rollback` can revert.
+    undo_depth: usize,
+    /// Position right after the newest entry of `undo`, counting every entry ever remembered
+    /// and not reverted.
+    undo_end: u64,
+    /// Ids of the open savepoints with the `undo_end` they were taken at, oldest first. Entries
+    /// of `undo` newer than the oldest one are kept whatever the undo depth.
+    savepoints: Vec<(u64, u64)>,
+    next_savepoint: u64,
+    /// Whether adjustments are applied rather than rejected.
+    allow_adjustments: bool,
+    clock: Clock,
+    /// Transactions waiting for the clock by the time they are due, in the order they arrived.
+    scheduled: BTreeMap<u64, Vec<Transaction>>,
+    /// Id of the next transaction expanded from a recurrence, and the step to the one after.
+    next_generated: TransactionId,
+    generated_step: TransactionId,
+    /// Closes periods as the clock moves into the next one when set.
+    period_length: Option<PeriodLength>,
+    /// `PeriodLength::key` of the current period, once the clock was read.
+    period_key: Option<u64>,
+    /// Counters of the accounts when the current period started, missing for accounts created
+    /// since.
+    period_start: FxHashMap<Client, AccountCounters>,
+    /// Statements of the closed periods, oldest first.
+    statements: Vec<PeriodStatement>,
+    /// Reports suspicious sequences of applied transactions to the observers when set.
+    velocity: Option<VelocityDetector>,
+    /// Ids of `transactions` and `settled`, checked before them when set.
+    bloom: Option<BloomFilter>,
+    /// Seconds deposits stay held before they become available, see `set_deposit_hold`.
+    deposit_hold: Option<u64>,
+    /// Client and amount of the deposits still held, by release time and id.
+    clearing: BTreeMap<(u64, TransactionId), (Client, Amount)>,
+    /// Whether deposits offset negative available balances first, see `set_recovery`.
+    recovery: bool,
+}
+
+/// Everything an applied transaction changed, as it was before: the accounts of the client and
+/// of the transaction it refers to (`None` when created by it), the stored transaction with its
+/// id and the length of the journal.
+struct Undo {
+    kind: TransactionKind,
+    tx: TransactionId,
+    accounts: Vec<(Client, Option<Account>)>,
+    transaction: Option<Transaction>,
+    settled: bool,
+    journal: usize,
+    suspense: Amount,
+}
+
+impl From<&Account> for AccountState {
+    fn from(account: &Account) -> Self {
+        Self {
+            client: account.client,
+            available: account.available,
+            held: account.held,
+            escrowed: account.escrowed,
+            frozen: account.frozen,
+            chargebacks: account.chargebacks,
+            flagged: account.flagged,
+            disputes_open: account.disputes_open,
+            disputes_total: account.disputes_total,
+            counters: Some(account.counters),
+        }
+    }
+}
+
+pub(crate) fn saturating_add(left: Amount, right: Amount) -> Amount {
+    left.checked_add(right)
+        .unwrap_or(if right.is_sign_negative() {
+            Amount::MIN
+        } else {
+            Amount::MAX
+        })
+}
+
+impl Default for PaymentEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Serializes the `state` of the engine: accounts, transactions and counters. Like `restore`,
+/// deserializing leaves policies, rules and observers out, the engine gets the defaults of
+/// `PaymentEngine::new`.
+impl Serialize for PaymentEngine {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.state().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for PaymentEngine {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let state = EngineState::deserialize(deserializer)?;
+        let mut engine = PaymentEngine::new();
+        engine.restore(state);
+        Ok(engine)
+    }
+}
+
+impl PaymentEngine {
+    pub fn new() -> Self {
+        Self::with_dispute_policy(DisputePolicy::default())
+    }
+
+    pub fn with_dispute_policy(dispute_policy: DisputePolicy) -> Self {
+        Self {
+            accounts: FxHashMap::default(),
+            transactions: FxHashMap::default(),
+            storage_mode: StorageMode::default(),
+            settled: RoaringBitmap::new(),
+            ledger: Ledger::new(),
+            dispute_policy,
+            risk_scorer: Box::new(NoopRiskScorer),
+            rules: Rules::default(),
+            observers: vec![],
+            applied: HashMap::new(),
+            rejected: 0,
+            blocked: 0,
+            tombstones: vec![],
+            pruned: FxHashMap::default(),
+            undo: VecDeque::new(),
+            undo_depth: 0,
+            undo_end: 0,
+            savepoints: vec![],
+            next_savepoint: 0,
+            allow_adjustments: false,
+            clock: Clock::default(),
+            scheduled: BTreeMap::new(),
+            next_generated: FIRST_GENERATED_ID,
+            generated_step: 1,
+            period_length: None,
+            period_key: None,
+            period_start: FxHashMap::default(),
+            statements: vec![],
+            velocity: None,
+            bloom: None,
+            deposit_hold: None,
+            clearing: BTreeMap::new(),
+            recovery: false,
+        }
+    }
+
+    /// Engine with room for the expected number of clients and transactions, so large batch
+    /// loads don't keep rehashing.
+    pub fn with_capacity(clients: usize, transactions: usize) -> Self {
+        let mut engine = Self::new();
+        engine.reserve(clients, transactions);
+        engine
+    }
+
+    /// Reserves room for at least this many additional clients and transactions.
+    pub fn reserve(&mut self, clients: usize, transactions: usize) {
+        self.accounts.reserve(clients);
+        self.transactions.reserve(transactions);
+    }
+
+    /// Only affects transactions processed afterwards. Compact mode stops keeping journal lines.
+    pub fn set_storage_mode(&mut self, storage_mode: StorageMode) {
+        self.storage_mode = storage_mode;
+        if storage_mode == StorageMode::Compact {
+            self.ledger.keep_journal(false);
+        }
+    }
+
+    /// Keeps the journal entries of the transactions processed afterwards in `ledger()`, for
+    /// journal exports and period activity. Off by default, when only balances are posted;
+    /// ignored in compact mode.
+    pub fn keep_journal(&mut self, journal: bool) {
+        self.ledger
+            .keep_journal(journal && self.storage_mode == StorageMode::Full);
+    }
+
+    /// Checks transaction ids against a bloom filter sized for `expected` transactions before
+    /// looking them up in the stored transactions, which saves the lookup for most new ids;
+    /// `None` drops the filter. Worth it for large stores with few duplicates.
+    pub fn set_bloom_filter(&mut self, expected: Option<usize>) {
+        self.bloom = expected.map(BloomFilter::new);
+        self.fill_bloom();
+    }
+
+    /// Inserts every known id into the bloom filter, if any.
+    fn fill_bloom(&mut self) {
+        if let Some(bloom) = self.bloom.as_mut() {
+            bloom.clear();
+            for tx in self.transactions.keys().copied().chain(self.settled.iter()) {
+                bloom.insert(tx);
+            }
+        }
+    }
+
+    /// Whether `tx` may be stored, `false` meaning it certainly isn't.
+    fn may_be_known(&self, tx: TransactionId) -> bool {
+        self.bloom
+            .as_ref()
+            .is_none_or(|bloom| bloom.may_contain(tx))
+    }
+
+    /// Credits deposits to the held funds of their client, releasing them into the available
+    /// funds once the engine clock is `period` seconds past the deposit, as `advance_time`
+    /// records or the system clock move it. `None`, the default, makes deposits available
+    /// right away. Only affects deposits processed afterwards; the ones already held are
+    /// released when their time comes either way.
+    pub fn set_deposit_hold(&mut self, period: Option<u64>) {
+        self.deposit_hold = period;
+    }
+
+    /// Credits the part of a deposit covering a negative available balance to the available
+    /// funds right away, even with a deposit hold period, as a journal entry of its own tagged
+    /// as recovery (`JournalEntry::recovery`). The rest of the deposit is credited as usual.
+    pub fn set_recovery(&mut self, recovery: bool) {
+        self.recovery = recovery;
+    }
+
+    /// Accounts with negative available funds, by client, see `Account::is_negative`.
+    pub fn negative_accounts(&self) -> impl Iterator<Item = &Account> {
+        self.accounts_iter().filter(|account| account.is_negative())
+    }
+
+    /// Number of deposits still held for the deposit hold period.
+    pub fn clearing(&self) -> usize {
+        self.clearing.len()
+    }
+
+    /// Remembers what the last `depth` applied transactions changed so that they can be
+    /// reverted with `rollback`; nothing is remembered by default.
+    pub fn set_undo_depth(&mut self, depth: usize) {
+        self.undo_depth = depth;
+        self.trim_undo();
+    }
+
+    /// Drops the oldest entries of `undo` beyond the undo depth, unless an open savepoint still
+    /// needs them.
+    fn trim_undo(&mut self) {
+        let oldest_savepoint = self.savepoints.first().map(|(_, position)| *position);
+        while self.undo.len() > self.undo_depth {
+            let front = self.undo_end - self.undo.len() as u64;
+            if oldest_savepoint.is_some_and(|position| front >= position) {
+                break;
+            }
+            self.undo.pop_front();
+        }
+    }
+
+    /// Whether applied transactions are remembered for `rollback` or a savepoint.
+    fn keeps_undo(&self) -> bool {
+        self.undo_depth > 0 || !self.savepoints.is_empty()
+    }
+
+    /// Forgets everything `rollback` could revert and closes the open savepoints.
+    fn forget_undo(&mut self) {
+        self.undo.clear();
+        self.savepoints.clear();
+    }
+
+    /// Marks the current state so that `rollback_to` can return to it, for speculatively
+    /// applying transactions and abandoning them. Everything applied while a savepoint is open
+    /// is remembered regardless of the undo depth, so savepoints should be released with
+    /// `release` once they are no longer needed.
+    pub fn savepoint(&mut self) -> Savepoint {
+        let id = self.next_savepoint;
+        self.next_savepoint += 1;
+        self.savepoints.push((id, self.undo_end));
+        Savepoint { id }
+    }
+
+    /// Reverts every transaction applied since `savepoint` was taken, as `rollback` does, and
+    /// returns how many were reverted. The savepoint and those taken after it are closed.
+    pub fn rollback_to(&mut self, savepoint: Savepoint) -> Result<usize, SavepointError> {
+        let index = self.savepoint_index(&savepoint)?;
+        let (_, position) = self.savepoints[index];
+        self.savepoints.truncate(index);
+        let reverted = self.rollback((self.undo_end - position) as usize);
+        self.trim_undo();
+        Ok(reverted)
+    }
+
+    /// Keeps what was applied since `savepoint` was taken and closes it together with the
+    /// savepoints taken after it.
+    pub fn release(&mut self, savepoint: Savepoint) -> Result<(), SavepointError> {
+        let index = self.savepoint_index(&savepoint)?;
+        self.savepoints.truncate(index);
+        self.trim_undo();
+        Ok(())
+    }
+
+    fn savepoint_index(&self, savepoint: &Savepoint) -> Result<usize, SavepointError> {
+        self.savepoints
+            .iter()
+            .position(|(id, _)| *id == savepoint.id)
+            .ok_or(SavepointError::Closed(savepoint.id))
+    }
+
+    /// Reverts the `n` most recently applied transactions, newest first, restoring the balances
+    /// and dispute states, the stored transactions and the journal as they were before. Stops
+    /// early when fewer are remembered (see `set_undo_depth`) and returns how many were
+    /// reverted. Rejected transactions are not counted, and neither observers nor the risk
+    /// scorer are told. Savepoints taken after the oldest reverted transaction are closed.
+    pub fn rollback(&mut self, n: usize) -> usize {
+        let mut reverted = 0;
+        while reverted < n {
+            let undo = match self.undo.pop_back() {
+                Some(undo) => undo,
+                None => break,
+            };
+            if let Some(count) = self.applied.get_mut(&undo.kind) {
+                *count -= 1;
+                if *count == 0 {
+                    self.applied.remove(&undo.kind);
+                }
+            }
+            self.revert(undo);
+            self.undo_end -= 1;
+            reverted += 1;
+        }
+        let end = self.undo_end;
+        self.savepoints.retain(|(_, position)| *position <= end);
+        reverted
+    }
+
+    /// Puts back the accounts, stored transaction and journal `undo` remembers.
+    fn revert(&mut self, undo: Undo) {
+        for (client, account) in undo.accounts {
+            match account {
+                Some(account) => self.accounts.insert(client, account),
+                None => self.accounts.remove(&client),
+            };
+        }
+        match undo.transaction {
+            Some(transaction) => self.transactions.insert(undo.tx, transaction),
+            None => self.transactions.remove(&undo.tx),
+        };
+        if !undo.settled {
+            self.settled.remove(undo.tx);
+        }
+        if undo.kind == TransactionKind::Deposit {
+            self.clearing.retain(|(_, tx), _| *tx != undo.tx);
+        }
+        self.ledger.truncate(undo.journal, undo.suspense);
+    }
+
+    /// Applies `transactions` to a scratch engine holding copies of only the accounts and stored
+    /// transactions they involve, and reports the balances and rejections that would result.
+    /// This engine is left untouched. The risk scorer keeps state of its own, so it isn't
+    /// consulted; rules and the dispute policy are.
+    pub fn simulate<I>(&self, transactions: I) -> SimulationReport
+    where
+        I: IntoIterator<Item = Transaction>,
+    {
+        let transactions: Vec<Transaction> = transactions.into_iter().collect();
+        let mut scratch = PaymentEngine::with_dispute_policy(self.dispute_policy);
+        scratch.set_storage_mode(self.storage_mode);
+        scratch.ledger.keep_journal(false);
+        scratch.rules = self.rules.clone();
+        scratch.allow_adjustments = self.allow_adjustments;
+        scratch.clock = self.clock;
+        scratch.next_generated = self.next_generated;
+        scratch.generated_step = self.generated_step;
+        scratch.period_length = self.period_length;
+        scratch.period_key = self.period_key;
+        scratch.deposit_hold = self.deposit_hold;
+        scratch.recovery = self.recovery;
+        let mut clients = vec![];
+        for transaction in &transactions {
+            let tx = transaction.tx();
+            clients.push(transaction.client());
+            if let Some(stored) = self.transactions.get(&tx) {
+                clients.push(stored.client());
+                scratch.transactions.insert(tx, stored.clone());
+            }
+            if self.settled.contains(tx) {
+                scratch.settled.insert(tx);
+            }
+        }
+        clients.sort_unstable();
+        clients.dedup();
+        for client in &clients {
+            if let Some(account) = self.accounts.get(client) {
+                scratch.accounts.insert(*client, *account);
+            }
+        }
+        scratch.clearing = self
+            .clearing
+            .iter()
+            .filter(|(_, (client, _))| clients.binary_search(client).is_ok())
+            .map(|(key, deposit)| (*key, *deposit))
+            .collect();
+
+        let mut report = SimulationReport::default();
+        for transaction in transactions {
+            match scratch.process_transaction(transaction) {
+                Ok(()) => report.applied += 1,
+                Err(err) => report.rejections.push(err),
+            }
+        }
+        report.accounts = clients
+            .iter()
+            .filter_map(|client| scratch.accounts.get(client).copied())
+            .collect();
+        report
+    }
+
+    /// What applying a transaction of `client` referring to `tx` may change.
+    fn undo_for(&self, kind: TransactionKind, client: Client, tx: TransactionId) -> Undo {
+        let transaction = self.transactions.get(&tx).cloned();
+        let mut accounts = vec![(client, self.accounts.get(&client).copied())];
+        if let Some(owner) = transaction.as_ref().map(Transaction::client) {
+            if owner != client {
+                accounts.push((owner, self.accounts.get(&owner).copied()));
+            }
+        }
+        Undo {
+            kind,
+            tx,
+            accounts,
+            transaction,
+            settled: self.settled.contains(tx),
+            journal: self.ledger.entries().len(),
+            suspense: self.ledger.suspense(),
+        }
+    }
+
+    pub fn subscribe(&mut self, observer: Box<dyn EngineObserver>) {
+        self.observers.push(observer);
+    }
+
+    /// Applies adjustments instead of rejecting them with `AdjustmentsDisabled`. They are manual
+    /// postings, so apart from the client rules they skip the checks of deposits and
+    /// withdrawals: amount bounds, frozen accounts and available funds.
+    pub fn set_allow_adjustments(&mut self, allow: bool) {
+        self.allow_adjustments = allow;
+    }
+
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+    }
+
+    /// Current time of the engine clock, in seconds since the Unix epoch.
+    pub fn now(&self) -> u64 {
+        match self.clock {
+            Clock::System => std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs()),
+            Clock::Simulated(now) => now,
+        }
+    }
+
+    /// Moves the clock forward to `to`, simulating it from now on, and applies the scheduled
+    /// transactions that are then due. The clock never goes back.
+    pub fn advance_time(&mut self, to: u64) {
+        self.clock = Clock::Simulated(self.now().max(to));
+        self.run_due();
+    }
+
+    /// Gives the transactions expanded from recurrences the ids `first`, `first + step` and so
+    /// on, so that engines processing different clients side by side don't hand out the same
+    /// ids. `first` must not be below `FIRST_GENERATED_ID`.
+    pub fn set_generated_ids(&mut self, first: TransactionId, step: TransactionId) {
+        self.next_generated = first.max(FIRST_GENERATED_ID);
+        self.generated_step = step.max(1);
+    }
+
+    /// Schedules the occurrences of a recurrence, see `Transaction::Recurring`, and applies the
+    /// ones already due. Nothing is scheduled when the ids left don't cover them all. The
+    /// recurrence is stored under its id, so that a replay isn't expanded again.
+    fn expand_recurrence(
+        &mut self,
+        recurrence: Transaction,
+    ) -> Result<(), TransactionValidationError> {
+        if self.is_replay(&recurrence) {
+            return Ok(());
+        }
+        if let Transaction::Recurring {
+            client,
+            tx,
+            amount,
+            interval,
+            count,
+            start,
+        } = recurrence
+        {
+            self.check_unknown(client, tx)?;
+            let step = u64::from(self.generated_step);
+            let last = u64::from(self.next_generated) + (u64::from(count) - 1) * step;
+            if last > u64::from(TransactionId::MAX) {
+                return Err(TransactionValidationError::InvalidRecurrence { client, tx });
+            }
+            let start = start.unwrap_or_else(|| self.now());
+            for occurrence in 0..count {
+                let id = self.next_generated;
+                let transaction = if amount < Amount::ZERO {
+                    Transaction::new_withdrawal(client, id, -amount)?
+                } else {
+                    Transaction::new_deposit(client, id, amount)?
+                };
+                let execute_at = start.saturating_add(interval.saturating_mul(occurrence.into()));
+                self.scheduled
+                    .entry(execute_at)
+                    .or_default()
+                    .push(transaction);
+                self.next_generated = self.next_generated.saturating_add(self.generated_step);
+            }
+            self.transactions.insert(tx, recurrence);
+            if let Some(bloom) = self.bloom.as_mut() {
+                bloom.insert(tx);
+            }
+            *self.applied.entry(TransactionKind::Recurring).or_insert(0) += 1;
+            self.run_due();
+        }
+        Ok(())
+    }
+
+    /// Closes the current period whenever the clock moves into the next day, week or month, as
+    /// if a `Transaction::ClosePeriod` was processed. Scheduled transactions fall in the period
+    /// of their time.
+    pub fn set_period_length(&mut self, length: Option<PeriodLength>) {
+        self.period_length = length;
+        self.period_key = None;
+    }
+
+    /// Number of the current period, counting the periods closed so far.
+    pub fn period(&self) -> u32 {
+        self.ledger.period()
+    }
+
+    /// Ends the current period: records a `PeriodStatement` of every account, starts the
+    /// period counters of the accounts over and stamps the journal entries posted from now on
+    /// with the next period. Nothing is left to `rollback` afterwards.
+    pub fn close_period(&mut self) {
+        let period = self.ledger.period();
+        let mut accounts: Vec<&Account> = self.accounts.values().collect();
+        accounts.sort_unstable_by_key(|account| account.client);
+        for account in accounts {
+            let start = self
+                .period_start
+                .insert(account.client, account.counters)
+                .unwrap_or_default();
+            let since = |now: Amount, then: Amount| now.checked_sub(then).unwrap_or(Amount::ZERO);
+            self.statements.push(PeriodStatement {
+                period,
+                client: account.client,
+                available: account.available,
+                held: account.held,
+                escrowed: account.escrowed,
+                locked: account.frozen,
+                credits: since(account.counters.available_credits, start.available_credits),
+                debits: since(account.counters.available_debits, start.available_debits),
+                transactions: account.counters.revision.saturating_sub(start.revision),
+            });
+        }
+        self.ledger.set_period(period.saturating_add(1));
+        self.forget_undo();
+    }
+
+    /// Statements of the closed periods, oldest first and by client within a period.
+    pub fn statements(&self) -> &[PeriodStatement] {
+        &self.statements
+    }
+
+    /// Closes the current period when `time` is in a later one, see `set_period_length`.
+    fn roll_period(&mut self, time: u64) {
+        let Some(length) = self.period_length else {
+            return;
+        };
+        let key = length.key(time);
+        match self.period_key {
+            Some(current) if current >= key => {}
+            Some(_) => {
+                self.close_period();
+                self.period_key = Some(key);
+            }
+            None => self.period_key = Some(key),
+        }
+    }
+
+    /// Number of scheduled transactions not due yet.
+    pub fn scheduled(&self) -> usize {
+        self.scheduled.values().map(Vec::len).sum()
+    }
+
+    /// Applies the scheduled transactions that are due, oldest first, and closes the periods
+    /// the clock moved past. Their rejections are counted and reported here, there's no caller
+    /// to hand them to.
+    fn run_due(&mut self) {
+        if self.scheduled.is_empty() && self.clearing.is_empty() && self.period_length.is_none() {
+            return;
+        }
+        let now = self.now();
+        while let Some(due) = self.scheduled.first_entry() {
+            if *due.key() > now {
+                break;
+            }
+            let execute_at = *due.key();
+            let due = due.remove();
+            self.release_cleared(execute_at);
+            self.roll_period(execute_at);
+            self.ledger.set_time(execute_at);
+            for transaction in due {
+                let memo = transaction.memo().map(str::to_string);
+                if let Err(err) = self.process_now(transaction) {
+                    report(ErrorEvent::rejected(&err).with_memo(memo.as_deref()));
+                }
+            }
+        }
+        self.release_cleared(now);
+        self.roll_period(now);
+    }
+
+    /// Moves the held deposits due by `time` into the available funds, oldest first, in the
+    /// period of their release time. Releases can't be rolled back, so nothing is left to
+    /// `rollback` after one.
+    fn release_cleared(&mut self, time: u64) {
+        let mut released = false;
+        while let Some(deposit) = self.clearing.first_entry() {
+            let (release_at, tx) = *deposit.key();
+            if release_at > time {
+                break;
+            }
+            let (client, amount) = deposit.remove();
+            self.roll_period(release_at);
+            self.ledger.set_time(release_at);
+            if let Some(account) = self.accounts.get_mut(&client) {
+                let entry = JournalEntry::new(
+                    tx,
+                    TransactionKind::Release,
+                    LedgerAccount::Held(client),
+                    LedgerAccount::Available(client),
+                    amount,
+                );
+                if let Err(err) = self.ledger.post(account, entry) {
+                    report(ErrorEvent::rejected(&err));
+                }
+                released = true;
+            }
+        }
+        if released {
+            self.forget_undo();
+        }
+    }
+
+    pub fn set_rules(&mut self, rules: Rules) {
+        self.rules = rules;
+    }
+
+    /// Attributes of `client` known to the rules, see `Rules::clients`.
+    pub fn client_profile(&self, client: Client) -> Option<&ClientProfile> {
+        self.rules.clients.get(client)
+    }
+
+    /// Replaces the scorer consulted before each transaction (a no-op by default).
+    pub fn set_risk_scorer(&mut self, risk_scorer: Box<dyn RiskScorer>) {
+        self.risk_scorer = risk_scorer;
+    }
+
+    /// Looks for the patterns of `rules` in the applied transactions, reporting them to
+    /// `EngineObserver::on_suspicious_activity`. Detection starts over with every call.
+    pub fn set_velocity_rules(&mut self, rules: VelocityRules) {
+        self.velocity = rules.enabled().then(|| VelocityDetector::new(rules));
+    }
+
+    pub fn get_accounts(&self) -> Vec<Account> {
+        self.accounts_iter().copied().collect()
+    }
+
+    /// Same order as `get_accounts`, but borrows the accounts instead of cloning them.
+    pub fn accounts_iter(&self) -> impl Iterator<Item = &Account> {
+        self.accounts_in(0..=Client::MAX)
+    }
+
+    /// Accounts of the clients in `clients`, sorted by client. When the engine holds many of
+    /// them, they are found by walking the client ids in order rather than by sorting, so the
+    /// accounts stream out without being collected first.
+    pub fn accounts_in(&self, clients: RangeInclusive<Client>) -> impl Iterator<Item = &Account> {
+        let ids = usize::from(*clients.end()).saturating_sub(usize::from(*clients.start())) + 1;
+        if self.accounts.len().saturating_mul(SCAN_RATIO) >= ids {
+            SortedAccounts::Scan {
+                accounts: &self.accounts,
+                clients,
+            }
+        } else {
+            let mut accounts: Vec<&Account> = self
+                .accounts
+                .values()
+                .filter(|account| clients.contains(&account.client))
+                .collect();
+            accounts.sort_unstable_by_key(|account| account.client);
+            SortedAccounts::Sorted(accounts.into_iter())
+        }
+    }
+
+    pub fn get_account(&self, client: Client) -> Option<&Account> {
+        self.accounts.get(&client)
+    }
+
+    /// Iterates over all accounts in no particular order.
+    pub fn iter_accounts(&self) -> impl Iterator<Item = &Account> {
+        self.accounts.values()
+    }
+
+    /// Looks up a stored deposit, withdrawal or hold. Withdrawals processed in
+    /// `StorageMode::Compact` are not stored.
+    pub fn get_transaction(&self, tx: TransactionId) -> Option<&Transaction> {
+        self.transactions.get(&tx)
+    }
+
+    /// Funds in each escrow of `client` that holds any, by name; they add up to
+    /// `Account::escrowed`.
+    pub fn escrows(&self, client: Client) -> BTreeMap<&str, Amount> {
+        let mut escrows = BTreeMap::new();
+        for transaction in self.transactions.values() {
+            if let Transaction::EscrowHold {
+                client: owner,
+                amount,
+                escrow,
+                released: false,
+                ..
+            } = transaction
+            {
+                if *owner == client {
+                    let funds = escrows.entry(&**escrow).or_insert(Amount::ZERO);
+                    *funds = saturating_add(*funds, *amount);
+                }
+            }
+        }
+        escrows
+    }
+
+    /// Stored deposits, withdrawals and holds ordered by transaction id.
+    pub fn transactions_iter(&self) -> impl Iterator<Item = &Transaction> {
+        let mut transactions: Vec<&Transaction> = self.transactions.values().collect();
+        transactions.sort_unstable_by_key(|transaction| transaction.tx());
+        transactions.into_iter()
+    }
+
+    /// Journal entries behind the account balances and the platform suspense balance.
+    pub fn ledger(&self) -> &Ledger {
+        &self.ledger
+    }
+
+    /// Clients purged from the snapshots the engine was restored from, see `Tombstone`.
+    pub fn tombstones(&self) -> &[Tombstone] {
+        &self.tombstones
+    }
+
+    /// Accepted funds of the transactions dropped by `prune`, per client.
+    pub fn pruned_funds(&self) -> impl Iterator<Item = (Client, &Funds)> + '_ {
+        self.pruned.iter().map(|(client, funds)| (*client, funds))
+    }
+
+    /// Drops the stored transactions `retention` selects, keeping their ids so that they are
+    /// still rejected as duplicates, and returns how many were dropped. Dropped transactions
+    /// can no longer be disputed, reversed or exported. Nothing is left to `rollback`
+    /// afterwards.
+    pub fn prune(&mut self, retention: Retention) -> u64 {
+        let newest = self
+            .transactions
+            .keys()
+            .copied()
+            .chain(self.settled.max())
+            .max()
+            .unwrap_or(0);
+        let mut dropped = 0;
+        let pruned = &mut self.pruned;
+        let settled = &mut self.settled;
+        self.transactions.retain(|tx, transaction| {
+            if !retention.drops(transaction, newest) {
+                return true;
+            }
+            pruned
+                .entry(transaction.client())
+                .or_default()
+                .record(transaction);
+            settled.insert(*tx);
+            dropped += 1;
+            false
+        });
+        self.forget_undo();
+        dropped
+    }
+
+    /// Lifts the freeze of the account of `client`, once an operator has settled what froze it.
+    /// Returns whether the account was frozen, `None` when the client has no account. Nothing is
+    /// left to `rollback` after an account was unfrozen.
+    pub fn unfreeze(&mut self, client: Client) -> Option<bool> {
+        if !self.accounts.get(&client)?.frozen {
+            return Some(false);
+        }
+        self.forget_undo();
+        let account = self.accounts.get_mut(&client)?;
+        account.frozen = false;
+        account.counters.revision += 1;
+        for observer in self.observers.iter_mut() {
+            observer.on_account_unfrozen(account);
+        }
+        Some(true)
+    }
+
+    /// Copies everything needed to rebuild the engine with `restore`.
+    pub fn state(&self) -> EngineState {
+        let mut accounts: Vec<AccountState> =
+            self.accounts.values().map(AccountState::from).collect();
+        accounts.sort_by_key(|account| account.client);
+        let mut transactions: Vec<Transaction> = self.transactions.values().cloned().collect();
+        transactions.sort_by_key(|transaction| transaction.tx());
+
+        EngineState {
+            accounts,
+            transactions,
+            settled: self.settled.iter().collect(),
+            journal: self.ledger.entries().to_vec(),
+            applied: {
+                let mut applied: Vec<(TransactionKind, u64)> = self
+                    .applied
+                    .iter()
+                    .map(|(kind, count)| (*kind, *count))
+                    .collect();
+                applied.sort();
+                applied
+            },
+            rejected: self.rejected,
+            blocked: self.blocked,
+            tombstones: self.tombstones.clone(),
+            pruned: {
+                let mut pruned: Vec<(Client, Funds)> = self
+                    .pruned
+                    .iter()
+                    .map(|(client, funds)| (*client, *funds))
+                    .collect();
+                pruned.sort_by_key(|(client, _)| *client);
+                pruned
+            },
+            clock: match self.clock {
+                Clock::System => None,
+                Clock::Simulated(now) => Some(now),
+            },
+            scheduled: self
+                .scheduled
+                .iter()
+                .flat_map(|(execute_at, due)| {
+                    due.iter()
+                        .map(|transaction| (*execute_at, transaction.clone()))
+                })
+                .collect(),
+            next_generated: (self.next_generated != FIRST_GENERATED_ID)
+                .then_some(self.next_generated),
+            period: self.ledger.period(),
+            period_key: self.period_key,
+            period_start: {
+                let mut period_start: Vec<(Client, AccountCounters)> = self
+                    .period_start
+                    .iter()
+                    .map(|(client, counters)| (*client, *counters))
+                    .collect();
+                period_start.sort_by_key(|(client, _)| *client);
+                period_start
+            },
+            statements: self.statements.clone(),
+            clearing: self
+                .clearing
+                .iter()
+                .map(|((release_at, tx), (client, amount))| ClearingDeposit {
+                    client: *client,
+                    tx: *tx,
+                    amount: *amount,
+                    release_at: *release_at,
+                })
+                .collect(),
+        }
+    }
+
+    /// Replaces accounts, transactions and counters with a previously taken `state`. Nothing is
+    /// left to `rollback` afterwards.
+    pub fn restore(&mut self, state: EngineState) {
+        self.accounts = state
+            .accounts
+            .into_iter()
+            .map(|account| {
+                (
+                    account.client,
+                    Account {
+                        client: account.client,
+                        available: account.available,
+                        held: account.held,
+                        escrowed: account.escrowed,
+                        frozen: account.frozen,
+                        chargebacks: account.chargebacks,
+                        flagged: account.flagged,
+                        disputes_open: account.disputes_open,
+                        disputes_total: account.disputes_total,
+                        counters: account.counters.unwrap_or_else(|| {
+                            AccountCounters::from_balances(
+                                account.available,
+                                account.held,
+                                account.escrowed,
+                            )
+                        }),
+                    },
+                )
+            })
+            .collect();
+        self.transactions = state
+            .transactions
+            .into_iter()
+            .map(|transaction| (transaction.tx(), transaction))
+            .collect();
+        self.settled = state.settled.into_iter().collect();
+        let suspense = self.accounts.values().fold(Amount::ZERO, |sum, account| {
+            saturating_add(sum, account.total())
+        });
+        self.ledger.restore(state.journal, suspense);
+        self.applied = state.applied.into_iter().collect();
+        self.rejected = state.rejected;
+        self.blocked = state.blocked;
+        self.tombstones = state.tombstones;
+        self.pruned = state.pruned.into_iter().collect();
+        if let Some(now) = state.clock {
+            self.clock = Clock::Simulated(now);
+        }
+        self.scheduled = BTreeMap::new();
+        for (execute_at, transaction) in state.scheduled {
+            self.scheduled
+                .entry(execute_at)
+                .or_default()
+                .push(transaction);
+        }
+        self.ledger.set_period(state.period);
+        self.period_key = state.period_key;
+        self.period_start = state.period_start.into_iter().collect();
+        self.statements = state.statements;
+        self.clearing = state
+            .clearing
+            .into_iter()
+            .map(|deposit| {
+                (
+                    (deposit.release_at, deposit.tx),
+                    (deposit.client, deposit.amount),
+                )
+            })
+            .collect();
+        if let Some(next) = state.next_generated {
+            // keep to the ids set by `set_generated_ids`
+            let behind = next.saturating_sub(self.next_generated);
+            let steps = behind.div_ceil(self.generated_step);
+            self.next_generated = self
+                .next_generated
+                .saturating_add(steps.saturating_mul(self.generated_step));
+        }
+        self.fill_bloom();
+        self.forget_undo();
+    }
+
+    /// Moves the accounts, transactions and counters of `other` into this engine. Meant for
+    /// engines that processed disjoint sets of clients, so a client or transaction id known to
+    /// both is an error, in which case neither engine is changed. Nothing is left to `rollback`
+    /// after a merge.
+    pub fn merge(&mut self, other: PaymentEngine) -> Result<(), MergeError> {
+        if let Some(client) = other
+            .accounts
+            .keys()
+            .filter(|client| self.accounts.contains_key(client))
+            .min()
+        {
+            return Err(MergeError::ConflictingClient(*client));
+        }
+        if let Some(tx) = other
+            .transactions
+            .keys()
+            .copied()
+            .chain(other.settled.iter())
+            .filter(|tx| self.is_known(*tx))
+            .min()
+        {
+            return Err(MergeError::ConflictingTransaction(tx));
+        }
+
+        self.accounts.extend(other.accounts);
+        self.transactions.extend(other.transactions);
+        self.settled |= other.settled;
+        self.fill_bloom();
+        self.ledger.merge(other.ledger);
+        for (kind, count) in other.applied {
+            *self.applied.entry(kind).or_insert(0) += count;
+        }
+        self.rejected += other.rejected;
+        self.blocked += other.blocked;
+        self.tombstones.extend(other.tombstones);
+        self.pruned.extend(other.pruned);
+        if let Clock::Simulated(other_now) = other.clock {
+            self.clock = Clock::Simulated(match self.clock {
+                Clock::Simulated(now) => now.max(other_now),
+                Clock::System => other_now,
+            });
+        }
+        for (execute_at, due) in other.scheduled {
+            self.scheduled.entry(execute_at).or_default().extend(due);
+        }
+        self.next_generated = self.next_generated.max(other.next_generated);
+        self.period_key = self.period_key.max(other.period_key);
+        self.period_start.extend(other.period_start);
+        self.statements.extend(other.statements);
+        self.statements
+            .sort_by_key(|statement| (statement.period, statement.client));
+        self.clearing.extend(other.clearing);
+        self.forget_undo();
+        Ok(())
+    }
+
+    pub fn metrics(&self) -> EngineMetrics {
+        let mut metrics = EngineMetrics {
+            applied: self.applied.clone(),
+            rejected: self.rejected,
+            blocked: self.blocked,
+            scheduled: self.scheduled() as u64,
+            ..EngineMetrics::default()
+        };
+        for account in self.accounts.values() {
+            metrics.total_available = saturating_add(metrics.total_available, account.available);
+            metrics.total_held = saturating_add(metrics.total_held, account.held);
+            metrics.total_escrowed = saturating_add(metrics.total_escrowed, account.escrowed);
+            metrics.frozen_accounts += account.frozen as u64;
+            metrics.flagged_accounts += account.flagged as u64;
+        }
+        metrics.open_disputes = self
+            .transactions
+            .values()
+            .filter(|transaction| {
+                matches!(
+                    transaction,
+                    Transaction::Deposit {
+                        dispute: DisputeState::Open,
+                        ..
+                    } | Transaction::Withdrawal {
+                        dispute: DisputeState::Open,
+                        ..
+                    }
+                )
+            })
+            .count() as u64;
+        metrics
+    }
+
+    fn is_known(&self, tx: TransactionId) -> bool {
+        self.may_be_known(tx) && (self.transactions.contains_key(&tx) || self.settled.contains(tx))
+    }
+
+    /// Rejects a new transaction reusing a known id. Identical replays of stored transactions
+    /// never get here, see `is_replay`, and settled ids are not stored to compare with.
+    fn check_unknown(
+        &self,
+        client: Client,
+        tx: TransactionId,
+    ) -> Result<(), TransactionValidationError> {
+        if !self.may_be_known(tx) {
+            return Ok(());
+        }
+        if self.transactions.contains_key(&tx) {
+            return Err(TransactionValidationError::ConflictingDuplicate { client, tx });
+        }
+        if self.settled.contains(tx) {
+            return Err(TransactionValidationError::Duplicate { client, tx });
+        }
+        Ok(())
+    }
+
+    /// Whether `transaction` repeats the stored transaction with its id: same type, client and
+    /// amount, and for a recurrence the same schedule. A hold also repeats the withdrawal it was
+    /// captured into.
+    fn is_replay(&self, transaction: &Transaction) -> bool {
+        let amount = match transaction.amount() {
+            Some(amount) => amount,
+            None => return false,
+        };
+        if !self.may_be_known(transaction.tx()) {
+            return false;
+        }
+        let stored = match self.transactions.get(&transaction.tx()) {
+            Some(stored) => stored,
+            None => return false,
+        };
+        let kind = match (transaction.kind(), stored.kind()) {
+            (TransactionKind::Hold, TransactionKind::Withdrawal) => true,
+            (kind, stored) => kind == stored,
+        };
+        let schedule = |transaction: &Transaction| match transaction {
+            Transaction::Recurring {
+                interval,
+                count,
+                start,
+                ..
+            } => Some((*interval, *count, *start)),
+            _ => None,
+        };
+        kind && stored.client() == transaction.client()
+            && stored.amount() == Some(amount)
+            && schedule(stored) == schedule(transaction)
+    }
+
+    fn store_withdrawal(&mut self, withdrawal: Transaction) {
+        let tx = withdrawal.tx();
+        match self.storage_mode {
+            StorageMode::Full => {
+                self.transactions.insert(tx, withdrawal);
+            }
+            StorageMode::Compact => {
+                // a captured hold is replaced as well
+                self.transactions.remove(&tx);
+                self.settled.insert(tx);
+            }
+        }
+    }
+
+    fn process_deposit(
+        &mut self,
+        mut deposit: Transaction,
+    ) -> Result<(), TransactionValidationError> {
+        if let Transaction::Deposit {
+            tx, client, amount, ..
+        } = deposit
+        {
+            self.check_unknown(client, tx)?;
+
+            let account = self
+                .accounts
+                .entry(client)
+                .or_insert_with(|| Account::new(client));
+
+            let recovered = if self.recovery && account.is_negative() {
+                amount.min(-account.available)
+            } else {
+                Amount::ZERO
+            };
+            let rest = amount - recovered;
+            let credit = match self.deposit_hold {
+                Some(_) => LedgerAccount::Held(client),
+                None => LedgerAccount::Available(client),
+            };
+            // the rest first: crediting the recovered part can't overflow, so nothing is left
+            // half posted
+            if rest > Amount::ZERO {
+                self.ledger.post(
+                    account,
+                    JournalEntry::new(
+                        tx,
+                        TransactionKind::Deposit,
+                        LedgerAccount::Suspense,
+                        credit,
+                        rest,
+                    ),
+                )?;
+            }
+            if recovered > Amount::ZERO {
+                self.ledger.post(
+                    account,
+                    JournalEntry::new(
+                        tx,
+                        TransactionKind::Deposit,
+                        LedgerAccount::Suspense,
+                        LedgerAccount::Available(client),
+                        recovered,
+                    )
+                    .as_recovery(),
+                )?;
+            }
+            if let (Some(period), true) = (self.deposit_hold, rest > Amount::ZERO) {
+                let release_at = self.ledger.time().saturating_add(period);
+                self.clearing.insert((release_at, tx), (client, rest));
+            }
+            if let Transaction::Deposit { posted, .. } = &mut deposit {
+                *posted = Some(self.ledger.time());
+            }
+            self.transactions.insert(tx, deposit);
+        }
+        Ok(())
+    }
+
+    fn process_adjustment(
+        &mut self,
+        adjustment: Transaction,
+    ) -> Result<(), TransactionValidationError> {
+        if let Transaction::Adjustment {
+            tx, client, amount, ..
+        } = adjustment
+        {
+            if !self.allow_adjustments {
+                return Err(TransactionValidationError::AdjustmentsDisabled { client, tx });
+            }
+            self.check_unknown(client, tx)?;
+
+            let account = self
+                .accounts
+                .entry(client)
+                .or_insert_with(|| Account::new(client));
+            let (debit, credit, amount) = if amount < Amount::ZERO {
+                (
+                    LedgerAccount::Available(client),
+                    LedgerAccount::Suspense,
+                    -amount,
+                )
+            } else {
+                (
+                    LedgerAccount::Suspense,
+                    LedgerAccount::Available(client),
+                    amount,
+                )
+            };
+            self.ledger.post(
+                account,
+                JournalEntry::new(tx, TransactionKind::Adjustment, debit, credit, amount),
+            )?;
+            self.transactions.insert(tx, adjustment);
+        }
+        Ok(())
+    }
+
+    fn process_withdrawal(
+        &mut self,
+        withdrawal: Transaction,
+    ) -> Result<(), TransactionValidationError> {
+        if let Transaction::Withdrawal {
+            tx, client, amount, ..
+        } = withdrawal
+        {
+            self.check_unknown(client, tx)?;
+            let account = match self.accounts.get_mut(&client) {
+                Some(account) => account,
+                None => {
+                    return Err(TransactionValidationError::MissingAccount { client, tx });
+                }
+            };
+            if account.frozen && !self.rules.is_frozen_exception(client) {
+                return Err(TransactionValidationError::FrozenAccount { client, tx });
+            }
+            if account.available < amount {
+                return Err(TransactionValidationError::InsufficientFunds {
+                    client,
+                    tx,
+                    requested: amount,
+                    available: account.available,
+                });
+            }
+            check_reserve(account, &self.rules, tx, amount)?;
+            self.ledger.post(
+                account,
+                JournalEntry::new(
+                    tx,
+                    TransactionKind::Withdrawal,
+                    LedgerAccount::Available(client),
+                    LedgerAccount::Suspense,
+                    amount,
+                ),
+            )?;
+            self.store_withdrawal(withdrawal);
+        }
+
+        Ok(())
+    }
+
+    fn process_dispute(
+        &mut self,
+        tx: TransactionId,
+        dispute_client: Client,
+    ) -> Result<(), TransactionValidationError> {
+        let next = match self.transactions.get(&tx) {
+            Some(Transaction::Deposit {
+                client,
+                tx,
+                dispute,
+                reversed,
+                ..
+            })
+            | Some(Transaction::Withdrawal {
+                client,
+                tx,
+                dispute,
+                reversed,
+                ..
+            }) => {
+                if *client != dispute_client {
+                    return Err(TransactionValidationError::InvalidTransaction {
+                        client: dispute_client,
+                        tx: *tx,
+                    });
+                };
+
+                if *reversed {
+                    return Err(TransactionValidationError::Reversed {
+                        client: dispute_client,
+                        tx: *tx,
+                    });
+                }
+                let next = dispute.transition(DisputeState::Open, dispute_client, *tx)?;
+                if !self.accounts.contains_key(client) {
+                    return Err(TransactionValidationError::MissingAccount {
+                        client: *client,
+                        tx: *tx,
+                    });
+                };
+                next
+            }
+            _ => {
+                return Err(TransactionValidationError::InvalidTransaction {
+                    client: dispute_client,
+                    tx,
+                })
+            }
+        };
+
+        if let Some(Transaction::Deposit {
+            client,
+            dispute,
+            amount,
+            ..
+        }) = self.transactions.get_mut(&tx)
+        {
+            if let Some(account) = self.accounts.get_mut(client) {
+                self.ledger.post(
+                    account,
+                    JournalEntry::new(
+                        tx,
+                        TransactionKind::Dispute,
+                        LedgerAccount::Available(*client),
+                        LedgerAccount::Held(*client),
+                        *amount,
+                    ),
+                )?;
+                *dispute = next;
+                account.disputes_open += 1;
+                account.disputes_total += 1;
+            }
+        }
+        if let Some(Transaction::Withdrawal {
+            client,
+            dispute,
+            amount,
+            ..
+        }) = self.transactions.get_mut(&tx)
+        {
+            if let Some(account) = self.accounts.get_mut(client) {
+                self.ledger.post(
+                    account,
+                    JournalEntry::new(
+                        tx,
+                        TransactionKind::Dispute,
+                        LedgerAccount::Held(*client),
+                        LedgerAccount::Available(*client),
+                        *amount,
+                    ),
+                )?;
+                *dispute = next;
+                account.disputes_open += 1;
+                account.disputes_total += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn process_resolve(
+        &mut self,
+        tx: TransactionId,
+        resolve_client: Client,
+    ) -> Result<(), TransactionValidationError> {
+        let next = match self.transactions.get(&tx) {
+            Some(Transaction::Deposit {
+                client,
+                tx,
+                dispute,
+                ..
+            })
+            | Some(Transaction::Withdrawal {
+                client,
+                tx,
+                dispute,
+                ..
+            }) => {
+                if *client != resolve_client {
+                    return Err(TransactionValidationError::InvalidTransaction {
+                        client: resolve_client,
+                        tx: *tx,
+                    });
+                };
+                dispute.transition(DisputeState::Resolved, resolve_client, *tx)?
+            }
+            _ => {
+                return Err(TransactionValidationError::InvalidTransaction {
+                    client: resolve_client,
+                    tx,
+                })
+            }
+        };
+
+        if let Some(Transaction::Deposit {
+            client,
+            amount,
+            dispute,
+            ..
+        }) = self.transactions.get_mut(&tx)
+        {
+            if let Some(account) = self.accounts.get_mut(client) {
+                self.ledger.post(
+                    account,
+                    JournalEntry::new(
+                        tx,
+                        TransactionKind::Resolve,
+                        LedgerAccount::Held(*client),
+                        LedgerAccount::Available(*client),
+                        *amount,
+                    ),
+                )?;
+                *dispute = next;
+                account.disputes_open = account.disputes_open.saturating_sub(1);
+            } else {
+                return Err(TransactionValidationError::MissingAccount {
+                    client: *client,
+                    tx,
+                });
+            }
+        }
+
+        if let Some(Transaction::Withdrawal {
+            client,
+            amount,
+            dispute,
+            ..
+        }) = self.transactions.get_mut(&tx)
+        {
+            if let Some(account) = self.accounts.get_mut(client) {
+                self.ledger.post(
+                    account,
+                    JournalEntry::new(
+                        tx,
+                        TransactionKind::Resolve,
+                        LedgerAccount::Available(*client),
+                        LedgerAccount::Held(*client),
+                        *amount,
+                    ),
+                )?;
+                *dispute = next;
+                account.disputes_open = account.disputes_open.saturating_sub(1);
+            } else {
+                return Err(TransactionValidationError::MissingAccount {
+                    client: *client,
+                    tx,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn process_chargeback(
+        &mut self,
+        tx: TransactionId,
+        chargeback_client: Client,
+    ) -> Result<(), TransactionValidationError> {
+        let next = match self.transactions.get(&tx) {
+            Some(Transaction::Deposit {
+                client,
+                tx,
+                dispute,
+                ..
+            })
+            | Some(Transaction::Withdrawal {
+                client,
+                tx,
+                dispute,
+                ..
+            }) => {
+                if *client != chargeback_client {
+                    return Err(TransactionValidationError::InvalidTransaction {
+                        client: chargeback_client,
+                        tx: *tx,
+                    });
+                };
+                dispute.transition(DisputeState::ChargedBack, chargeback_client, *tx)?
+            }
+            _ => {
+                return Err(TransactionValidationError::InvalidTransaction {
+                    client: chargeback_client,
+                    tx,
+                })
+            }
+        };
+
+        if let Some(Transaction::Deposit {
+            client,
+            amount,
+            dispute,
+            ..
+        }) = self.transactions.get_mut(&tx)
+        {
+            if let Some(account) = self.accounts.get_mut(client) {
+                self.ledger.post(
+                    account,
+                    JournalEntry::new(
+                        tx,
+                        TransactionKind::Chargeback,
+                        LedgerAccount::Held(*client),
+                        LedgerAccount::Suspense,
+                        *amount,
+                    ),
+                )?;
+                account.register_chargeback(&self.dispute_policy);
+                *dispute = next;
+                account.disputes_open = account.disputes_open.saturating_sub(1);
+            } else {
+                return Err(TransactionValidationError::MissingAccount {
+                    client: *client,
+                    tx,
+                });
+            }
+        }
+
+        if let Some(Transaction::Withdrawal {
+            client,
+            amount,
+            dispute,
+            ..
+        }) = self.transactions.get_mut(&tx)
+        {
+            if let Some(account) = self.accounts.get_mut(client) {
+                self.ledger.post(
+                    account,
+                    JournalEntry::new(
+                        tx,
+                        TransactionKind::Chargeback,
+                        LedgerAccount::Held(*client),
+                        LedgerAccount::Suspense,
+                        *amount,
+                    ),
+                )?;
+                account.register_chargeback(&self.dispute_policy);
+                *dispute = next;
+                account.disputes_open = account.disputes_open.saturating_sub(1);
+            } else {
+                return Err(TransactionValidationError::MissingAccount {
+                    client: *client,
+                    tx,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn process_reversal(
+        &mut self,
+        tx: TransactionId,
+        reversal_client: Client,
+    ) -> Result<(), TransactionValidationError> {
+        match self.transactions.get(&tx) {
+            Some(Transaction::Deposit {
+                client,
+                tx,
+                dispute,
+                reversed,
+                ..
+            })
+            | Some(Transaction::Withdrawal {
+                client,
+                tx,
+                dispute,
+                reversed,
+                ..
+            }) => {
+                if *client != reversal_client {
+                    return Err(TransactionValidationError::InvalidTransaction {
+                        client: reversal_client,
+                        tx: *tx,
+                    });
+                }
+                if *reversed {
+                    return Err(TransactionValidationError::Reversed {
+                        client: reversal_client,
+                        tx: *tx,
+                    });
+                }
+                if matches!(dispute, DisputeState::Open | DisputeState::ChargedBack) {
+                    return Err(TransactionValidationError::InvalidTransaction {
+                        client: reversal_client,
+                        tx: *tx,
+                    });
+                }
+                if !self.accounts.contains_key(client) {
+                    return Err(TransactionValidationError::MissingAccount {
+                        client: *client,
+                        tx: *tx,
+                    });
+                }
+            }
+            _ => {
+                return Err(TransactionValidationError::InvalidTransaction {
+                    client: reversal_client,
+                    tx,
+                })
+            }
+        };
+
+        if let Some(Transaction::Deposit {
+            client,
+            amount,
+            reversed,
+            ..
+        }) = self.transactions.get_mut(&tx)
+        {
+            if let Some(account) = self.accounts.get_mut(client) {
+                self.ledger.post(
+                    account,
+                    JournalEntry::new(
+                        tx,
+                        TransactionKind::Reversal,
+                        LedgerAccount::Available(*client),
+                        LedgerAccount::Suspense,
+                        *amount,
+                    ),
+                )?;
+                *reversed = true;
+            }
+        }
+
+        if let Some(Transaction::Withdrawal {
+            client,
+            amount,
+            reversed,
+            ..
+        }) = self.transactions.get_mut(&tx)
+        {
+            if let Some(account) = self.accounts.get_mut(client) {
+                self.ledger.post(
+                    account,
+                    JournalEntry::new(
+                        tx,
+                        TransactionKind::Reversal,
+                        LedgerAccount::Suspense,
+                        LedgerAccount::Available(*client),
+                        *amount,
+                    ),
+                )?;
+                *reversed = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// The merchant won representment, so the charged back deposit is credited again.
+    fn process_chargeback_reversal(
+        &mut self,
+        tx: TransactionId,
+        reversal_client: Client,
+    ) -> Result<(), TransactionValidationError> {
+        match self.transactions.get_mut(&tx) {
+            Some(Transaction::Deposit {
+                client,
+                tx,
+                amount,
+                dispute,
+                ..
+            }) => {
+                if *client != reversal_client {
+                    return Err(TransactionValidationError::InvalidTransaction {
+                        client: reversal_client,
+                        tx: *tx,
+                    });
+                }
+                let next =
+                    dispute.transition(DisputeState::ChargebackReversed, reversal_client, *tx)?;
+                let account = match self.accounts.get_mut(client) {
+                    Some(account) => account,
+                    None => {
+                        return Err(TransactionValidationError::MissingAccount {
+                            client: *client,
+                            tx: *tx,
+                        })
+                    }
+                };
+                self.ledger.post(
+                    account,
+                    JournalEntry::new(
+                        *tx,
+                        TransactionKind::ChargebackReversal,
+                        LedgerAccount::Suspense,
+                        LedgerAccount::Available(*client),
+                        *amount,
+                    ),
+                )?;
+                if self.dispute_policy.unfreeze_on_chargeback_reversal {
+                    account.frozen = false;
+                }
+                *dispute = next;
+                Ok(())
+            }
+            _ => Err(TransactionValidationError::InvalidTransaction {
+                client: reversal_client,
+                tx,
+            }),
+        }
+    }
+
+    fn process_hold(&mut self, hold: Transaction) -> Result<(), TransactionValidationError> {
+        if let Transaction::Hold {
+            tx, client, amount, ..
+        } = hold
+        {
+            self.check_unknown(client, tx)?;
+            let account = match self.accounts.get_mut(&client) {
+                Some(account) => account,
+                None => {
+                    return Err(TransactionValidationError::MissingAccount { client, tx });
+                }
+            };
+            if account.frozen && !self.rules.is_frozen_exception(client) {
+                return Err(TransactionValidationError::FrozenAccount { client, tx });
+            }
+            if account.available < amount {
+                return Err(TransactionValidationError::InsufficientFunds {
+                    client,
+                    tx,
+                    requested: amount,
+                    available: account.available,
+                });
+            }
+            check_reserve(account, &self.rules, tx, amount)?;
+            self.ledger.post(
+                account,
+                JournalEntry::new(
+                    tx,
+                    TransactionKind::Hold,
+                    LedgerAccount::Available(client),
+                    LedgerAccount::Held(client),
+                    amount,
+                ),
+            )?;
+            self.transactions.insert(tx, hold);
+        }
+
+        Ok(())
+    }
+
+    fn pending_hold(
+        &self,
+        tx: TransactionId,
+        hold_client: Client,
+    ) -> Result<(Client, Amount), TransactionValidationError> {
+        match self.transactions.get(&tx) {
+            Some(Transaction::Hold {
+                client,
+                amount,
+                released,
+                ..
+            }) => {
+                if *client != hold_client || *released {
+                    return Err(TransactionValidationError::InvalidTransaction {
+                        client: hold_client,
+                        tx,
+                    });
+                }
+                if !self.accounts.contains_key(client) {
+                    return Err(TransactionValidationError::MissingAccount {
+                        client: *client,
+                        tx,
+                    });
+                }
+                Ok((*client, *amount))
+            }
+            _ => Err(TransactionValidationError::InvalidTransaction {
+                client: hold_client,
+                tx,
+            }),
+        }
+    }
+
+    /// Settles the held funds; from now on the hold is stored as a regular withdrawal.
+    fn process_capture(
+        &mut self,
+        tx: TransactionId,
+        capture_client: Client,
+    ) -> Result<(), TransactionValidationError> {
+        let (client, amount) = self.pending_hold(tx, capture_client)?;
+        if let Some(account) = self.accounts.get_mut(&client) {
+            if account.frozen && !self.rules.is_frozen_exception(client) {
+                return Err(TransactionValidationError::FrozenAccount { client, tx });
+            }
+            self.ledger.post(
+                account,
+                JournalEntry::new(
+                    tx,
+                    TransactionKind::Capture,
+                    LedgerAccount::Held(client),
+                    LedgerAccount::Suspense,
+                    amount,
+                ),
+            )?;
+        }
+        // the captured withdrawal keeps the memo and counterparty of the hold it replaces
+        let (memo, counterparty) = match self.transactions.get_mut(&tx) {
+            Some(Transaction::Hold {
+                memo, counterparty, ..
+            }) => (memo.take(), counterparty.take()),
+            _ => (None, None),
+        };
+        self.store_withdrawal(Transaction::Withdrawal {
+            client,
+            tx,
+            amount,
+            dispute: DisputeState::None,
+            reversed: false,
+            memo,
+            counterparty,
+        });
+        Ok(())
+    }
+
+    fn process_release(
+        &mut self,
+        tx: TransactionId,
+        release_client: Client,
+    ) -> Result<(), TransactionValidationError> {
+        let (client, amount) = self.pending_hold(tx, release_client)?;
+        if let Some(account) = self.accounts.get_mut(&client) {
+            self.ledger.post(
+                account,
+                JournalEntry::new(
+                    tx,
+                    TransactionKind::Release,
+                    LedgerAccount::Held(client),
+                    LedgerAccount::Available(client),
+                    amount,
+                ),
+            )?;
+        }
+        if let Some(Transaction::Hold { released, .. }) = self.transactions.get_mut(&tx) {
+            *released = true;
+        }
+        Ok(())
+    }
+
+    fn process_escrow_hold(
+        &mut self,
+        escrow_hold: Transaction,
+    ) -> Result<(), TransactionValidationError> {
+        if let Transaction::EscrowHold {
+            tx, client, amount, ..
+        } = escrow_hold
+        {
+            self.check_unknown(client, tx)?;
+            let account = match self.accounts.get_mut(&client) {
+                Some(account) => account,
+                None => {
+                    return Err(TransactionValidationError::MissingAccount { client, tx });
+                }
+            };
+            if account.frozen && !self.rules.is_frozen_exception(client) {
+                return Err(TransactionValidationError::FrozenAccount { client, tx });
+            }
+            if account.available < amount {
+                return Err(TransactionValidationError::InsufficientFunds {
+                    client,
+                    tx,
+                    requested: amount,
+                    available: account.available,
+                });
+            }
+            check_reserve(account, &self.rules, tx, amount)?;
+            self.ledger.post(
+                account,
+                JournalEntry::new(
+                    tx,
+                    TransactionKind::EscrowHold,
+                    LedgerAccount::Available(client),
+                    LedgerAccount::Escrow(client),
+                    amount,
+                ),
+            )?;
+            self.transactions.insert(tx, escrow_hold);
+        }
+        Ok(())
+    }
+
+    /// Returns the escrow hold `tx` to the available funds of the client, or pays it out of the
+    /// platform when `payee` is set. An escrow hold naming a counterparty can only be paid out
+    /// to that one, and paying out is refused on a frozen account like a capture.
+    fn process_escrow_release(
+        &mut self,
+        tx: TransactionId,
+        release_client: Client,
+        payee: Option<Box<str>>,
+    ) -> Result<(), TransactionValidationError> {
+        let invalid = TransactionValidationError::InvalidTransaction {
+            client: release_client,
+            tx,
+        };
+        let (client, amount) = match self.transactions.get(&tx) {
+            Some(Transaction::EscrowHold {
+                client,
+                amount,
+                released: false,
+                counterparty,
+                ..
+            }) if *client == release_client => {
+                if let (Some(named), Some(payee)) = (counterparty, &payee) {
+                    if named != payee {
+                        return Err(invalid);
+                    }
+                }
+                (*client, *amount)
+            }
+            _ => return Err(invalid),
+        };
+        let account = match self.accounts.get_mut(&client) {
+            Some(account) => account,
+            None => return Err(TransactionValidationError::MissingAccount { client, tx }),
+        };
+        let credit = match payee {
+            Some(_) => {
+                if account.frozen && !self.rules.is_frozen_exception(client) {
+                    return Err(TransactionValidationError::FrozenAccount { client, tx });
+                }
+                LedgerAccount::Suspense
+            }
+            None => LedgerAccount::Available(client),
+        };
+        self.ledger.post(
+            account,
+            JournalEntry::new(
+                tx,
+                TransactionKind::EscrowRelease,
+                LedgerAccount::Escrow(client),
+                credit,
+                amount,
+            ),
+        )?;
+        if let Some(Transaction::EscrowHold {
+            released,
+            paid_out,
+            counterparty,
+            ..
+        }) = self.transactions.get_mut(&tx)
+        {
+            *released = true;
+            if payee.is_some() {
+                *paid_out = true;
+                *counterparty = payee;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets the amount of a stored deposit or withdrawal to `corrected`, or voids it when `None`,
+    /// and posts the difference as a correction. The corrected transaction must pass the rules
+    /// again, and a correction taking funds from the account is checked like a withdrawal.
+    fn process_correction(
+        &mut self,
+        tx: TransactionId,
+        correction_client: Client,
+        corrected: Option<Amount>,
+    ) -> Result<(), TransactionValidationError> {
+        let (deposit, client, amount, dispute, reversed) = match self.transactions.get(&tx) {
+            Some(Transaction::Deposit {
+                client,
+                amount,
+                dispute,
+                reversed,
+                ..
+            }) => (true, *client, *amount, *dispute, *reversed),
+            Some(Transaction::Withdrawal {
+                client,
+                amount,
+                dispute,
+                reversed,
+                ..
+            }) => (false, *client, *amount, *dispute, *reversed),
+            _ => {
+                return Err(TransactionValidationError::InvalidTransaction {
+                    client: correction_client,
+                    tx,
+                })
+            }
+        };
+        if client != correction_client
+            || matches!(dispute, DisputeState::Open | DisputeState::ChargedBack)
+        {
+            return Err(TransactionValidationError::InvalidTransaction {
+                client: correction_client,
+                tx,
+            });
+        }
+        if reversed {
+            return Err(TransactionValidationError::Reversed { client, tx });
+        }
+        if let Some(corrected) = corrected {
+            let transaction = if deposit {
+                Transaction::new_deposit(client, tx, corrected)?
+            } else {
+                Transaction::new_withdrawal(client, tx, corrected)?
+            };
+            self.rules.check(&transaction)?;
+        }
+
+        let account = self
+            .accounts
+            .get_mut(&client)
+            .ok_or(TransactionValidationError::MissingAccount { client, tx })?;
+        if account.frozen && !self.rules.is_frozen_exception(client) {
+            return Err(TransactionValidationError::FrozenAccount { client, tx });
+        }
+        let corrected_amount = corrected.unwrap_or(Amount::ZERO);
+        // change of the available funds of the client
+        let change = if deposit {
+            corrected_amount - amount
+        } else {
+            amount - corrected_amount
+        };
+        if change < Amount::ZERO && account.available < -change {
+            return Err(TransactionValidationError::InsufficientFunds {
+                client,
+                tx,
+                requested: -change,
+                available: account.available,
+            });
+        }
+        if change < Amount::ZERO {
+            check_reserve(account, &self.rules, tx, -change)?;
+        }
+        if change != Amount::ZERO {
+            let (debit, credit, moved) = if change < Amount::ZERO {
+                (
+                    LedgerAccount::Available(client),
+                    LedgerAccount::Suspense,
+                    -change,
+                )
+            } else {
+                (
+                    LedgerAccount::Suspense,
+                    LedgerAccount::Available(client),
+                    change,
+                )
+            };
+            self.ledger.post(
+                account,
+                JournalEntry::new(tx, TransactionKind::Correction, debit, credit, moved),
+            )?;
+        }
+
+        match self.transactions.get_mut(&tx) {
+            Some(Transaction::Deposit {
+                amount, reversed, ..
+            })
+            | Some(Transaction::Withdrawal {
+                amount, reversed, ..
+            }) => match corrected {
+                Some(corrected) => *amount = corrected,
+                None => *reversed = true,
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Applies `transaction`, after the scheduled transactions that are due. A scheduled
+    /// transaction that isn't due yet is only queued, and only counted once applied.
+    pub fn process_transaction(
+        &mut self,
+        transaction: Transaction,
+    ) -> Result<(), TransactionValidationError> {
+        check_not_reserved(&transaction)?;
+        let transaction = match transaction {
+            Transaction::AdvanceTime { to, .. } => {
+                self.advance_time(to);
+                return Ok(());
+            }
+            Transaction::Recurring { .. } => {
+                self.run_due();
+                return self.expand_recurrence(transaction);
+            }
+            Transaction::ClosePeriod { .. } => {
+                self.run_due();
+                self.close_period();
+                return Ok(());
+            }
+            Transaction::Scheduled {
+                execute_at,
+                transaction,
+            } => {
+                self.run_due();
+                if execute_at > self.now() {
+                    self.scheduled
+                        .entry(execute_at)
+                        .or_default()
+                        .push(*transaction);
+                    return Ok(());
+                }
+                *transaction
+            }
+            transaction => {
+                self.run_due();
+                transaction
+            }
+        };
+        self.ledger.set_time(self.now());
+        self.process_now(transaction)
+    }
+
+    fn process_now(&mut self, transaction: Transaction) -> Result<(), TransactionValidationError> {
+        // replays of a stored transaction are ignored, so that inputs can be processed twice
+        if self.is_replay(&transaction) {
+            return Ok(());
+        }
+        let client = transaction.client();
+        let tx = transaction.tx();
+        let kind = transaction.kind();
+        let previous = self.accounts.get(&client).copied();
+        let undo = self.keeps_undo().then(|| self.undo_for(kind, client, tx));
+
+        if let Err(err) = self.check_and_apply(transaction) {
+            self.count_rejected(client, tx, &err);
+            return Err(err);
+        }
+        self.count_applied(kind, client, tx, previous, undo);
+        Ok(())
+    }
+
+    /// Applies all of `transactions` in order, or none of them when one is rejected, in which
+    /// case only that one is counted as rejected and shown to observers. Scheduled transactions
+    /// and clock moves can't be part of a batch. Observers hear of the
+    /// applied transactions once the whole batch went through. Risk flags raised before the
+    /// rejection are still reported and the risk scorer is not rewound, as with `rollback`.
+    pub fn process_batch(&mut self, transactions: Vec<Transaction>) -> BatchResult {
+        let mut applied: Vec<(
+            TransactionKind,
+            Client,
+            TransactionId,
+            Option<Account>,
+            Undo,
+        )> = vec![];
+        self.run_due();
+        self.ledger.set_time(self.now());
+        for (index, transaction) in transactions.into_iter().enumerate() {
+            if check_not_reserved(&transaction).is_ok() && self.is_replay(&transaction) {
+                continue;
+            }
+            let client = transaction.client();
+            let tx = transaction.tx();
+            let kind = transaction.kind();
+            let previous = self.accounts.get(&client).copied();
+            let undo = self.undo_for(kind, client, tx);
+            let result =
+                check_not_reserved(&transaction).and_then(|()| self.check_and_apply(transaction));
+            if let Err(error) = result {
+                for (.., undo) in applied.into_iter().rev() {
+                    self.revert(undo);
+                }
+                self.count_rejected(client, tx, &error);
+                return BatchResult::Rejected { index, error };
+            }
+            applied.push((kind, client, tx, previous, undo));
+        }
+        let count = applied.len();
+        for (kind, client, tx, previous, undo) in applied {
+            let undo = self.keeps_undo().then_some(undo);
+            self.count_applied(kind, client, tx, previous, undo);
+        }
+        BatchResult::Applied(count)
+    }
+
+    fn count_rejected(
+        &mut self,
+        client: Client,
+        tx: TransactionId,
+        err: &TransactionValidationError,
+    ) {
+        self.rejected += 1;
+        if matches!(err, TransactionValidationError::ClientBlocked { .. }) {
+            self.blocked += 1;
+        }
+        for observer in self.observers.iter_mut() {
+            observer.on_rejected(client, tx, err);
+        }
+    }
+
+    /// Counts an applied transaction, remembers its `undo` and tells observers about it.
+    /// `previous` is the account of `client` before the transaction.
+    fn count_applied(
+        &mut self,
+        kind: TransactionKind,
+        client: Client,
+        tx: TransactionId,
+        previous: Option<Account>,
+        undo: Option<Undo>,
+    ) {
+        *self.applied.entry(kind).or_insert(0) += 1;
+        if let Some(account) = self.accounts.get_mut(&client) {
+            account.counters.revision += 1;
+        }
+        if let Some(undo) = undo {
+            self.undo.push_back(undo);
+            self.undo_end += 1;
+            self.trim_undo();
+        }
+
+        let time = self.velocity.as_ref().map(|_| self.now());
+        let suspicious = match (self.velocity.as_mut(), self.accounts.get(&client), time) {
+            (Some(velocity), Some(account), Some(time)) => {
+                let before = previous.map_or(Amount::ZERO, |previous| previous.available);
+                // a difference too large for an amount saturates, like the suspense balance
+                let moved = account
+                    .available
+                    .checked_sub(before)
+                    .map_or(Amount::MAX, |moved| moved.abs());
+                velocity.observe(kind, tx, moved, account, time)
+            }
+            _ => None,
+        };
+        if let Some(account) = self.accounts.get(&client) {
+            for observer in self.observers.iter_mut() {
+                if previous.is_none() {
+                    observer.on_account_created(account);
+                }
+                match kind {
+                    TransactionKind::Dispute => observer.on_dispute_opened(tx, account),
+                    TransactionKind::Resolve => observer.on_dispute_resolved(tx, account),
+                    TransactionKind::Chargeback => observer.on_chargeback(tx, account),
+                    _ => {}
+                }
+                if account.frozen && !previous.is_some_and(|previous| previous.frozen) {
+                    observer.on_account_frozen(account);
+                }
+                observer.on_applied(kind, tx, account);
+                if let Some(activity) = &suspicious {
+                    observer.on_suspicious_activity(activity);
+                }
+            }
+        }
+    }
+
+    fn check_and_apply(
+        &mut self,
+        transaction: Transaction,
+    ) -> Result<(), TransactionValidationError> {
+        self.rules.check(&transaction)?;
+        self.check_tier(&transaction)?;
+
+        let client = transaction.client();
+        let tx = transaction.tx();
+        let decision = self
+            .risk_scorer
+            .score(&transaction, self.accounts.get(&client));
+        if let RiskDecision::Veto(reason) = decision {
+            return Err(TransactionValidationError::RiskVeto { client, tx, reason });
+        }
+
+        self.apply_transaction(transaction)?;
+        if let Some(bloom) = self.bloom.as_mut() {
+            bloom.insert(tx);
+        }
+
+        if let RiskDecision::Flag(reason) = decision {
+            report(
+                ErrorEvent::new(
+                    "risk_flag",
+                    "",
+                    format!("transaction {} flagged by risk checks: {}", tx, reason),
+                )
+                .with_transaction(client, tx),
+            );
+            if let Some(account) = self.accounts.get_mut(&client) {
+                account.flagged = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Balance limit and dispute window of the tier of the client, see `Rules::tiers`.
+    fn check_tier(&self, transaction: &Transaction) -> Result<(), TransactionValidationError> {
+        let client = transaction.client();
+        let tx = transaction.tx();
+        let limits = match self.rules.tier_limits(client) {
+            Some(limits) => limits,
+            None => return Ok(()),
+        };
+        match (transaction, limits.max_balance, limits.dispute_window) {
+            (Transaction::Deposit { amount, .. }, Some(limit), _) => {
+                let funds = self
+                    .accounts
+                    .get(&client)
+                    .map_or(Amount::ZERO, Account::total_funds);
+                if funds.checked_add(*amount).is_none_or(|funds| funds > limit) {
+                    return Err(TransactionValidationError::BalanceLimitExceeded {
+                        client,
+                        tx,
+                        limit,
+                    });
+                }
+            }
+            (Transaction::Dispute { .. }, _, Some(window)) => {
+                let deposited = match self.transactions.get(&tx) {
+                    Some(Transaction::Deposit { posted, .. }) => *posted,
+                    _ => None,
+                };
+                if deposited.is_some_and(|time| self.now().saturating_sub(time) > window) {
+                    return Err(TransactionValidationError::DisputeWindowExpired { client, tx });
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn apply_transaction(
+        &mut self,
+        transaction: Transaction,
+    ) -> Result<(), TransactionValidationError> {
+        match transaction {
+            Transaction::Deposit { .. } => {
+                self.process_deposit(transaction)?;
+            }
+            Transaction::Withdrawal { .. } => {
+                self.process_withdrawal(transaction)?;
+            }
+            Transaction::Dispute { tx, client, .. } => {
+                self.process_dispute(tx, client)?;
+            }
+            Transaction::Resolve { tx, client, .. } => {
+                self.process_resolve(tx, client)?;
+            }
+            Transaction::Chargeback { tx, client, .. } => {
+                self.process_chargeback(tx, client)?;
+            }
+            Transaction::Reversal { tx, client, .. } => {
+                self.process_reversal(tx, client)?;
+            }
+            Transaction::ChargebackReversal { tx, client, .. } => {
+                self.process_chargeback_reversal(tx, client)?;
+            }
+            Transaction::Hold { .. } => {
+                self.process_hold(transaction)?;
+            }
+            Transaction::Capture { tx, client, .. } => {
+                self.process_capture(tx, client)?;
+            }
+            Transaction::Release { tx, client, .. } => {
+                self.process_release(tx, client)?;
+            }
+            Transaction::Correction { tx, client, amount } => {
+                self.process_correction(tx, client, amount)?;
+            }
+            Transaction::Adjustment { .. } => {
+                self.process_adjustment(transaction)?;
+            }
+            Transaction::EscrowHold { .. } => {
+                self.process_escrow_hold(transaction)?;
+            }
+            Transaction::EscrowRelease {
+                tx,
+                client,
+                counterparty,
+            } => {
+                self.process_escrow_release(tx, client, counterparty)?;
+            }
+            // only `process_transaction` unwraps these, they end up here from batches
+            Transaction::Scheduled { .. }
+            | Transaction::AdvanceTime { .. }
+            | Transaction::Recurring { .. }
+            | Transaction::ClosePeriod { .. } => {
+                return Err(TransactionValidationError::InvalidTransaction {
+                    client: transaction.client(),
+                    tx: transaction.tx(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::amount;
+    use crate::testkit::Scenario;
+
+    /// Comparable summary of everything `rollback` restores, rejections aren't.
+    fn rollback_state(engine: &PaymentEngine) -> serde_json::Value {
+        let mut state = engine.state();
+        state.applied.sort_by_key(|(kind, _)| kind.name());
+        state.rejected = 0;
+        serde_json::to_value(state).unwrap()
+    }
+
+    #[test]
+    fn rollback_reverts_interleaved_disputes() {
+        let mut engine = PaymentEngine::new();
+        engine.set_undo_depth(16);
+        let transactions = [
+            Transaction::new_deposit(1, 1, amount!(10.0)).unwrap(),
+            Transaction::new_deposit(2, 2, amount!(5.0)).unwrap(),
+            Transaction::new_dispute(1, 1),
+            Transaction::new_withdrawal(2, 3, amount!(1.0)).unwrap(),
+            Transaction::new_dispute(2, 2),
+            Transaction::new_resolve(1, 1),
+            Transaction::new_chargeback(2, 2),
+            Transaction::new_dispute(1, 1),
+            Transaction::new_chargeback(1, 1),
+        ];
+        let mut states = vec![rollback_state(&engine)];
+        for transaction in transactions {
+            engine.process_transaction(transaction).unwrap();
+            states.push(rollback_state(&engine));
+        }
+        // rejected transactions leave nothing to revert
+        assert!(engine
+            .process_transaction(Transaction::new_dispute(1, 1))
+            .is_err());
+
+        assert_eq!(engine.rollback(2), 2);
+        assert_eq!(rollback_state(&engine), states[7]);
+        let account = engine.get_account(1).unwrap();
+        assert_eq!(account.available(), amount!(10.0));
+        assert!(!account.frozen());
+
+        // the dispute reopened after resolving it can be charged back again
+        engine
+            .process_transaction(Transaction::new_dispute(1, 1))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::new_chargeback(1, 1))
+            .unwrap();
+        assert_eq!(rollback_state(&engine), states[9]);
+
+        assert_eq!(engine.rollback(5), 5);
+        assert_eq!(rollback_state(&engine), states[4]);
+        assert_eq!(engine.get_account(2).unwrap().held(), amount!(0.0));
+        assert_eq!(engine.rollback(10), 4);
+        assert_eq!(rollback_state(&engine), states[0]);
+        assert!(engine.get_account(1).is_none());
+    }
+
+    #[test]
+    fn rollback_is_limited_to_the_undo_depth() {
+        let mut engine = PaymentEngine::new();
+        engine.set_storage_mode(StorageMode::Compact);
+        engine.set_undo_depth(2);
+        let mut states = vec![];
+        for transaction in [
+            Transaction::new_deposit(1, 1, amount!(3.0)).unwrap(),
+            Transaction::new_hold(1, 2, amount!(1.0)).unwrap(),
+            Transaction::new_capture(1, 2),
+        ] {
+            engine.process_transaction(transaction).unwrap();
+            states.push(rollback_state(&engine));
+        }
+        assert_eq!(engine.rollback(3), 2);
+        assert_eq!(rollback_state(&engine), states[0]);
+        assert!(engine.get_transaction(2).is_none());
+        engine
+            .process_transaction(Transaction::new_withdrawal(1, 2, amount!(1.0)).unwrap())
+            .unwrap();
+        assert_eq!(engine.get_account(1).unwrap().total(), amount!(2.0));
+    }
+
+    #[test]
+    fn savepoints_outlast_the_undo_depth() {
+        let mut engine = PaymentEngine::new();
+        engine.set_undo_depth(1);
+        engine
+            .process_transaction(Transaction::new_deposit(1, 1, amount!(10.0)).unwrap())
+            .unwrap();
+        let before = rollback_state(&engine);
+
+        let outer = engine.savepoint();
+        engine
+            .process_transaction(Transaction::new_withdrawal(1, 2, amount!(4.0)).unwrap())
+            .unwrap();
+        let inner = engine.savepoint();
+        engine
+            .process_transaction(Transaction::new_deposit(2, 3, amount!(1.0)).unwrap())
+            .unwrap();
+        engine
+            .process_transaction(Transaction::new_dispute(1, 1))
+            .unwrap();
+        assert_eq!(engine.release(inner), Ok(()));
+        engine
+            .process_transaction(Transaction::new_resolve(1, 1))
+            .unwrap();
+
+        assert_eq!(engine.rollback_to(outer), Ok(4));
+        assert_eq!(rollback_state(&engine), before);
+        assert!(engine.get_account(2).is_none());
+        // what was applied before the savepoint is still limited by the undo depth
+        assert_eq!(engine.rollback(1), 0);
+
+        engine.set_undo_depth(4);
+        engine
+            .process_transaction(Transaction::new_deposit(1, 4, amount!(2.0)).unwrap())
+            .unwrap();
+        let savepoint = engine.savepoint();
+        let stale = Savepoint { id: savepoint.id };
+        engine
+            .process_transaction(Transaction::new_deposit(1, 5, amount!(1.0)).unwrap())
+            .unwrap();
+        // rolling back past a savepoint closes it
+        assert_eq!(engine.rollback(2), 2);
+        assert_eq!(
+            engine.rollback_to(stale),
+            Err(SavepointError::Closed(savepoint.id))
+        );
+        let savepoint = engine.savepoint();
+        engine.restore(EngineState::default());
+        assert!(engine.release(savepoint).is_err());
+    }
+
+    #[test]
+    fn simulate_leaves_the_engine_untouched() {
+        let mut engine = PaymentEngine::new();
+        engine.set_storage_mode(StorageMode::Compact);
+        for transaction in [
+            Transaction::new_deposit(1, 1, amount!(10.0)).unwrap(),
+            Transaction::new_deposit(2, 2, amount!(3.0)).unwrap(),
+            Transaction::new_withdrawal(2, 3, amount!(1.0)).unwrap(),
+        ] {
+            engine.process_transaction(transaction).unwrap();
+        }
+        let before = rollback_state(&engine);
+
+        let report = engine.simulate([
+            Transaction::new_dispute(1, 1),
+            Transaction::new_withdrawal(1, 4, amount!(1.0)).unwrap(),
+            Transaction::new_deposit(2, 3, amount!(5.0)).unwrap(),
+            Transaction::new_deposit(3, 5, amount!(2.0)).unwrap(),
+            Transaction::new_chargeback(1, 1),
+        ]);
+        assert_eq!(report.applied, 3);
+        assert_eq!(
+            report.rejections,
+            vec![
+                TransactionValidationError::InsufficientFunds {
+                    client: 1,
+                    tx: 4,
+                    requested: amount!(1.0),
+                    available: amount!(0.0),
+                },
+                TransactionValidationError::Duplicate { client: 2, tx: 3 },
+            ]
+        );
+        let account = report.account(1).unwrap();
+        assert_eq!(account.total(), amount!(0.0));
+        assert!(account.frozen());
+        assert_eq!(report.account(2).unwrap().available(), amount!(2.0));
+        assert_eq!(report.account(3).unwrap().available(), amount!(2.0));
+        assert_eq!(report.accounts.len(), 3);
+
+        assert_eq!(rollback_state(&engine), before);
+        assert_eq!(engine.metrics().rejected, 0);
+    }
+
+    #[test]
+    fn deposit_only() {
+        Scenario::new()
+            .deposit(1, 1, "100")
+            .expect_ok()
+            .expect_available(1, "100");
+    }
+
+    #[test]
+    fn deposit_duplicate_transactions_are_omitted() {
+        let scenario = Scenario::new()
+            .deposit(1, 1, "100")
+            // an identical replay is ignored, a different record with the same id is a conflict
+            .deposit(1, 1, "100")
+            .expect_ok()
+            .deposit(1, 1, "90")
+            .expect_error(TransactionValidationError::ConflictingDuplicate { client: 1, tx: 1 })
+            .withdraw(1, 1, "100")
+            .expect_error(TransactionValidationError::ConflictingDuplicate { client: 1, tx: 1 })
+            .expect_available(1, "100");
+        assert_eq!(
+            scenario
+                .engine()
+                .metrics()
+                .applied(TransactionKind::Deposit),
+            1
+        );
+        assert_eq!(scenario.engine().metrics().rejected, 2);
+    }
+
+    #[test]
+    fn deposit_only_creates_an_account() {
+        Scenario::new()
+            .withdraw(1, 1, "100")
+            .dispute(1, 1)
+            .resolve(1, 1)
+            .chargeback(1, 1)
+            .expect_no_account(1)
+            .deposit(1, 1, "100")
+            .expect_ok()
+            .expect_total(1, "100");
+    }
+
+    #[test]
+    fn withdrawal_decreses_available_funds() {
+        Scenario::new()
+            .deposit(1, 1, "100")
+            .withdraw(1, 2, "50")
+            .expect_available(1, "50");
+    }
+
+    #[test]
+    fn withdrawal_of_more_funds_than_available_returns_error() {
+        Scenario::new()
+            .deposit(1, 1, "100")
+            .withdraw(1, 2, "150")
+            .expect_err()
+            .expect_available(1, "100");
+    }
+
+    #[test]
+    fn errors_carry_their_context() {
+        let mut engine = PaymentEngine::new();
+        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, amount!(100.0)).unwrap());
+        let err = engine
+            .process_transaction(Transaction::new_withdrawal(1, 2, amount!(150.0)).unwrap())
+            .unwrap_err();
+        assert_eq!(
+            err,
+            TransactionValidationError::InsufficientFunds {
+                client: 1,
+                tx: 2,
+                requested: amount!(150.0),
+                available: amount!(100.0),
+            }
+        );
+        assert_eq!((err.code(), err.number()), ("insufficient_funds", 3));
+
+        let err = engine
+            .process_transaction(Transaction::new_dispute(2, 1))
+            .unwrap_err();
+        assert_eq!(
+            err,
+            TransactionValidationError::InvalidTransaction { client: 2, tx: 1 }
+        );
+        assert_ne!(
+            err.to_string(),
+            TransactionValidationError::DisputeChargeback { client: 2, tx: 1 }.to_string()
+        );
+    }
+
+    #[test]
+    fn dispute_of_non_existing_transaction_returns_error() {
+        Scenario::new().dispute(1, 1).expect_err();
+    }
+
+    #[test]
+    fn dispute_marks_transaction_as_under_dispute() {
+        Scenario::new()
+            .deposit(1, 1, "100")
+            .dispute(1, 1)
+            .expect_ok()
+            .expect_dispute(1, DisputeState::Open)
+            .expect_available(1, "0")
+            .expect_held(1, "100");
+    }
+
+    #[test]
+    fn dispute_duplicate_dispute_does_nothing() {
+        Scenario::new()
+            .deposit(1, 1, "100")
+            .dispute(1, 1)
+            .expect_ok()
+            .expect_available(1, "0")
+            .expect_held(1, "100")
+            .dispute(1, 1)
+            .expect_err()
+            .expect_available(1, "0")
+            .expect_held(1, "100");
+    }
+
+    #[test]
+    fn dispute_transaction_that_was_chargebacked_returns_error() {
+        Scenario::new()
+            .deposit(1, 1, "100")
+            .dispute(1, 1)
+            .expect_ok()
+            .chargeback(1, 1)
+            .expect_ok()
+            .dispute(1, 1)
+            .expect_err();
+    }
+
+    #[test]
+    fn chargeback_of_non_existing_transaction_returns_error() {
+        Scenario::new()
+            .deposit(1, 1, "100")
+            .chargeback(1, 2)
+            .expect_err();
+    }
+
+    #[test]
+    fn chargeback_of_non_disputed_transaction_returns_error() {
+        Scenario::new()
+            .deposit(1, 1, "100")
+            .chargeback(1, 1)
+            .expect_err();
+    }
+
+    #[test]
+    fn chargeback_marks_transaction_as_chargeback() {
+        Scenario::new()
+            .deposit(1, 1, "100")
+            .dispute(1, 1)
+            .chargeback(1, 1)
+            .expect_ok()
+            .expect_dispute(1, DisputeState::ChargedBack);
+    }
+
+    #[test]
+    fn chargeback_freezes_account() {
+        Scenario::new()
+            .deposit(1, 1, "100")
+            .dispute(1, 1)
+            .chargeback(1, 1)
+            .expect_ok()
+            .expect_frozen(1);
+    }
+
+    #[test]
+    fn resolve_of_non_existing_transaction_returns_error() {
+        Scenario::new()
+            .deposit(1, 1, "100")
+            .resolve(1, 2)
+            .expect_err();
+    }
+
+    #[test]
+    fn resolve_of_non_disputed_transaction_returns_error() {
+        Scenario::new()
+            .deposit(1, 1, "100")
+            .resolve(1, 1)
+            .expect_err()
+            .withdraw(1, 1, "100")
+            .resolve(1, 1)
+            .expect_err();
+    }
+
+    #[test]
+    fn resolve_of_chargeback_transaction_returns_error() {
+        Scenario::new()
+            .deposit(1, 1, "100")
+            .dispute(1, 1)
+            .chargeback(1, 1)
+            .resolve(1, 1)
+            .expect_err();
+    }
+
+    #[test]
+    fn resolve_clears_dispute() {
+        Scenario::new()
+            .deposit(1, 1, "100")
+            .dispute(1, 1)
+            .expect_dispute(1, DisputeState::Open)
+            .resolve(1, 1)
+            .expect_ok()
+            .expect_dispute(1, DisputeState::Resolved);
+    }
+
+    #[test]
+    fn dispute_resolve_chargeback_of_mismatched_tx_and_client_returns_error() {
+        Scenario::new()
+            .deposit(1, 1, "100")
+            .dispute(2, 1)
+            .expect_err()
+            .dispute(1, 1)
+            .resolve(2, 1)
+            .expect_err()
+            .chargeback(2, 1)
+            .expect_err();
+    }
+
+    #[test]
+    fn dispute_resolve_of_deposit_with_withdraw() {
+        Scenario::new()
+            .deposit(1, 1, "100")
+            .withdraw(1, 2, "50")
+            .expect_available(1, "50")
+            .expect_held(1, "0")
+            .dispute(1, 1)
+            .expect_available(1, "-50")
+            .expect_held(1, "100")
+            .resolve(1, 1)
+            .expect_available(1, "50")
+            .expect_held(1, "0");
+    }
+
+    #[test]
+    fn dispute_resolve_of_withdraw() {
+        Scenario::new()
+            .deposit(1, 1, "100")
+            .withdraw(1, 2, "50")
+            .expect_available(1, "50")
+            .expect_held(1, "0")
+            .dispute(1, 2)
+            .expect_available(1, "100")
+            .expect_held(1, "-50")
+            .resolve(1, 2)
+            .expect_available(1, "50")
+            .expect_held(1, "0");
+    }
+
+    #[test]
+    fn chargeback_of_deposit() {
+        Scenario::new()
+            .deposit(1, 1, "100")
+            .withdraw(1, 2, "50")
+            .expect_available(1, "50")
+            .expect_held(1, "0")
+            .dispute(1, 1)
+            .expect_available(1, "-50")
+            .expect_held(1, "100")
+            .chargeback(1, 1)
+            .expect_available(1, "-50")
+            .expect_held(1, "0")
+            .expect_frozen(1);
+    }
+
+    #[test]
+    fn frozen_account_only_deposits_works() {
+        Scenario::new()
+            .deposit(1, 1, "100")
+            .deposit(1, 2, "100")
+            .dispute(1, 1)
+            .chargeback(1, 1)
+            .expect_available(1, "100")
+            .expect_frozen(1)
+            .withdraw(1, 3, "100")
+            .expect_err()
+            .deposit(1, 4, "100")
+            .expect_ok()
+            .expect_available(1, "200")
+            .expect_frozen(1);
+    }
+
+    #[test]
+    fn reversal_of_deposit_restores_funds() {
+        Scenario::new()
+            .deposit(1, 1, "100")
+            .deposit(1, 2, "20")
+            .reverse(1, 1)
+            .expect_ok()
+            .expect_available(1, "20")
+            .expect_held(1, "0")
+            .expect_not_frozen(1);
+    }
+
+    #[test]
+    fn reversal_of_withdrawal_restores_funds() {
+        Scenario::new()
+            .deposit(1, 1, "100")
+            .withdraw(1, 2, "40")
+            .reverse(1, 2)
+            .expect_ok()
+            .expect_available(1, "100");
+    }
+
+    #[test]
+    fn reversed_transaction_cannot_be_disputed_or_reversed_again() {
+        Scenario::new()
+            .deposit(1, 1, "100")
+            .reverse(1, 1)
+            .dispute(1, 1)
+            .expect_error(TransactionValidationError::Reversed { client: 1, tx: 1 })
+            .reverse(1, 1)
+            .expect_error(TransactionValidationError::Reversed { client: 1, tx: 1 })
+            .expect_available(1, "0")
+            .expect_held(1, "0");
+    }
+
+    #[test]
+    fn reversal_of_disputed_or_mismatched_transaction_returns_error() {
+        Scenario::new()
+            .deposit(1, 1, "100")
+            .reverse(2, 1)
+            .expect_err()
+            .reverse(1, 2)
+            .expect_err()
+            .dispute(1, 1)
+            .reverse(1, 1)
+            .expect_err()
+            .expect_available(1, "0")
+            .expect_held(1, "100");
+    }
+
+    #[test]
+    fn corrections_post_the_difference_and_update_the_stored_amount() {
+        let mut engine = PaymentEngine::new();
+        engine.keep_journal(true);
+        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, amount!(10.0)).unwrap());
+        let _ =
+            engine.process_transaction(Transaction::new_withdrawal(1, 2, amount!(4.0)).unwrap());
+        let _ = engine.process_transaction(Transaction::new_deposit(1, 3, amount!(1.0)).unwrap());
+        let _ = engine.process_transaction(Transaction::new_dispute(1, 3));
+
+        engine
+            .process_transaction(Transaction::new_correction(1, 1, Some(amount!(12.0))).unwrap())
+            .unwrap();
+        engine
+            .process_transaction(Transaction::new_correction(1, 2, None).unwrap())
+            .unwrap();
+        assert_eq!(engine.get_account(1).unwrap().available(), amount!(12.0));
+        assert_eq!(
+            engine.get_transaction(1).unwrap().amount(),
+            Some(amount!(12.0))
+        );
+        let entries = engine.ledger().entries();
+        let corrections: Vec<&JournalEntry> = entries
+            .iter()
+            .filter(|entry| entry.kind == TransactionKind::Correction)
+            .collect();
+        assert_eq!(corrections.len(), 2);
+        assert_eq!(corrections[0].amount, amount!(2.0));
+        assert_eq!(corrections[1].credit, LedgerAccount::Available(1));
+
+        // the corrected deposit would take more than is still available
+        engine
+            .process_transaction(Transaction::new_withdrawal(1, 4, amount!(5.0)).unwrap())
+            .unwrap();
+        assert!(matches!(
+            engine.process_transaction(
+                Transaction::new_correction(1, 1, Some(amount!(0.5))).unwrap()
+            ),
+            Err(TransactionValidationError::InsufficientFunds { .. })
+        ));
+        for (client, tx, err) in [
+            (1, 2, "reversed"),
+            (1, 3, "invalid_transaction"),
+            (2, 1, "invalid_transaction"),
+        ] {
+            let correction = Transaction::new_correction(client, tx, Some(amount!(1.0))).unwrap();
+            assert_eq!(
+                engine.process_transaction(correction).unwrap_err().code(),
+                err
+            );
+        }
+        assert!(Transaction::new_correction(1, 1, Some(amount!(0.0))).is_err());
+        assert_eq!(engine.metrics().applied(TransactionKind::Correction), 2);
+    }
+
+    #[test]
+    fn adjustments_bypass_the_lock_and_the_funds_check_when_allowed() {
+        let adjustment = |tx, amount| {
+            Transaction::new_adjustment(1, tx, amount, "goodwill".to_string()).unwrap()
+        };
+        let mut engine = PaymentEngine::new();
+        engine.keep_journal(true);
+        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, amount!(5.0)).unwrap());
+        let _ = engine.process_transaction(Transaction::new_dispute(1, 1));
+        let _ = engine.process_transaction(Transaction::new_chargeback(1, 1));
+        assert!(matches!(
+            engine.process_transaction(adjustment(2, amount!(3.0))),
+            Err(TransactionValidationError::AdjustmentsDisabled { tx: 2, .. })
+        ));
+
+        engine.set_allow_adjustments(true);
+        engine
+            .process_transaction(adjustment(2, amount!(3.0)))
+            .unwrap();
+        engine
+            .process_transaction(adjustment(3, amount!(-4.5)))
+            .unwrap();
+        let account = engine.get_account(1).unwrap();
+        assert!(account.frozen());
+        assert_eq!(account.available(), amount!(-1.5));
+        let entry = engine.ledger().entries().last().unwrap();
+        assert_eq!(entry.kind, TransactionKind::Adjustment);
+        assert_eq!(entry.debit, LedgerAccount::Available(1));
+        assert_eq!(entry.amount, amount!(4.5));
+        assert_eq!(
+            engine.get_transaction(2).unwrap().reason(),
+            Some("goodwill")
+        );
+        assert_eq!(
+            crate::invariants::verify_funds(
+                engine.accounts_iter(),
+                engine.transactions_iter(),
+                engine.tombstones(),
+                engine.pruned_funds(),
+            ),
+            vec![]
+        );
+
+        assert!(matches!(
+            Transaction::new_adjustment(1, 4, amount!(1.0), " ".to_string()),
+            Err(TransactionValidationError::MissingReason { tx: 4, .. })
+        ));
+        assert!(Transaction::new_adjustment(1, 4, amount!(0.0), "fee".to_string()).is_err());
+        assert!(matches!(
+            engine.process_transaction(Transaction::new_dispute(1, 2)),
+            Err(TransactionValidationError::InvalidTransaction { .. })
+        ));
+    }
+
+    #[test]
+    fn escrows_are_returned_to_the_client_or_paid_out_to_the_counterparty() {
+        let escrow_hold = |tx, amount, escrow: &str| {
+            Transaction::new_escrow_hold(1, tx, amount, escrow.to_string()).unwrap()
+        };
+        let mut engine = PaymentEngine::new();
+        engine.keep_journal(true);
+        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, amount!(100.0)).unwrap());
+        engine
+            .process_transaction(escrow_hold(2, amount!(30.0), "order-7"))
+            .unwrap();
+        engine
+            .process_transaction(
+                escrow_hold(3, amount!(20.0), "order-7")
+                    .with_counterparty(Some("ACME".to_string())),
+            )
+            .unwrap();
+        engine
+            .process_transaction(escrow_hold(4, amount!(10.0), "deal-2"))
+            .unwrap();
+        assert!(matches!(
+            engine.process_transaction(escrow_hold(5, amount!(50.0), "deal-2")),
+            Err(TransactionValidationError::InsufficientFunds { .. })
+        ));
+        let account = engine.get_account(1).unwrap();
+        assert_eq!(account.available(), amount!(40.0));
+        assert_eq!(account.held(), amount!(0.0));
+        assert_eq!(account.escrowed(), amount!(60.0));
+        assert_eq!(account.total(), amount!(100.0));
+        assert_eq!(
+            engine.escrows(1),
+            BTreeMap::from([("deal-2", amount!(10.0)), ("order-7", amount!(50.0))])
+        );
+
+        engine
+            .process_transaction(Transaction::new_escrow_release(1, 2))
+            .unwrap();
+        // an escrow naming its counterparty can't be paid out to another one
+        let elsewhere =
+            Transaction::new_escrow_release(1, 3).with_counterparty(Some("Other".to_string()));
+        assert!(matches!(
+            engine.process_transaction(elsewhere),
+            Err(TransactionValidationError::InvalidTransaction { tx: 3, .. })
+        ));
+        engine
+            .process_transaction(
+                Transaction::new_escrow_release(1, 3).with_counterparty(Some("ACME".to_string())),
+            )
+            .unwrap();
+        engine
+            .process_transaction(
+                Transaction::new_escrow_release(1, 4).with_counterparty(Some("Shop".to_string())),
+            )
+            .unwrap();
+        assert!(matches!(
+            engine.process_transaction(Transaction::new_escrow_release(1, 2)),
+            Err(TransactionValidationError::InvalidTransaction { tx: 2, .. })
+        ));
+
+        let account = engine.get_account(1).unwrap();
+        assert_eq!(account.available(), amount!(70.0));
+        assert_eq!(account.escrowed(), amount!(0.0));
+        assert_eq!(account.total(), amount!(70.0));
+        assert!(engine.escrows(1).is_empty());
+        assert_eq!(
+            engine.get_transaction(4).unwrap().counterparty(),
+            Some("Shop")
+        );
+        let payout = engine.ledger().entries().last().unwrap();
+        assert_eq!(payout.kind, TransactionKind::EscrowRelease);
+        assert_eq!(payout.debit, LedgerAccount::Escrow(1));
+        assert_eq!(payout.credit, LedgerAccount::Suspense);
+        assert_eq!(engine.ledger().suspense(), amount!(70.0));
+        assert_eq!(
+            crate::invariants::verify_funds(
+                engine.accounts_iter(),
+                engine.transactions_iter(),
+                engine.tombstones(),
+                engine.pruned_funds(),
+            ),
+            vec![]
+        );
+        assert!(matches!(
+            Transaction::new_escrow_hold(1, 6, amount!(1.0), String::new()),
+            Err(TransactionValidationError::MissingEscrow { tx: 6, .. })
+        ));
+    }
+
+    #[test]
+    fn held_deposits_become_available_as_the_clock_moves() {
+        let mut engine = PaymentEngine::new();
+        engine.set_deposit_hold(Some(100));
+        let scenario = Scenario::with_engine(engine)
+            .deposit(1, 1, "10")
+            .expect_available(1, "0")
+            .expect_held(1, "10")
+            .withdraw(1, 2, "5")
+            .expect_err()
+            .apply(Transaction::new_advance_time(0, 3, 60))
+            .deposit(1, 4, "5")
+            .apply(Transaction::new_advance_time(0, 5, 100))
+            .expect_available(1, "10")
+            .expect_held(1, "5")
+            // disputed and charged back before it's released
+            .dispute(1, 4)
+            .expect_available(1, "5")
+            .expect_held(1, "10")
+            .chargeback(1, 4)
+            .expect_held(1, "5");
+        assert_eq!(scenario.engine().clearing(), 1);
+
+        let mut restored = PaymentEngine::new();
+        restored.restore(scenario.engine().state());
+        Scenario::with_engine(restored)
+            .apply(Transaction::new_advance_time(0, 6, 160))
+            .expect_available(1, "10")
+            .expect_held(1, "0")
+            .expect_total(1, "10")
+            .expect_frozen(1);
+    }
+
+    #[test]
+    fn deposits_offset_negative_balances_first() {
+        let mut engine = PaymentEngine::new();
+        engine.keep_journal(true);
+        engine.set_deposit_hold(Some(100));
+        engine.set_recovery(true);
+        let scenario = Scenario::with_engine(engine)
+            .deposit(1, 1, "10")
+            .apply(Transaction::new_advance_time(0, 2, 100))
+            .withdraw(1, 3, "8")
+            .dispute(1, 1)
+            .expect_available(1, "-8")
+            .expect_held(1, "10");
+        let negative: Vec<Client> = scenario
+            .engine()
+            .negative_accounts()
+            .map(Account::client)
+            .collect();
+        assert_eq!(negative, vec![1]);
+
+        // available at once despite the hold period, and only the rest of the second is held
+        let scenario = scenario
+            .deposit(1, 4, "5")
+            .expect_available(1, "-3")
+            .expect_held(1, "10")
+            .deposit(1, 5, "5")
+            .expect_available(1, "0")
+            .expect_held(1, "12");
+        assert_eq!(scenario.engine().negative_accounts().count(), 0);
+        assert_eq!(scenario.engine().clearing(), 1);
+        let entries: Vec<(TransactionId, LedgerAccount, Amount, bool)> = scenario
+            .engine()
+            .ledger()
+            .entries()
+            .iter()
+            .filter(|entry| entry.tx >= 4)
+            .map(|entry| (entry.tx, entry.credit, entry.amount, entry.recovery))
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                (4, LedgerAccount::Available(1), amount!(5.0), true),
+                (5, LedgerAccount::Held(1), amount!(2.0), false),
+                (5, LedgerAccount::Available(1), amount!(3.0), true),
+            ]
+        );
+    }
+
+    #[test]
+    fn scheduled_transactions_wait_for_the_clock() {
+        let mut engine = PaymentEngine::new();
+        engine.set_clock(Clock::Simulated(100));
+        let deposit = Transaction::new_deposit(1, 1, amount!(10.0)).unwrap();
+        let payout = Transaction::new_withdrawal(1, 2, amount!(4.0)).unwrap();
+        engine
+            .process_transaction(Transaction::new_scheduled(200, payout))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::new_scheduled(150, deposit))
+            .unwrap();
+        assert!(engine.get_account(1).is_none());
+        assert_eq!(engine.metrics().scheduled, 2);
+
+        engine
+            .process_transaction(Transaction::new_advance_time(0, 3, 160))
+            .unwrap();
+        assert_eq!(engine.now(), 160);
+        assert_eq!(engine.get_account(1).unwrap().available(), amount!(10.0));
+        assert_eq!(engine.scheduled(), 1);
+        // the clock never goes back
+        engine.advance_time(120);
+        assert_eq!(engine.now(), 160);
+
+        let mut restored = PaymentEngine::new();
+        restored.restore(engine.state());
+        assert_eq!(restored.scheduled(), 1);
+        restored.advance_time(200);
+        assert_eq!(restored.get_account(1).unwrap().available(), amount!(6.0));
+        assert_eq!(restored.metrics().applied(TransactionKind::Withdrawal), 1);
+        assert_eq!(restored.scheduled(), 0);
+
+        // due right away
+        let deposit = Transaction::new_deposit(2, 4, amount!(1.0)).unwrap();
+        restored
+            .process_transaction(Transaction::new_scheduled(50, deposit))
+            .unwrap();
+        assert_eq!(restored.get_account(2).unwrap().available(), amount!(1.0));
+        let deposit = Transaction::new_deposit(2, 5, amount!(1.0)).unwrap();
+        assert!(matches!(
+            restored.process_batch(vec![Transaction::new_scheduled(500, deposit)]),
+            BatchResult::Rejected { index: 0, .. }
+        ));
+    }
+
+    #[test]
+    fn recurrences_expand_into_scheduled_transactions_with_reserved_ids() {
+        let mut engine = PaymentEngine::new();
+        engine.set_clock(Clock::Simulated(0));
+        let salary = Transaction::new_recurring(1, 1, amount!(10.0), 30, 3, Some(0)).unwrap();
+        let rent = Transaction::new_recurring(1, 2, amount!(-4.0), 30, 2, Some(10)).unwrap();
+        engine.process_transaction(salary).unwrap();
+        engine.process_transaction(rent).unwrap();
+        assert_eq!(engine.get_account(1).unwrap().available(), amount!(10.0));
+        assert_eq!(engine.scheduled(), 4);
+        assert_eq!(engine.metrics().applied(TransactionKind::Recurring), 2);
+
+        engine.advance_time(60);
+        assert_eq!(engine.get_account(1).unwrap().available(), amount!(22.0));
+        assert_eq!(engine.metrics().applied(TransactionKind::Withdrawal), 2);
+        let ids: Vec<TransactionId> = engine.transactions_iter().map(Transaction::tx).collect();
+        assert_eq!(ids.len(), 7);
+        assert_eq!(ids[..2], [1, 2]);
+        assert!(ids[2..].iter().all(|tx| *tx >= FIRST_GENERATED_ID));
+
+        // the ids are taken up where the snapshot left off
+        let mut restored = PaymentEngine::new();
+        restored.set_generated_ids(FIRST_GENERATED_ID + 1, 2);
+        restored.restore(engine.state());
+        let bonus = Transaction::new_recurring(1, 3, amount!(1.0), 0, 1, None).unwrap();
+        restored.process_transaction(bonus).unwrap();
+        assert!(restored.is_known(FIRST_GENERATED_ID + 5));
+
+        assert!(matches!(
+            engine.process_transaction(
+                Transaction::new_deposit(1, FIRST_GENERATED_ID + 9, amount!(1.0)).unwrap()
+            ),
+            Err(TransactionValidationError::ReservedId { .. })
+        ));
+        assert!(matches!(
+            Transaction::new_recurring(1, 4, amount!(1.0), 0, 2, None),
+            Err(TransactionValidationError::InvalidRecurrence { .. })
+        ));
+        assert!(matches!(
+            Transaction::new_recurring(1, 4, amount!(1.0), 60, MAX_OCCURRENCES + 1, None),
+            Err(TransactionValidationError::InvalidRecurrence { .. })
+        ));
+    }
 
     #[test]
-    fn deposit_only() {
+    fn replayed_recurrences_are_ignored_and_reused_ids_rejected() {
         let mut engine = PaymentEngine::new();
-        engine
-            .process_transaction(Transaction::new_deposit(1, 1, dec!(100.0)).unwrap())
-            .unwrap();
-        let account = engine.accounts.get(&(1 as Client)).unwrap();
-        assert_eq!(account.available, dec!(100.0));
+        engine.set_clock(Clock::Simulated(0));
+        let salary = || Transaction::new_recurring(1, 5, amount!(10.0), 0, 1, None).unwrap();
+        engine.process_transaction(salary()).unwrap();
+        engine.process_transaction(salary()).unwrap();
+        assert_eq!(engine.get_account(1).unwrap().available(), amount!(10.0));
+        assert_eq!(engine.metrics().applied(TransactionKind::Recurring), 1);
+
+        assert_eq!(
+            engine.process_transaction(
+                Transaction::new_recurring(1, 5, amount!(10.0), 60, 2, None).unwrap()
+            ),
+            Err(TransactionValidationError::ConflictingDuplicate { client: 1, tx: 5 })
+        );
+        assert_eq!(
+            engine.process_transaction(Transaction::new_deposit(1, 5, amount!(1.0)).unwrap()),
+            Err(TransactionValidationError::ConflictingDuplicate { client: 1, tx: 5 })
+        );
+        assert_eq!(engine.get_account(1).unwrap().available(), amount!(10.0));
+        assert_eq!(engine.scheduled(), 0);
     }
 
     #[test]
-    fn deposit_duplicate_transactions_are_omitted() {
+    fn closing_a_period_records_statements_and_starts_the_counters_over() {
         let mut engine = PaymentEngine::new();
+        engine.keep_journal(true);
+        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, amount!(10.0)).unwrap());
+        let _ =
+            engine.process_transaction(Transaction::new_withdrawal(1, 2, amount!(3.0)).unwrap());
         engine
-            .process_transaction(Transaction::new_deposit(1, 1, dec!(100.0)).unwrap())
+            .process_transaction(Transaction::new_close_period(0, 3))
             .unwrap();
+        let _ = engine.process_transaction(Transaction::new_deposit(1, 4, amount!(1.0)).unwrap());
+        engine.close_period();
 
-        let duplicate_result =
-            engine.process_transaction(Transaction::new_deposit(1, 1, dec!(100.0)).unwrap());
-        assert!(duplicate_result.is_err());
+        assert_eq!(engine.period(), 2);
+        let statements = engine.statements();
+        assert_eq!(statements.len(), 2);
+        assert_eq!(statements[0].period, 0);
+        assert_eq!(statements[0].available, amount!(7.0));
+        assert_eq!(statements[0].credits, amount!(10.0));
+        assert_eq!(statements[0].debits, amount!(3.0));
+        assert_eq!(statements[0].transactions, 2);
+        assert_eq!(statements[1].available, amount!(8.0));
+        assert_eq!(statements[1].credits, amount!(1.0));
+        assert_eq!(statements[1].debits, Amount::ZERO);
+        assert_eq!(statements[1].transactions, 1);
+        let periods: Vec<u32> = engine
+            .ledger()
+            .entries()
+            .iter()
+            .map(|entry| entry.period)
+            .collect();
+        assert_eq!(periods, vec![0, 0, 1]);
 
-        let account = engine.accounts.get(&(1 as Client)).unwrap();
-        assert_eq!(account.available, dec!(100.0));
+        let mut restored = PaymentEngine::new();
+        restored.restore(engine.state());
+        assert_eq!(restored.period(), 2);
+        assert_eq!(restored.statements(), engine.statements());
     }
 
     #[test]
-    fn deposit_only_creates_an_account() {
+    fn periods_close_as_the_clock_moves_into_the_next_month() {
         let mut engine = PaymentEngine::new();
-        let _ = engine.process_transaction(Transaction::new_withdrawal(1, 1, dec!(100.0)).unwrap());
-        let _ = engine.process_transaction(Transaction::new_dispute(1, 1));
-        let _ = engine.process_transaction(Transaction::new_resolve(1, 1));
-        let _ = engine.process_transaction(Transaction::new_chargeback(1, 1));
+        // 2024-01-31T12:00:00Z
+        engine.set_clock(Clock::Simulated(1_706_702_400));
+        engine.set_period_length(Some(PeriodLength::Monthly));
+        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, amount!(5.0)).unwrap());
+        // 2024-02-01T00:00:00Z
+        let payout = Transaction::new_withdrawal(1, 2, amount!(2.0)).unwrap();
+        engine
+            .process_transaction(Transaction::new_scheduled(1_706_745_600, payout))
+            .unwrap();
+        engine.advance_time(1_706_745_599);
+        assert_eq!(engine.period(), 0);
 
-        let account = engine.accounts.get(&(1 as Client));
-        assert!(account.is_none());
+        // 2024-03-01T00:00:00Z, leap day included
+        engine.advance_time(1_709_251_200);
+        assert_eq!(engine.period(), 2);
+        let statements = engine.statements();
+        assert_eq!(statements[0].available, amount!(5.0));
+        assert_eq!(statements[1].available, amount!(3.0));
+        assert_eq!(statements[1].debits, amount!(2.0));
+        assert_eq!(PeriodLength::Weekly.key(0), 0);
+        // 1970-01-05 was the first Monday
+        assert_eq!(PeriodLength::Weekly.key(4 * 86_400), 1);
+    }
 
+    #[test]
+    fn hold_moves_funds_from_available_to_held() {
+        let mut engine = PaymentEngine::new();
+        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, amount!(100.0)).unwrap());
         engine
-            .process_transaction(Transaction::new_deposit(1, 1, dec!(100.0)).unwrap())
+            .process_transaction(Transaction::new_hold(1, 2, amount!(30.0)).unwrap())
             .unwrap();
+
         let account = engine.accounts.get(&(1 as Client)).unwrap();
-        assert_eq!(account.client, 1);
+        assert_eq!(account.available, amount!(70.0));
+        assert_eq!(account.held, amount!(30.0));
+
+        let result =
+            engine.process_transaction(Transaction::new_hold(1, 3, amount!(80.0)).unwrap());
+        assert!(result.is_err());
     }
 
     #[test]
-    fn withdrawal_decreses_available_funds() {
+    fn capture_converts_hold_into_withdrawal() {
         let mut engine = PaymentEngine::new();
-        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, dec!(100.0)).unwrap());
-        let _ = engine.process_transaction(Transaction::new_withdrawal(1, 2, dec!(50.0)).unwrap());
+        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, amount!(100.0)).unwrap());
+        let _ = engine.process_transaction(Transaction::new_hold(1, 2, amount!(30.0)).unwrap());
+        engine
+            .process_transaction(Transaction::new_capture(1, 2))
+            .unwrap();
 
         let account = engine.accounts.get(&(1 as Client)).unwrap();
-        assert_eq!(account.available, dec!(50.0));
+        assert_eq!(account.available, amount!(70.0));
+        assert_eq!(account.held, amount!(0.0));
+        assert!(matches!(
+            engine.transactions.get(&2),
+            Some(Transaction::Withdrawal { .. })
+        ));
+
+        assert!(engine
+            .process_transaction(Transaction::new_capture(1, 2))
+            .is_err());
+        assert!(engine
+            .process_transaction(Transaction::new_release(1, 2))
+            .is_err());
     }
 
     #[test]
-    fn withdrawal_of_more_funds_than_available_returns_error() {
+    fn release_returns_held_funds() {
         let mut engine = PaymentEngine::new();
-        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, dec!(100.0)).unwrap());
-        let result =
-            engine.process_transaction(Transaction::new_withdrawal(1, 2, dec!(150.0)).unwrap());
+        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, amount!(100.0)).unwrap());
+        let _ = engine.process_transaction(Transaction::new_hold(1, 2, amount!(30.0)).unwrap());
+
+        assert!(engine
+            .process_transaction(Transaction::new_release(2, 2))
+            .is_err());
+        engine
+            .process_transaction(Transaction::new_release(1, 2))
+            .unwrap();
 
-        assert!(result.is_err());
         let account = engine.accounts.get(&(1 as Client)).unwrap();
-        assert_eq!(account.available, dec!(100.0));
+        assert_eq!(account.available, amount!(100.0));
+        assert_eq!(account.held, amount!(0.0));
+
+        assert!(engine
+            .process_transaction(Transaction::new_release(1, 2))
+            .is_err());
+        assert!(engine
+            .process_transaction(Transaction::new_capture(1, 2))
+            .is_err());
+        assert!(engine
+            .process_transaction(Transaction::new_dispute(1, 2))
+            .is_err());
     }
 
     #[test]
-    fn dispute_of_non_existing_transaction_returns_error() {
-        let mut engine = PaymentEngine::new();
-        let result = engine.process_transaction(Transaction::new_dispute(1, 1));
-        assert!(result.is_err());
+    fn resolved_transaction_can_be_disputed_again() {
+        Scenario::new()
+            .deposit(1, 1, "100")
+            .dispute(1, 1)
+            .resolve(1, 1)
+            .dispute(1, 1)
+            .expect_ok()
+            .expect_available(1, "0")
+            .expect_held(1, "100");
     }
 
     #[test]
-    fn dispute_marks_transaction_as_under_dispute() {
-        let mut engine = PaymentEngine::new();
-        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, dec!(100.0)).unwrap());
+    fn dispute_state_transitions() {
+        assert_eq!(
+            DisputeState::None
+                .transition(DisputeState::Open, 1, 1)
+                .unwrap(),
+            DisputeState::Open
+        );
+        assert!(DisputeState::None
+            .transition(DisputeState::Resolved, 1, 1)
+            .is_err());
+        assert!(DisputeState::None
+            .transition(DisputeState::ChargedBack, 1, 1)
+            .is_err());
+        assert!(matches!(
+            DisputeState::Open.transition(DisputeState::Open, 1, 1),
+            Err(TransactionValidationError::Duplicate { tx: 1, .. })
+        ));
+        assert!(matches!(
+            DisputeState::ChargedBack.transition(DisputeState::Open, 1, 1),
+            Err(TransactionValidationError::DisputeChargeback { tx: 1, .. })
+        ));
+        assert!(DisputeState::ChargedBack
+            .transition(DisputeState::Resolved, 1, 1)
+            .is_err());
+    }
 
-        engine
-            .process_transaction(Transaction::new_dispute(1, 1))
-            .unwrap();
+    #[test]
+    fn chargeback_reversal_recredits_deposit() {
+        Scenario::new()
+            .deposit(1, 1, "100")
+            .dispute(1, 1)
+            .chargeback(1, 1)
+            .reverse_chargeback(1, 1)
+            .expect_ok()
+            .expect_available(1, "100")
+            .expect_held(1, "0")
+            .expect_frozen(1)
+            .expect_dispute(1, DisputeState::ChargebackReversed)
+            .reverse_chargeback(1, 1)
+            .expect_err()
+            .dispute(1, 1)
+            .expect_err();
+    }
 
-        if let Transaction::Deposit { dispute, .. } = engine.transactions.get(&1).unwrap() {
-            assert_eq!(dispute, &true);
-        } else {
-            assert!(false);
+    #[test]
+    fn chargeback_reversal_unfreezes_account_per_policy() {
+        Scenario::with_engine(PaymentEngine::with_dispute_policy(DisputePolicy {
+            unfreeze_on_chargeback_reversal: true,
+            ..DisputePolicy::default()
+        }))
+        .deposit(1, 1, "100")
+        .dispute(1, 1)
+        .chargeback(1, 1)
+        .reverse_chargeback(1, 1)
+        .expect_ok()
+        .expect_not_frozen(1);
+    }
+
+    #[test]
+    fn chargeback_reversal_of_non_charged_back_transaction_returns_error() {
+        Scenario::new()
+            .deposit(1, 1, "100")
+            .withdraw(1, 2, "10")
+            .reverse_chargeback(1, 1)
+            .expect_err()
+            .dispute(1, 1)
+            .reverse_chargeback(1, 1)
+            .expect_err()
+            .reverse_chargeback(1, 2)
+            .expect_err()
+            .expect_available(1, "-10")
+            .expect_held(1, "100");
+    }
+
+    fn charge_back_deposit(engine: &mut PaymentEngine, tx: TransactionId) {
+        let _ = engine.process_transaction(Transaction::new_deposit(1, tx, amount!(10.0)).unwrap());
+        let _ = engine.process_transaction(Transaction::new_dispute(1, tx));
+        let _ = engine.process_transaction(Transaction::new_chargeback(1, tx));
+    }
+
+    #[test]
+    fn chargeback_threshold_delays_freeze() {
+        let mut engine = PaymentEngine::with_dispute_policy(DisputePolicy {
+            chargeback_threshold: 2,
+            ..DisputePolicy::default()
+        });
+        charge_back_deposit(&mut engine, 1);
+        charge_back_deposit(&mut engine, 2);
+        {
+            let account = engine.accounts.get(&(1 as Client)).unwrap();
+            assert_eq!(account.chargebacks, 2);
+            assert!(!account.frozen);
         }
 
+        charge_back_deposit(&mut engine, 3);
         let account = engine.accounts.get(&(1 as Client)).unwrap();
-        assert_eq!(account.available, dec!(0.0));
-        assert_eq!(account.held, dec!(100.0));
+        assert_eq!(account.chargebacks, 3);
+        assert!(account.frozen);
+        assert!(!account.flagged);
     }
 
     #[test]
-    fn dispute_duplicate_dispute_does_nothing() {
+    fn chargeback_threshold_flags_account_instead_of_freezing() {
+        let mut engine = PaymentEngine::with_dispute_policy(DisputePolicy {
+            chargeback_threshold: 1,
+            chargeback_action: ChargebackAction::Flag,
+            ..DisputePolicy::default()
+        });
+        charge_back_deposit(&mut engine, 1);
+        assert!(!engine.accounts.get(&(1 as Client)).unwrap().flagged);
+
+        charge_back_deposit(&mut engine, 2);
+        let account = engine.accounts.get(&(1 as Client)).unwrap();
+        assert!(account.flagged);
+        assert!(!account.frozen);
+    }
+
+    struct VetoWithdrawals;
+
+    impl RiskScorer for VetoWithdrawals {
+        fn score(&mut self, transaction: &Transaction, account: Option<&Account>) -> RiskDecision {
+            match transaction {
+                Transaction::Withdrawal { .. } if account.is_some() => {
+                    RiskDecision::Veto("no withdrawals".to_string())
+                }
+                Transaction::Deposit { amount, .. } if *amount > amount!(50.0) => {
+                    RiskDecision::Flag("big deposit".to_string())
+                }
+                _ => RiskDecision::Allow,
+            }
+        }
+    }
+
+    #[test]
+    fn risk_scorer_can_veto_and_flag_transactions() {
         let mut engine = PaymentEngine::new();
-        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, dec!(100.0)).unwrap());
+        engine.set_risk_scorer(Box::new(VetoWithdrawals));
 
-        engine
-            .process_transaction(Transaction::new_dispute(1, 1))
-            .unwrap();
+        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, amount!(10.0)).unwrap());
+        assert!(!engine.accounts.get(&(1 as Client)).unwrap().flagged);
+
+        let result =
+            engine.process_transaction(Transaction::new_withdrawal(1, 2, amount!(5.0)).unwrap());
+        assert!(matches!(
+            result,
+            Err(TransactionValidationError::RiskVeto { tx: 2, .. })
+        ));
+        assert!(!engine.transactions.contains_key(&2));
 
+        let _ = engine.process_transaction(Transaction::new_deposit(1, 3, amount!(100.0)).unwrap());
         let account = engine.accounts.get(&(1 as Client)).unwrap();
-        assert_eq!(account.available, dec!(0.0));
-        assert_eq!(account.held, dec!(100.0));
+        assert_eq!(account.available, amount!(110.0));
+        assert!(account.flagged);
+    }
 
-        let result = engine.process_transaction(Transaction::new_dispute(1, 1));
-        assert!(result.is_err());
+    #[test]
+    fn rules_are_checked_before_processing() {
+        let mut engine = PaymentEngine::new();
+        engine.set_rules(Rules {
+            deny_clients: [2].into_iter().collect(),
+            frozen_exceptions: [1].into_iter().collect(),
+            ..Rules::default()
+        });
+
+        let result =
+            engine.process_transaction(Transaction::new_deposit(2, 1, amount!(10.0)).unwrap());
+        assert!(matches!(
+            result,
+            Err(TransactionValidationError::ClientNotAllowed { client: 2, .. })
+        ));
+        assert!(!engine.accounts.contains_key(&(2 as Client)));
+
+        let _ = engine.process_transaction(Transaction::new_deposit(1, 2, amount!(100.0)).unwrap());
+        let _ = engine.process_transaction(Transaction::new_deposit(1, 3, amount!(100.0)).unwrap());
+        let _ = engine.process_transaction(Transaction::new_dispute(1, 2));
+        let _ = engine.process_transaction(Transaction::new_chargeback(1, 2));
+        engine
+            .process_transaction(Transaction::new_withdrawal(1, 4, amount!(50.0)).unwrap())
+            .unwrap();
         let account = engine.accounts.get(&(1 as Client)).unwrap();
-        assert_eq!(account.available, dec!(0.0));
-        assert_eq!(account.held, dec!(100.0));
+        assert!(account.frozen);
+        assert_eq!(account.available, amount!(50.0));
     }
 
     #[test]
-    fn dispute_transaction_that_was_chargebacked_returns_error() {
+    fn tiers_limit_balances_and_dispute_windows() {
         let mut engine = PaymentEngine::new();
-        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, dec!(100.0)).unwrap());
-        let _ = engine
+        engine.set_clock(Clock::Simulated(0));
+        // no journal is kept, the deposits remember when they were made
+        engine.set_storage_mode(StorageMode::Compact);
+        let mut rules: Rules = toml::from_str(
+            r#"
+            [tiers.basic]
+            max_balance = 100.0
+            dispute_window = 3600
+            "#,
+        )
+        .unwrap();
+        rules
+            .assign_tiers([(1, "basic".to_string())].into_iter().collect())
+            .unwrap();
+        engine.set_rules(rules);
+
+        for tx in [1, 2] {
+            let deposit = Transaction::new_deposit(1, tx, amount!(50.0)).unwrap();
+            engine.process_transaction(deposit).unwrap();
+        }
+        let result =
+            engine.process_transaction(Transaction::new_deposit(1, 3, amount!(0.01)).unwrap());
+        assert!(matches!(
+            result,
+            Err(TransactionValidationError::BalanceLimitExceeded { tx: 3, .. })
+        ));
+        // clients outside of a tier have no limit
+        engine
+            .process_transaction(Transaction::new_deposit(2, 4, amount!(500.0)).unwrap())
+            .unwrap();
+
+        engine.advance_time(3600);
+        engine
             .process_transaction(Transaction::new_dispute(1, 1))
             .unwrap();
-        let _ = engine
-            .process_transaction(Transaction::new_chargeback(1, 1))
+        engine.advance_time(3601);
+        let report = engine.simulate([Transaction::new_dispute(1, 2)]);
+        assert!(matches!(
+            report.rejections.as_slice(),
+            [TransactionValidationError::DisputeWindowExpired { tx: 2, .. }]
+        ));
+        let result = engine.process_transaction(Transaction::new_dispute(1, 2));
+        assert!(matches!(
+            result,
+            Err(TransactionValidationError::DisputeWindowExpired { tx: 2, .. })
+        ));
+        engine
+            .process_transaction(Transaction::new_dispute(2, 4))
             .unwrap();
-        let result = engine.process_transaction(Transaction::new_dispute(1, 1));
-        assert!(result.is_err());
     }
 
     #[test]
-    fn chargeback_of_non_existing_transaction_returns_error() {
+    fn withdrawals_keep_the_reserve_available() {
+        let mut rules: Rules = toml::from_str(
+            r#"
+            reserve = 10.0
+
+            [tiers.basic]
+            reserve = 50.0
+            "#,
+        )
+        .unwrap();
+        rules
+            .assign_tiers([(2, "basic".to_string())].into_iter().collect())
+            .unwrap();
+        // a reserve of its own goes before the tier's and the global one
+        rules.clients.entry(3).reserve = Some(Amount::ZERO);
         let mut engine = PaymentEngine::new();
-        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, dec!(100.0)).unwrap());
-        let result = engine.process_transaction(Transaction::new_chargeback(1, 2));
-        assert!(result.is_err());
+        engine.set_rules(rules);
+        let violation = |client, tx, reserve| TransactionValidationError::ReserveViolation {
+            client,
+            tx,
+            reserve,
+        };
+
+        Scenario::with_engine(engine)
+            .deposit(1, 1, "100")
+            .withdraw(1, 2, "90")
+            .expect_ok()
+            .withdraw(1, 3, "0.01")
+            .expect_error(violation(1, 3, amount!(10.0)))
+            .hold(1, 4, "1")
+            .expect_error(violation(1, 4, amount!(10.0)))
+            .deposit(2, 5, "100")
+            .withdraw(2, 6, "60")
+            .expect_error(violation(2, 6, amount!(50.0)))
+            .withdraw(2, 7, "50")
+            .expect_ok()
+            .deposit(3, 8, "100")
+            .withdraw(3, 9, "100")
+            .expect_ok()
+            .expect_total(3, "0");
     }
 
     #[test]
-    fn chargeback_of_non_disputed_transaction_returns_error() {
+    fn corrections_and_escrow_holds_keep_the_reserve_available() {
         let mut engine = PaymentEngine::new();
-        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, dec!(100.0)).unwrap());
-        let result = engine.process_transaction(Transaction::new_chargeback(1, 1));
-        assert!(result.is_err());
+        engine.set_rules(toml::from_str("reserve = 10.0").unwrap());
+        let violation = |tx| TransactionValidationError::ReserveViolation {
+            client: 1,
+            tx,
+            reserve: amount!(10.0),
+        };
+
+        Scenario::with_engine(engine)
+            .deposit(1, 1, "100")
+            .withdraw(1, 2, "50")
+            .expect_ok()
+            // raising the withdrawal or lowering the deposit would leave 5 available
+            .apply(Transaction::new_correction(1, 2, Some(amount!(95.0))).unwrap())
+            .expect_error(violation(2))
+            .apply(Transaction::new_correction(1, 1, Some(amount!(55.0))).unwrap())
+            .expect_error(violation(1))
+            .apply(Transaction::new_correction(1, 1, Some(amount!(60.0))).unwrap())
+            .expect_ok()
+            .expect_available(1, "10")
+            .deposit(1, 3, "20")
+            .apply(
+                Transaction::new_escrow_hold(1, 4, amount!(25.0), "order-1".to_string()).unwrap(),
+            )
+            .expect_error(violation(4))
+            .apply(
+                Transaction::new_escrow_hold(1, 5, amount!(20.0), "order-1".to_string()).unwrap(),
+            )
+            .expect_ok()
+            .expect_available(1, "10")
+            .expect_total(1, "30");
+    }
+
+    #[derive(Default)]
+    struct Events(std::sync::Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl EngineObserver for Events {
+        fn on_account_created(&mut self, account: &Account) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("created {}", account.client));
+        }
+
+        fn on_dispute_opened(&mut self, tx: TransactionId, _account: &Account) {
+            self.0.lock().unwrap().push(format!("dispute {}", tx));
+        }
+
+        fn on_chargeback(&mut self, tx: TransactionId, _account: &Account) {
+            self.0.lock().unwrap().push(format!("chargeback {}", tx));
+        }
+
+        fn on_account_frozen(&mut self, account: &Account) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("frozen {}", account.client));
+        }
+
+        fn on_account_unfrozen(&mut self, account: &Account) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("unfrozen {}", account.client));
+        }
+
+        fn on_rejected(
+            &mut self,
+            _client: Client,
+            tx: TransactionId,
+            _error: &TransactionValidationError,
+        ) {
+            self.0.lock().unwrap().push(format!("rejected {}", tx));
+        }
     }
 
     #[test]
-    fn chargeback_marks_transaction_as_chargeback() {
+    fn observers_are_notified_about_engine_events() {
+        let events = Events::default();
+        let log = events.0.clone();
         let mut engine = PaymentEngine::new();
-        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, dec!(100.0)).unwrap());
+        engine.subscribe(Box::new(events));
+
+        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, amount!(100.0)).unwrap());
+        let _ = engine.process_transaction(Transaction::new_deposit(1, 2, amount!(10.0)).unwrap());
+        let _ =
+            engine.process_transaction(Transaction::new_withdrawal(1, 3, amount!(500.0)).unwrap());
         let _ = engine.process_transaction(Transaction::new_dispute(1, 1));
-        let result = engine.process_transaction(Transaction::new_chargeback(1, 1));
-        assert!(result.is_ok());
+        let _ = engine.process_transaction(Transaction::new_chargeback(1, 1));
+        assert_eq!(engine.unfreeze(1), Some(true));
+        assert_eq!(engine.unfreeze(1), Some(false));
+        assert_eq!(engine.unfreeze(2), None);
+        assert!(!engine.get_account(1).unwrap().frozen());
 
-        let tx = engine.transactions.get(&1).unwrap();
-        if let Transaction::Deposit { chargeback, .. } = tx {
-            assert!(chargeback);
-        } else {
-            assert!(false);
-        }
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                "created 1",
+                "rejected 3",
+                "dispute 1",
+                "chargeback 1",
+                "frozen 1",
+                "unfrozen 1"
+            ]
+        );
     }
 
     #[test]
-    fn chargeback_freezes_account() {
+    fn accounts_stream_sorted_by_client() {
         let mut engine = PaymentEngine::new();
-        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, dec!(100.0)).unwrap());
-        let _ = engine.process_transaction(Transaction::new_dispute(1, 1));
-        let result = engine.process_transaction(Transaction::new_chargeback(1, 1));
-        assert!(result.is_ok());
-        let account = engine.accounts.get(&1).unwrap();
-        assert!(account.frozen);
+        for (tx, client) in [9, 3, 500, 7, 1].into_iter().enumerate() {
+            engine
+                .process_transaction(
+                    Transaction::new_deposit(client, tx as TransactionId, amount!(1.0)).unwrap(),
+                )
+                .unwrap();
+        }
+        let clients = |accounts: Vec<&Account>| -> Vec<Client> {
+            accounts.iter().map(|account| account.client()).collect()
+        };
+        // few accounts for the whole id space are sorted, many for a small range are walked
+        assert_eq!(
+            clients(engine.accounts_iter().collect()),
+            vec![1, 3, 7, 9, 500]
+        );
+        assert_eq!(clients(engine.accounts_in(2..=9).collect()), vec![3, 7, 9]);
+        assert!(engine.accounts_in(10..=499).next().is_none());
+        assert_eq!(engine.get_accounts().last().map(Account::client), Some(500));
     }
 
     #[test]
-    fn resolve_of_non_existing_transaction_returns_error() {
+    fn bloom_filter_keeps_duplicate_checks() {
         let mut engine = PaymentEngine::new();
-        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, dec!(100.0)).unwrap());
-        let result = engine.process_transaction(Transaction::new_resolve(1, 2));
-        assert!(result.is_err());
+        engine
+            .process_transaction(Transaction::new_deposit(1, 1, amount!(10.0)).unwrap())
+            .unwrap();
+        // ids stored before the filter is set are in it as well
+        engine.set_bloom_filter(Some(100));
+        engine
+            .process_transaction(Transaction::new_deposit(1, 2, amount!(5.0)).unwrap())
+            .unwrap();
+        engine
+            .process_transaction(Transaction::new_deposit(1, 2, amount!(5.0)).unwrap())
+            .unwrap();
+        for tx in [1, 2] {
+            assert_eq!(
+                engine
+                    .process_transaction(Transaction::new_withdrawal(1, tx, amount!(1.0)).unwrap()),
+                Err(TransactionValidationError::ConflictingDuplicate { client: 1, tx })
+            );
+        }
+        assert_eq!(engine.get_account(1).unwrap().available(), amount!(15.0));
+
+        let mut restored = PaymentEngine::new();
+        restored.set_bloom_filter(Some(100));
+        restored.restore(engine.state());
+        assert_eq!(
+            restored.process_transaction(Transaction::new_deposit(1, 2, amount!(6.0)).unwrap()),
+            Err(TransactionValidationError::ConflictingDuplicate { client: 1, tx: 2 })
+        );
     }
 
     #[test]
-    fn resolve_of_non_disputed_transaction_returns_error() {
+    fn engines_round_trip_through_serde() {
         let mut engine = PaymentEngine::new();
-        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, dec!(100.0)).unwrap());
-        let result = engine.process_transaction(Transaction::new_resolve(1, 1));
-        assert!(result.is_err());
+        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, amount!(10.0)).unwrap());
+        let _ = engine.process_transaction(Transaction::new_deposit(2, 2, amount!(4.0)).unwrap());
+        let _ = engine.process_transaction(Transaction::new_dispute(2, 2));
+        let _ =
+            engine.process_transaction(Transaction::new_withdrawal(1, 3, amount!(50.0)).unwrap());
 
-        let _ = engine.process_transaction(Transaction::new_withdrawal(1, 1, dec!(100.0)).unwrap());
-        let result = engine.process_transaction(Transaction::new_resolve(1, 1));
-        assert!(result.is_err());
+        let json = serde_json::to_string(&engine).unwrap();
+        let mut copy: PaymentEngine = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_string(&copy).unwrap(), json);
+        assert_eq!(copy.get_account(2).unwrap().held(), amount!(4.0));
+        assert_eq!(copy.metrics().rejected, 1);
+        // the copy carries on with the transactions of the original
+        assert_eq!(
+            copy.process_transaction(Transaction::new_deposit(1, 1, amount!(9.0)).unwrap()),
+            Err(TransactionValidationError::ConflictingDuplicate { client: 1, tx: 1 })
+        );
+        copy.process_transaction(Transaction::new_chargeback(2, 2))
+            .unwrap();
+        assert!(copy.get_account(2).unwrap().frozen());
     }
 
     #[test]
-    fn resolve_of_chargeback_transaction_returns_error() {
+    fn metrics_summarize_engine_state() {
         let mut engine = PaymentEngine::new();
-        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, dec!(100.0)).unwrap());
+        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, amount!(100.0)).unwrap());
+        let _ = engine.process_transaction(Transaction::new_deposit(2, 2, amount!(50.0)).unwrap());
+        let _ = engine.process_transaction(Transaction::new_deposit(2, 3, amount!(5.0)).unwrap());
+        let _ =
+            engine.process_transaction(Transaction::new_withdrawal(1, 4, amount!(500.0)).unwrap());
         let _ = engine.process_transaction(Transaction::new_dispute(1, 1));
-        let _ = engine.process_transaction(Transaction::new_chargeback(1, 1));
-        let result = engine.process_transaction(Transaction::new_resolve(1, 1));
-        assert!(result.is_err());
+        let _ = engine.process_transaction(Transaction::new_dispute(2, 2));
+        let _ = engine.process_transaction(Transaction::new_chargeback(2, 2));
+
+        let metrics = engine.metrics();
+        assert_eq!(metrics.applied(TransactionKind::Deposit), 3);
+        assert_eq!(metrics.applied(TransactionKind::Withdrawal), 0);
+        assert_eq!(metrics.applied(TransactionKind::Dispute), 2);
+        assert_eq!(metrics.applied(TransactionKind::Chargeback), 1);
+        assert_eq!(metrics.rejected, 1);
+        assert_eq!(metrics.total_available, amount!(5.0));
+        assert_eq!(metrics.total_held, amount!(100.0));
+        assert_eq!(metrics.open_disputes, 1);
+        assert_eq!(metrics.frozen_accounts, 1);
     }
 
     #[test]
-    fn resolve_clears_dispute() {
+    fn query_apis_expose_accounts_and_transactions() {
         let mut engine = PaymentEngine::new();
-        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, dec!(100.0)).unwrap());
+        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, amount!(100.0)).unwrap());
+        let _ = engine.process_transaction(Transaction::new_deposit(2, 2, amount!(20.0)).unwrap());
         let _ = engine.process_transaction(Transaction::new_dispute(1, 1));
 
-        let tx = engine.transactions.get(&1).unwrap();
-        if let Transaction::Deposit { dispute, .. } = tx {
-            assert_eq!(*dispute, true);
-        } else {
-            assert!(false);
-        }
+        let account = engine.get_account(1).unwrap();
+        assert_eq!(account.client(), 1);
+        assert_eq!(account.available(), amount!(0.0));
+        assert_eq!(account.held(), amount!(100.0));
+        assert_eq!(account.total(), amount!(100.0));
+        assert!(!account.frozen());
+        assert!(engine.get_account(3).is_none());
 
-        let result = engine.process_transaction(Transaction::new_resolve(1, 1));
-        assert!(result.is_ok());
+        let mut clients: Vec<Client> = engine.iter_accounts().map(|acc| acc.client()).collect();
+        clients.sort_unstable();
+        assert_eq!(clients, vec![1, 2]);
 
-        let tx = engine.transactions.get(&1).unwrap();
-        if let Transaction::Deposit { dispute, .. } = tx {
-            assert_eq!(*dispute, false);
-        } else {
-            assert!(false);
-        }
+        let transaction = engine.get_transaction(1).unwrap();
+        assert_eq!(transaction.kind(), TransactionKind::Deposit);
+        assert_eq!(transaction.amount(), Some(amount!(100.0)));
+        assert!(engine.get_transaction(3).is_none());
     }
 
     #[test]
-    fn dispute_resolve_chargeback_of_mismatched_tx_and_client_returns_error() {
+    fn balance_overflow_returns_error_and_keeps_state() {
         let mut engine = PaymentEngine::new();
-        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, dec!(100.0)).unwrap());
-
-        let result = engine.process_transaction(Transaction::new_dispute(2, 1));
-        assert!(result.is_err());
+        engine
+            .process_transaction(Transaction::new_deposit(1, 1, Amount::MAX).unwrap())
+            .unwrap();
+        let result =
+            engine.process_transaction(Transaction::new_deposit(1, 2, amount!(1.0)).unwrap());
+        assert!(matches!(
+            result,
+            Err(TransactionValidationError::ArithmeticOverflow { .. })
+        ));
+        assert!(!engine.transactions.contains_key(&2));
+        assert_eq!(engine.get_account(1).unwrap().available(), Amount::MAX);
+    }
 
-        let _ = engine.process_transaction(Transaction::new_dispute(1, 1));
+    #[test]
+    fn dispute_overflow_returns_error_and_keeps_state() {
+        let mut engine = PaymentEngine::new();
+        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, Amount::MAX).unwrap());
+        let _ = engine.process_transaction(Transaction::new_withdrawal(1, 2, Amount::MAX).unwrap());
+        let _ = engine.process_transaction(Transaction::new_deposit(1, 3, Amount::MAX).unwrap());
 
-        let result = engine.process_transaction(Transaction::new_resolve(2, 1));
-        assert!(result.is_err());
+        // disputing the withdrawal would push available funds past the maximum
+        let result = engine.process_transaction(Transaction::new_dispute(1, 2));
+        assert!(matches!(
+            result,
+            Err(TransactionValidationError::ArithmeticOverflow { .. })
+        ));
+        let account = engine.get_account(1).unwrap();
+        assert_eq!(account.available(), Amount::MAX);
+        assert_eq!(account.held(), amount!(0.0));
+        if let Some(Transaction::Withdrawal { dispute, .. }) = engine.get_transaction(2) {
+            assert_eq!(*dispute, DisputeState::None);
+        } else {
+            unreachable!();
+        }
+    }
 
-        let result = engine.process_transaction(Transaction::new_chargeback(2, 1));
-        assert!(result.is_err());
+    #[test]
+    fn metrics_saturate_instead_of_overflowing() {
+        let mut engine = PaymentEngine::new();
+        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, Amount::MAX).unwrap());
+        let _ = engine.process_transaction(Transaction::new_deposit(2, 2, Amount::MAX).unwrap());
+        assert_eq!(engine.metrics().total_available, Amount::MAX);
     }
 
     #[test]
-    fn dispute_resolve_of_deposit_with_withdraw() {
+    fn journal_lines_are_only_kept_when_asked_for() {
         let mut engine = PaymentEngine::new();
+        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, amount!(5.0)).unwrap());
+        assert!(engine.ledger().entries().is_empty());
+        assert_eq!(engine.ledger().suspense(), amount!(5.0));
 
-        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, dec!(100.0)).unwrap());
-        let _ = engine.process_transaction(Transaction::new_withdrawal(1, 2, dec!(50.0)).unwrap());
-        {
-            let account = engine.accounts.get(&(1 as Client)).unwrap();
-            assert_eq!(account.available, dec!(50.0));
-            assert_eq!(account.held, dec!(0.0));
-        }
+        engine.keep_journal(true);
+        let _ = engine.process_transaction(Transaction::new_deposit(1, 2, amount!(1.0)).unwrap());
+        assert_eq!(engine.ledger().entries().len(), 1);
+        engine.set_storage_mode(StorageMode::Compact);
+        engine.keep_journal(true);
+        let _ = engine.process_transaction(Transaction::new_deposit(1, 3, amount!(1.0)).unwrap());
+        assert_eq!(engine.ledger().entries().len(), 1);
+        assert_eq!(engine.get_account(1).unwrap().available(), amount!(7.0));
+    }
 
-        let _ = engine.process_transaction(Transaction::new_dispute(1, 1));
-        {
-            let account = engine.accounts.get(&(1 as Client)).unwrap();
-            assert_eq!(account.available, dec!(-50.0));
-            assert_eq!(account.held, dec!(100.0));
+    #[test]
+    fn journal_accounts_for_every_balance() {
+        let mut engine = PaymentEngine::new();
+        engine.keep_journal(true);
+        // entries are stamped with the clock
+        engine.set_clock(Clock::Simulated(0));
+        let transactions = [
+            Transaction::new_deposit(1, 1, amount!(10.0)).unwrap(),
+            Transaction::new_deposit(2, 2, amount!(4.0)).unwrap(),
+            Transaction::new_withdrawal(1, 3, amount!(2.5)).unwrap(),
+            Transaction::new_dispute(1, 1),
+            Transaction::new_resolve(1, 1),
+            Transaction::new_hold(1, 4, amount!(1.0)).unwrap(),
+            Transaction::new_capture(1, 4),
+            Transaction::new_hold(2, 5, amount!(1.5)).unwrap(),
+            Transaction::new_release(2, 5),
+            Transaction::new_reversal(1, 3),
+            Transaction::new_dispute(2, 2),
+            Transaction::new_chargeback(2, 2),
+        ];
+        for transaction in transactions {
+            engine.process_transaction(transaction).unwrap();
         }
 
-        let _ = engine.process_transaction(Transaction::new_resolve(1, 1));
-        {
-            let account = engine.accounts.get(&(1 as Client)).unwrap();
-            assert_eq!(account.available, dec!(50.0));
-            assert_eq!(account.held, dec!(0.0));
+        let entries = engine.ledger().entries();
+        assert_eq!(entries.len(), 12);
+        assert_eq!(
+            entries[3],
+            JournalEntry::new(
+                1,
+                TransactionKind::Dispute,
+                LedgerAccount::Available(1),
+                LedgerAccount::Held(1),
+                amount!(10.0),
+            )
+        );
+        let balance = |account| {
+            entries
+                .iter()
+                .fold(Amount::ZERO, |sum, entry| sum + entry.effect(account))
+        };
+        for account in engine.accounts_iter() {
+            let client = account.client();
+            assert_eq!(
+                account.available(),
+                balance(LedgerAccount::Available(client))
+            );
+            assert_eq!(account.held(), balance(LedgerAccount::Held(client)));
         }
+        let total = engine
+            .accounts_iter()
+            .fold(Amount::ZERO, |sum, account| sum + account.total());
+        assert_eq!(total, amount!(9.0));
+        assert_eq!(engine.ledger().suspense(), total);
+        assert_eq!(balance(LedgerAccount::Suspense), total);
+
+        let mut restored = PaymentEngine::new();
+        restored.restore(engine.state());
+        assert_eq!(restored.ledger().entries(), entries);
+        assert_eq!(restored.ledger().suspense(), total);
     }
 
     #[test]
-    fn dispute_resolve_of_withdraw() {
+    fn captured_holds_keep_their_memo() {
         let mut engine = PaymentEngine::new();
+        engine
+            .process_transaction(Transaction::new_deposit(1, 1, amount!(5.0)).unwrap())
+            .unwrap();
+        engine
+            .process_transaction(
+                Transaction::new_hold(1, 2, amount!(2.0))
+                    .unwrap()
+                    .with_memo(Some("order 42".to_string())),
+            )
+            .unwrap();
+        engine
+            .process_transaction(Transaction::new_capture(1, 2))
+            .unwrap();
+        let captured = engine.get_transaction(2).unwrap();
+        assert_eq!(captured.kind(), TransactionKind::Withdrawal);
+        assert_eq!(captured.memo(), Some("order 42"));
+        assert_eq!(engine.get_account(1).unwrap().total(), amount!(3.0));
+    }
 
-        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, dec!(100.0)).unwrap());
-        let _ = engine.process_transaction(Transaction::new_withdrawal(1, 2, dec!(50.0)).unwrap());
-        {
-            let account = engine.accounts.get(&(1 as Client)).unwrap();
-            assert_eq!(account.available, dec!(50.0));
-            assert_eq!(account.held, dec!(0.0));
-        }
+    #[test]
+    fn compact_storage_only_remembers_withdrawal_ids() {
+        let mut engine = PaymentEngine::new();
+        engine.set_storage_mode(StorageMode::Compact);
+        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, amount!(100.0)).unwrap());
+        let _ =
+            engine.process_transaction(Transaction::new_withdrawal(1, 2, amount!(10.0)).unwrap());
+        let _ = engine.process_transaction(Transaction::new_hold(1, 3, amount!(20.0)).unwrap());
+        let _ = engine.process_transaction(Transaction::new_capture(1, 3));
+        assert!(engine.get_transaction(1).is_some());
+        assert!(engine.get_transaction(2).is_none());
+        assert!(engine.get_transaction(3).is_none());
 
-        let _ = engine.process_transaction(Transaction::new_dispute(1, 2));
-        {
-            let account = engine.accounts.get(&(1 as Client)).unwrap();
-            assert_eq!(account.available, dec!(100.0));
-            assert_eq!(account.held, dec!(-50.0));
+        for tx in [2, 3] {
+            assert!(matches!(
+                engine.process_transaction(Transaction::new_deposit(1, tx, amount!(1.0)).unwrap()),
+                Err(TransactionValidationError::Duplicate { .. })
+            ));
+            assert!(matches!(
+                engine.process_transaction(Transaction::new_dispute(1, tx)),
+                Err(TransactionValidationError::InvalidTransaction { .. })
+            ));
         }
+        engine
+            .process_transaction(Transaction::new_dispute(1, 1))
+            .unwrap();
+        assert_eq!(engine.get_account(1).unwrap().held(), amount!(100.0));
+    }
 
-        let _ = engine.process_transaction(Transaction::new_resolve(1, 2));
-        {
-            let account = engine.accounts.get(&(1 as Client)).unwrap();
-            assert_eq!(account.available, dec!(50.0));
-            assert_eq!(account.held, dec!(0.0));
-        }
+    #[test]
+    fn merge_combines_disjoint_engines() {
+        let mut left = PaymentEngine::new();
+        let _ = left.process_transaction(Transaction::new_deposit(1, 1, amount!(10.0)).unwrap());
+        let _ = left.process_transaction(Transaction::new_dispute(1, 1));
+        let mut right = PaymentEngine::new();
+        let _ = right.process_transaction(Transaction::new_deposit(2, 2, amount!(5.0)).unwrap());
+        let _ = right.process_transaction(Transaction::new_withdrawal(2, 3, amount!(9.0)).unwrap());
+
+        left.merge(right).unwrap();
+        assert_eq!(left.get_account(1).unwrap().held(), amount!(10.0));
+        assert_eq!(left.get_account(2).unwrap().available(), amount!(5.0));
+        assert_eq!(left.metrics().applied(TransactionKind::Deposit), 2);
+        assert_eq!(left.metrics().rejected, 1);
+        left.process_transaction(Transaction::new_resolve(1, 1))
+            .unwrap();
+
+        let mut clash = PaymentEngine::new();
+        let _ = clash.process_transaction(Transaction::new_deposit(3, 2, amount!(1.0)).unwrap());
+        assert_eq!(
+            left.merge(clash),
+            Err(MergeError::ConflictingTransaction(2))
+        );
+        let mut clash = PaymentEngine::new();
+        let _ = clash.process_transaction(Transaction::new_deposit(2, 9, amount!(1.0)).unwrap());
+        assert_eq!(left.merge(clash), Err(MergeError::ConflictingClient(2)));
+        assert!(left.get_transaction(9).is_none());
     }
 
     #[test]
-    fn chargeback_of_deposit() {
+    fn prune_drops_settled_and_old_transactions() {
         let mut engine = PaymentEngine::new();
-        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, dec!(100.0)).unwrap());
-        let _ = engine.process_transaction(Transaction::new_withdrawal(1, 2, dec!(50.0)).unwrap());
-        {
-            let account = engine.accounts.get(&(1 as Client)).unwrap();
-            assert_eq!(account.available, dec!(50.0));
-            assert_eq!(account.held, dec!(0.0));
+        let transactions = [
+            Transaction::new_deposit(1, 1, amount!(10.0)).unwrap(),
+            Transaction::new_deposit(1, 2, amount!(5.0)).unwrap(),
+            Transaction::new_dispute(1, 2),
+            Transaction::new_resolve(1, 2),
+            Transaction::new_deposit(1, 3, amount!(1.0)).unwrap(),
+            Transaction::new_dispute(1, 3),
+            Transaction::new_hold(1, 4, amount!(2.0)).unwrap(),
+            Transaction::new_deposit(2, 10, amount!(3.0)).unwrap(),
+        ];
+        for transaction in transactions {
+            engine.process_transaction(transaction).unwrap();
         }
 
-        let _ = engine.process_transaction(Transaction::new_dispute(1, 1));
-        {
-            let account = engine.accounts.get(&(1 as Client)).unwrap();
-            assert_eq!(account.available, dec!(-50.0));
-            assert_eq!(account.held, dec!(100.0));
-        }
+        let resolved = Retention {
+            resolved: true,
+            ..Retention::default()
+        };
+        assert_eq!(engine.prune(resolved), 1);
+        assert!(engine.get_transaction(2).is_none());
+        assert!(matches!(
+            engine.process_transaction(Transaction::new_deposit(1, 2, amount!(5.0)).unwrap()),
+            Err(TransactionValidationError::Duplicate { tx: 2, .. })
+        ));
 
-        let _ = engine.process_transaction(Transaction::new_chargeback(1, 1));
-        {
-            let account = engine.accounts.get(&(1 as Client)).unwrap();
-            assert_eq!(account.available, dec!(-50.0));
-            assert_eq!(account.held, dec!(0.0));
-            assert_eq!(account.frozen, true);
-        }
+        let old = Retention {
+            older_than: Some(5),
+            ..Retention::default()
+        };
+        assert_eq!(engine.prune(old), 1);
+        assert!(engine.get_transaction(1).is_none());
+        // the open dispute and the pending hold stay whatever their age
+        assert!(engine.get_transaction(3).is_some());
+        assert!(engine.get_transaction(4).is_some());
+        assert_eq!(
+            crate::invariants::verify_funds(
+                engine.accounts_iter(),
+                engine.transactions_iter(),
+                engine.tombstones(),
+                engine.pruned_funds(),
+            ),
+            vec![]
+        );
+
+        let mut restored = PaymentEngine::new();
+        restored.restore(engine.state());
+        assert_eq!(
+            restored.pruned_funds().collect::<Vec<_>>(),
+            engine.pruned_funds().collect::<Vec<_>>()
+        );
     }
 
     #[test]
-    fn frozen_account_only_deposits_works() {
+    fn batches_are_applied_atomically() {
         let mut engine = PaymentEngine::new();
-        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, dec!(100.0)).unwrap());
-        let _ = engine.process_transaction(Transaction::new_deposit(1, 2, dec!(100.0)).unwrap());
-        let _ = engine.process_transaction(Transaction::new_dispute(1, 1));
-        let _ = engine.process_transaction(Transaction::new_chargeback(1, 1));
-        {
-            let account = engine.accounts.get(&(1 as Client)).unwrap();
-            assert_eq!(account.available, dec!(100.0));
-            assert_eq!(account.frozen, true);
-        }
+        let _ = engine.process_transaction(Transaction::new_deposit(1, 1, amount!(10.0)).unwrap());
+        let before = engine.state();
 
-        assert!(engine
-            .process_transaction(Transaction::new_withdrawal(1, 3, dec!(100.0)).unwrap())
-            .is_err());
-        assert!(engine
-            .process_transaction(Transaction::new_deposit(1, 4, dec!(100.0)).unwrap())
-            .is_ok());
-        {
-            let account = engine.accounts.get(&(1 as Client)).unwrap();
-            assert_eq!(account.available, dec!(200.0));
-            assert_eq!(account.frozen, true);
-        }
+        let legs = vec![
+            Transaction::new_withdrawal(1, 2, amount!(4.0)).unwrap(),
+            Transaction::new_deposit(2, 3, amount!(4.0)).unwrap(),
+            Transaction::new_dispute(1, 1),
+            Transaction::new_withdrawal(1, 4, amount!(1.0)).unwrap(),
+        ];
+        assert_eq!(
+            engine.process_batch(legs),
+            BatchResult::Rejected {
+                index: 3,
+                error: TransactionValidationError::InsufficientFunds {
+                    client: 1,
+                    tx: 4,
+                    requested: amount!(1.0),
+                    available: amount!(-4.0),
+                },
+            }
+        );
+        assert_eq!(
+            serde_json::to_value(engine.state()).unwrap(),
+            serde_json::to_value(EngineState {
+                rejected: 1,
+                ..before
+            })
+            .unwrap()
+        );
+
+        let legs = vec![
+            Transaction::new_withdrawal(1, 2, amount!(4.0)).unwrap(),
+            Transaction::new_deposit(2, 3, amount!(4.0)).unwrap(),
+            Transaction::new_deposit(1, 1, amount!(10.0)).unwrap(),
+        ];
+        assert_eq!(engine.process_batch(legs), BatchResult::Applied(2));
+        assert_eq!(engine.get_account(1).unwrap().available(), amount!(6.0));
+        assert_eq!(engine.get_account(2).unwrap().available(), amount!(4.0));
+        assert_eq!(engine.metrics().applied(TransactionKind::Deposit), 2);
     }
 }
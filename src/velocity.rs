@@ -0,0 +1,226 @@
+//! Detection of suspicious sequences of transactions, as opposed to the single transactions
+//! `risk` looks at. Detections don't change the outcome of any transaction, they are handed to
+//! observers as `SuspiciousActivity` for review and suspicious activity reports.
+
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+
+use crate::transactions::{Account, Amount, Client, TransactionId, TransactionKind};
+
+/// Patterns `VelocityDetector` looks for. Every pattern is disabled unless configured.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VelocityRules {
+    /// Deposits of at most this amount count as small deposits.
+    pub small_deposit: Option<Amount>,
+    /// A withdrawal emptying the available balance after this many small deposits in a row is
+    /// reported.
+    pub small_deposits: usize,
+    /// More than this many disputes by one client within `dispute_window_minutes` of the engine
+    /// clock are reported.
+    pub max_disputes: Option<usize>,
+    pub dispute_window_minutes: u64,
+}
+
+impl Default for VelocityRules {
+    fn default() -> Self {
+        Self {
+            small_deposit: None,
+            small_deposits: 5,
+            max_disputes: None,
+            dispute_window_minutes: 60,
+        }
+    }
+}
+
+impl VelocityRules {
+    pub fn enabled(&self) -> bool {
+        self.small_deposit.is_some() || self.max_disputes.is_some()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SuspiciousPattern {
+    /// Small deposits followed by a withdrawal of everything available.
+    Structuring,
+    /// Many disputes in a short time.
+    DisputeVelocity,
+}
+
+impl SuspiciousPattern {
+    pub fn name(self) -> &'static str {
+        match self {
+            SuspiciousPattern::Structuring => "structuring",
+            SuspiciousPattern::DisputeVelocity => "dispute_velocity",
+        }
+    }
+}
+
+/// A detected pattern, reported with the transaction completing it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SuspiciousActivity {
+    pub client: Client,
+    pub tx: TransactionId,
+    /// Engine clock when the transaction was applied, in seconds since the Unix epoch.
+    pub time: u64,
+    pub pattern: SuspiciousPattern,
+    pub detail: String,
+}
+
+/// Follows the applied transactions of every client, see `PaymentEngine::set_velocity_rules`.
+#[derive(Debug, Default)]
+pub struct VelocityDetector {
+    rules: VelocityRules,
+    /// Small deposits since the last larger deposit or withdrawal, per client.
+    small_deposits: HashMap<Client, usize>,
+    /// Times of the disputes within the window, per client, oldest first.
+    disputes: HashMap<Client, VecDeque<u64>>,
+}
+
+impl VelocityDetector {
+    pub fn new(rules: VelocityRules) -> Self {
+        Self {
+            rules,
+            ..Self::default()
+        }
+    }
+
+    /// Takes note of an applied transaction that moved `amount` and left the client's account
+    /// as `account`, and returns the pattern it completes, if any.
+    pub fn observe(
+        &mut self,
+        kind: TransactionKind,
+        tx: TransactionId,
+        amount: Amount,
+        account: &Account,
+        time: u64,
+    ) -> Option<SuspiciousActivity> {
+        let client = account.client();
+        let activity = |pattern, detail| SuspiciousActivity {
+            client,
+            tx,
+            time,
+            pattern,
+            detail,
+        };
+        match kind {
+            TransactionKind::Deposit => {
+                let limit = self.rules.small_deposit?;
+                let count = self.small_deposits.entry(client).or_insert(0);
+                *count = if amount <= limit { *count + 1 } else { 0 };
+                None
+            }
+            TransactionKind::Withdrawal => {
+                let count = self.small_deposits.remove(&client).unwrap_or(0);
+                let limit = self.rules.small_deposit?;
+                if count < self.rules.small_deposits.max(1) || account.available() > Amount::ZERO {
+                    return None;
+                }
+                Some(activity(
+                    SuspiciousPattern::Structuring,
+                    format!(
+                        "withdrawal of {} emptied the account after {} deposits of at most {}",
+                        amount, count, limit
+                    ),
+                ))
+            }
+            TransactionKind::Dispute => {
+                let max_disputes = self.rules.max_disputes?;
+                let window = self.rules.dispute_window_minutes.saturating_mul(60);
+                let recent = self.disputes.entry(client).or_default();
+                while recent
+                    .front()
+                    .is_some_and(|opened| time.saturating_sub(*opened) >= window)
+                {
+                    recent.pop_front();
+                }
+                recent.push_back(time);
+                if recent.len() <= max_disputes {
+                    return None;
+                }
+                Some(activity(
+                    SuspiciousPattern::DisputeVelocity,
+                    format!(
+                        "{} disputes within {} minutes",
+                        recent.len(),
+                        self.rules.dispute_window_minutes
+                    ),
+                ))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::amount;
+    use crate::observer::SuspiciousActivityLog;
+    use crate::transactions::{Clock, PaymentEngine, Transaction};
+
+    #[test]
+    fn small_deposits_emptied_and_dispute_bursts_are_reported() {
+        let mut engine = PaymentEngine::new();
+        engine.set_clock(Clock::Simulated(0));
+        engine.set_velocity_rules(VelocityRules {
+            small_deposit: Some(amount!(10.0)),
+            small_deposits: 3,
+            max_disputes: Some(1),
+            dispute_window_minutes: 10,
+        });
+        let log = SuspiciousActivityLog::default();
+        engine.subscribe(Box::new(log.clone()));
+
+        for tx in 1..=3 {
+            let deposit = Transaction::new_deposit(1, tx, amount!(9.0)).unwrap();
+            engine.process_transaction(deposit).unwrap();
+        }
+        // a partial withdrawal resets the count without being reported
+        let withdrawal = Transaction::new_withdrawal(1, 4, amount!(1.0)).unwrap();
+        engine.process_transaction(withdrawal).unwrap();
+        for tx in 5..=7 {
+            let deposit = Transaction::new_deposit(1, tx, amount!(5.0)).unwrap();
+            engine.process_transaction(deposit).unwrap();
+        }
+        let withdrawal = Transaction::new_withdrawal(1, 8, amount!(41.0)).unwrap();
+        engine.process_transaction(withdrawal).unwrap();
+
+        for tx in 10..=12 {
+            let deposit = Transaction::new_deposit(2, tx, amount!(100.0)).unwrap();
+            engine.process_transaction(deposit).unwrap();
+        }
+        engine
+            .process_transaction(Transaction::new_dispute(2, 10))
+            .unwrap();
+        engine.advance_time(600);
+        // the first dispute is out of the window by now
+        engine
+            .process_transaction(Transaction::new_dispute(2, 11))
+            .unwrap();
+        engine.advance_time(660);
+        engine
+            .process_transaction(Transaction::new_dispute(2, 12))
+            .unwrap();
+
+        let activity = log.activity();
+        assert_eq!(activity.len(), 2);
+        assert_eq!(activity[0].client, 1);
+        assert_eq!(activity[0].tx, 8);
+        assert_eq!(activity[0].time, 0);
+        assert_eq!(activity[0].pattern, SuspiciousPattern::Structuring);
+        assert_eq!(
+            activity[0].detail,
+            format!(
+                "withdrawal of {} emptied the account after 3 deposits of at most {}",
+                amount!(41.0),
+                amount!(10.0)
+            )
+        );
+        assert_eq!(activity[1].client, 2);
+        assert_eq!(activity[1].tx, 12);
+        assert_eq!(activity[1].time, 660);
+        assert_eq!(activity[1].pattern, SuspiciousPattern::DisputeVelocity);
+        assert_eq!(activity[1].detail, "2 disputes within 10 minutes");
+    }
+}
@@ -0,0 +1,155 @@
+//! JavaScript bindings for running the engine in a browser, e.g. in a demo or an upload
+//! validator. Build the library with
+//! `cargo build --lib --target wasm32-unknown-unknown --no-default-features --features wasm`
+//! and run `wasm-bindgen` on the result.
+//!
+//! Inputs are `type,client,tx,amount` CSV documents passed as bytes; reports come back as CSV
+//! text in the default dialect and report format.
+
+use std::error::Error;
+
+use wasm_bindgen::prelude::*;
+
+use crate::export::{write_accounts, ExportOptions};
+use crate::ingest::{check_records, records_from_reader, CsvDialect, PrecisionPolicy};
+use crate::observer::Rejection;
+use crate::transactions::{PaymentEngine, TransactionValidationError};
+
+/// An engine kept across calls, so several documents can be applied one after the other.
+#[wasm_bindgen]
+#[derive(Default)]
+pub struct Engine {
+    engine: PaymentEngine,
+    rejections: Vec<Rejection>,
+}
+
+#[wasm_bindgen]
+impl Engine {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Engine {
+        Engine::default()
+    }
+
+    /// Applies every record of `input` and returns how many were applied; the others can be
+    /// listed with `rejections`.
+    #[wasm_bindgen(js_name = processCsv)]
+    pub fn process_csv(&mut self, input: &[u8]) -> u32 {
+        self.apply(input)
+    }
+
+    /// The account report, as printed by the command line tool.
+    pub fn report(&self) -> Result<String, JsError> {
+        self.report_csv().map_err(js_error)
+    }
+
+    /// Records that couldn't be parsed or were refused, as `client,tx,code,reason` CSV.
+    pub fn rejections(&self) -> Result<String, JsError> {
+        self.rejections_csv().map_err(js_error)
+    }
+}
+
+impl Engine {
+    fn apply(&mut self, input: &[u8]) -> u32 {
+        let precision = PrecisionPolicy::default();
+        let mut applied = 0;
+        for record in records_from_reader(input, &CsvDialect::default()) {
+            let result = record
+                .into_transaction(&precision)
+                .and_then(|transaction| self.engine.process_transaction(transaction));
+            match result {
+                Ok(()) => applied += 1,
+                Err(err) => self.rejections.push(rejection(&err)),
+            }
+        }
+        applied
+    }
+
+    fn report_csv(&self) -> Result<String, Box<dyn Error>> {
+        let mut output = vec![];
+        write_accounts(
+            self.engine.accounts_iter(),
+            &mut output,
+            &ExportOptions::default(),
+        )?;
+        Ok(String::from_utf8(output)?)
+    }
+
+    fn rejections_csv(&self) -> Result<String, Box<dyn Error>> {
+        let mut wtr = csv::Writer::from_writer(vec![]);
+        wtr.write_record(["client", "tx", "code", "reason"])?;
+        for rejection in &self.rejections {
+            wtr.write_record([
+                rejection.client.to_string(),
+                rejection.tx.to_string(),
+                rejection.code.to_string(),
+                rejection.reason.clone(),
+            ])?;
+        }
+        Ok(String::from_utf8(wtr.into_inner()?)?)
+    }
+}
+
+/// Processes `input` with a fresh engine and returns the account report.
+#[wasm_bindgen(js_name = processCsv)]
+pub fn process_csv(input: &[u8]) -> Result<String, JsError> {
+    let mut engine = Engine::new();
+    engine.apply(input);
+    engine.report()
+}
+
+/// Rows of `input` the engine would skip, as `line,reason` CSV; only the header for a clean
+/// document.
+#[wasm_bindgen(js_name = validateCsv)]
+pub fn validate_csv(input: &[u8]) -> Result<String, JsError> {
+    validation_csv(input).map_err(js_error)
+}
+
+fn validation_csv(input: &[u8]) -> Result<String, Box<dyn Error>> {
+    let invalid = check_records(input, &PrecisionPolicy::default(), &CsvDialect::default())?;
+    let mut wtr = csv::Writer::from_writer(vec![]);
+    wtr.write_record(["line", "reason"])?;
+    for record in &invalid {
+        wtr.write_record([record.line.to_string(), record.reason.clone()])?;
+    }
+    Ok(String::from_utf8(wtr.into_inner()?)?)
+}
+
+fn rejection(err: &TransactionValidationError) -> Rejection {
+    Rejection {
+        client: err.client(),
+        tx: err.tx(),
+        code: err.code(),
+        reason: err.to_string(),
+    }
+}
+
+fn js_error(err: Box<dyn Error>) -> JsError {
+    JsError::new(&err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn documents_are_processed_from_bytes() {
+        let mut engine = Engine::new();
+        let applied = engine.process_csv(
+            b"type,client,tx,amount\ndeposit,1,1,2.0\nwithdrawal,1,2,5.0\ndeposit,2,3,-1\n",
+        );
+        assert_eq!(applied, 1);
+        assert_eq!(
+            engine.process_csv(b"type,client,tx,amount\ndeposit,1,4,1\n"),
+            1
+        );
+        let report = engine.report_csv().unwrap();
+        assert_eq!(report.lines().count(), 2);
+        assert!(report.starts_with("client,available,held,total,locked\n1,"));
+        let rejections = engine.rejections_csv().unwrap();
+        assert_eq!(rejections.lines().count(), 3);
+        assert!(rejections.contains("\n1,2,"));
+
+        let invalid = validation_csv(b"type,client,tx,amount\ndeposit,1,1,x\n").unwrap();
+        assert!(invalid.starts_with("line,reason\n2,"));
+    }
+}
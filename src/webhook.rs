@@ -0,0 +1,527 @@
+//! Notifications POSTed to webhooks when an engine charges back a transaction or freezes an
+//! account.
+//!
+//! Every notification is a JSON object with the `event` (`chargeback` or `account_frozen`), the
+//! charged back `tx` for chargebacks and the account as the report lists it. A background thread
+//! delivers them in order, so the engine never waits for a webhook. A webhook that doesn't
+//! answer with a 2xx status is tried again after a backoff doubling each time, and a
+//! notification it never accepts is appended to the dead-letter file as a JSON line, as are the
+//! notifications that find the delivery queue full. Requests are plain HTTP/1.1 over TCP, https
+//! endpoints need a proxy in front of them.
+
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, LineWriter, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::diagnostics::{report, ErrorEvent};
+use crate::observer::EngineObserver;
+use crate::transactions::{Account, TransactionId};
+
+/// Longest wait for a webhook to accept a connection, the request or to answer it.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest wait between two attempts, however many retries came before.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// What the webhooks are told, the body of the request.
+#[derive(Debug, Clone, Serialize)]
+pub struct Notification {
+    pub event: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tx: Option<TransactionId>,
+    pub account: Account,
+}
+
+/// A notification that wasn't delivered, as the line written to the dead-letter file.
+#[derive(Serialize)]
+struct Undelivered<'a> {
+    url: &'a str,
+    reason: String,
+    notification: &'a Notification,
+}
+
+/// `http://host[:port][/path]` URL of a webhook, IPv6 hosts being written in brackets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+    url: String,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl std::str::FromStr for Endpoint {
+    type Err = String;
+
+    fn from_str(url: &str) -> Result<Self, Self::Err> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("webhook {} is not an http:// URL", url))?;
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.strip_prefix('[') {
+            // the colons of IPv6 addresses are inside the brackets
+            Some(bracketed) => {
+                let (host, rest) = bracketed
+                    .split_once(']')
+                    .ok_or_else(|| format!("webhook {} has an unclosed [", url))?;
+                match rest.strip_prefix(':') {
+                    Some(port) => (host, Some(port)),
+                    None if rest.is_empty() => (host, None),
+                    None => return Err(format!("webhook {} has an invalid port", url)),
+                }
+            }
+            None => match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        let port = match port {
+            Some(port) => port
+                .parse()
+                .map_err(|_| format!("webhook {} has an invalid port", url))?,
+            None => 80,
+        };
+        if host.is_empty() {
+            return Err(format!("webhook {} names no host", url));
+        }
+        Ok(Self {
+            url: url.to_string(),
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+impl Endpoint {
+    fn post(&self, body: &str) -> io::Result<()> {
+        let address = (self.host.as_str(), self.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "host has no address"))?;
+        let mut stream = TcpStream::connect_timeout(&address, TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        // IPv6 hosts keep their brackets in the Host header
+        let host = if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path,
+            host,
+            self.port,
+            body.len(),
+            body
+        )?;
+        stream.flush()?;
+        let mut status_line = String::new();
+        BufReader::new(stream).read_line(&mut status_line)?;
+        match status_line.split_whitespace().nth(1).map(str::parse::<u16>) {
+            Some(Ok(status)) if (200..300).contains(&status) => Ok(()),
+            Some(Ok(status)) => Err(io::Error::other(format!("answered {}", status))),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected answer {:?}", status_line.trim_end()),
+            )),
+        }
+    }
+}
+
+/// How often and how patiently a notification is delivered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retry {
+    /// Attempts after the first one.
+    pub retries: u32,
+    /// Wait before the first retry, doubled for each following one.
+    pub backoff: Duration,
+}
+
+enum Message {
+    Notify(Notification),
+    /// Sent by `finish` after the last notification.
+    Finish,
+}
+
+/// The dead-letter file, shared by the engine thread writing the notifications the queue has
+/// no room for and the delivery thread writing those no attempt delivered.
+type DeadLetter = Arc<Mutex<Option<LineWriter<File>>>>;
+
+/// Observer posting a notification to every webhook for each chargeback and frozen account of
+/// the engines it is subscribed to; clones share the same delivery thread.
+#[derive(Clone)]
+pub struct WebhookNotifier {
+    sender: SyncSender<Message>,
+    endpoints: Arc<Vec<Endpoint>>,
+    dead_letter: DeadLetter,
+    worker: Arc<Mutex<Option<JoinHandle<()>>>>,
+}
+
+impl WebhookNotifier {
+    /// Notifier of `endpoints` holding up to `queue` notifications waiting for delivery, and
+    /// appending those it can't deliver to `dead_letter` when set.
+    pub fn new(
+        endpoints: Vec<Endpoint>,
+        retry: Retry,
+        queue: usize,
+        dead_letter: Option<&Path>,
+    ) -> io::Result<Self> {
+        let dead_letter = match dead_letter {
+            Some(path) => Some(LineWriter::new(
+                OpenOptions::new().create(true).append(true).open(path)?,
+            )),
+            None => None,
+        };
+        let endpoints = Arc::new(endpoints);
+        let dead_letter = Arc::new(Mutex::new(dead_letter));
+        let (sender, receiver) = sync_channel(queue);
+        let worker = {
+            let endpoints = Arc::clone(&endpoints);
+            let dead_letter = Arc::clone(&dead_letter);
+            thread::spawn(move || deliver(receiver, &endpoints, retry, &dead_letter))
+        };
+        Ok(Self {
+            sender,
+            endpoints,
+            dead_letter,
+            worker: Arc::new(Mutex::new(Some(worker))),
+        })
+    }
+
+    /// Waits until every notification so far was delivered or written to the dead-letter
+    /// file, then stops the delivery thread. Notifications sent afterwards are dropped.
+    pub fn finish(&self) {
+        let _ = self.sender.send(Message::Finish);
+        if let Some(worker) = self.worker.lock().unwrap().take() {
+            let _ = worker.join();
+        }
+    }
+
+    fn notify(&self, event: &'static str, tx: Option<TransactionId>, account: &Account) {
+        let notification = Notification {
+            event,
+            tx,
+            account: *account,
+        };
+        if let Err(TrySendError::Full(Message::Notify(notification))) =
+            self.sender.try_send(Message::Notify(notification))
+        {
+            // a webhook that is down mustn't make notifications pile up in memory
+            for endpoint in self.endpoints.iter() {
+                let reason = "the delivery queue is full".to_string();
+                undelivered(&self.dead_letter, endpoint, reason, &notification);
+            }
+        }
+    }
+}
+
+impl EngineObserver for WebhookNotifier {
+    fn on_chargeback(&mut self, tx: TransactionId, account: &Account) {
+        self.notify("chargeback", Some(tx), account);
+    }
+
+    fn on_account_frozen(&mut self, account: &Account) {
+        self.notify("account_frozen", None, account);
+    }
+}
+
+fn deliver(
+    receiver: Receiver<Message>,
+    endpoints: &[Endpoint],
+    retry: Retry,
+    dead_letter: &DeadLetter,
+) {
+    for message in receiver {
+        let notification = match message {
+            Message::Notify(notification) => notification,
+            Message::Finish => return,
+        };
+        let body = match serde_json::to_string(&notification) {
+            Ok(body) => body,
+            Err(err) => {
+                report(ErrorEvent::new(
+                    "webhook_failed",
+                    "unable to encode webhook",
+                    err,
+                ));
+                continue;
+            }
+        };
+        for endpoint in endpoints {
+            if let Err(err) = post_with_retries(endpoint, &body, retry) {
+                undelivered(dead_letter, endpoint, err.to_string(), &notification);
+            }
+        }
+    }
+}
+
+/// Reports that `endpoint` didn't get `notification` and appends it to the dead-letter file.
+fn undelivered(
+    dead_letter: &DeadLetter,
+    endpoint: &Endpoint,
+    reason: String,
+    notification: &Notification,
+) {
+    let mut event = ErrorEvent::new(
+        "webhook_failed",
+        "unable to deliver webhook",
+        format!("{}: {}", endpoint.url, reason),
+    );
+    if let Some(tx) = notification.tx {
+        event = event.with_transaction(notification.account.client(), tx);
+    }
+    report(event);
+    if let Some(file) = dead_letter.lock().unwrap().as_mut() {
+        let undelivered = Undelivered {
+            url: &endpoint.url,
+            reason,
+            notification,
+        };
+        let written = serde_json::to_string(&undelivered)
+            .map_err(io::Error::from)
+            .and_then(|line| writeln!(file, "{}", line));
+        if let Err(err) = written {
+            report(ErrorEvent::new(
+                "write_failed",
+                "unable to write undelivered webhook",
+                err,
+            ));
+        }
+    }
+}
+
+/// Posts `body` to `endpoint`, returning the error of the last attempt if none succeeded.
+fn post_with_retries(endpoint: &Endpoint, body: &str, retry: Retry) -> io::Result<()> {
+    let mut backoff = retry.backoff;
+    let mut attempt = 0;
+    loop {
+        match endpoint.post(body) {
+            Ok(()) => return Ok(()),
+            Err(err) if attempt == retry.retries => return Err(err),
+            Err(err) => log::debug!("webhook {} failed, retrying: {}", endpoint.url, err),
+        }
+        thread::sleep(backoff);
+        backoff = backoff.saturating_mul(2).min(MAX_BACKOFF);
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::amount;
+    use crate::transactions::{
+        ChargebackAction, Client, DisputePolicy, PaymentEngine, Transaction,
+    };
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::sync::mpsc::{channel, Sender};
+
+    const NO_RETRY: Retry = Retry {
+        retries: 0,
+        backoff: Duration::ZERO,
+    };
+
+    /// Reads the next request on `listener` and answers it with `status` once `answer` allows
+    /// it, returning its body.
+    fn serve(listener: &TcpListener, status: u16, answer: impl FnOnce()) -> String {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut length = 0;
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "POST /hooks HTTP/1.1\r\n");
+        loop {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            if line == "\r\n" {
+                break;
+            }
+            if let Some(value) = line.strip_prefix("Content-Length: ") {
+                length = value.trim().parse().unwrap();
+            }
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        answer();
+        write!(reader.get_mut(), "HTTP/1.1 {} Whatever\r\n\r\n", status).unwrap();
+        String::from_utf8(body).unwrap()
+    }
+
+    /// Serves `statuses.len()` requests on a local port, answering each with the next status,
+    /// and returns the webhook URL and the bodies it received.
+    fn listen(statuses: Vec<u16>) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hooks", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            statuses
+                .into_iter()
+                .map(|status| serve(&listener, status, || ()))
+                .collect()
+        });
+        (url, server)
+    }
+
+    /// Charges back a deposit of `client`, its transaction id being the client id.
+    fn charge_back(engine: &mut PaymentEngine, client: Client) {
+        let tx = TransactionId::from(client);
+        for transaction in [
+            Transaction::new_deposit(client, tx, amount!(10.0)).unwrap(),
+            Transaction::new_dispute(client, tx),
+            Transaction::new_chargeback(client, tx),
+        ] {
+            engine.process_transaction(transaction).unwrap();
+        }
+    }
+
+    fn dead_letters(path: &Path) -> Vec<serde_json::Value> {
+        let lines = std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let _ = std::fs::remove_file(path);
+        lines
+    }
+
+    fn dead_letter_path(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "payments-webhooks-{}-{}.jsonl",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn chargebacks_and_freezes_are_posted_as_json() {
+        let (url, server) = listen(vec![200, 204]);
+        let notifier = WebhookNotifier::new(vec![url.parse().unwrap()], NO_RETRY, 8, None).unwrap();
+        let mut engine = PaymentEngine::new();
+        engine.subscribe(Box::new(notifier.clone()));
+        charge_back(&mut engine, 3);
+        notifier.finish();
+
+        let bodies: Vec<serde_json::Value> = server
+            .join()
+            .unwrap()
+            .iter()
+            .map(|body| serde_json::from_str(body).unwrap())
+            .collect();
+        assert_eq!(bodies[0]["event"], "chargeback");
+        assert_eq!(bodies[0]["tx"], 3);
+        assert_eq!(bodies[0]["account"]["client"], 3);
+        assert_eq!(bodies[1]["event"], "account_frozen");
+        assert!(bodies[1].get("tx").is_none());
+        assert_eq!(bodies[1]["account"]["locked"], true);
+    }
+
+    #[test]
+    fn failed_deliveries_are_retried_then_dead_lettered() {
+        // the first attempt is refused, the retry accepted
+        let (flaky, server) = listen(vec![500, 200, 200]);
+        let closed = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}/", listener.local_addr().unwrap())
+        };
+        let path = dead_letter_path("retries");
+        let retry = Retry {
+            retries: 2,
+            backoff: Duration::from_millis(1),
+        };
+        let endpoints = vec![flaky.parse().unwrap(), closed.parse().unwrap()];
+        let notifier = WebhookNotifier::new(endpoints, retry, 8, Some(&path)).unwrap();
+        let mut engine = PaymentEngine::new();
+        engine.subscribe(Box::new(notifier.clone()));
+        charge_back(&mut engine, 3);
+        notifier.finish();
+
+        let bodies = server.join().unwrap();
+        assert_eq!(bodies.len(), 3);
+        assert_eq!(bodies[0], bodies[1]);
+        let dead_letters = dead_letters(&path);
+        assert_eq!(dead_letters.len(), 2);
+        assert_eq!(dead_letters[0]["url"], closed.as_str());
+        assert_eq!(dead_letters[0]["notification"]["event"], "chargeback");
+        assert_eq!(dead_letters[1]["notification"]["event"], "account_frozen");
+    }
+
+    #[test]
+    fn notifications_overflowing_the_queue_are_dead_lettered() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hooks", listener.local_addr().unwrap());
+        let (received, delivering): (Sender<()>, _) = channel();
+        let (release, released) = channel::<()>();
+        // the first notification is held up until the others were sent
+        let server = thread::spawn(move || {
+            let first = serve(&listener, 200, || {
+                received.send(()).unwrap();
+                released.recv().unwrap();
+            });
+            vec![first, serve(&listener, 200, || ())]
+        });
+        let path = dead_letter_path("overflow");
+        let notifier =
+            WebhookNotifier::new(vec![url.parse().unwrap()], NO_RETRY, 1, Some(&path)).unwrap();
+        // flagging instead of freezing, each chargeback is a single notification
+        let mut engine = PaymentEngine::with_dispute_policy(DisputePolicy {
+            chargeback_action: ChargebackAction::Flag,
+            ..DisputePolicy::default()
+        });
+        engine.subscribe(Box::new(notifier.clone()));
+        charge_back(&mut engine, 1);
+        delivering.recv().unwrap();
+        for client in 2..=4 {
+            charge_back(&mut engine, client);
+        }
+        release.send(()).unwrap();
+        notifier.finish();
+
+        let clients: Vec<serde_json::Value> = server
+            .join()
+            .unwrap()
+            .iter()
+            .map(|body| serde_json::from_str::<serde_json::Value>(body).unwrap())
+            .map(|body| body["account"]["client"].clone())
+            .collect();
+        assert_eq!(clients, [1, 2]);
+        let dead_letters = dead_letters(&path);
+        assert_eq!(dead_letters.len(), 2);
+        assert_eq!(dead_letters[0]["url"], url.as_str());
+        assert_eq!(dead_letters[0]["reason"], "the delivery queue is full");
+        assert_eq!(dead_letters[0]["notification"]["account"]["client"], 3);
+        assert_eq!(dead_letters[1]["notification"]["account"]["client"], 4);
+    }
+
+    #[test]
+    fn only_http_urls_are_endpoints() {
+        let endpoint: Endpoint = "http://hooks.internal:9000/payments".parse().unwrap();
+        assert_eq!(endpoint.host, "hooks.internal");
+        assert_eq!(endpoint.port, 9000);
+        assert_eq!(endpoint.path, "/payments");
+        let endpoint: Endpoint = "http://hooks.internal".parse().unwrap();
+        assert_eq!((endpoint.port, endpoint.path.as_str()), (80, "/"));
+        let endpoint: Endpoint = "http://[::1]:8080/hooks".parse().unwrap();
+        assert_eq!((endpoint.host.as_str(), endpoint.port), ("::1", 8080));
+        let endpoint: Endpoint = "http://[fe80::1]".parse().unwrap();
+        assert_eq!((endpoint.host.as_str(), endpoint.port), ("fe80::1", 80));
+        assert!("https://hooks.internal/".parse::<Endpoint>().is_err());
+        assert!("http://hooks.internal:port/".parse::<Endpoint>().is_err());
+        assert!("http://[::1/".parse::<Endpoint>().is_err());
+        assert!("http://[::1]8080/".parse::<Endpoint>().is_err());
+        assert!("http:///".parse::<Endpoint>().is_err());
+    }
+}
@@ -0,0 +1,133 @@
+//! Runs the `payments` binary over every `tests/fixtures/<name>.csv` and compares what it prints
+//! to the checked-in `<name>.expected.csv` (the account report on stdout) and
+//! `<name>.expected.jsonl` (the error events on stderr, without their `reason`, whose wording
+//! and amounts depend on the amount backend). Extra arguments come from `<name>.args`, one per
+//! line.
+//!
+//! Builds with the `fixed-amount` feature skip amounts with more than 4 decimal places, so they
+//! compare with `<name>.fixed-amount.expected.csv` and `.jsonl` where those exist.
+//!
+//! After a deliberate change of the output, regenerate the expected files with
+//! `UPDATE_GOLDEN=1 cargo test --test golden`, and with `--features fixed-amount` as well, then
+//! review the diff.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Not a `PAYMENTS_` variable, those override the configuration of the binary.
+const UPDATE: &str = "UPDATE_GOLDEN";
+
+/// Prefix of the expected files of this build where they differ from the default ones.
+const BACKEND: Option<&str> = if cfg!(feature = "fixed-amount") {
+    Some("fixed-amount")
+} else {
+    None
+};
+
+fn fixtures() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let mut inputs: Vec<PathBuf> = fs::read_dir(&dir)
+        .expect("tests/fixtures exists")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            let name = path.file_name().unwrap().to_string_lossy();
+            name.ends_with(".csv") && !name.ends_with(".expected.csv")
+        })
+        .collect();
+    inputs.sort();
+    inputs
+}
+
+/// `input` with its `.csv` extension replaced by `extension`.
+fn sibling(input: &Path, extension: &str) -> PathBuf {
+    input.with_extension(extension)
+}
+
+/// Stdout and stderr of the binary run over `input`.
+fn run(input: &Path) -> (String, String) {
+    let args = match fs::read_to_string(sibling(input, "args")) {
+        Ok(args) => args
+            .lines()
+            .map(str::trim)
+            .filter(|arg| !arg.is_empty())
+            .map(String::from)
+            .collect(),
+        Err(_) => vec![],
+    };
+    let mut command = Command::new(env!("CARGO_BIN_EXE_payments"));
+    command
+        .args(&args)
+        .args(["--errors-format", "json"])
+        .arg(input)
+        .env_remove("RUST_LOG");
+    // the configuration comes from the arguments alone
+    for (name, _) in std::env::vars_os() {
+        if name.to_string_lossy().starts_with("PAYMENTS_") {
+            command.env_remove(name);
+        }
+    }
+    let output = command.output().expect("the binary runs");
+    assert!(
+        output.status.success(),
+        "{} failed: {}",
+        input.display(),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let errors = String::from_utf8(output.stderr)
+        .unwrap()
+        .lines()
+        .map(|line| {
+            let mut event: serde_json::Value = serde_json::from_str(line)
+                .unwrap_or_else(|_| panic!("{} logged {:?}", input.display(), line));
+            event.as_object_mut().unwrap().remove("reason");
+            format!("{}\n", event)
+        })
+        .collect();
+    (String::from_utf8(output.stdout).unwrap(), errors)
+}
+
+#[test]
+fn outputs_match_the_golden_files() {
+    let update = std::env::var_os(UPDATE).is_some();
+    let mut mismatches = vec![];
+    for input in fixtures() {
+        let (report, errors) = run(&input);
+        for (extension, actual) in [("expected.csv", report), ("expected.jsonl", errors)] {
+            let default = sibling(&input, extension);
+            let specific =
+                BACKEND.map(|backend| sibling(&input, &format!("{}.{}", backend, extension)));
+            if update {
+                match &specific {
+                    // only outputs differing from the default ones get a file of their own
+                    Some(specific) if fs::read_to_string(&default).ok() == Some(actual.clone()) => {
+                        let _ = fs::remove_file(specific);
+                    }
+                    Some(specific) => fs::write(specific, &actual).unwrap(),
+                    None => fs::write(&default, &actual).unwrap(),
+                }
+                continue;
+            }
+            let golden = specific
+                .filter(|specific| specific.exists())
+                .unwrap_or(default);
+            let expected = fs::read_to_string(&golden)
+                .unwrap_or_else(|_| panic!("{} is missing, set {}=1", golden.display(), UPDATE));
+            if actual != expected {
+                mismatches.push(format!(
+                    "{}:\n--- expected\n{}--- actual\n{}",
+                    golden.display(),
+                    expected,
+                    actual
+                ));
+            }
+        }
+    }
+    assert!(
+        mismatches.is_empty(),
+        "outputs differ from the golden files, set {}=1 to regenerate them if the change is \
+         intended\n\n{}",
+        UPDATE,
+        mismatches.join("\n")
+    );
+}